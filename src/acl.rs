@@ -0,0 +1,265 @@
+use std::{net::IpAddr, str::FromStr};
+
+/// An address block in CIDR notation, e.g. `10.0.0.0/8` or `fd00::/8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+/// What to do with a client matched by an ACL rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AclAction {
+    /// Process the query as normal.
+    Allow,
+    /// Silently drop the query.
+    Deny,
+    /// Answer with a REFUSED response code.
+    Refuse,
+}
+
+/// An ordered list of rules, the first matching rule wins.
+#[derive(Debug, Clone)]
+pub struct Acl {
+    rules: Vec<(Cidr, AclAction)>,
+    default: AclAction,
+}
+
+/// Separate ACLs for each kind of service the server offers.
+#[derive(Debug, Clone)]
+pub struct AccessControl {
+    /// Queries which have to be forwarded upstream.
+    pub recursion: Acl,
+    /// Queries which can be answered from local data.
+    pub authoritative: Acl,
+    /// Zone transfer requests.
+    pub transfer: Acl,
+}
+
+impl Cidr {
+    pub fn new(addr: IpAddr, prefix_len: u8) -> anyhow::Result<Self> {
+        let max_len = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix_len > max_len {
+            anyhow::bail!("prefix length {prefix_len} is too long for {addr}");
+        }
+        Ok(Cidr { addr, prefix_len })
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// The address with the bits past the prefix cleared.
+    pub fn network(&self) -> IpAddr {
+        match self.addr {
+            IpAddr::V4(addr) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                IpAddr::V4((u32::from(addr) & mask).into())
+            }
+            IpAddr::V6(addr) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                IpAddr::V6((u128::from(addr) & mask).into())
+            }
+        }
+    }
+
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(addr) & mask
+            }
+            (IpAddr::V4(_), IpAddr::V6(addr)) => match addr.to_ipv4_mapped() {
+                Some(addr) => self.contains(IpAddr::V4(addr)),
+                None => false,
+            },
+            (IpAddr::V6(_), IpAddr::V4(_)) => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('/') {
+            Some((addr, prefix_len)) => Cidr::new(addr.parse()?, prefix_len.parse()?),
+            None => {
+                let addr = s.parse::<IpAddr>()?;
+                Cidr::new(addr, if addr.is_ipv4() { 32 } else { 128 })
+            }
+        }
+    }
+}
+
+impl FromStr for AclAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(AclAction::Allow),
+            "deny" => Ok(AclAction::Deny),
+            "refuse" => Ok(AclAction::Refuse),
+            _ => Err(anyhow::format_err!("unknown acl action {s:?}")),
+        }
+    }
+}
+
+impl Acl {
+    pub fn new(default: AclAction) -> Self {
+        Acl {
+            rules: Vec::new(),
+            default,
+        }
+    }
+
+    pub fn push(&mut self, cidr: Cidr, action: AclAction) {
+        self.rules.push((cidr, action));
+    }
+
+    pub fn check(&self, addr: IpAddr) -> AclAction {
+        self.rules
+            .iter()
+            .find(|(cidr, _)| cidr.contains(addr))
+            .map(|(_, action)| *action)
+            .unwrap_or(self.default)
+    }
+}
+
+impl Default for AccessControl {
+    fn default() -> Self {
+        AccessControl {
+            recursion: Acl::new(AclAction::Allow),
+            authoritative: Acl::new(AclAction::Allow),
+            transfer: Acl::new(AclAction::Refuse),
+        }
+    }
+}
+
+impl AccessControl {
+    /// Add a rule from a `<policy>:<action>:<cidr>` string, e.g. `recursion:refuse:0.0.0.0/0`.
+    pub fn push_rule(&mut self, rule: &str) -> anyhow::Result<()> {
+        let mut parts = rule.splitn(3, ':');
+        let (Some(policy), Some(action), Some(cidr)) = (parts.next(), parts.next(), parts.next())
+        else {
+            anyhow::bail!("acl rule should be <policy>:<action>:<cidr>, got {rule:?}");
+        };
+        let acl = match policy {
+            "recursion" => &mut self.recursion,
+            "authoritative" => &mut self.authoritative,
+            "transfer" => &mut self.transfer,
+            _ => anyhow::bail!("unknown acl policy {policy:?}"),
+        };
+        acl.push(cidr.parse()?, action.parse()?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn cidrs_contain_addresses_in_their_prefix() {
+        let cidr = "10.1.0.0/16".parse::<Cidr>().unwrap();
+        assert!(cidr.contains(addr("10.1.2.3")));
+        assert!(!cidr.contains(addr("10.2.0.1")));
+        assert!(cidr.contains(addr("::ffff:10.1.2.3")));
+        assert!(!cidr.contains(addr("fd00::1")));
+
+        let cidr = "fd00::/8".parse::<Cidr>().unwrap();
+        assert!(cidr.contains(addr("fd12::1")));
+        assert!(!cidr.contains(addr("fe80::1")));
+        assert!(!cidr.contains(addr("10.0.0.1")));
+
+        let cidr = "0.0.0.0/0".parse::<Cidr>().unwrap();
+        assert!(cidr.contains(addr("192.0.2.1")));
+        assert!(cidr.contains(addr("255.255.255.255")));
+
+        let cidr = "192.0.2.1".parse::<Cidr>().unwrap();
+        assert_eq!(cidr.prefix_len(), 32);
+        assert!(cidr.contains(addr("192.0.2.1")));
+        assert!(!cidr.contains(addr("192.0.2.2")));
+        assert_eq!("2001:db8::1".parse::<Cidr>().unwrap().prefix_len(), 128);
+
+        assert_eq!(
+            "192.0.2.77/24".parse::<Cidr>().unwrap().network(),
+            addr("192.0.2.0")
+        );
+        assert_eq!(
+            "2001:db8:ffff::/32".parse::<Cidr>().unwrap().network(),
+            addr("2001:db8::")
+        );
+    }
+
+    #[test]
+    fn invalid_cidrs() {
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("fd00::/129".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/x".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let mut access_control = AccessControl::default();
+        assert_eq!(
+            access_control.recursion.check(addr("192.0.2.1")),
+            AclAction::Allow
+        );
+        assert_eq!(
+            access_control.transfer.check(addr("192.0.2.1")),
+            AclAction::Refuse
+        );
+
+        access_control
+            .push_rule("recursion:allow:10.0.0.0/8")
+            .unwrap();
+        access_control
+            .push_rule("recursion:deny:10.1.0.0/16")
+            .unwrap();
+        access_control
+            .push_rule("recursion:refuse:0.0.0.0/0")
+            .unwrap();
+        access_control.push_rule("transfer:allow:::1").unwrap();
+        let recursion = &access_control.recursion;
+        assert_eq!(recursion.check(addr("10.1.2.3")), AclAction::Allow);
+        assert_eq!(recursion.check(addr("192.0.2.1")), AclAction::Refuse);
+        assert_eq!(recursion.check(addr("::1")), AclAction::Allow);
+        assert_eq!(access_control.transfer.check(addr("::1")), AclAction::Allow);
+        assert_eq!(
+            access_control.authoritative.check(addr("192.0.2.1")),
+            AclAction::Allow
+        );
+    }
+
+    #[test]
+    fn invalid_rules() {
+        let mut access_control = AccessControl::default();
+        assert!(access_control.push_rule("recursion:allow").is_err());
+        assert!(access_control.push_rule("queries:allow:0.0.0.0/0").is_err());
+        assert!(access_control
+            .push_rule("recursion:drop:0.0.0.0/0")
+            .is_err());
+        assert!(access_control.push_rule("recursion:allow:nowhere").is_err());
+    }
+}
@@ -0,0 +1,538 @@
+//! Pi-hole style domain blocking.
+//!
+//! Lists are either hosts files (`0.0.0.0 ads.example.com`) or plain lists with one domain per
+//! line. In plain lists, `example.com` blocks just that name, `*.example.com` blocks every name
+//! beneath it, and `.example.com` blocks the name and everything beneath it.
+
+use std::{
+    collections::HashSet,
+    fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::PathBuf,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::message::{
+    Class, DomainName, Message, Question, RecordType, ResourceRecord, ResourceRecordData,
+    ResponseCode,
+};
+
+/// TTL of synthesized answers, kept short so unblocking takes effect quickly.
+const BLOCKED_TTL: u32 = 2;
+
+/// Names in hosts files which aren't meant to be blocked.
+const HOSTS_FILE_IGNORED: [&str; 5] = [
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+];
+
+/// How to answer a blocked query.
+#[derive(Debug, Clone)]
+pub enum BlockMode {
+    /// Respond with NXDOMAIN.
+    NxDomain,
+    /// Respond with `0.0.0.0` or `::`.
+    Null,
+    /// Respond with the given addresses, or no data if there isn't one for the query type.
+    Sinkhole {
+        v4: Option<Ipv4Addr>,
+        v6: Option<Ipv6Addr>,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct BlocklistConfig {
+    pub blocklists: Vec<PathBuf>,
+    /// Names in these lists are never blocked.
+    pub allowlists: Vec<PathBuf>,
+    pub mode: BlockMode,
+    /// How often to check the lists for changes.
+    pub reload_interval: Duration,
+}
+
+#[derive(Debug)]
+struct DomainList {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    exact: HashSet<DomainName>,
+    /// Names whose subdomains are blocked, stored without the leading `*.`.
+    wildcard: HashSet<DomainName>,
+    subtree: HashSet<DomainName>,
+    /// Queries matched by this list.
+    hits: u64,
+}
+
+/// How much a list is being used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListStats {
+    pub path: PathBuf,
+    /// Names, wildcards and subtrees in the list.
+    pub entries: usize,
+    /// Queries matched by the list since it was first loaded.
+    pub hits: u64,
+}
+
+#[derive(Debug)]
+struct Lists {
+    blocklists: Vec<DomainList>,
+    allowlists: Vec<DomainList>,
+}
+
+#[derive(Debug)]
+pub struct Blocklists {
+    config: BlocklistConfig,
+    lists: Mutex<Lists>,
+    last_refresh: Mutex<Instant>,
+}
+
+impl Default for BlocklistConfig {
+    fn default() -> Self {
+        BlocklistConfig {
+            blocklists: Vec::new(),
+            allowlists: Vec::new(),
+            mode: BlockMode::Null,
+            reload_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+impl FromStr for BlockMode {
+    type Err = anyhow::Error;
+
+    /// Either `nxdomain`, `null`, or a comma-separated list of sinkhole addresses.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nxdomain" => Ok(BlockMode::NxDomain),
+            "null" => Ok(BlockMode::Null),
+            _ => {
+                let (mut v4, mut v6) = (None, None);
+                for addr in s.split(',') {
+                    match addr.parse::<IpAddr>()? {
+                        IpAddr::V4(addr) => v4 = Some(addr),
+                        IpAddr::V6(addr) => v6 = Some(addr),
+                    }
+                }
+                Ok(BlockMode::Sinkhole { v4, v6 })
+            }
+        }
+    }
+}
+
+impl DomainList {
+    /// Read the list at `path`. Lines which can't be parsed are skipped with a warning, so that one
+    /// bad entry doesn't take the rest of the list with it.
+    fn load(path: PathBuf) -> anyhow::Result<Self> {
+        let modified = fs::metadata(&path)?.modified().ok();
+        let contents = fs::read_to_string(&path)?;
+        let mut list = DomainList {
+            path,
+            modified,
+            exact: HashSet::new(),
+            wildcard: HashSet::new(),
+            subtree: HashSet::new(),
+            hits: 0,
+        };
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if let Err(e) = list.add_line(line) {
+                eprintln!(
+                    "blocklist: skipping line {} of {}: {}",
+                    number + 1,
+                    list.path.display(),
+                    e
+                );
+            }
+        }
+        Ok(list)
+    }
+
+    fn add_line(&mut self, line: &str) -> anyhow::Result<()> {
+        let mut tokens = line.split_whitespace().peekable();
+        let is_hosts_line = tokens
+            .peek()
+            .is_some_and(|token| token.parse::<IpAddr>().is_ok());
+        if is_hosts_line {
+            tokens.next();
+            let names = tokens
+                .filter(|name| !HOSTS_FILE_IGNORED.contains(name))
+                .map(DomainName::new)
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            self.exact.extend(names);
+        } else if let Some(entry) = tokens.next() {
+            if let Some(name) = entry.strip_prefix("*.") {
+                self.wildcard.insert(DomainName::new(name)?);
+            } else if let Some(name) = entry.strip_prefix('.') {
+                self.subtree.insert(DomainName::new(name)?);
+            } else {
+                self.exact.insert(DomainName::new(entry)?);
+            }
+        }
+        Ok(())
+    }
+
+    fn entries(&self) -> usize {
+        self.exact.len() + self.wildcard.len() + self.subtree.len()
+    }
+
+    fn contains(&self, name: &DomainName) -> bool {
+        if self.exact.contains(name) {
+            return true;
+        }
+        let mut ancestor = Some(name.clone());
+        while let Some(domain) = ancestor {
+            if self.subtree.contains(&domain) {
+                return true;
+            }
+            if &domain != name && self.wildcard.contains(&domain) {
+                return true;
+            }
+            ancestor = domain.parent();
+        }
+        false
+    }
+}
+
+impl Lists {
+    fn iter(&self) -> impl Iterator<Item = &DomainList> {
+        self.blocklists.iter().chain(self.allowlists.iter())
+    }
+
+    fn iter_mut(&mut self) -> impl Iterator<Item = &mut DomainList> {
+        self.blocklists.iter_mut().chain(self.allowlists.iter_mut())
+    }
+
+    fn is_blocked(&mut self, name: &DomainName) -> bool {
+        if let Some(list) = self.allowlists.iter_mut().find(|l| l.contains(name)) {
+            list.hits += 1;
+            return false;
+        }
+        match self.blocklists.iter_mut().find(|l| l.contains(name)) {
+            Some(list) => {
+                list.hits += 1;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Blocklists {
+    pub fn load(config: BlocklistConfig) -> anyhow::Result<Self> {
+        let blocklists = config
+            .blocklists
+            .iter()
+            .cloned()
+            .map(DomainList::load)
+            .collect::<anyhow::Result<_>>()?;
+        let allowlists = config
+            .allowlists
+            .iter()
+            .cloned()
+            .map(DomainList::load)
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Blocklists {
+            config,
+            lists: Mutex::new(Lists {
+                blocklists,
+                allowlists,
+            }),
+            last_refresh: Mutex::new(Instant::now()),
+        })
+    }
+
+    /// Reload any lists which have changed on disk, if it's time to check again.
+    ///
+    /// Files are checked and read without holding the lock on the lists, so queries aren't held up
+    /// by a slow disk or a large list. Only the swap happens under the lock.
+    pub fn refresh(&self, now: Instant) {
+        {
+            let mut last_refresh = self.last_refresh.lock().unwrap();
+            if now.saturating_duration_since(*last_refresh) < self.config.reload_interval {
+                return;
+            }
+            *last_refresh = now;
+        }
+
+        let loaded: Vec<_> = {
+            let lists = self.lists.lock().unwrap();
+            lists
+                .iter()
+                .map(|list| (list.path.clone(), list.modified))
+                .collect()
+        };
+        let mut reloaded = Vec::new();
+        for (index, (path, modified)) in loaded.into_iter().enumerate() {
+            if fs::metadata(&path).and_then(|m| m.modified()).ok() == modified {
+                continue;
+            }
+            match DomainList::load(path.clone()) {
+                Ok(list) => reloaded.push((index, list)),
+                Err(e) => eprintln!(
+                    "blocklist: failed to reload {}, keeping old entries: {}",
+                    path.display(),
+                    e
+                ),
+            }
+        }
+
+        let mut lists = self.lists.lock().unwrap();
+        for (index, mut list) in reloaded {
+            let old = lists.iter_mut().nth(index).unwrap();
+            list.hits = old.hits;
+            eprintln!(
+                "blocklist: reloaded {} ({} entries, {} hits so far)",
+                list.path.display(),
+                list.entries(),
+                list.hits,
+            );
+            *old = list;
+        }
+    }
+
+    /// The counters for every list, blocklists first.
+    pub fn stats(&self) -> Vec<ListStats> {
+        let lists = self.lists.lock().unwrap();
+        lists
+            .iter()
+            .map(|list| ListStats {
+                path: list.path.clone(),
+                entries: list.entries(),
+                hits: list.hits,
+            })
+            .collect()
+    }
+
+    /// A reply to `query_message` if any of its questions are blocked.
+    pub fn filter(&self, query_message: &Message) -> Option<Message> {
+        let mut blocked = false;
+        {
+            let mut lists = self.lists.lock().unwrap();
+            for question in query_message.questions.iter() {
+                blocked |= lists.is_blocked(&question.name);
+            }
+        }
+        if !blocked {
+            return None;
+        }
+
+        if matches!(self.config.mode, BlockMode::NxDomain) {
+            return Some(Message::new_error_reply(
+                query_message,
+                ResponseCode::NameError,
+            ));
+        }
+        let answers = query_message
+            .questions
+            .iter()
+            .filter_map(|question| self.blocked_answer(question))
+            .collect();
+        Some(Message::new_reply(
+            query_message,
+            query_message.questions.clone(),
+            answers,
+        ))
+    }
+
+    fn blocked_answer(&self, question: &Question) -> Option<ResourceRecord> {
+        let data = match (question.ty, &self.config.mode) {
+            (RecordType::Address, BlockMode::Null) => ResourceRecordData::IPv4([0; 4]),
+            (RecordType::Ipv6Address, BlockMode::Null) => ResourceRecordData::IPv6([0; 16]),
+            (RecordType::Address, BlockMode::Sinkhole { v4: Some(addr), .. }) => {
+                ResourceRecordData::IPv4(addr.octets())
+            }
+            (RecordType::Ipv6Address, BlockMode::Sinkhole { v6: Some(addr), .. }) => {
+                ResourceRecordData::IPv6(addr.octets())
+            }
+            _ => return None,
+        };
+        Some(ResourceRecord::new(
+            question.name.clone(),
+            question.ty,
+            Class::Internet,
+            BLOCKED_TTL,
+            data,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process, thread};
+
+    use super::*;
+
+    /// A list in the temporary directory, named for this test process.
+    fn list(name: &str, contents: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("dns-server-test-{}-{name}", process::id()));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn query(name: &str, ty: RecordType) -> Message {
+        Message::new_query(vec![Question {
+            name: DomainName::new(name).unwrap(),
+            ty,
+            class: Class::Internet,
+        }])
+    }
+
+    fn blocked(blocklists: &Blocklists, name: &str) -> bool {
+        blocklists
+            .filter(&query(name, RecordType::Address))
+            .is_some()
+    }
+
+    fn blocklists(blocklists: Vec<PathBuf>, allowlists: Vec<PathBuf>, mode: &str) -> Blocklists {
+        Blocklists::load(BlocklistConfig {
+            blocklists,
+            allowlists,
+            mode: mode.parse().unwrap(),
+            ..BlocklistConfig::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn hosts_files_and_plain_lists() {
+        let hosts = list(
+            "hosts",
+            "# ad servers\n0.0.0.0 ads.example tracker.example\n127.0.0.1 localhost\n::1 ip6-localhost\n",
+        );
+        let plain = list(
+            "plain",
+            "exact.example\n*.wild.example  # subdomains only\n.tree.example\n\n",
+        );
+        let blocklists = blocklists(vec![hosts.clone(), plain.clone()], Vec::new(), "null");
+        let cases = [
+            ("ads.example", true),
+            ("tracker.example", true),
+            ("sub.ads.example", false),
+            ("localhost", false),
+            ("ip6-localhost", false),
+            ("exact.example", true),
+            ("www.exact.example", false),
+            ("wild.example", false),
+            ("a.wild.example", true),
+            ("a.b.wild.example", true),
+            ("tree.example", true),
+            ("a.tree.example", true),
+            ("example", false),
+        ];
+        for (name, expected) in cases {
+            assert_eq!(blocked(&blocklists, name), expected, "{name}");
+        }
+        let hits: Vec<_> = blocklists.stats().iter().map(|list| list.hits).collect();
+        assert_eq!(hits, [2, 5]);
+        fs::remove_file(hosts).unwrap();
+        fs::remove_file(plain).unwrap();
+    }
+
+    #[test]
+    fn allowlists_win() {
+        let block = list("allowlists-block", ".example\n");
+        let allow = list("allowlists-allow", "good.example\n*.cdn.example\n");
+        let blocklists = blocklists(vec![block.clone()], vec![allow.clone()], "nxdomain");
+        assert!(blocked(&blocklists, "bad.example"));
+        assert!(!blocked(&blocklists, "good.example"));
+        assert!(!blocked(&blocklists, "a.cdn.example"));
+        assert!(blocked(&blocklists, "cdn.example"));
+        let stats = blocklists.stats();
+        assert_eq!(stats[1].path, allow);
+        assert_eq!((stats[1].entries, stats[1].hits), (2, 2));
+        fs::remove_file(block).unwrap();
+        fs::remove_file(allow).unwrap();
+    }
+
+    #[test]
+    fn block_modes() {
+        let path = list("block-modes", "ads.example\n");
+        let answer = |mode: &str, ty| {
+            let response_message = blocklists(vec![path.clone()], Vec::new(), mode)
+                .filter(&query("ads.example", ty))
+                .unwrap();
+            (
+                response_message.header.response_code,
+                response_message
+                    .answers
+                    .first()
+                    .map(|answer| format!("{:?}", answer.data)),
+            )
+        };
+        let data = |data: ResourceRecordData| (ResponseCode::Ok, Some(format!("{data:?}")));
+
+        assert_eq!(
+            answer("nxdomain", RecordType::Address),
+            (ResponseCode::NameError, None)
+        );
+        assert_eq!(
+            answer("null", RecordType::Address),
+            data(ResourceRecordData::IPv4([0; 4]))
+        );
+        assert_eq!(
+            answer("null", RecordType::Ipv6Address),
+            data(ResourceRecordData::IPv6([0; 16]))
+        );
+        assert_eq!(
+            answer("null", RecordType::MailExchange),
+            (ResponseCode::Ok, None)
+        );
+        let sinkhole = "192.0.2.1,2001:db8::1";
+        assert_eq!(
+            answer(sinkhole, RecordType::Address),
+            data(ResourceRecordData::IPv4([192, 0, 2, 1]))
+        );
+        let v6 = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets();
+        assert_eq!(
+            answer(sinkhole, RecordType::Ipv6Address),
+            data(ResourceRecordData::IPv6(v6))
+        );
+        assert_eq!(
+            answer("192.0.2.1", RecordType::Ipv6Address),
+            (ResponseCode::Ok, None)
+        );
+        assert!("sinkhole".parse::<BlockMode>().is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn changed_lists_are_reloaded() {
+        let path = list("reload", "old.example\n");
+        let blocklists = blocklists(vec![path.clone()], Vec::new(), "null");
+        assert!(blocked(&blocklists, "old.example"));
+
+        // Far enough apart for the modification time to change
+        thread::sleep(Duration::from_millis(50));
+        fs::write(&path, "new.example\n").unwrap();
+        let loaded_at = *blocklists.last_refresh.lock().unwrap();
+        blocklists.refresh(loaded_at + Duration::from_secs(30));
+        assert!(blocked(&blocklists, "old.example"));
+        blocklists.refresh(loaded_at + Duration::from_secs(60));
+        assert!(!blocked(&blocklists, "old.example"));
+        assert!(blocked(&blocklists, "new.example"));
+
+        // A list which can't be read any more keeps its old entries
+        fs::remove_file(&path).unwrap();
+        blocklists.refresh(loaded_at + Duration::from_secs(120));
+        assert!(blocked(&blocklists, "new.example"));
+    }
+
+    #[test]
+    fn bad_lines_are_skipped() {
+        let path = list(
+            "bad-lines",
+            "ads.example\nbad..example\n0.0.0.0 tracker.example bad..example\n.tree.example\n",
+        );
+        let blocklists = blocklists(vec![path.clone()], Vec::new(), "null");
+        assert!(blocked(&blocklists, "ads.example"));
+        assert!(blocked(&blocklists, "a.tree.example"));
+        // A hosts line with a bad name is skipped as a whole
+        assert!(!blocked(&blocklists, "tracker.example"));
+        assert_eq!(blocklists.stats()[0].entries, 2);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,607 @@
+//! A cache of upstream responses, so a name is only looked up again once its records expire.
+//!
+//! An upstream which uses the client subnet to pick its answer says which network the answer
+//! applies to, and the answer is only reused for clients in that network (RFC 7871 section 7.3).
+//!
+//! Expired responses can be kept for a while too, to answer with when upstreams are down (RFC
+//! 8767), and popular ones can be looked up again before they expire.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    acl::Cidr,
+    message::{
+        Class, ClientSubnet, DomainName, ExtendedErrorCode, Message, Question, RecordType,
+        ResourceRecordData, ResponseCode,
+    },
+};
+
+/// The most names kept at once. Past this, the name whose responses would be dropped soonest goes
+/// to make room.
+const MAX_ENTRIES: usize = 10_000;
+/// The longest a response is kept, however long its TTLs.
+const MAX_TTL: u32 = 86400;
+/// The TTL given to expired records, so clients soon ask again (RFC 8767 section 4).
+const STALE_ANSWER_TTL: u32 = 30;
+/// A response is about to expire once it's into the last 1/N of its TTL.
+const EXPIRING_FRACTION: u32 = 10;
+
+/// What was asked: the question and the flags which change the answer.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    name: DomainName,
+    ty: RecordType,
+    class: Class,
+    pub dnssec_ok: bool,
+    pub authentic_data: bool,
+    pub checking_disabled: bool,
+}
+
+/// What was asked, and the subnet it was asked for.
+type Lookup = (CacheKey, Option<ClientSubnet>);
+
+/// Responses by what was asked, shared between clones.
+#[derive(Debug, Clone, Default)]
+pub struct Cache {
+    entries: Arc<Mutex<Entries>>,
+    /// What's being looked up again in the background, so it's only done once at a time.
+    refreshing: Arc<Mutex<HashSet<Lookup>>>,
+}
+
+/// A cached response, and how much it's wanted.
+#[derive(Debug)]
+pub struct CacheHit {
+    pub response_message: Message,
+    /// How many times the response has been used, including this one.
+    pub hits: u32,
+    /// Whether it's about to expire.
+    pub expiring: bool,
+}
+
+/// The responses for each key, and the order they can be dropped in.
+#[derive(Debug, Default)]
+struct Entries {
+    by_key: HashMap<CacheKey, Slot>,
+    /// Keys by when the last of their responses can be dropped, soonest first. The number keeps
+    /// keys which go at the same time apart.
+    by_expiry: BTreeMap<(Instant, u64), CacheKey>,
+    next_id: u64,
+}
+
+#[derive(Debug)]
+struct Slot {
+    entries: Vec<Entry>,
+    /// Where the key is in `Entries::by_expiry`.
+    position: (Instant, u64),
+}
+
+#[derive(Debug)]
+struct Entry {
+    /// The clients the response applies to, or every client.
+    scope: Option<Cidr>,
+    response_message: Message,
+    stored_at: Instant,
+    ttl: u32,
+    /// How long the response is kept after it expires.
+    keep_stale: Duration,
+    hits: u32,
+}
+
+impl CacheKey {
+    pub fn new(
+        question: &Question,
+        dnssec_ok: bool,
+        authentic_data: bool,
+        checking_disabled: bool,
+    ) -> Self {
+        CacheKey {
+            name: question.name.clone(),
+            ty: question.ty,
+            class: question.class,
+            dnssec_ok,
+            authentic_data,
+            checking_disabled,
+        }
+    }
+
+    pub fn question(&self) -> Question {
+        Question {
+            name: self.name.clone(),
+            ty: self.ty,
+            class: self.class,
+        }
+    }
+}
+
+impl Cache {
+    /// The cached response for `key` which applies to a client in `client_subnet`, with its TTLs
+    /// counted down. A client without a subnet only gets responses which apply to everyone.
+    pub fn get(&self, key: &CacheKey, client_subnet: Option<&ClientSubnet>) -> Option<CacheHit> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        let entries = &mut entries.by_key.get_mut(key)?.entries;
+        let index = select(entries, client_subnet, |entry| entry.expires_at() > now)?;
+        let entry = &mut entries[index];
+        entry.hits += 1;
+        let elapsed = now.duration_since(entry.stored_at).as_secs() as u32;
+        let mut response_message = entry.response_message.clone();
+        set_ttls(&mut response_message, |ttl| ttl.saturating_sub(elapsed));
+        Some(CacheHit {
+            response_message,
+            hits: entry.hits,
+            expiring: entry.ttl - elapsed.min(entry.ttl) <= entry.ttl / EXPIRING_FRACTION,
+        })
+    }
+
+    /// A response for `key` which expired no more than `max_stale` ago, for when there's nothing
+    /// better. Its TTLs are short, and it says it's stale (RFC 8914 section 4.4).
+    pub fn get_stale(
+        &self,
+        key: &CacheKey,
+        client_subnet: Option<&ClientSubnet>,
+        max_stale: Duration,
+    ) -> Option<Message> {
+        let now = Instant::now();
+        let entries = self.entries.lock().unwrap();
+        let entries = &entries.by_key.get(key)?.entries;
+        let index = select(entries, client_subnet, |entry| {
+            entry.expires_at() <= now && entry.expires_at() + max_stale > now
+        })?;
+        let mut response_message = entries[index].response_message.clone();
+        set_ttls(&mut response_message, |_| STALE_ANSWER_TTL);
+        response_message.add_extended_error(ExtendedErrorCode::StaleAnswer, "");
+        Some(response_message)
+    }
+
+    /// Keep `response_message` until its records expire, and `keep_stale` after that, if it's
+    /// worth keeping. `client_subnet` is what was sent upstream, which together with the scope in
+    /// the response says who else the response applies to.
+    pub fn insert(
+        &self,
+        key: CacheKey,
+        client_subnet: Option<&ClientSubnet>,
+        response_message: &Message,
+        keep_stale: Duration,
+    ) {
+        if !matches!(
+            response_message.header.response_code,
+            ResponseCode::Ok | ResponseCode::NameError
+        ) || response_message.header.truncation
+        {
+            return;
+        }
+        let Some(ttl) = ttl(response_message) else {
+            return;
+        };
+        let scope = match (
+            client_subnet,
+            response_message
+                .edns()
+                .and_then(|edns| edns.client_subnet().cloned()),
+        ) {
+            // A scope longer than what was sent can only apply to what was sent (RFC 7871
+            // section 7.3.1)
+            (Some(client_subnet), Some(response_subnet))
+                if response_subnet.scope_prefix_len > 0 =>
+            {
+                Cidr::new(
+                    client_subnet.addr,
+                    response_subnet
+                        .scope_prefix_len
+                        .min(client_subnet.source_prefix_len),
+                )
+                .ok()
+            }
+            _ => None,
+        };
+
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        let entries = &mut *entries;
+        entries.make_room(&key, now);
+        let mut responses = match entries.by_key.remove(&key) {
+            Some(slot) => {
+                entries.by_expiry.remove(&slot.position);
+                slot.entries
+            }
+            None => Vec::new(),
+        };
+        responses.retain(|entry| {
+            entry.keep_until() > now
+                && entry
+                    .scope
+                    .map(|scope| (scope.network(), scope.prefix_len()))
+                    != scope.map(|scope| (scope.network(), scope.prefix_len()))
+        });
+        responses.push(Entry {
+            scope,
+            response_message: response_message.clone(),
+            stored_at: now,
+            ttl,
+            keep_stale,
+            hits: 0,
+        });
+        let keep_until = responses.iter().map(Entry::keep_until).max().unwrap();
+        let position = (keep_until, entries.next_id);
+        entries.next_id += 1;
+        entries.by_expiry.insert(position, key.clone());
+        entries.by_key.insert(
+            key,
+            Slot {
+                entries: responses,
+                position,
+            },
+        );
+    }
+
+    /// Note that `key` is being looked up again for `client_subnet`, unless it already is, or
+    /// `limit` lookups already are.
+    pub fn start_refresh(
+        &self,
+        key: &CacheKey,
+        client_subnet: Option<&ClientSubnet>,
+        limit: Option<usize>,
+    ) -> bool {
+        let mut refreshing = self.refreshing.lock().unwrap();
+        limit.map_or(true, |limit| refreshing.len() < limit)
+            && refreshing.insert((key.clone(), client_subnet.cloned()))
+    }
+
+    pub fn finish_refresh(&self, key: &CacheKey, client_subnet: Option<&ClientSubnet>) {
+        self.refreshing
+            .lock()
+            .unwrap()
+            .remove(&(key.clone(), client_subnet.cloned()));
+    }
+
+    /// Make the responses for `key` `seconds` older.
+    #[cfg(test)]
+    pub(crate) fn age(&self, key: &CacheKey, seconds: u64) {
+        let mut entries = self.entries.lock().unwrap();
+        for entry in entries.by_key.get_mut(key).unwrap().entries.iter_mut() {
+            entry.stored_at = entry
+                .stored_at
+                .checked_sub(Duration::from_secs(seconds))
+                .unwrap();
+        }
+    }
+}
+
+impl Entries {
+    /// Drop the keys whose responses are all past keeping, and then, if there's still no room for
+    /// `key`, the one due to go soonest. Each key is only looked at once it's first in line, so
+    /// this stays cheap however full the cache is.
+    fn make_room(&mut self, key: &CacheKey, now: Instant) {
+        while let Some((&(keep_until, _), _)) = self.by_expiry.first_key_value() {
+            let full = self.by_key.len() >= MAX_ENTRIES && !self.by_key.contains_key(key);
+            if keep_until > now && !full {
+                break;
+            }
+            if let Some((_, oldest)) = self.by_expiry.pop_first() {
+                self.by_key.remove(&oldest);
+            }
+        }
+    }
+}
+
+impl Entry {
+    fn expires_at(&self) -> Instant {
+        self.stored_at + Duration::from_secs(self.ttl.into())
+    }
+
+    fn keep_until(&self) -> Instant {
+        self.expires_at() + self.keep_stale
+    }
+}
+
+/// Where in `entries` the most specific one is which applies to a client in `client_subnet` and
+/// is `usable`. A client without a subnet only gets responses which apply to everyone.
+fn select(
+    entries: &[Entry],
+    client_subnet: Option<&ClientSubnet>,
+    usable: impl Fn(&Entry) -> bool,
+) -> Option<usize> {
+    entries
+        .iter()
+        .enumerate()
+        .filter(|(_, entry)| usable(entry))
+        .filter(|(_, entry)| match (entry.scope, client_subnet) {
+            (None, _) => true,
+            (Some(scope), Some(client_subnet)) => {
+                client_subnet.source_prefix_len >= scope.prefix_len()
+                    && scope.contains(client_subnet.addr)
+            }
+            (Some(_), None) => false,
+        })
+        .max_by_key(|(_, entry)| entry.scope.map(|scope| scope.prefix_len()))
+        .map(|(index, _)| index)
+}
+
+/// Change the TTL of every record but the OPT record, which doesn't have a real one.
+fn set_ttls(response_message: &mut Message, ttl: impl Fn(u32) -> u32) {
+    for record in response_message
+        .answers
+        .iter_mut()
+        .chain(response_message.authorities.iter_mut())
+        .chain(response_message.additionals.iter_mut())
+        .filter(|record| record.ty != RecordType::Opt)
+    {
+        record.time_to_live = ttl(record.time_to_live);
+    }
+}
+
+/// How long a response can be kept: the shortest TTL in it, with negative answers kept for the
+/// SOA minimum (RFC 2308 section 5). Nothing to go on means it isn't kept.
+fn ttl(response_message: &Message) -> Option<u32> {
+    response_message
+        .answers
+        .iter()
+        .chain(response_message.authorities.iter())
+        .map(|record| match &record.data {
+            ResourceRecordData::StartOfAuthority { minimum, .. } => {
+                record.time_to_live.min(*minimum)
+            }
+            _ => record.time_to_live,
+        })
+        .min()
+        .filter(|&ttl| ttl > 0)
+        .map(|ttl| ttl.min(MAX_TTL))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::message::{EdnsOption, ResourceRecord};
+
+    use super::*;
+
+    fn question(name: &str) -> Question {
+        Question {
+            name: DomainName::new(name).unwrap(),
+            ty: RecordType::Address,
+            class: Class::Internet,
+        }
+    }
+
+    fn key(name: &str) -> CacheKey {
+        CacheKey::new(&question(name), false, false, false)
+    }
+
+    fn response(name: &str, ttl: u32, addr: [u8; 4]) -> Message {
+        let question = question(name);
+        let answer = ResourceRecord::new(
+            question.name.clone(),
+            RecordType::Address,
+            Class::Internet,
+            ttl,
+            ResourceRecordData::IPv4(addr),
+        );
+        let query_message = Message::new_query(vec![question.clone()]);
+        Message::new_reply(&query_message, vec![question], vec![answer])
+    }
+
+    fn subnet(addr: &str, source_prefix_len: u8, scope_prefix_len: u8) -> ClientSubnet {
+        ClientSubnet {
+            source_prefix_len,
+            scope_prefix_len,
+            addr: addr.parse().unwrap(),
+        }
+    }
+
+    /// The TTL and address of the answer in `hit`.
+    fn answer(hit: Option<CacheHit>) -> Option<(u32, [u8; 4])> {
+        let answer = hit?.response_message.answers.first()?.clone();
+        match answer.data {
+            ResourceRecordData::IPv4(addr) => Some((answer.time_to_live, addr)),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn responses_are_kept_until_they_expire() {
+        let cache = Cache::default();
+        let key = key("www.example");
+        cache.insert(
+            key.clone(),
+            None,
+            &response("www.example", 60, [192, 0, 2, 1]),
+            Duration::ZERO,
+        );
+
+        let hit = cache.get(&key, None).unwrap();
+        assert_eq!(hit.hits, 1);
+        assert!(!hit.expiring);
+        assert_eq!(hit.response_message.answers[0].time_to_live, 60);
+        assert!(cache.get(&self::key("mail.example"), None).is_none());
+
+        cache.age(&key, 30);
+        let hit = cache.get(&key, None).unwrap();
+        assert_eq!(hit.hits, 2);
+        assert!(!hit.expiring);
+        assert_eq!(hit.response_message.answers[0].time_to_live, 30);
+
+        cache.age(&key, 25);
+        assert!(cache.get(&key, None).unwrap().expiring);
+        cache.age(&key, 5);
+        assert!(cache.get(&key, None).is_none());
+    }
+
+    #[test]
+    fn only_answers_worth_keeping_are_kept() {
+        let cache = Cache::default();
+        let mut server_failure = response("a.example", 60, [192, 0, 2, 1]);
+        server_failure.header.response_code = ResponseCode::ServerFailure;
+        let mut truncated = response("b.example", 60, [192, 0, 2, 1]);
+        truncated.header.truncation = true;
+        let uncacheable = response("c.example", 0, [192, 0, 2, 1]);
+        for (name, response_message) in [
+            ("a.example", server_failure),
+            ("b.example", truncated),
+            ("c.example", uncacheable),
+        ] {
+            cache.insert(key(name), None, &response_message, Duration::ZERO);
+            assert!(cache.get(&key(name), None).is_none(), "{name}");
+        }
+
+        // Kept for the SOA minimum rather than the SOA's own TTL
+        let query_message = Message::new_query(vec![question("d.example")]);
+        let mut name_error = Message::new_error_reply(&query_message, ResponseCode::NameError);
+        name_error.authorities.push(ResourceRecord::new(
+            DomainName::new("example").unwrap(),
+            RecordType::StartOfAuthority,
+            Class::Internet,
+            3600,
+            ResourceRecordData::StartOfAuthority {
+                primary_name_server: DomainName::new("ns.example").unwrap(),
+                responsible_mailbox: DomainName::new("hostmaster.example").unwrap(),
+                serial: 1,
+                refresh: 3600,
+                retry: 600,
+                expire: 86400,
+                minimum: 60,
+            },
+        ));
+        let key = key("d.example");
+        cache.insert(key.clone(), None, &name_error, Duration::ZERO);
+        let hit = cache.get(&key, None).unwrap();
+        assert_eq!(
+            hit.response_message.header.response_code,
+            ResponseCode::NameError
+        );
+        cache.age(&key, 60);
+        assert!(cache.get(&key, None).is_none());
+    }
+
+    #[test]
+    fn stale_responses_are_kept_for_a_while() {
+        let cache = Cache::default();
+        let key = key("www.example");
+        let response_message = response("www.example", 10, [192, 0, 2, 1]);
+        cache.insert(
+            key.clone(),
+            None,
+            &response_message,
+            Duration::from_secs(60),
+        );
+        let max_stale = Duration::from_secs(60);
+        assert!(cache.get_stale(&key, None, max_stale).is_none());
+
+        cache.age(&key, 11);
+        assert!(cache.get(&key, None).is_none());
+        let stale = cache.get_stale(&key, None, max_stale).unwrap();
+        assert_eq!(stale.answers[0].time_to_live, STALE_ANSWER_TTL);
+        let edns = stale.edns().unwrap();
+        assert!(edns.options.iter().any(|option| matches!(
+            option,
+            EdnsOption::ExtendedError(error) if error.info_code == ExtendedErrorCode::StaleAnswer
+        )));
+        assert!(cache
+            .get_stale(&key, None, Duration::from_secs(1))
+            .is_none());
+    }
+
+    #[test]
+    fn answers_for_a_subnet_are_only_used_in_it() {
+        let cache = Cache::default();
+        let key = key("www.example");
+        let everyone = response("www.example", 60, [192, 0, 2, 1]);
+        cache.insert(key.clone(), None, &everyone, Duration::ZERO);
+        let sent = subnet("198.51.100.0", 24, 0);
+        let mut scoped = response("www.example", 60, [192, 0, 2, 2]);
+        scoped.add_edns_option(EdnsOption::ClientSubnet(subnet("198.51.100.0", 24, 28)));
+        cache.insert(key.clone(), Some(&sent), &scoped, Duration::ZERO);
+
+        let scoped_answer = Some((60, [192, 0, 2, 2]));
+        let everyone_answer = Some((60, [192, 0, 2, 1]));
+        // The scope is narrowed to what was sent
+        assert_eq!(
+            answer(cache.get(&key, Some(&subnet("198.51.100.0", 24, 0)))),
+            scoped_answer
+        );
+        assert_eq!(
+            answer(cache.get(&key, Some(&subnet("198.51.101.0", 24, 0)))),
+            everyone_answer
+        );
+        assert_eq!(
+            answer(cache.get(&key, Some(&subnet("198.51.0.0", 16, 0)))),
+            everyone_answer
+        );
+        assert_eq!(answer(cache.get(&key, None)), everyone_answer);
+
+        // A new answer for the same scope replaces the old one
+        let mut rescoped = response("www.example", 60, [192, 0, 2, 3]);
+        rescoped.add_edns_option(EdnsOption::ClientSubnet(subnet("198.51.100.0", 24, 24)));
+        cache.insert(key.clone(), Some(&sent), &rescoped, Duration::ZERO);
+        assert_eq!(cache.entries.lock().unwrap().by_key[&key].entries.len(), 2);
+        assert_eq!(
+            answer(cache.get(&key, Some(&subnet("198.51.100.0", 24, 0)))),
+            Some((60, [192, 0, 2, 3]))
+        );
+    }
+
+    #[test]
+    fn refreshes_happen_once_at_a_time() {
+        let cache = Cache::default();
+        let (a, b, c) = (key("a.example"), key("b.example"), key("c.example"));
+        assert!(cache.start_refresh(&a, None, Some(2)));
+        assert!(!cache.start_refresh(&a, None, Some(2)));
+        assert!(cache.start_refresh(&a, Some(&subnet("192.0.2.0", 24, 0)), None));
+        assert!(!cache.start_refresh(&b, None, Some(2)));
+        assert!(cache.start_refresh(&c, None, None));
+        cache.finish_refresh(&a, None);
+        assert!(cache.start_refresh(&a, None, None));
+    }
+
+    #[test]
+    fn the_response_due_to_go_first_makes_room() {
+        let cache = Cache::default();
+        for index in 0..MAX_ENTRIES {
+            let name = format!("{index}.example");
+            let ttl = if index == 7 { 30 } else { 60 };
+            cache.insert(
+                key(&name),
+                None,
+                &response(&name, ttl, [192, 0, 2, 1]),
+                Duration::ZERO,
+            );
+        }
+
+        cache.insert(
+            key("new.example"),
+            None,
+            &response("new.example", 60, [192, 0, 2, 2]),
+            Duration::ZERO,
+        );
+
+        assert_eq!(cache.entries.lock().unwrap().by_key.len(), MAX_ENTRIES);
+        assert!(cache.get(&key("7.example"), None).is_none());
+        assert!(cache.get(&key("0.example"), None).is_some());
+        assert!(cache.get(&key("new.example"), None).is_some());
+    }
+
+    #[test]
+    fn responses_past_keeping_are_dropped() {
+        let cache = Cache::default();
+        let old = key("old.example");
+        cache.insert(
+            old.clone(),
+            None,
+            &response("old.example", 0, [192, 0, 2, 1]),
+            Duration::ZERO,
+        );
+
+        cache.insert(
+            key("new.example"),
+            None,
+            &response("new.example", 60, [192, 0, 2, 2]),
+            Duration::ZERO,
+        );
+
+        let entries = cache.entries.lock().unwrap();
+        assert!(!entries.by_key.contains_key(&old));
+        assert_eq!(entries.by_expiry.len(), 1);
+    }
+}
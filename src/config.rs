@@ -0,0 +1,419 @@
+use std::{net::SocketAddr, time::Duration};
+
+use crate::{
+    acl::{AccessControl, Cidr},
+    blocklist::BlocklistConfig,
+    cookie::CookieConfig,
+    dnssec::DnssecConfig,
+    forward::{ClientSubnetConfig, ForwardingRule, PrefetchConfig, ServeStaleConfig, UpstreamAddr},
+    local_records::LocalRecordsConfig,
+    message::DomainName,
+    rpz::{PolicySource, RpzConfig},
+    rrl::RrlConfig,
+    tsig::Key,
+    zone::ZonesConfig,
+};
+
+#[derive(Debug)]
+pub struct Config {
+    /// The upstream resolver queries are forwarded to, unless a view has its own.
+    pub resolver_addr: UpstreamAddr,
+    /// Response rate limiting, disabled unless a rate is given.
+    pub rrl: RrlConfig,
+    /// DNS cookies, which are always answered but only required if asked.
+    pub cookies: CookieConfig,
+    /// Views in order of precedence, ending with the default view which matches every client.
+    pub views: Vec<ViewConfig>,
+    /// Match views against the EDNS Client Subnet address of queries which have one.
+    pub match_client_subnet: bool,
+    /// How much of clients' addresses upstreams are told.
+    pub client_subnet: ClientSubnetConfig,
+    /// Answering from expired cache entries when upstreams are down, off by default.
+    pub serve_stale: ServeStaleConfig,
+    /// Looking up popular answers again before they expire, off by default.
+    pub prefetch: PrefetchConfig,
+    /// Where to listen for DNS-over-HTTPS requests, if anywhere.
+    pub doh_addr: Option<SocketAddr>,
+    /// DNSSEC validation of forwarded answers, off by default.
+    pub dnssec: DnssecConfig,
+    /// TSIG keys shared with other servers, which any view's zones can use.
+    pub tsig_keys: Vec<Key>,
+}
+
+/// Settings which can differ between clients. Options given after `--view <name>` apply to that
+/// view, and options given before any `--view` make up the default view.
+#[derive(Debug, Clone)]
+pub struct ViewConfig {
+    pub name: String,
+    /// Clients this view applies to, or every client if empty.
+    pub match_clients: Vec<Cidr>,
+    /// Overrides the global resolver.
+    pub resolver_addr: Option<UpstreamAddr>,
+    /// Queries for these domains go to their own upstreams instead.
+    pub forwarding_rules: Vec<ForwardingRule>,
+    /// Which clients may use which services.
+    pub access_control: AccessControl,
+    /// Response policy zones, in order of precedence.
+    pub rpz: RpzConfig,
+    /// Records answered directly instead of being forwarded.
+    pub local_records: LocalRecordsConfig,
+    /// Domains to block rather than resolve.
+    pub blocklists: BlocklistConfig,
+    /// Zones answered authoritatively and served to secondaries. Transfers, UPDATE and NOTIFY
+    /// only reach the zones of the view the client matches.
+    pub zones: ZonesConfig,
+}
+
+impl ViewConfig {
+    fn new(name: &str) -> Self {
+        ViewConfig {
+            name: name.to_string(),
+            match_clients: Vec::new(),
+            resolver_addr: None,
+            forwarding_rules: Vec::new(),
+            access_control: AccessControl::default(),
+            rpz: RpzConfig { zones: Vec::new() },
+            local_records: LocalRecordsConfig::default(),
+            blocklists: BlocklistConfig::default(),
+            zones: ZonesConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_args(args: &[String]) -> anyhow::Result<Self> {
+        let mut resolver_addr = None;
+        let mut rrl = RrlConfig::default();
+        let mut cookies = CookieConfig::default();
+        let mut views = vec![ViewConfig::new("default")];
+        let mut match_client_subnet = false;
+        let mut client_subnet = ClientSubnetConfig::default();
+        let mut serve_stale = ServeStaleConfig::default();
+        let mut prefetch = PrefetchConfig::default();
+        let mut doh_addr = None;
+        let mut dnssec = DnssecConfig::default();
+        let mut tsig_keys = Vec::new();
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| anyhow::format_err!("error: no value given for {arg}"))
+            };
+            let is_default_view = views.len() == 1;
+            let view = views.last_mut().unwrap();
+            match arg.as_str() {
+                "--resolver" if is_default_view => {
+                    resolver_addr = Some(value()?.parse::<UpstreamAddr>()?)
+                }
+                "--resolver" => view.resolver_addr = Some(value()?.parse::<UpstreamAddr>()?),
+                "--forward" => view.forwarding_rules.push(value()?.parse()?),
+                "--acl" => view.access_control.push_rule(value()?)?,
+                "--rrl-responses-per-second" => rrl.responses_per_second = value()?.parse()?,
+                "--rrl-slip" => rrl.slip = value()?.parse()?,
+                "--rrl-window" => rrl.window = value()?.parse()?,
+                "--rrl-log-only" => rrl.log_only = true,
+                "--require-cookies" => cookies.require = true,
+                "--cookie-secret-rotation" => {
+                    cookies.secret_rotation = Duration::from_secs(value()?.parse()?)
+                }
+                "--blocklist" => view.blocklists.blocklists.push(value()?.into()),
+                "--allowlist" => view.blocklists.allowlists.push(value()?.into()),
+                "--blocklist-mode" => view.blocklists.mode = value()?.parse()?,
+                "--blocklist-reload-interval" => {
+                    view.blocklists.reload_interval = Duration::from_secs(value()?.parse()?)
+                }
+                "--rpz-zone" => {
+                    let value = value()?;
+                    let (origin, path) = value.split_once('=').ok_or_else(|| {
+                        anyhow::format_err!("error: --rpz-zone should be <origin>=<path>")
+                    })?;
+                    view.rpz
+                        .zones
+                        .push((DomainName::new(origin)?, PolicySource::File(path.into())));
+                }
+                "--rpz-primary" => {
+                    let value = value()?;
+                    let (origin, primary) = value.split_once('=').ok_or_else(|| {
+                        anyhow::format_err!("error: --rpz-primary should be <origin>=<primary>")
+                    })?;
+                    let primary = PolicySource::Primary(primary.parse::<SocketAddr>()?);
+                    view.rpz.zones.push((DomainName::new(origin)?, primary));
+                }
+                "--local-record" => view.local_records.records.push(value()?.clone()),
+                "--hosts-file" => view.local_records.hosts_files.push(value()?.into()),
+                "--local-ttl" => view.local_records.ttl = value()?.parse()?,
+                "--dnssec-key" => {
+                    let value = value()?;
+                    let (zone, path) = value.split_once('=').ok_or_else(|| {
+                        anyhow::format_err!("error: --dnssec-key should be <zone>=<path>")
+                    })?;
+                    let signing = &mut view.local_records.signing;
+                    signing.keys.push((DomainName::new(zone)?, path.into()));
+                }
+                "--dnssec-denial" => view.local_records.signing.denial = value()?.parse()?,
+                "--dnssec-inception-offset" => {
+                    view.local_records.signing.inception_offset = value()?.parse()?
+                }
+                "--dnssec-signature-validity" => {
+                    view.local_records.signing.validity = value()?.parse()?
+                }
+                "--view" => views.push(ViewConfig::new(value()?)),
+                "--match-clients" if is_default_view => {
+                    anyhow::bail!("error: --match-clients should follow --view")
+                }
+                "--match-clients" => {
+                    for cidr in value()?.split(',') {
+                        view.match_clients.push(cidr.parse()?);
+                    }
+                }
+                "--match-client-subnet" => match_client_subnet = true,
+                "--client-subnet" => client_subnet.limit = Some(value()?.parse()?),
+                "--client-subnet-domain" => {
+                    let value = value()?;
+                    let (domain, limit) = value.split_once('=').ok_or_else(|| {
+                        anyhow::format_err!(
+                            "error: --client-subnet-domain should be <domain>=<limit>"
+                        )
+                    })?;
+                    client_subnet
+                        .domains
+                        .push((DomainName::new(domain)?, limit.parse()?));
+                }
+                "--serve-stale" => {
+                    serve_stale.max_stale = Some(Duration::from_secs(value()?.parse()?))
+                }
+                "--stale-answer-client-timeout" => {
+                    serve_stale.client_response_timeout = Duration::from_millis(value()?.parse()?)
+                }
+                "--prefetch" => prefetch.min_hits = Some(value()?.parse()?),
+                "--prefetch-concurrency" => prefetch.max_concurrent = value()?.parse()?,
+                "--doh-listen" => doh_addr = Some(value()?.parse::<SocketAddr>()?),
+                "--doq-listen" => anyhow::bail!(
+                    "error: --doq-listen isn't supported, DNS-over-QUIC needs QUIC and TLS 1.3"
+                ),
+                "--dnssec-validation" => dnssec.validate = true,
+                "--trust-anchor" => dnssec.trust_anchors.push(value()?.clone()),
+                "--zone" => {
+                    let value = value()?;
+                    let (origin, path) = value.split_once('=').ok_or_else(|| {
+                        anyhow::format_err!("error: --zone should be <origin>=<path>")
+                    })?;
+                    view.zones
+                        .primaries
+                        .push((DomainName::new(origin)?, path.into()));
+                }
+                "--secondary-zone" => {
+                    let value = value()?;
+                    let (origin, primary) = value.split_once('=').ok_or_else(|| {
+                        anyhow::format_err!("error: --secondary-zone should be <origin>=<primary>")
+                    })?;
+                    view.zones
+                        .secondaries
+                        .push((DomainName::new(origin)?, primary.parse::<SocketAddr>()?));
+                }
+                "--zone-directory" => view.zones.directory = value()?.into(),
+                "--allow-update" => {
+                    let value = value()?;
+                    let (origin, cidrs) = value.split_once('=').ok_or_else(|| {
+                        anyhow::format_err!("error: --allow-update should be <origin>=<cidrs>")
+                    })?;
+                    let origin = DomainName::new(origin)?;
+                    for cidr in cidrs.split(',') {
+                        view.zones
+                            .allow_update
+                            .push((origin.clone(), cidr.parse()?));
+                    }
+                }
+                "--tsig-key" => {
+                    let value = value()?;
+                    let (name, algorithm, secret) = value
+                        .split_once('=')
+                        .and_then(|(name, key)| {
+                            let (algorithm, secret) = key.split_once(':')?;
+                            Some((name, algorithm, secret))
+                        })
+                        .ok_or_else(|| {
+                            anyhow::format_err!(
+                                "error: --tsig-key should be <name>=<algorithm>:<secret>"
+                            )
+                        })?;
+                    tsig_keys.push(Key::new(name, algorithm, secret)?);
+                }
+                "--transfer-key" | "--update-key" | "--primary-key" => {
+                    let value = value()?;
+                    let (origin, key) = value.split_once('=').ok_or_else(|| {
+                        anyhow::format_err!("error: {arg} should be <origin>=<key name>")
+                    })?;
+                    let zone_keys = match arg.as_str() {
+                        "--transfer-key" => &mut view.zones.transfer_keys,
+                        "--update-key" => &mut view.zones.update_keys,
+                        _ => &mut view.zones.primary_keys,
+                    };
+                    zone_keys.push((DomainName::new(origin)?, DomainName::new(key)?));
+                }
+                "--notify" => {
+                    let value = value()?;
+                    let (origin, secondary) = value.split_once('=').ok_or_else(|| {
+                        anyhow::format_err!("error: --notify should be <origin>=<secondary>")
+                    })?;
+                    view.zones
+                        .notify
+                        .push((DomainName::new(origin)?, secondary.parse::<SocketAddr>()?));
+                }
+                _ => anyhow::bail!("error: unknown argument {arg:?}"),
+            }
+        }
+
+        // The default view matches everyone, so it has to go last
+        views.rotate_left(1);
+
+        // Zones saved by different views would overwrite each other
+        for (i, view) in views.iter().enumerate() {
+            for other in views[i + 1..].iter() {
+                if view.zones.directory != other.zones.directory {
+                    continue;
+                }
+                if let Some(origin) = view
+                    .zones
+                    .origins()
+                    .find(|origin| other.zones.origins().any(|other| other == *origin))
+                {
+                    anyhow::bail!(
+                        "error: views {} and {} would both save zone {} in {}, so one needs its \
+                         own --zone-directory",
+                        view.name,
+                        other.name,
+                        origin,
+                        view.zones.directory.display()
+                    );
+                }
+            }
+        }
+
+        Ok(Config {
+            resolver_addr: resolver_addr
+                .ok_or_else(|| anyhow::format_err!("error: no resolver address given"))?,
+            rrl,
+            cookies,
+            views,
+            match_client_subnet,
+            client_subnet,
+            serve_stale,
+            prefetch,
+            doh_addr,
+            dnssec,
+            tsig_keys,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn config(args: &[&str]) -> anyhow::Result<Config> {
+        let args = args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        Config::from_args(&args)
+    }
+
+    fn error(args: &[&str]) -> String {
+        config(args).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn options_after_view_apply_to_that_view() {
+        let config = config(&[
+            "--resolver",
+            "192.0.2.53:53",
+            "--blocklist",
+            "ads.txt",
+            "--local-record",
+            "nas.lan. A 192.168.1.30",
+            "--tsig-key",
+            "transfer.key=hmac-sha256:c2VjcmV0",
+            "--view",
+            "internal",
+            "--match-clients",
+            "10.0.0.0/8,fd00::/8",
+            "--resolver",
+            "10.0.0.53:53",
+            "--zone",
+            "example=internal.zone",
+            "--zone-directory",
+            "internal",
+            "--transfer-key",
+            "example=transfer.key",
+            "--rrl-responses-per-second",
+            "5",
+        ])
+        .unwrap();
+        assert_eq!(config.resolver_addr.to_string(), "192.0.2.53:53");
+        assert_eq!(config.rrl.responses_per_second, 5);
+        assert_eq!(config.tsig_keys.len(), 1);
+
+        let [internal, default] = &config.views[..] else {
+            panic!("expected two views");
+        };
+        assert_eq!(internal.name, "internal");
+        assert_eq!(internal.match_clients.len(), 2);
+        assert_eq!(
+            internal.resolver_addr.as_ref().unwrap().to_string(),
+            "10.0.0.53:53"
+        );
+        assert_eq!(internal.zones.primaries.len(), 1);
+        assert_eq!(internal.zones.transfer_keys.len(), 1);
+        assert!(internal.blocklists.blocklists.is_empty());
+        assert!(internal.local_records.records.is_empty());
+
+        assert_eq!(default.name, "default");
+        assert!(default.match_clients.is_empty());
+        assert!(default.resolver_addr.is_none());
+        assert_eq!(default.blocklists.blocklists, [PathBuf::from("ads.txt")]);
+        assert_eq!(default.local_records.records.len(), 1);
+        assert!(default.zones.primaries.is_empty());
+    }
+
+    #[test]
+    fn invalid_arguments() {
+        assert!(error(&[]).contains("no resolver address"));
+        assert!(error(&["--resolver"]).contains("no value given for --resolver"));
+        assert!(error(&["--resolver", "192.0.2.53:53", "--verbose"]).contains("--verbose"));
+        assert!(error(&[
+            "--resolver",
+            "192.0.2.53:53",
+            "--match-clients",
+            "10.0.0.0/8"
+        ])
+        .contains("should follow --view"));
+        assert!(error(&["--resolver", "192.0.2.53:53", "--zone", "example"])
+            .contains("<origin>=<path>"));
+        assert!(
+            error(&["--resolver", "192.0.2.53:53", "--tsig-key", "key=c2VjcmV0"])
+                .contains("<name>=<algorithm>:<secret>")
+        );
+        assert!(config(&["--resolver", "192.0.2.53"]).is_err());
+        assert!(config(&["--resolver", "192.0.2.53:53", "--rrl-slip", "often"]).is_err());
+    }
+
+    #[test]
+    fn views_must_save_zones_apart() {
+        let args = [
+            "--resolver",
+            "192.0.2.53:53",
+            "--secondary-zone",
+            "example=192.0.2.1:53",
+            "--view",
+            "internal",
+            "--match-clients",
+            "10.0.0.0/8",
+            "--secondary-zone",
+            "example=10.0.0.1:53",
+        ];
+        assert!(error(&args).contains("would both save zone example"));
+        let args = [&args[..], &["--zone-directory", "internal"]].concat();
+        assert!(config(&args).is_ok());
+    }
+}
@@ -0,0 +1,378 @@
+//! DNS cookies (RFC 7873): a lightweight check that whoever sent a query has seen our earlier
+//! replies, so isn't spoofing its address.
+//!
+//! A client sends a client cookie, which is the same for every query to a server, and we reply
+//! with a server cookie made from it and the client's address (RFC 9018). When the client sends
+//! that back, we know the address is real. Upstreams get the same treatment from us.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use rand::Rng;
+
+use crate::{
+    crypto::siphash::siphash24,
+    message::{Cookie, EdnsOption, Message},
+};
+
+/// The server cookie format defined by RFC 9018.
+const SERVER_COOKIE_VERSION: u8 = 1;
+/// How long a server cookie is good for, in seconds (RFC 9018 section 4.3).
+const SERVER_COOKIE_LIFETIME: i64 = 3600;
+/// How far ahead of our clock a server cookie can be, for servers sharing a secret.
+const SERVER_COOKIE_CLOCK_SKEW: i64 = 300;
+
+#[derive(Debug, Clone)]
+pub struct CookieConfig {
+    /// Answer UDP queries which have a client cookie but no valid server cookie with BADCOOKIE,
+    /// rather than answering them as normal.
+    pub require: bool,
+    /// How often to replace the secret server cookies are made with. Cookies made with the one
+    /// before are still accepted, so this should be longer than their lifetime.
+    pub secret_rotation: Duration,
+}
+
+/// Issues and checks server cookies.
+#[derive(Debug)]
+pub struct ServerCookies {
+    config: CookieConfig,
+    secrets: Mutex<Secrets>,
+}
+
+#[derive(Debug)]
+struct Secrets {
+    current: [u8; 16],
+    previous: [u8; 16],
+    rotated_at: Instant,
+}
+
+/// The cookie a query came with.
+#[derive(Debug, Clone)]
+pub struct ClientCookie {
+    client: [u8; 8],
+    /// Whether it also had a server cookie we made recently for this client.
+    pub valid: bool,
+}
+
+/// Client cookies for upstreams, and the server cookies they've given us, shared between clones.
+#[derive(Debug, Clone)]
+pub struct ClientCookies {
+    secret: [u8; 16],
+    server_cookies: Arc<Mutex<HashMap<IpAddr, Vec<u8>>>>,
+}
+
+impl Default for CookieConfig {
+    fn default() -> Self {
+        CookieConfig {
+            require: false,
+            secret_rotation: Duration::from_secs(86400),
+        }
+    }
+}
+
+impl ServerCookies {
+    pub fn new(config: CookieConfig) -> Self {
+        let secret = rand::thread_rng().gen();
+        ServerCookies {
+            config,
+            secrets: Mutex::new(Secrets {
+                current: secret,
+                previous: secret,
+                rotated_at: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn required(&self) -> bool {
+        self.config.require
+    }
+
+    /// The cookie in `query_message`, if it has one, and whether its server cookie checks out.
+    pub fn check(&self, query_message: &Message, client: IpAddr) -> Option<ClientCookie> {
+        let edns = query_message.edns()?;
+        let cookie = edns.cookie()?;
+        let valid = self.is_valid(cookie, client);
+        Some(ClientCookie {
+            client: cookie.client,
+            valid,
+        })
+    }
+
+    fn is_valid(&self, cookie: &Cookie, client: IpAddr) -> bool {
+        if cookie.server.len() != 16 || cookie.server[0] != SERVER_COOKIE_VERSION {
+            return false;
+        }
+        let timestamp = u32::from_be_bytes(cookie.server[4..8].try_into().unwrap());
+        // Timestamps wrap around like serial numbers (RFC 9018 section 4.3)
+        let age = i64::from(now().wrapping_sub(timestamp) as i32);
+        if !(-SERVER_COOKIE_CLOCK_SKEW..=SERVER_COOKIE_LIFETIME).contains(&age) {
+            return false;
+        }
+        let (current, previous) = self.secrets();
+        [current, previous]
+            .iter()
+            .any(|secret| server_cookie(secret, &cookie.client, timestamp, client) == cookie.server)
+    }
+
+    /// Add a fresh server cookie for `cookie` to `response_message`.
+    pub fn add(&self, response_message: &mut Message, cookie: &ClientCookie, client: IpAddr) {
+        let (secret, _) = self.secrets();
+        let cookie = Cookie {
+            client: cookie.client,
+            server: server_cookie(&secret, &cookie.client, now(), client),
+        };
+        response_message.add_edns_option(EdnsOption::Cookie(cookie));
+    }
+
+    /// The current and previous secrets, replacing the current one if it's due.
+    fn secrets(&self) -> ([u8; 16], [u8; 16]) {
+        let mut secrets = self.secrets.lock().unwrap();
+        if secrets.rotated_at.elapsed() >= self.config.secret_rotation {
+            secrets.previous = secrets.current;
+            secrets.current = rand::thread_rng().gen();
+            secrets.rotated_at = Instant::now();
+        }
+        (secrets.current, secrets.previous)
+    }
+}
+
+/// A server cookie: version, three reserved bytes, a timestamp, then a hash over all of those,
+/// the client cookie and the client's address (RFC 9018 section 4).
+fn server_cookie(
+    secret: &[u8; 16],
+    client_cookie: &[u8; 8],
+    timestamp: u32,
+    client: IpAddr,
+) -> Vec<u8> {
+    let mut cookie = vec![SERVER_COOKIE_VERSION, 0, 0, 0];
+    cookie.extend_from_slice(&timestamp.to_be_bytes());
+    let mut data = client_cookie.to_vec();
+    data.extend_from_slice(&cookie);
+    match client {
+        IpAddr::V4(addr) => data.extend_from_slice(&addr.octets()),
+        IpAddr::V6(addr) => data.extend_from_slice(&addr.octets()),
+    }
+    cookie.extend_from_slice(&siphash24(secret, &data));
+    cookie
+}
+
+impl Default for ClientCookies {
+    fn default() -> Self {
+        ClientCookies {
+            secret: rand::thread_rng().gen(),
+            server_cookies: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl ClientCookies {
+    /// The cookie to send to `server`: our client cookie for it, and its server cookie for us if
+    /// it's given us one.
+    pub fn cookie(&self, server: IpAddr) -> Cookie {
+        Cookie {
+            client: self.client_cookie(server),
+            server: self
+                .server_cookies
+                .lock()
+                .unwrap()
+                .get(&server)
+                .cloned()
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Check that a response from `server` echoes our client cookie, if it has a cookie at all,
+    /// and remember its server cookie for next time (RFC 7873 section 5.3).
+    pub fn check_response(&self, server: IpAddr, response_message: &Message) -> anyhow::Result<()> {
+        let Some(edns) = response_message.edns() else {
+            return Ok(());
+        };
+        let Some(cookie) = edns.cookie() else {
+            return Ok(());
+        };
+        if cookie.client != self.client_cookie(server) {
+            anyhow::bail!("response from {server} has the wrong client cookie");
+        }
+        if !cookie.server.is_empty() {
+            self.server_cookies
+                .lock()
+                .unwrap()
+                .insert(server, cookie.server.clone());
+        }
+        Ok(())
+    }
+
+    /// A different client cookie for each server, so that one can't be used to track us across
+    /// servers.
+    fn client_cookie(&self, server: IpAddr) -> [u8; 8] {
+        match server {
+            IpAddr::V4(addr) => siphash24(&self.secret, &addr.octets()),
+            IpAddr::V6(addr) => siphash24(&self.secret, &addr.octets()),
+        }
+    }
+}
+
+fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs() as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encoding::{decode_hex, encode_hex};
+
+    use super::*;
+
+    const CLIENT: [u8; 8] = [0x24, 0x64, 0xc4, 0xab, 0xcf, 0x10, 0xc9, 0x57];
+
+    fn query(server: Vec<u8>) -> Message {
+        let mut query_message = Message::new_query(Vec::new());
+        query_message.add_edns_option(EdnsOption::Cookie(Cookie {
+            client: CLIENT,
+            server,
+        }));
+        query_message
+    }
+
+    /// The server cookie `server_cookies` adds to a response for `client`.
+    fn issue(server_cookies: &ServerCookies, client: IpAddr) -> Vec<u8> {
+        let cookie = server_cookies.check(&query(Vec::new()), client).unwrap();
+        assert!(!cookie.valid);
+        let mut response_message = Message::new_query(Vec::new());
+        server_cookies.add(&mut response_message, &cookie, client);
+        let cookie = response_message.edns().unwrap().cookie().unwrap().clone();
+        assert_eq!(cookie.client, CLIENT);
+        cookie.server
+    }
+
+    // RFC 9018 appendix A.1
+    #[test]
+    fn server_cookies_match_the_rfc() {
+        let secret = decode_hex("e5e973e5a6b2a43f48e7dc849e37bfcf").unwrap();
+        let cookie = server_cookie(
+            &secret.try_into().unwrap(),
+            &CLIENT,
+            1559731985,
+            "198.51.100.100".parse().unwrap(),
+        );
+        assert_eq!(encode_hex(&cookie), "010000005cf79f111f8130c3eee29480");
+    }
+
+    #[test]
+    fn server_cookies_are_valid_for_the_client_they_were_issued_to() {
+        let server_cookies = ServerCookies::new(CookieConfig::default());
+        let client = "192.0.2.1".parse().unwrap();
+        let server = issue(&server_cookies, client);
+        assert_eq!(server.len(), 16);
+        assert!(
+            server_cookies
+                .check(&query(server.clone()), client)
+                .unwrap()
+                .valid
+        );
+
+        let other_client = "192.0.2.2".parse().unwrap();
+        assert!(
+            !server_cookies
+                .check(&query(server.clone()), other_client)
+                .unwrap()
+                .valid
+        );
+
+        let mut tampered = server.clone();
+        tampered[15] ^= 1;
+        assert!(
+            !server_cookies
+                .check(&query(tampered), client)
+                .unwrap()
+                .valid
+        );
+        let mut wrong_version = server.clone();
+        wrong_version[0] = 2;
+        assert!(
+            !server_cookies
+                .check(&query(wrong_version), client)
+                .unwrap()
+                .valid
+        );
+        assert!(
+            !server_cookies
+                .check(&query(server[..8].to_vec()), client)
+                .unwrap()
+                .valid
+        );
+
+        assert!(server_cookies
+            .check(&Message::new_query(Vec::new()), client)
+            .is_none());
+    }
+
+    #[test]
+    fn old_server_cookies_are_invalid() {
+        let server_cookies = ServerCookies::new(CookieConfig::default());
+        let client = "2001:db8::1".parse().unwrap();
+        let (secret, _) = server_cookies.secrets();
+        let expired = server_cookie(&secret, &CLIENT, now() - 3601, client);
+        assert!(!server_cookies.check(&query(expired), client).unwrap().valid);
+        let too_new = server_cookie(&secret, &CLIENT, now() + 301, client);
+        assert!(!server_cookies.check(&query(too_new), client).unwrap().valid);
+        let recent = server_cookie(&secret, &CLIENT, now() - 3000, client);
+        assert!(server_cookies.check(&query(recent), client).unwrap().valid);
+    }
+
+    #[test]
+    fn server_cookies_outlive_one_secret_rotation() {
+        // Every use of the secrets rotates them
+        let server_cookies = ServerCookies::new(CookieConfig {
+            require: false,
+            secret_rotation: Duration::ZERO,
+        });
+        let client = "192.0.2.1".parse().unwrap();
+        let server = issue(&server_cookies, client);
+        assert!(
+            server_cookies
+                .check(&query(server.clone()), client)
+                .unwrap()
+                .valid
+        );
+        assert!(!server_cookies.check(&query(server), client).unwrap().valid);
+    }
+
+    #[test]
+    fn responses_must_echo_the_client_cookie() {
+        let client_cookies = ClientCookies::default();
+        let server = "192.0.2.53".parse().unwrap();
+        let other_server = "192.0.2.54".parse().unwrap();
+        let cookie = client_cookies.cookie(server);
+        assert!(cookie.server.is_empty());
+        assert_ne!(cookie.client, client_cookies.cookie(other_server).client);
+
+        let response = |client| {
+            let mut response_message = Message::new_query(Vec::new());
+            response_message.add_edns_option(EdnsOption::Cookie(Cookie {
+                client,
+                server: vec![1; 16],
+            }));
+            response_message
+        };
+        assert!(client_cookies
+            .check_response(server, &response([0; 8]))
+            .is_err());
+        assert!(client_cookies.cookie(server).server.is_empty());
+        client_cookies
+            .check_response(server, &response(cookie.client))
+            .unwrap();
+        assert_eq!(client_cookies.cookie(server).server, vec![1; 16]);
+        assert!(client_cookies.cookie(other_server).server.is_empty());
+
+        // Responses without cookies are fine, servers needn't support them
+        client_cookies
+            .check_response(server, &Message::new_query(Vec::new()))
+            .unwrap();
+    }
+}
@@ -0,0 +1,320 @@
+//! Arbitrary precision unsigned integers, just enough for verifying signatures.
+//!
+//! Nothing here is constant time, which is fine for verification since everything involved is
+//! public. Signing uses `montgomery` instead.
+
+use std::cmp::Ordering;
+
+use crate::encoding::decode_hex;
+
+/// An unsigned integer, stored as little-endian 32-bit limbs without trailing zeros.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BigUint {
+    limbs: Vec<u32>,
+}
+
+impl BigUint {
+    pub fn zero() -> Self {
+        BigUint { limbs: Vec::new() }
+    }
+
+    pub fn from_u32(value: u32) -> Self {
+        BigUint::from_limbs(vec![value])
+    }
+
+    fn from_limbs(mut limbs: Vec<u32>) -> Self {
+        while limbs.last() == Some(&0) {
+            limbs.pop();
+        }
+        BigUint { limbs }
+    }
+
+    pub fn from_bytes_be(bytes: &[u8]) -> Self {
+        let limbs = bytes
+            .rchunks(4)
+            .map(|chunk| {
+                chunk
+                    .iter()
+                    .fold(0u32, |limb, &byte| (limb << 8) | byte as u32)
+            })
+            .collect();
+        BigUint::from_limbs(limbs)
+    }
+
+    /// For constants, which are known to be valid.
+    pub fn from_hex(hex: &str) -> Self {
+        BigUint::from_bytes_be(&decode_hex(hex).expect("constant should be valid hex"))
+    }
+
+    pub fn from_bytes_le(bytes: &[u8]) -> Self {
+        let reversed = bytes.iter().rev().copied().collect::<Vec<_>>();
+        BigUint::from_bytes_be(&reversed)
+    }
+
+    /// Big-endian bytes, zero-padded to `length`. Higher bytes which don't fit are dropped.
+    pub fn to_bytes_be(&self, length: usize) -> Vec<u8> {
+        let mut bytes = vec![0; length];
+        for (i, byte) in bytes.iter_mut().rev().enumerate() {
+            if let Some(limb) = self.limbs.get(i / 4) {
+                *byte = (limb >> ((i % 4) * 8)) as u8;
+            }
+        }
+        bytes
+    }
+
+    pub fn to_bytes_le(&self, length: usize) -> Vec<u8> {
+        let mut bytes = self.to_bytes_be(length);
+        bytes.reverse();
+        bytes
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.limbs.is_empty()
+    }
+
+    pub fn bit_length(&self) -> usize {
+        match self.limbs.last() {
+            Some(top) => self.limbs.len() * 32 - top.leading_zeros() as usize,
+            None => 0,
+        }
+    }
+
+    pub fn bit(&self, i: usize) -> bool {
+        self.limbs
+            .get(i / 32)
+            .is_some_and(|limb| (limb >> (i % 32)) & 1 != 0)
+    }
+
+    pub fn is_odd(&self) -> bool {
+        self.bit(0)
+    }
+
+    pub fn add(&self, other: &BigUint) -> BigUint {
+        let mut limbs = Vec::with_capacity(self.limbs.len().max(other.limbs.len()) + 1);
+        let mut carry = 0u64;
+        for i in 0..self.limbs.len().max(other.limbs.len()) {
+            let sum = *self.limbs.get(i).unwrap_or(&0) as u64
+                + *other.limbs.get(i).unwrap_or(&0) as u64
+                + carry;
+            limbs.push(sum as u32);
+            carry = sum >> 32;
+        }
+        limbs.push(carry as u32);
+        BigUint::from_limbs(limbs)
+    }
+
+    /// `self - other`, which mustn't be negative.
+    pub fn sub(&self, other: &BigUint) -> BigUint {
+        assert!(*self >= *other, "bignum subtraction underflowed");
+        let mut limbs = Vec::with_capacity(self.limbs.len());
+        let mut borrow = 0i64;
+        for (i, &limb) in self.limbs.iter().enumerate() {
+            let difference = limb as i64 - *other.limbs.get(i).unwrap_or(&0) as i64 - borrow;
+            limbs.push(difference as u32);
+            borrow = (difference < 0) as i64;
+        }
+        BigUint::from_limbs(limbs)
+    }
+
+    pub fn mul(&self, other: &BigUint) -> BigUint {
+        let mut limbs = vec![0u32; self.limbs.len() + other.limbs.len()];
+        for (i, &a) in self.limbs.iter().enumerate() {
+            let mut carry = 0u64;
+            for (j, &b) in other.limbs.iter().enumerate() {
+                let product = a as u64 * b as u64 + limbs[i + j] as u64 + carry;
+                limbs[i + j] = product as u32;
+                carry = product >> 32;
+            }
+            limbs[i + other.limbs.len()] = carry as u32;
+        }
+        BigUint::from_limbs(limbs)
+    }
+
+    /// The quotient and remainder of `self / divisor`.
+    pub fn div_rem(&self, divisor: &BigUint) -> (BigUint, BigUint) {
+        assert!(!divisor.is_zero(), "bignum division by zero");
+        if *self < *divisor {
+            return (BigUint::zero(), self.clone());
+        }
+        if divisor.limbs.len() == 1 {
+            let d = divisor.limbs[0] as u64;
+            let mut quotient = vec![0u32; self.limbs.len()];
+            let mut remainder = 0u64;
+            for (i, &limb) in self.limbs.iter().enumerate().rev() {
+                let current = (remainder << 32) | limb as u64;
+                quotient[i] = (current / d) as u32;
+                remainder = current % d;
+            }
+            return (
+                BigUint::from_limbs(quotient),
+                BigUint::from_u32(remainder as u32),
+            );
+        }
+
+        // Knuth's algorithm D, with the divisor normalized so its top bit is set
+        let shift = divisor.limbs.last().unwrap().leading_zeros();
+        let v = shift_limbs_left(&divisor.limbs, shift);
+        let mut u = shift_limbs_left(&self.limbs, shift);
+        if u.len() == self.limbs.len() {
+            u.push(0);
+        }
+        let n = v.len();
+        let m = u.len() - n - 1;
+        let mut quotient = vec![0u32; m + 1];
+        let base = 1u64 << 32;
+
+        for j in (0..=m).rev() {
+            let numerator = ((u[j + n] as u64) << 32) | u[j + n - 1] as u64;
+            let mut q_hat = numerator / v[n - 1] as u64;
+            let mut r_hat = numerator % v[n - 1] as u64;
+            while q_hat >= base
+                || q_hat as u128 * v[n - 2] as u128 > ((r_hat as u128) << 32) | u[j + n - 2] as u128
+            {
+                q_hat -= 1;
+                r_hat += v[n - 1] as u64;
+                if r_hat >= base {
+                    break;
+                }
+            }
+
+            // Multiply and subtract
+            let mut borrow = 0i64;
+            let mut carry = 0u64;
+            for i in 0..n {
+                let product = q_hat * v[i] as u64 + carry;
+                carry = product >> 32;
+                let difference = u[i + j] as i64 - borrow - (product & 0xFFFF_FFFF) as i64;
+                u[i + j] = difference as u32;
+                borrow = (difference < 0) as i64;
+            }
+            let difference = u[j + n] as i64 - borrow - carry as i64;
+            u[j + n] = difference as u32;
+
+            if difference < 0 {
+                // Subtracted one time too many, so add it back
+                q_hat -= 1;
+                let mut carry = 0u64;
+                for i in 0..n {
+                    let sum = u[i + j] as u64 + v[i] as u64 + carry;
+                    u[i + j] = sum as u32;
+                    carry = sum >> 32;
+                }
+                u[j + n] = u[j + n].wrapping_add(carry as u32);
+            }
+            quotient[j] = q_hat as u32;
+        }
+
+        let remainder = shift_limbs_right(&u[..n], shift);
+        (
+            BigUint::from_limbs(quotient),
+            BigUint::from_limbs(remainder),
+        )
+    }
+
+    pub fn rem(&self, modulus: &BigUint) -> BigUint {
+        self.div_rem(modulus).1
+    }
+
+    /// `self ^ exponent mod modulus`.
+    pub fn mod_pow(&self, exponent: &BigUint, modulus: &BigUint) -> BigUint {
+        let base = self.rem(modulus);
+        let mut result = BigUint::from_u32(1).rem(modulus);
+        for i in (0..exponent.bit_length()).rev() {
+            result = result.mul(&result).rem(modulus);
+            if exponent.bit(i) {
+                result = result.mul(&base).rem(modulus);
+            }
+        }
+        result
+    }
+}
+
+impl Ord for BigUint {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.limbs
+            .len()
+            .cmp(&other.limbs.len())
+            .then_with(|| self.limbs.iter().rev().cmp(other.limbs.iter().rev()))
+    }
+}
+
+impl PartialOrd for BigUint {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+fn shift_limbs_left(limbs: &[u32], shift: u32) -> Vec<u32> {
+    if shift == 0 {
+        return limbs.to_vec();
+    }
+    let mut shifted = Vec::with_capacity(limbs.len() + 1);
+    let mut carry = 0;
+    for &limb in limbs {
+        shifted.push((limb << shift) | carry);
+        carry = limb >> (32 - shift);
+    }
+    if carry != 0 {
+        shifted.push(carry);
+    }
+    shifted
+}
+
+fn shift_limbs_right(limbs: &[u32], shift: u32) -> Vec<u32> {
+    if shift == 0 {
+        return limbs.to_vec();
+    }
+    let mut shifted = vec![0; limbs.len()];
+    for i in 0..limbs.len() {
+        let high = limbs.get(i + 1).map_or(0, |&limb| limb << (32 - shift));
+        shifted[i] = (limbs[i] >> shift) | high;
+    }
+    shifted
+}
+
+/// Arithmetic modulo a prime, on values already reduced below it.
+#[derive(Debug, Clone)]
+pub struct PrimeField {
+    pub modulus: BigUint,
+}
+
+impl PrimeField {
+    pub fn new(modulus: BigUint) -> Self {
+        PrimeField { modulus }
+    }
+
+    pub fn add(&self, a: &BigUint, b: &BigUint) -> BigUint {
+        let sum = a.add(b);
+        if sum >= self.modulus {
+            sum.sub(&self.modulus)
+        } else {
+            sum
+        }
+    }
+
+    pub fn sub(&self, a: &BigUint, b: &BigUint) -> BigUint {
+        if a >= b {
+            a.sub(b)
+        } else {
+            a.add(&self.modulus).sub(b)
+        }
+    }
+
+    pub fn mul(&self, a: &BigUint, b: &BigUint) -> BigUint {
+        a.mul(b).rem(&self.modulus)
+    }
+
+    pub fn neg(&self, a: &BigUint) -> BigUint {
+        self.sub(&BigUint::zero(), a)
+    }
+
+    pub fn pow(&self, a: &BigUint, exponent: &BigUint) -> BigUint {
+        a.mod_pow(exponent, &self.modulus)
+    }
+
+    /// The multiplicative inverse, by Fermat's little theorem.
+    pub fn inv(&self, a: &BigUint) -> BigUint {
+        let exponent = self.modulus.sub(&BigUint::from_u32(2));
+        self.pow(a, &exponent)
+    }
+}
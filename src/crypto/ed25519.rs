@@ -0,0 +1,393 @@
+//! Ed25519 signing and verification (RFC 8032).
+//!
+//! Signing works on the secret scalar and nonce in constant time, using `montgomery` arithmetic
+//! and a Montgomery ladder. Verification only involves public values, so it uses the simpler
+//! variable-time `bignum` arithmetic.
+
+use super::{
+    bignum::{BigUint, PrimeField},
+    montgomery::{self, Modulus, Residue},
+    sha2::sha512,
+};
+
+/// 2^255 - 19
+const P: &str = "7fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffed";
+/// -121665 / 121666
+const D: &str = "52036cee2b6ffe738cc740797779e89800700a4d4141d8ab75eb4dca135978a3";
+/// The order of the base point.
+const L: &str = "1000000000000000000000000000000014def9dea2f79cd65812631a5cf5d3ed";
+/// A square root of -1.
+const SQRT_M1: &str = "2b8324804fc1df0b2b4d00993dfbd7a72f431806ad2fe478c4ee1b274a0ea0b0";
+/// (p - 5) / 8, for computing square roots.
+const SQRT_EXPONENT: &str = "0ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffd";
+const BX: &str = "216936d3cd6e53fec0a4e231fdd6dc5c692cc7609525a7b2c9562d608f25d51a";
+const BY: &str = "6666666666666666666666666666666666666666666666666666666666666658";
+
+/// A point in extended coordinates, where x = X/Z, y = Y/Z and xy = T/Z.
+#[derive(Debug, Clone)]
+struct Point {
+    x: BigUint,
+    y: BigUint,
+    z: BigUint,
+    t: BigUint,
+}
+
+struct Curve {
+    field: PrimeField,
+    d: BigUint,
+}
+
+/// A point in extended coordinates with constant-time arithmetic, for the secret values involved
+/// in signing.
+#[derive(Debug, Clone, Copy)]
+struct SigningPoint {
+    x: Residue,
+    y: Residue,
+    z: Residue,
+    t: Residue,
+}
+
+struct SigningCurve {
+    field: Modulus,
+    order: Modulus,
+    /// 2d, as used by the addition formula.
+    d2: Residue,
+    base: SigningPoint,
+}
+
+/// The public key for a private key, which is a 32 byte seed.
+pub fn public_key(private_key: &[u8]) -> Option<Vec<u8>> {
+    if private_key.len() != 32 {
+        return None;
+    }
+    let curve = SigningCurve::new();
+    let (scalar, _) = expand_private_key(private_key);
+    let scalar = curve.order.reduce_bytes_le(&scalar);
+    Some(curve.compress(&curve.mul(&scalar, &curve.base)).to_vec())
+}
+
+/// Sign `message` with a private key which `public_key` accepts. Ed25519 signatures are
+/// deterministic, so no random numbers are needed.
+pub fn sign(private_key: &[u8], message: &[u8]) -> Vec<u8> {
+    let curve = SigningCurve::new();
+    let order = &curve.order;
+    let (scalar, prefix) = expand_private_key(private_key);
+    let scalar = order.reduce_bytes_le(&scalar);
+    let public_key = curve.compress(&curve.mul(&scalar, &curve.base));
+
+    let r = order.reduce_wide_bytes_le(&sha512(&[&prefix, message].concat()));
+    let encoded_r = curve.compress(&curve.mul(&r, &curve.base));
+    let k = order.reduce_wide_bytes_le(&sha512(&[&encoded_r, &public_key, message].concat()));
+    let s = order.add(&r, &order.mul(&k, &scalar));
+    [encoded_r, order.to_bytes_le(&s)].concat()
+}
+
+pub fn verify(public_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    if public_key.len() != 32 || signature.len() != 64 {
+        return false;
+    }
+    let curve = Curve::new();
+    let l = BigUint::from_hex(L);
+
+    let (Some(r), Some(a)) = (
+        curve.decompress(&signature[..32]),
+        curve.decompress(public_key),
+    ) else {
+        return false;
+    };
+    let s = BigUint::from_bytes_le(&signature[32..]);
+    if s >= l {
+        return false;
+    }
+    let mut hashed = signature[..32].to_vec();
+    hashed.extend_from_slice(public_key);
+    hashed.extend_from_slice(message);
+    let k = BigUint::from_bytes_le(&sha512(&hashed)).rem(&l);
+
+    let lhs = curve.mul(&s, &curve.base());
+    let rhs = curve.add(&r, &curve.mul(&k, &a));
+    curve.equal(&lhs, &rhs)
+}
+
+/// The secret scalar (little-endian) and the prefix used for nonces, from the hash of the
+/// private key.
+fn expand_private_key(private_key: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let hash = sha512(private_key);
+    let mut scalar = [0; 32];
+    scalar.copy_from_slice(&hash[..32]);
+    scalar[0] &= 0xF8;
+    scalar[31] &= 0x7F;
+    scalar[31] |= 0x40;
+    let mut prefix = [0; 32];
+    prefix.copy_from_slice(&hash[32..]);
+    (scalar, prefix)
+}
+
+impl Curve {
+    fn new() -> Self {
+        Curve {
+            field: PrimeField::new(BigUint::from_hex(P)),
+            d: BigUint::from_hex(D),
+        }
+    }
+
+    fn base(&self) -> Point {
+        self.affine_point(BigUint::from_hex(BX), BigUint::from_hex(BY))
+    }
+
+    fn affine_point(&self, x: BigUint, y: BigUint) -> Point {
+        Point {
+            t: self.field.mul(&x, &y),
+            x,
+            y,
+            z: BigUint::from_u32(1),
+        }
+    }
+
+    fn identity() -> Point {
+        Point {
+            x: BigUint::zero(),
+            y: BigUint::from_u32(1),
+            z: BigUint::from_u32(1),
+            t: BigUint::zero(),
+        }
+    }
+
+    /// The point encoded as the y coordinate and the sign of x.
+    fn decompress(&self, encoded: &[u8]) -> Option<Point> {
+        let f = &self.field;
+        let mut bytes = encoded.to_vec();
+        let x_sign = bytes[31] >> 7 != 0;
+        bytes[31] &= 0x7F;
+        let y = BigUint::from_bytes_le(&bytes);
+        if y >= f.modulus {
+            return None;
+        }
+
+        // x^2 = (y^2 - 1) / (d y^2 + 1)
+        let one = BigUint::from_u32(1);
+        let y2 = f.mul(&y, &y);
+        let u = f.sub(&y2, &one);
+        let v = f.add(&f.mul(&self.d, &y2), &one);
+        let v3 = f.mul(&f.mul(&v, &v), &v);
+        let v7 = f.mul(&f.mul(&v3, &v3), &v);
+        let mut x = f.mul(
+            &f.mul(&u, &v3),
+            &f.pow(&f.mul(&u, &v7), &BigUint::from_hex(SQRT_EXPONENT)),
+        );
+        let vx2 = f.mul(&v, &f.mul(&x, &x));
+        if vx2 != u {
+            if vx2 != f.neg(&u) {
+                return None;
+            }
+            x = f.mul(&x, &BigUint::from_hex(SQRT_M1));
+        }
+
+        if x.is_zero() && x_sign {
+            return None;
+        }
+        if x.is_odd() != x_sign {
+            x = f.neg(&x);
+        }
+        Some(self.affine_point(x, y))
+    }
+
+    fn add(&self, p: &Point, q: &Point) -> Point {
+        let field = &self.field;
+        let two = BigUint::from_u32(2);
+        let a = field.mul(&field.sub(&p.y, &p.x), &field.sub(&q.y, &q.x));
+        let b = field.mul(&field.add(&p.y, &p.x), &field.add(&q.y, &q.x));
+        let c = field.mul(&field.mul(&p.t, &two), &field.mul(&self.d, &q.t));
+        let d = field.mul(&field.mul(&p.z, &two), &q.z);
+        let e = field.sub(&b, &a);
+        let f = field.sub(&d, &c);
+        let g = field.add(&d, &c);
+        let h = field.add(&b, &a);
+        Point {
+            x: field.mul(&e, &f),
+            y: field.mul(&g, &h),
+            z: field.mul(&f, &g),
+            t: field.mul(&e, &h),
+        }
+    }
+
+    fn mul(&self, scalar: &BigUint, p: &Point) -> Point {
+        let mut result = Curve::identity();
+        for i in (0..scalar.bit_length()).rev() {
+            result = self.add(&result, &result);
+            if scalar.bit(i) {
+                result = self.add(&result, p);
+            }
+        }
+        result
+    }
+
+    fn equal(&self, p: &Point, q: &Point) -> bool {
+        let f = &self.field;
+        f.mul(&p.x, &q.z) == f.mul(&q.x, &p.z) && f.mul(&p.y, &q.z) == f.mul(&q.y, &p.z)
+    }
+}
+
+impl SigningCurve {
+    fn new() -> Self {
+        let field = Modulus::new(BigUint::from_hex(P));
+        let d = field.residue(&BigUint::from_hex(D));
+        let x = field.residue(&BigUint::from_hex(BX));
+        let y = field.residue(&BigUint::from_hex(BY));
+        SigningCurve {
+            d2: field.add(&d, &d),
+            base: SigningPoint {
+                x,
+                y,
+                z: field.one(),
+                t: field.mul(&x, &y),
+            },
+            order: Modulus::new(BigUint::from_hex(L)),
+            field,
+        }
+    }
+
+    /// The y coordinate, with the sign of x in the top bit.
+    fn compress(&self, p: &SigningPoint) -> [u8; 32] {
+        let f = &self.field;
+        let z_inv = f.inv(&p.z);
+        let x = f.to_bytes_le(&f.mul(&p.x, &z_inv));
+        let mut encoded = f.to_bytes_le(&f.mul(&p.y, &z_inv));
+        encoded[31] |= (x[0] & 1) << 7;
+        encoded
+    }
+
+    /// The same unified formula as `Curve::add`, which also doubles.
+    fn add(&self, p: &SigningPoint, q: &SigningPoint) -> SigningPoint {
+        let field = &self.field;
+        let a = field.mul(&field.sub(&p.y, &p.x), &field.sub(&q.y, &q.x));
+        let b = field.mul(&field.add(&p.y, &p.x), &field.add(&q.y, &q.x));
+        let c = field.mul(&field.mul(&p.t, &self.d2), &q.t);
+        let d = field.mul(&field.add(&p.z, &p.z), &q.z);
+        let e = field.sub(&b, &a);
+        let f = field.sub(&d, &c);
+        let g = field.add(&d, &c);
+        let h = field.add(&b, &a);
+        SigningPoint {
+            x: field.mul(&e, &f),
+            y: field.mul(&g, &h),
+            z: field.mul(&f, &g),
+            t: field.mul(&e, &h),
+        }
+    }
+
+    /// `scalar * p` by a Montgomery ladder, which adds and doubles once for each of the 256 bits
+    /// whatever their values.
+    fn mul(&self, scalar: &Residue, p: &SigningPoint) -> SigningPoint {
+        let bits = self.order.to_bytes_le(scalar);
+        let mut r0 = SigningPoint {
+            x: self.field.zero(),
+            y: self.field.one(),
+            z: self.field.one(),
+            t: self.field.zero(),
+        };
+        let mut r1 = *p;
+        for i in (0..256).rev() {
+            let bit = montgomery::bit_le(&bits, i);
+            r0.conditional_swap(&mut r1, bit);
+            r1 = self.add(&r0, &r1);
+            r0 = self.add(&r0, &r0);
+            r0.conditional_swap(&mut r1, bit);
+        }
+        r0
+    }
+}
+
+impl SigningPoint {
+    fn conditional_swap(&mut self, other: &mut Self, choice: u8) {
+        montgomery::conditional_swap(&mut self.x, &mut other.x, choice);
+        montgomery::conditional_swap(&mut self.y, &mut other.y, choice);
+        montgomery::conditional_swap(&mut self.z, &mut other.z, choice);
+        montgomery::conditional_swap(&mut self.t, &mut other.t, choice);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encoding::decode_hex;
+
+    use super::*;
+
+    /// The private keys of test vectors 1 to 3 of RFC 8032 section 7.1.
+    const PRIVATE_KEYS: [&str; 3] = [
+        "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60",
+        "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb",
+        "c5aa8df43f9f837bedb7442f31dcb7b166d38535076f094b85ce3a2e0b4458f7",
+    ];
+
+    /// Their public keys, messages and signatures.
+    const VECTORS: [(&str, &str, &str); 3] = [
+        (
+            "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+            "",
+            "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e06522490155\
+             5fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+        ),
+        (
+            "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+            "72",
+            "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da\
+             085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+        ),
+        (
+            "fc51cd8e6218a1a38da47ed00230f0580816ed13ba3303ac5deb911548908025",
+            "af82",
+            "6291d657deec24024827e69c3abe01a30ce548a284743a445e3680d7db5ac3ac\
+             18ff9b538d16f290ae67f760984dc6594a7c15e9716ed28dc027beceea1ec40a",
+        ),
+    ];
+
+    #[test]
+    fn known_signatures_verify() {
+        for (public_key, message, signature) in VECTORS {
+            let public_key = decode_hex(public_key).unwrap();
+            let message = decode_hex(message).unwrap();
+            let signature = decode_hex(signature).unwrap();
+            assert!(verify(&public_key, &message, &signature));
+
+            for i in [0, 40] {
+                let mut bad_signature = signature.clone();
+                bad_signature[i] ^= 1;
+                assert!(!verify(&public_key, &message, &bad_signature));
+            }
+            let mut bad_message = message.clone();
+            bad_message.push(0);
+            assert!(!verify(&public_key, &bad_message, &signature));
+            assert!(!verify(&public_key, &message, &signature[1..]));
+        }
+    }
+
+    #[test]
+    fn signatures_are_deterministic() {
+        for (private_key, (public, message, signature)) in PRIVATE_KEYS.iter().zip(VECTORS) {
+            let private_key = decode_hex(private_key).unwrap();
+            let message = decode_hex(message).unwrap();
+            assert_eq!(public_key(&private_key), decode_hex(public).ok());
+            assert_eq!(sign(&private_key, &message), decode_hex(signature).unwrap());
+        }
+        assert_eq!(public_key(&[0; 31]), None);
+    }
+
+    #[test]
+    fn non_canonical_s_fails() {
+        // S + L has the same value mod L, but isn't allowed (RFC 8032 section 5.1.7)
+        let (public_key, message, signature) = VECTORS[0];
+        let public_key = decode_hex(public_key).unwrap();
+        let message = decode_hex(message).unwrap();
+        let mut signature = decode_hex(signature).unwrap();
+        let l =
+            decode_hex("edd3f55c1a631258d69cf7a2def9de1400000000000000000000000000000010").unwrap();
+        let mut carry = 0u16;
+        for i in 0..32 {
+            let sum = signature[32 + i] as u16 + l[i] as u16 + carry;
+            signature[32 + i] = sum as u8;
+            carry = sum >> 8;
+        }
+        assert!(!verify(&public_key, &message, &signature));
+    }
+}
@@ -0,0 +1,70 @@
+//! HMAC (RFC 2104).
+
+use super::sha2::{sha256, sha512};
+
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    hmac::<64, 32>(sha256, key, data)
+}
+
+pub fn hmac_sha512(key: &[u8], data: &[u8]) -> [u8; 64] {
+    hmac::<128, 64>(sha512, key, data)
+}
+
+/// HMAC over a hash with `BLOCK` byte blocks, which keys are padded to, and `OUTPUT` byte digests.
+fn hmac<const BLOCK: usize, const OUTPUT: usize>(
+    hash: fn(&[u8]) -> [u8; OUTPUT],
+    key: &[u8],
+    data: &[u8],
+) -> [u8; OUTPUT] {
+    let mut block = [0u8; BLOCK];
+    if key.len() > BLOCK {
+        block[..OUTPUT].copy_from_slice(&hash(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = block.iter().map(|byte| byte ^ 0x36).collect::<Vec<_>>();
+    inner.extend_from_slice(data);
+    let mut outer = block.iter().map(|byte| byte ^ 0x5c).collect::<Vec<_>>();
+    outer.extend_from_slice(&hash(&inner));
+    hash(&outer)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encoding::encode_hex;
+
+    use super::*;
+
+    // RFC 4231 test cases 1, 2 and 6
+    #[test]
+    fn known_answers() {
+        let cases: [(&[u8], &[u8], &str, &str); 3] = [
+            (
+                &[0x0b; 20],
+                b"Hi There",
+                "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7",
+                "87aa7cdea5ef619d4ff0b4241a1d6cb02379f4e2ce4ec2787ad0b30545e17cde\
+                 daa833b7d6b8a702038b274eaea3f4e4be9d914eeb61f1702e696c203a126854",
+            ),
+            (
+                b"Jefe",
+                b"what do ya want for nothing?",
+                "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+                "164b7a7bfcf819e2e395fbe73b56e0a387bd64222e831fd610270cd7ea250554\
+                 9758bf75c05a994a6d034f65f8f0e6fdcaeab1a34d4a6b4b636e070a38bce737",
+            ),
+            (
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First",
+                "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54",
+                "80b24263c7c1a3ebb71493c1dd7be8b49b46d1f41b4aeec1121b013783f8f352\
+                 6b56d037e05f2598bd0fd2215d6a1e5295e64f73f63f0aec8b915a985d786598",
+            ),
+        ];
+        for (key, data, expected_sha256, expected_sha512) in cases {
+            assert_eq!(encode_hex(&hmac_sha256(key, data)), expected_sha256);
+            assert_eq!(encode_hex(&hmac_sha512(key, data)), expected_sha512);
+        }
+    }
+}
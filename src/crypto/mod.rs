@@ -0,0 +1,27 @@
+//! The hashes and signature algorithms needed for DNSSEC, TSIG and cookies, written out by hand
+//! since there's no cryptography library to depend on. Only P-256 and Ed25519 can sign as well as
+//! verify, since both can sign deterministically without a source of secure random numbers. Their
+//! signing uses the constant-time arithmetic in `montgomery`, keeping private keys out of timings.
+
+pub mod bignum;
+pub mod ed25519;
+pub mod hmac;
+pub mod montgomery;
+pub mod p256;
+pub mod rsa;
+pub mod sha1;
+pub mod sha2;
+pub mod siphash;
+
+/// Pad a message for a Merkle–Damgård hash with `BLOCK` byte blocks: a 1 bit, zeros, and the
+/// length in bits as a `LENGTH` byte big-endian number.
+fn pad_message<const BLOCK: usize, const LENGTH: usize>(data: &[u8]) -> Vec<u8> {
+    let mut padded = data.to_vec();
+    padded.push(0x80);
+    while padded.len() % BLOCK != BLOCK - LENGTH {
+        padded.push(0);
+    }
+    let bit_length = (data.len() as u128) * 8;
+    padded.extend_from_slice(&bit_length.to_be_bytes()[16 - LENGTH..]);
+    padded
+}
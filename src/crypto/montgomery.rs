@@ -0,0 +1,364 @@
+//! Constant-time arithmetic modulo an odd number of up to 256 bits, for the secret side of
+//! signing: private keys, nonces and the points made from them.
+//!
+//! Numbers are kept as four 64-bit limbs in Montgomery form, every operation does the same work
+//! whatever the values, and choices are made with masks rather than branches. Only the modulus and
+//! exponents, which are public, affect how long anything takes.
+
+use super::bignum::BigUint;
+
+const LIMBS: usize = 4;
+
+/// A number modulo some `Modulus`, as `a * 2^256 mod m`.
+#[derive(Debug, Clone, Copy)]
+pub struct Residue([u64; LIMBS]);
+
+#[derive(Debug, Clone)]
+pub struct Modulus {
+    modulus: BigUint,
+    m: [u64; LIMBS],
+    /// -m^-1 mod 2^64.
+    m_prime: u64,
+    /// 2^512 mod m, which converts into Montgomery form.
+    r2: [u64; LIMBS],
+    /// 2^768 mod m, which converts the upper half of a 512-bit number.
+    r3: [u64; LIMBS],
+}
+
+impl Modulus {
+    pub fn new(modulus: BigUint) -> Self {
+        assert!(
+            modulus.is_odd() && modulus.bit_length() <= 256,
+            "the modulus should be odd and at most 256 bits"
+        );
+        let power_of_two = |bits: usize| {
+            let mut bytes = vec![0; bits / 8 + 1];
+            bytes[0] = 1;
+            limbs(&BigUint::from_bytes_be(&bytes).rem(&modulus))
+        };
+        let m = limbs(&modulus);
+        // Newton's method doubles the correct low bits of the inverse each time
+        let mut inverse = 1u64;
+        for _ in 0..6 {
+            inverse = inverse.wrapping_mul(2u64.wrapping_sub(m[0].wrapping_mul(inverse)));
+        }
+        Modulus {
+            m,
+            m_prime: inverse.wrapping_neg(),
+            r2: power_of_two(512),
+            r3: power_of_two(768),
+            modulus,
+        }
+    }
+
+    pub fn zero(&self) -> Residue {
+        Residue([0; LIMBS])
+    }
+
+    pub fn one(&self) -> Residue {
+        self.reduce_limbs([1, 0, 0, 0])
+    }
+
+    /// A public number, such as a curve constant.
+    pub fn residue(&self, value: &BigUint) -> Residue {
+        self.reduce_limbs(limbs(&value.rem(&self.modulus)))
+    }
+
+    /// A 32 byte big-endian number, reduced modulo m.
+    pub fn reduce_bytes_be(&self, bytes: &[u8; 32]) -> Residue {
+        let mut le = *bytes;
+        le.reverse();
+        self.reduce_bytes_le(&le)
+    }
+
+    /// A 32 byte little-endian number, reduced modulo m.
+    pub fn reduce_bytes_le(&self, bytes: &[u8; 32]) -> Residue {
+        self.reduce_limbs(limbs_from_le(bytes))
+    }
+
+    /// A 32 byte big-endian number, or nothing if it isn't less than m. Only whether it is
+    /// depends on the value.
+    pub fn checked_bytes_be(&self, bytes: &[u8; 32]) -> Option<Residue> {
+        let mut le = *bytes;
+        le.reverse();
+        let value = limbs_from_le(&le);
+        let (_, borrow) = sub_limbs(&value, &self.m);
+        (borrow == 1).then(|| self.reduce_limbs(value))
+    }
+
+    /// A 64 byte little-endian number, such as a SHA-512 hash, reduced modulo m.
+    pub fn reduce_wide_bytes_le(&self, bytes: &[u8; 64]) -> Residue {
+        let mut low = [0; 32];
+        let mut high = [0; 32];
+        low.copy_from_slice(&bytes[..32]);
+        high.copy_from_slice(&bytes[32..]);
+        // low * R + high * 2^256 * R, in Montgomery form
+        let low = self.mont_mul(&limbs_from_le(&low), &self.r2);
+        let high = self.mont_mul(&limbs_from_le(&high), &self.r3);
+        self.add(&Residue(low), &Residue(high))
+    }
+
+    pub fn to_bytes_be(&self, a: &Residue) -> [u8; 32] {
+        let mut bytes = self.to_bytes_le(a);
+        bytes.reverse();
+        bytes
+    }
+
+    pub fn to_bytes_le(&self, a: &Residue) -> [u8; 32] {
+        let value = self.mont_mul(&a.0, &[1, 0, 0, 0]);
+        let mut bytes = [0; 32];
+        for (chunk, limb) in bytes.chunks_mut(8).zip(value.iter()) {
+            chunk.copy_from_slice(&limb.to_le_bytes());
+        }
+        bytes
+    }
+
+    pub fn is_zero(&self, a: &Residue) -> bool {
+        a.0.iter().fold(0, |acc, limb| acc | limb) == 0
+    }
+
+    pub fn add(&self, a: &Residue, b: &Residue) -> Residue {
+        let mut sum = [0; LIMBS];
+        let mut carry = 0;
+        for (sum, (a, b)) in sum.iter_mut().zip(a.0.iter().zip(b.0.iter())) {
+            let s = *a as u128 + *b as u128 + carry as u128;
+            *sum = s as u64;
+            carry = (s >> 64) as u64;
+        }
+        Residue(self.subtract_modulus(sum, carry))
+    }
+
+    pub fn sub(&self, a: &Residue, b: &Residue) -> Residue {
+        let (difference, borrow) = sub_limbs(&a.0, &b.0);
+        // Add m back if it went below zero
+        let mask = borrow.wrapping_neg();
+        let mut result = [0; LIMBS];
+        let mut carry = 0;
+        for i in 0..LIMBS {
+            let s = difference[i] as u128 + (self.m[i] & mask) as u128 + carry as u128;
+            result[i] = s as u64;
+            carry = (s >> 64) as u64;
+        }
+        Residue(result)
+    }
+
+    pub fn mul(&self, a: &Residue, b: &Residue) -> Residue {
+        Residue(self.mont_mul(&a.0, &b.0))
+    }
+
+    /// `a` to the power of a public exponent.
+    pub fn pow(&self, a: &Residue, exponent: &BigUint) -> Residue {
+        let mut result = self.one();
+        for i in (0..exponent.bit_length()).rev() {
+            result = self.mul(&result, &result);
+            if exponent.bit(i) {
+                result = self.mul(&result, a);
+            }
+        }
+        result
+    }
+
+    /// The inverse of `a` by Fermat's little theorem, so m must be prime.
+    pub fn inv(&self, a: &Residue) -> Residue {
+        self.pow(a, &self.modulus.sub(&BigUint::from_u32(2)))
+    }
+
+    fn reduce_limbs(&self, value: [u64; LIMBS]) -> Residue {
+        Residue(self.mont_mul(&value, &self.r2))
+    }
+
+    /// a * b / 2^256 mod m, by coarsely integrated operand scanning. Any `a` below 2^256 works
+    /// as long as `b` is below m.
+    fn mont_mul(&self, a: &[u64; LIMBS], b: &[u64; LIMBS]) -> [u64; LIMBS] {
+        let mut t = [0u64; LIMBS + 2];
+        for &b_limb in b.iter() {
+            let mut carry = 0u64;
+            for j in 0..LIMBS {
+                let s = t[j] as u128 + a[j] as u128 * b_limb as u128 + carry as u128;
+                t[j] = s as u64;
+                carry = (s >> 64) as u64;
+            }
+            let s = t[LIMBS] as u128 + carry as u128;
+            t[LIMBS] = s as u64;
+            t[LIMBS + 1] = (s >> 64) as u64;
+
+            // Add a multiple of m which clears the lowest limb, then shift it away
+            let u = t[0].wrapping_mul(self.m_prime);
+            let s = t[0] as u128 + u as u128 * self.m[0] as u128;
+            let mut carry = (s >> 64) as u64;
+            for j in 1..LIMBS {
+                let s = t[j] as u128 + u as u128 * self.m[j] as u128 + carry as u128;
+                t[j - 1] = s as u64;
+                carry = (s >> 64) as u64;
+            }
+            let s = t[LIMBS] as u128 + carry as u128;
+            t[LIMBS - 1] = s as u64;
+            t[LIMBS] = t[LIMBS + 1] + (s >> 64) as u64;
+        }
+        let mut result = [0; LIMBS];
+        result.copy_from_slice(&t[..LIMBS]);
+        self.subtract_modulus(result, t[LIMBS])
+    }
+
+    /// `value + 2^256 * high` less m if that's at least m, given it's less than 2m.
+    fn subtract_modulus(&self, value: [u64; LIMBS], high: u64) -> [u64; LIMBS] {
+        let (difference, borrow) = sub_limbs(&value, &self.m);
+        // Keep the difference unless it went below zero, which a high bit makes up for
+        let keep_value = (borrow & !high & 1).wrapping_neg();
+        let mut result = difference;
+        for (result, value) in result.iter_mut().zip(value.iter()) {
+            *result ^= (*result ^ value) & keep_value;
+        }
+        result
+    }
+}
+
+/// Swap `a` and `b` if `choice` is 1.
+pub fn conditional_swap(a: &mut Residue, b: &mut Residue, choice: u8) {
+    let mask = (choice as u64).wrapping_neg();
+    for i in 0..LIMBS {
+        let t = (a.0[i] ^ b.0[i]) & mask;
+        a.0[i] ^= t;
+        b.0[i] ^= t;
+    }
+}
+
+/// Bit `i` of a little-endian number, without branching on it.
+pub fn bit_le(bytes: &[u8; 32], i: usize) -> u8 {
+    (bytes[i / 8] >> (i % 8)) & 1
+}
+
+fn limbs(value: &BigUint) -> [u64; LIMBS] {
+    let bytes = value.to_bytes_le(32);
+    let mut le = [0; 32];
+    le.copy_from_slice(&bytes);
+    limbs_from_le(&le)
+}
+
+fn limbs_from_le(bytes: &[u8; 32]) -> [u64; LIMBS] {
+    let mut value = [0; LIMBS];
+    for (limb, chunk) in value.iter_mut().zip(bytes.chunks(8)) {
+        let mut limb_bytes = [0; 8];
+        limb_bytes.copy_from_slice(chunk);
+        *limb = u64::from_le_bytes(limb_bytes);
+    }
+    value
+}
+
+/// `a - b`, and 1 if that borrowed.
+fn sub_limbs(a: &[u64; LIMBS], b: &[u64; LIMBS]) -> ([u64; LIMBS], u64) {
+    let mut difference = [0; LIMBS];
+    let mut borrow = 0;
+    for i in 0..LIMBS {
+        let (d, borrow1) = a[i].overflowing_sub(b[i]);
+        let (d, borrow2) = d.overflowing_sub(borrow);
+        difference[i] = d;
+        borrow = (borrow1 | borrow2) as u64;
+    }
+    (difference, borrow)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+
+    /// The P-256 field and group order, and the Ed25519 field and group order.
+    const MODULI: [&str; 4] = [
+        "ffffffff00000001000000000000000000000000ffffffffffffffffffffffff",
+        "ffffffff00000000ffffffffffffffffbce6faada7179e84f3b9cac2fc632551",
+        "7fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffed",
+        "1000000000000000000000000000000014def9dea2f79cd65812631a5cf5d3ed",
+    ];
+
+    fn to_biguint(modulus: &Modulus, a: &Residue) -> BigUint {
+        BigUint::from_bytes_be(&modulus.to_bytes_be(a))
+    }
+
+    /// Matches the variable-time arithmetic on random numbers, and the edges.
+    #[test]
+    fn arithmetic_matches_bignum() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for modulus in MODULI {
+            let m = BigUint::from_hex(modulus);
+            let field = Modulus::new(m.clone());
+            let mut values = vec![
+                BigUint::zero(),
+                BigUint::from_u32(1),
+                m.sub(&BigUint::from_u32(1)),
+            ];
+            for _ in 0..20 {
+                let bytes: [u8; 32] = rng.gen();
+                values.push(BigUint::from_bytes_be(&bytes).rem(&m));
+            }
+            for a in values.iter() {
+                let a_residue = field.residue(a);
+                assert_eq!(to_biguint(&field, &a_residue), *a);
+                if !a.is_zero() {
+                    let inverse = field.inv(&a_residue);
+                    let product = field.mul(&a_residue, &inverse);
+                    assert_eq!(to_biguint(&field, &product), BigUint::from_u32(1));
+                }
+                for b in values.iter().take(8) {
+                    let b_residue = field.residue(b);
+                    assert_eq!(
+                        to_biguint(&field, &field.add(&a_residue, &b_residue)),
+                        a.add(b).rem(&m)
+                    );
+                    assert_eq!(
+                        to_biguint(&field, &field.sub(&a_residue, &b_residue)),
+                        a.add(&m).sub(b).rem(&m)
+                    );
+                    assert_eq!(
+                        to_biguint(&field, &field.mul(&a_residue, &b_residue)),
+                        a.mul(b).rem(&m)
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn byte_conversions_reduce() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for modulus in MODULI {
+            let m = BigUint::from_hex(modulus);
+            let field = Modulus::new(m.clone());
+            for _ in 0..20 {
+                let bytes: [u8; 32] = rng.gen();
+                let value = BigUint::from_bytes_be(&bytes);
+                let residue = field.reduce_bytes_be(&bytes);
+                assert_eq!(to_biguint(&field, &residue), value.rem(&m));
+                assert_eq!(field.checked_bytes_be(&bytes).is_some(), value < m);
+
+                let wide: Vec<u8> = (0..64).map(|_| rng.gen()).collect();
+                let mut wide_bytes = [0; 64];
+                wide_bytes.copy_from_slice(&wide);
+                let residue = field.reduce_wide_bytes_le(&wide_bytes);
+                assert_eq!(
+                    to_biguint(&field, &residue),
+                    BigUint::from_bytes_le(&wide).rem(&m)
+                );
+            }
+            let m_bytes = m.to_bytes_be(32);
+            let mut m_bytes_array = [0; 32];
+            m_bytes_array.copy_from_slice(&m_bytes);
+            assert!(field.checked_bytes_be(&m_bytes_array).is_none());
+            assert!(field.is_zero(&field.reduce_bytes_be(&m_bytes_array)));
+        }
+    }
+
+    #[test]
+    fn conditional_swaps() {
+        let field = Modulus::new(BigUint::from_hex(MODULI[0]));
+        let a = field.residue(&BigUint::from_u32(3));
+        let b = field.residue(&BigUint::from_u32(5));
+        let value = |r: &Residue| to_biguint(&field, r);
+        let (mut c, mut d) = (a, b);
+        conditional_swap(&mut c, &mut d, 0);
+        assert_eq!((value(&c), value(&d)), (value(&a), value(&b)));
+        conditional_swap(&mut c, &mut d, 1);
+        assert_eq!((value(&c), value(&d)), (value(&b), value(&a)));
+    }
+}
@@ -0,0 +1,436 @@
+//! ECDSA signing and verification on the NIST P-256 curve (FIPS 186-4), with keys and signatures
+//! in the DNSSEC format of RFC 6605: the raw coordinates and the raw `r` and `s` values.
+//!
+//! Signing works on the private key and nonce in constant time, using `montgomery` arithmetic, a
+//! Montgomery ladder and complete addition formulas. Verification only involves public values, so
+//! it uses the simpler variable-time `bignum` arithmetic.
+
+use super::{
+    bignum::{BigUint, PrimeField},
+    hmac::hmac_sha256,
+    montgomery::{self, Modulus, Residue},
+};
+
+const P: &str = "ffffffff00000001000000000000000000000000ffffffffffffffffffffffff";
+const B: &str = "5ac635d8aa3a93e7b3ebbd55769886bc651d06b0cc53b0f63bce3c3e27d2604b";
+const N: &str = "ffffffff00000000ffffffffffffffffbce6faada7179e84f3b9cac2fc632551";
+const GX: &str = "6b17d1f2e12c4247f8bce6e563a440f277037d812deb33a0f4a13945d898c296";
+const GY: &str = "4fe342e2fe1a7f9b8ee7eb4a7c0f9e162bce33576b315ececbb6406837bf51f5";
+
+/// A point in Jacobian coordinates, where `z` is zero for the point at infinity.
+#[derive(Debug, Clone)]
+struct Point {
+    x: BigUint,
+    y: BigUint,
+    z: BigUint,
+}
+
+struct Curve {
+    field: PrimeField,
+    order: PrimeField,
+}
+
+/// A point in projective coordinates, (x / z, y / z), where the point at infinity is (0 : 1 : 0).
+/// Signing uses these, since the private key and nonce are secret.
+#[derive(Debug, Clone, Copy)]
+struct ProjectivePoint {
+    x: Residue,
+    y: Residue,
+    z: Residue,
+}
+
+/// The curve with constant-time arithmetic, for the secret values involved in signing.
+struct SigningCurve {
+    field: Modulus,
+    order: Modulus,
+    b: Residue,
+}
+
+/// The public key for a private key, as the raw coordinates, or `None` if the private key isn't
+/// valid.
+pub fn public_key(private_key: &[u8]) -> Option<Vec<u8>> {
+    let private_key: &[u8; 32] = private_key.try_into().ok()?;
+    let curve = SigningCurve::new();
+    let d = curve.order.checked_bytes_be(private_key)?;
+    if curve.order.is_zero(&d) {
+        return None;
+    }
+    let (x, y) = curve.to_affine(&curve.mul(&d, &curve.generator()));
+    Some([x, y].concat())
+}
+
+/// Sign `hash` (a SHA-256 digest) with a private key which `public_key` accepts. The nonce is
+/// derived from the key and hash as in RFC 6979, so no random numbers are needed.
+pub fn sign(private_key: &[u8], hash: &[u8]) -> Vec<u8> {
+    let private_key: &[u8; 32] = private_key.try_into().expect("a valid private key");
+    let hash: &[u8; 32] = hash.try_into().expect("a SHA-256 digest");
+    let curve = SigningCurve::new();
+    let order = &curve.order;
+    let d = order.reduce_bytes_be(private_key);
+    let e = order.reduce_bytes_be(hash);
+
+    let h = order.to_bytes_be(&e);
+    let mut v = [0x01; 32];
+    let mut k = hmac_sha256(&[0x00; 32], &[&v[..], &[0x00], private_key, &h].concat());
+    v = hmac_sha256(&k, &v);
+    k = hmac_sha256(&k, &[&v[..], &[0x01], private_key, &h].concat());
+    v = hmac_sha256(&k, &v);
+    loop {
+        v = hmac_sha256(&k, &v);
+        if let Some(nonce) = order.checked_bytes_be(&v) {
+            if !order.is_zero(&nonce) {
+                let (x, _) = curve.to_affine(&curve.mul(&nonce, &curve.generator()));
+                let r = order.reduce_bytes_be(&x);
+                let s = order.mul(&order.inv(&nonce), &order.add(&e, &order.mul(&r, &d)));
+                if !order.is_zero(&r) && !order.is_zero(&s) {
+                    return [order.to_bytes_be(&r), order.to_bytes_be(&s)].concat();
+                }
+            }
+        }
+        k = hmac_sha256(&k, &[&v[..], &[0x00]].concat());
+        v = hmac_sha256(&k, &v);
+    }
+}
+
+/// Whether `signature` is a valid signature of `hash` (a SHA-256 digest) under `public_key`.
+pub fn verify(public_key: &[u8], hash: &[u8], signature: &[u8]) -> bool {
+    if public_key.len() != 64 || signature.len() != 64 {
+        return false;
+    }
+    let curve = Curve::new();
+    let n = &curve.order.modulus;
+
+    let r = BigUint::from_bytes_be(&signature[..32]);
+    let s = BigUint::from_bytes_be(&signature[32..]);
+    if r.is_zero() || s.is_zero() || r >= *n || s >= *n {
+        return false;
+    }
+    let Some(q) = curve.affine_point(&public_key[..32], &public_key[32..]) else {
+        return false;
+    };
+
+    let e = BigUint::from_bytes_be(hash).rem(n);
+    let w = curve.order.inv(&s);
+    let u1 = curve.order.mul(&e, &w);
+    let u2 = curve.order.mul(&r, &w);
+    let sum = curve.double_mul(&u1, &curve.generator(), &u2, &q);
+    curve.to_affine(&sum).is_some_and(|(x, _)| x.rem(n) == r)
+}
+
+impl Point {
+    fn affine(x: BigUint, y: BigUint) -> Self {
+        Point {
+            x,
+            y,
+            z: BigUint::from_u32(1),
+        }
+    }
+
+    fn infinity() -> Self {
+        Point {
+            x: BigUint::from_u32(1),
+            y: BigUint::from_u32(1),
+            z: BigUint::zero(),
+        }
+    }
+}
+
+impl Curve {
+    fn new() -> Self {
+        Curve {
+            field: PrimeField::new(BigUint::from_hex(P)),
+            order: PrimeField::new(BigUint::from_hex(N)),
+        }
+    }
+
+    fn generator(&self) -> Point {
+        Point::affine(BigUint::from_hex(GX), BigUint::from_hex(GY))
+    }
+
+    /// The coordinates of a point, unless it's the point at infinity.
+    fn to_affine(&self, p: &Point) -> Option<(BigUint, BigUint)> {
+        if p.z.is_zero() {
+            return None;
+        }
+        let f = &self.field;
+        let z_inv = f.inv(&p.z);
+        let z_inv2 = f.mul(&z_inv, &z_inv);
+        Some((f.mul(&p.x, &z_inv2), f.mul(&p.y, &f.mul(&z_inv2, &z_inv))))
+    }
+
+    /// The point with these coordinates, if it's on the curve.
+    fn affine_point(&self, x: &[u8], y: &[u8]) -> Option<Point> {
+        let f = &self.field;
+        let x = BigUint::from_bytes_be(x);
+        let y = BigUint::from_bytes_be(y);
+        if x >= f.modulus || y >= f.modulus {
+            return None;
+        }
+        // y^2 = x^3 - 3x + b
+        let three = BigUint::from_u32(3);
+        let rhs = f.add(
+            &f.sub(&f.mul(&f.mul(&x, &x), &x), &f.mul(&three, &x)),
+            &BigUint::from_hex(B),
+        );
+        (f.mul(&y, &y) == rhs).then(|| Point::affine(x, y))
+    }
+
+    fn double(&self, p: &Point) -> Point {
+        let f = &self.field;
+        if p.z.is_zero() || p.y.is_zero() {
+            return Point::infinity();
+        }
+        // dbl-2001-b, using a = -3
+        let delta = f.mul(&p.z, &p.z);
+        let gamma = f.mul(&p.y, &p.y);
+        let beta = f.mul(&p.x, &gamma);
+        let alpha = f.mul(
+            &BigUint::from_u32(3),
+            &f.mul(&f.sub(&p.x, &delta), &f.add(&p.x, &delta)),
+        );
+        let beta4 = f.mul(&BigUint::from_u32(4), &beta);
+        let x = f.sub(&f.mul(&alpha, &alpha), &f.add(&beta4, &beta4));
+        let y_plus_z = f.add(&p.y, &p.z);
+        let z = f.sub(&f.sub(&f.mul(&y_plus_z, &y_plus_z), &gamma), &delta);
+        let gamma_squared = f.mul(&gamma, &gamma);
+        let y = f.sub(
+            &f.mul(&alpha, &f.sub(&beta4, &x)),
+            &f.mul(&BigUint::from_u32(8), &gamma_squared),
+        );
+        Point { x, y, z }
+    }
+
+    fn add(&self, p: &Point, q: &Point) -> Point {
+        let f = &self.field;
+        if p.z.is_zero() {
+            return q.clone();
+        }
+        if q.z.is_zero() {
+            return p.clone();
+        }
+        // add-1998-cmo-2
+        let z1z1 = f.mul(&p.z, &p.z);
+        let z2z2 = f.mul(&q.z, &q.z);
+        let u1 = f.mul(&p.x, &z2z2);
+        let u2 = f.mul(&q.x, &z1z1);
+        let s1 = f.mul(&p.y, &f.mul(&q.z, &z2z2));
+        let s2 = f.mul(&q.y, &f.mul(&p.z, &z1z1));
+        let h = f.sub(&u2, &u1);
+        let r = f.sub(&s2, &s1);
+        if h.is_zero() {
+            return if r.is_zero() {
+                self.double(p)
+            } else {
+                Point::infinity()
+            };
+        }
+        let h2 = f.mul(&h, &h);
+        let h3 = f.mul(&h2, &h);
+        let u1h2 = f.mul(&u1, &h2);
+        let x = f.sub(&f.sub(&f.mul(&r, &r), &h3), &f.add(&u1h2, &u1h2));
+        let y = f.sub(&f.mul(&r, &f.sub(&u1h2, &x)), &f.mul(&s1, &h3));
+        let z = f.mul(&f.mul(&p.z, &q.z), &h);
+        Point { x, y, z }
+    }
+
+    /// `a * p + b * q`, sharing the doublings between both.
+    fn double_mul(&self, a: &BigUint, p: &Point, b: &BigUint, q: &Point) -> Point {
+        let p_plus_q = self.add(p, q);
+        let mut result = Point::infinity();
+        for i in (0..a.bit_length().max(b.bit_length())).rev() {
+            result = self.double(&result);
+            match (a.bit(i), b.bit(i)) {
+                (true, true) => result = self.add(&result, &p_plus_q),
+                (true, false) => result = self.add(&result, p),
+                (false, true) => result = self.add(&result, q),
+                (false, false) => {}
+            }
+        }
+        result
+    }
+}
+
+impl SigningCurve {
+    fn new() -> Self {
+        let field = Modulus::new(BigUint::from_hex(P));
+        SigningCurve {
+            b: field.residue(&BigUint::from_hex(B)),
+            order: Modulus::new(BigUint::from_hex(N)),
+            field,
+        }
+    }
+
+    fn generator(&self) -> ProjectivePoint {
+        ProjectivePoint {
+            x: self.field.residue(&BigUint::from_hex(GX)),
+            y: self.field.residue(&BigUint::from_hex(GY)),
+            z: self.field.one(),
+        }
+    }
+
+    /// The big-endian coordinates of a point other than the point at infinity.
+    fn to_affine(&self, p: &ProjectivePoint) -> ([u8; 32], [u8; 32]) {
+        let f = &self.field;
+        let z_inv = f.inv(&p.z);
+        (
+            f.to_bytes_be(&f.mul(&p.x, &z_inv)),
+            f.to_bytes_be(&f.mul(&p.y, &z_inv)),
+        )
+    }
+
+    /// The complete addition formula of Renes, Costello and Batina (algorithm 4, for a = -3),
+    /// which works the same way for every pair of points, including doubling and infinity.
+    fn add(&self, p: &ProjectivePoint, q: &ProjectivePoint) -> ProjectivePoint {
+        let f = &self.field;
+        let t0 = f.mul(&p.x, &q.x);
+        let t1 = f.mul(&p.y, &q.y);
+        let t2 = f.mul(&p.z, &q.z);
+        let t3 = f.mul(&f.add(&p.x, &p.y), &f.add(&q.x, &q.y));
+        let t3 = f.sub(&t3, &f.add(&t0, &t1));
+        let t4 = f.mul(&f.add(&p.y, &p.z), &f.add(&q.y, &q.z));
+        let t4 = f.sub(&t4, &f.add(&t1, &t2));
+        let y3 = f.mul(&f.add(&p.x, &p.z), &f.add(&q.x, &q.z));
+        let y3 = f.sub(&y3, &f.add(&t0, &t2));
+        let z3 = f.mul(&self.b, &t2);
+        let x3 = f.sub(&y3, &z3);
+        let x3 = f.add(&x3, &f.add(&x3, &x3));
+        let z3 = f.sub(&t1, &x3);
+        let x3 = f.add(&t1, &x3);
+        let y3 = f.mul(&self.b, &y3);
+        let t2 = f.add(&t2, &f.add(&t2, &t2));
+        let y3 = f.sub(&f.sub(&y3, &t2), &t0);
+        let y3 = f.add(&y3, &f.add(&y3, &y3));
+        let t0 = f.sub(&f.add(&t0, &f.add(&t0, &t0)), &t2);
+        let t1 = f.mul(&t4, &y3);
+        let t2 = f.mul(&t0, &y3);
+        let y3 = f.add(&f.mul(&x3, &z3), &t2);
+        let x3 = f.sub(&f.mul(&t3, &x3), &t1);
+        let z3 = f.add(&f.mul(&t4, &z3), &f.mul(&t3, &t0));
+        ProjectivePoint {
+            x: x3,
+            y: y3,
+            z: z3,
+        }
+    }
+
+    /// `a * p` by a Montgomery ladder, which adds and doubles once for each of the 256 bits
+    /// whatever their values.
+    fn mul(&self, a: &Residue, p: &ProjectivePoint) -> ProjectivePoint {
+        let bits = self.order.to_bytes_le(a);
+        let mut r0 = ProjectivePoint {
+            x: self.field.zero(),
+            y: self.field.one(),
+            z: self.field.zero(),
+        };
+        let mut r1 = *p;
+        for i in (0..256).rev() {
+            let bit = montgomery::bit_le(&bits, i);
+            r0.conditional_swap(&mut r1, bit);
+            r1 = self.add(&r0, &r1);
+            r0 = self.add(&r0, &r0);
+            r0.conditional_swap(&mut r1, bit);
+        }
+        r0
+    }
+}
+
+impl ProjectivePoint {
+    fn conditional_swap(&mut self, other: &mut Self, choice: u8) {
+        montgomery::conditional_swap(&mut self.x, &mut other.x, choice);
+        montgomery::conditional_swap(&mut self.y, &mut other.y, choice);
+        montgomery::conditional_swap(&mut self.z, &mut other.z, choice);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use crate::{crypto::sha2::sha256, encoding::decode_hex};
+
+    use super::*;
+
+    // The P-256 key of RFC 6979 appendix A.2.5
+    const PRIVATE_KEY: &str = "c9afa9d845ba75166b5c215767b1d6934e50c3db36e89b127b8a622b120f6721";
+    const PUBLIC_KEY: &str = "\
+        60fed4ba255a9d31c961eb74c6356d68c049b8923b61fa6ce669622e60f29fb6\
+        7903fe1008b8bc99a41ae9e95628bc64f2f1b20c2d7e9f5177a3c294d4462299";
+
+    // Its SHA-256 signatures of "sample" and "test"
+    const SIGNATURES: [(&[u8], &str); 2] = [
+        (
+            b"sample",
+            "efd48b2aacb6a8fd1140dd9cd45e81d69d2c877b56aaf991c34d0ea84eaf3716\
+             f7cb1c942d657c41d436c7a1b6e29f65f3e900dbb9aff4064dc4ab2f843acda8",
+        ),
+        (
+            b"test",
+            "f1abb023518351cd71d881567b1ea663ed3efcf6c5132b354f28d3b0b7d38367\
+             019f4113742a2b14bd25926b49c649155f267e60d3814b4c0cc84250e46f0083",
+        ),
+    ];
+
+    #[test]
+    fn known_signatures_verify() {
+        let public_key = decode_hex(PUBLIC_KEY).unwrap();
+        for (message, signature) in SIGNATURES {
+            let hash = sha256(message);
+            let signature = decode_hex(signature).unwrap();
+            assert!(verify(&public_key, &hash, &signature));
+
+            for i in [0, 40] {
+                let mut bad_signature = signature.clone();
+                bad_signature[i] ^= 1;
+                assert!(!verify(&public_key, &hash, &bad_signature));
+            }
+            let mut bad_key = public_key.clone();
+            bad_key[63] ^= 1;
+            assert!(!verify(&bad_key, &hash, &signature));
+            assert!(!verify(&public_key, &sha256(b"other"), &signature));
+        }
+    }
+
+    #[test]
+    fn signatures_are_deterministic() {
+        let private_key = decode_hex(PRIVATE_KEY).unwrap();
+        assert_eq!(public_key(&private_key), decode_hex(PUBLIC_KEY).ok());
+        for (message, signature) in SIGNATURES {
+            assert_eq!(
+                sign(&private_key, &sha256(message)),
+                decode_hex(signature).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn signatures_by_other_keys_verify() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..4 {
+            let private_key: [u8; 32] = rng.gen();
+            let public_key = public_key(&private_key).unwrap();
+            let hash = sha256(&private_key);
+            assert!(verify(&public_key, &hash, &sign(&private_key, &hash)));
+        }
+        let n = decode_hex(N).unwrap();
+        assert_eq!(public_key(&n), None);
+        assert_eq!(public_key(&[0; 32]), None);
+        assert_eq!(public_key(&[1; 31]), None);
+    }
+
+    #[test]
+    fn out_of_range_signatures_fail() {
+        let public_key = decode_hex(PUBLIC_KEY).unwrap();
+        let hash = sha256(b"sample");
+        let n = decode_hex(N).unwrap();
+        let (_, s) = SIGNATURES[0].1.split_at(64);
+        let s = decode_hex(s).unwrap();
+        assert!(!verify(
+            &public_key,
+            &hash,
+            &[n.clone(), s.clone()].concat()
+        ));
+        assert!(!verify(&public_key, &hash, &[vec![0; 32], s].concat()));
+        assert!(!verify(&public_key, &hash, &[0; 63]));
+        assert!(!verify(&public_key[1..], &hash, &[0; 64]));
+    }
+}
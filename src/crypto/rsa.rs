@@ -0,0 +1,143 @@
+//! RSASSA-PKCS1-v1_5 signature verification (RFC 8017), with keys in the DNSKEY format of
+//! RFC 3110.
+
+use super::bignum::BigUint;
+
+/// The DER encoded DigestInfo which precedes the hash in a signature.
+pub const SHA1_PREFIX: &[u8] = &[
+    0x30, 0x21, 0x30, 0x09, 0x06, 0x05, 0x2b, 0x0e, 0x03, 0x02, 0x1a, 0x05, 0x00, 0x04, 0x14,
+];
+pub const SHA256_PREFIX: &[u8] = &[
+    0x30, 0x31, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01, 0x05,
+    0x00, 0x04, 0x20,
+];
+pub const SHA512_PREFIX: &[u8] = &[
+    0x30, 0x51, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x03, 0x05,
+    0x00, 0x04, 0x40,
+];
+
+/// Whether `signature` is a valid signature of `hash` under `public_key`, which is the exponent
+/// length, exponent and modulus.
+pub fn verify(public_key: &[u8], prefix: &[u8], hash: &[u8], signature: &[u8]) -> bool {
+    let Some((exponent, modulus)) = parse_public_key(public_key) else {
+        return false;
+    };
+    let length = (modulus.bit_length() + 7) >> 3;
+    let signature = BigUint::from_bytes_be(signature);
+    if signature >= modulus {
+        return false;
+    }
+    let encoded = signature.mod_pow(&exponent, &modulus).to_bytes_be(length);
+
+    // 0x00 0x01 0xFF... 0x00 DigestInfo
+    let digest_info_length = prefix.len() + hash.len();
+    if length < digest_info_length + 11 {
+        return false;
+    }
+    let padding_length = length - digest_info_length - 3;
+    let mut expected = vec![0x00, 0x01];
+    expected.resize(2 + padding_length, 0xFF);
+    expected.push(0x00);
+    expected.extend_from_slice(prefix);
+    expected.extend_from_slice(hash);
+    encoded == expected
+}
+
+fn parse_public_key(public_key: &[u8]) -> Option<(BigUint, BigUint)> {
+    let (&first, rest) = public_key.split_first()?;
+    let (exponent_length, rest) = match first {
+        0 => {
+            let length = u16::from_be_bytes([*rest.first()?, *rest.get(1)?]);
+            (length as usize, &rest[2..])
+        }
+        length => (length as usize, rest),
+    };
+    if rest.len() <= exponent_length {
+        return None;
+    }
+    let (exponent, modulus) = rest.split_at(exponent_length);
+    Some((
+        BigUint::from_bytes_be(exponent),
+        BigUint::from_bytes_be(modulus),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        crypto::{sha1::sha1, sha2::sha256, sha2::sha512},
+        encoding::decode_hex,
+    };
+
+    use super::*;
+
+    /// A 1024-bit key with exponent 65537, in the DNSKEY format.
+    fn public_key() -> Vec<u8> {
+        let modulus = decode_hex(
+            "f0169b326f82e692d810f7b5b9f679ab6419294e2b851f7a74362700d1840bb9\
+             4fde3acb96470d0b18029d5e5516935e348f4f841418b6472500cb642b962757\
+             51db539dba4908030657ce3b4477190da4eb28972be71c0864fdc6d1d42e9a59\
+             bd29107e0acee829d6c12392372553e9a50e4c4b607cc81d852c29adca5986fb",
+        )
+        .unwrap();
+        [&[3, 1, 0, 1][..], &modulus].concat()
+    }
+
+    // Signatures of "abc" made with OpenSSL
+    const SHA1_SIGNATURE: &str = "\
+        99a6542b89ed663f9673f10b4f20449dd57c6ac83835cbfa4679928204eca7f0\
+        bd7331a9f022a202df5724bee73cd989cc55e8b33b642b6473738b8ab948e655\
+        2efe1e41d1e47fc82deed5206d97b4a2d90300d80a4771fc1a307834c7e217ab\
+        1f4fc0cb70d43fbd7c9fcd0558b0981a3f5ff963539fe7469d447429c89e4c7f";
+    const SHA256_SIGNATURE: &str = "\
+        5ec211641322ade1b899b83231b6c3df1891bdb127c1404bb47906922dda931f\
+        16ee06772935eca35ac3bd68ab9779a0c0553018ec88b648db4027d264570095\
+        9282f03cd1fc3f05af15749b26c200fac38b4731dc2539193843f7c48d34d575\
+        5b6f8045e039686c1f454a55ab8a89f02b27a012e19aad32d0f682220ed34ab3";
+    const SHA512_SIGNATURE: &str = "\
+        77a38965ddc7961b3157104e96f81c35ab4aaa6e5db4798c90d11fb35f316168\
+        cb2a4c1ee2cbbee01a6689e3960b08f4f2898722970ec0f8bfb2c2ea9b2fc06d\
+        f44a88d5d07cf30ab480dbb672e2c4306d53b860748ead8e80d9ce79ef7b88e3\
+        18bdeb19856e18951c39cee3d58fc5bb76d74236da428966678b103099084be2";
+
+    #[test]
+    fn known_signatures_verify() {
+        let public_key = public_key();
+        let cases = [
+            (SHA1_PREFIX, sha1(b"abc").to_vec(), SHA1_SIGNATURE),
+            (SHA256_PREFIX, sha256(b"abc").to_vec(), SHA256_SIGNATURE),
+            (SHA512_PREFIX, sha512(b"abc").to_vec(), SHA512_SIGNATURE),
+        ];
+        for (prefix, hash, signature) in cases {
+            let signature = decode_hex(signature).unwrap();
+            assert!(verify(&public_key, prefix, &hash, &signature));
+
+            let mut bad_signature = signature.clone();
+            bad_signature[64] ^= 1;
+            assert!(!verify(&public_key, prefix, &hash, &bad_signature));
+            let mut bad_hash = hash.clone();
+            bad_hash[0] ^= 1;
+            assert!(!verify(&public_key, prefix, &bad_hash, &signature));
+        }
+        // The hash algorithm is part of what's signed
+        let signature = decode_hex(SHA256_SIGNATURE).unwrap();
+        assert!(!verify(
+            &public_key,
+            SHA512_PREFIX,
+            &sha256(b"abc"),
+            &signature
+        ));
+    }
+
+    #[test]
+    fn malformed_keys_and_signatures_fail() {
+        let hash = sha256(b"abc");
+        let signature = decode_hex(SHA256_SIGNATURE).unwrap();
+        assert!(!verify(&[], SHA256_PREFIX, &hash, &signature));
+        assert!(!verify(&[3, 1, 0, 1], SHA256_PREFIX, &hash, &signature));
+        assert!(!verify(&[0, 0], SHA256_PREFIX, &hash, &signature));
+        // A signature as large as the modulus
+        let public_key = public_key();
+        assert!(!verify(&public_key, SHA256_PREFIX, &hash, &public_key[4..]));
+    }
+}
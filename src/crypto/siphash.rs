@@ -0,0 +1,62 @@
+//! SipHash-2-4, a keyed hash for short inputs, as used for DNS server cookies (RFC 9018).
+
+pub fn siphash24(key: &[u8; 16], data: &[u8]) -> [u8; 8] {
+    let k0 = u64::from_le_bytes(key[..8].try_into().unwrap());
+    let k1 = u64::from_le_bytes(key[8..].try_into().unwrap());
+    let mut v = [
+        k0 ^ 0x736f6d6570736575,
+        k1 ^ 0x646f72616e646f6d,
+        k0 ^ 0x6c7967656e657261,
+        k1 ^ 0x7465646279746573,
+    ];
+
+    // The last block is padded with zeros and ends with the length's low byte
+    let mut last = [0u8; 8];
+    let chunks = data.chunks_exact(8);
+    last[..chunks.remainder().len()].copy_from_slice(chunks.remainder());
+    last[7] = data.len() as u8;
+    let blocks = chunks
+        .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()))
+        .chain([u64::from_le_bytes(last)]);
+    for m in blocks {
+        v[3] ^= m;
+        sip_round(&mut v);
+        sip_round(&mut v);
+        v[0] ^= m;
+    }
+
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        sip_round(&mut v);
+    }
+    (v[0] ^ v[1] ^ v[2] ^ v[3]).to_le_bytes()
+}
+
+fn sip_round(v: &mut [u64; 4]) {
+    v[0] = v[0].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(13) ^ v[0];
+    v[0] = v[0].rotate_left(32);
+    v[2] = v[2].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(16) ^ v[2];
+    v[0] = v[0].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(21) ^ v[0];
+    v[2] = v[2].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(17) ^ v[2];
+    v[2] = v[2].rotate_left(32);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encoding::encode_hex;
+
+    use super::*;
+
+    // Appendix A of the SipHash paper, with the key 00 01 .. 0f and the message 00 01 .. 0e
+    #[test]
+    fn known_answers() {
+        let key = std::array::from_fn(|i| i as u8);
+        let message = (0..15).collect::<Vec<u8>>();
+        assert_eq!(encode_hex(&siphash24(&key, &message)), "e545be4961ca29a1");
+        assert_eq!(encode_hex(&siphash24(&key, &[])), "310e0edd47db6f72");
+    }
+}
@@ -0,0 +1,1477 @@
+//! DNSSEC validation (RFC 4033, 4034 and 4035, with NSEC3 from RFC 5155).
+//!
+//! Queries are forwarded with the DO and CD bits set, so the upstream returns signatures and
+//! doesn't filter out bogus answers itself. Each RRset in the answer is then checked against a
+//! chain of trust built down from the trust anchors, one DS and DNSKEY lookup per zone cut, and
+//! missing answers against NSEC or NSEC3 proofs.
+
+use bytes::{BufMut, BytesMut};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    crypto::{
+        ed25519, p256, rsa,
+        sha1::sha1,
+        sha2::{sha256, sha384, sha512},
+    },
+    encoding::decode_base32hex,
+    forward::{forward, Forwarders, EDNS_UDP_PAYLOAD_SIZE},
+    message::{
+        Class, DomainName, Edns, ExtendedErrorCode, Message, Question, RecordType, ResourceRecord,
+        ResourceRecordData, ResponseCode,
+    },
+    zone_file,
+};
+
+/// The root zone's key signing keys, KSK-2017 and KSK-2024.
+const ROOT_TRUST_ANCHORS: &str = "
+. IN DS 20326 8 2 E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBC683457104237C7F8EC8D
+. IN DS 38696 8 2 683D2D0ACB8C9B712A1948B27F741219298D0A450D612C483AF444A4C0FB2B16
+";
+/// How long to remember what's at a zone cut.
+const ZONE_CUT_CACHE_TIME: Duration = Duration::from_secs(300);
+/// Expired zone cuts are only cleared out once there are this many.
+const MAX_ZONE_CUTS: usize = 10_000;
+/// RFC 9276 allows treating zones which need more NSEC3 hashing than this as unsigned.
+const MAX_NSEC3_ITERATIONS: u16 = 150;
+/// Guards against CNAME loops in answers.
+const MAX_CNAME_CHAIN: usize = 16;
+/// Bit 7 of the DNSKEY flags, set on keys which may sign the zone.
+const ZONE_KEY_FLAG: u16 = 0x0100;
+/// Bit 0 of the NSEC3 flags.
+const OPT_OUT_FLAG: u8 = 0x01;
+
+#[derive(Debug, Clone, Default)]
+pub struct DnssecConfig {
+    /// Check answers, returning SERVFAIL for bogus ones and setting AD on secure ones.
+    pub validate: bool,
+    /// DS or DNSKEY records in zone file syntax whose keys are trusted without a parent to vouch
+    /// for them. The root's keys if none are given.
+    pub trust_anchors: Vec<String>,
+}
+
+/// The outcome of validation (RFC 4035 section 4.3).
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Security {
+    Secure,
+    /// Not signed, and provably so.
+    Insecure,
+    Bogus(String),
+}
+
+/// What's known about the zone containing a name.
+#[derive(Debug, Clone)]
+enum Zone {
+    Secure {
+        apex: DomainName,
+        /// The zone's validated DNSKEYs.
+        keys: Vec<ResourceRecord>,
+    },
+    /// Beneath an unsigned delegation, or outside every trust anchor.
+    Insecure,
+    Bogus(String),
+}
+
+/// What a DS lookup says about a name beneath a secure zone.
+#[derive(Debug, Clone)]
+enum Cut {
+    /// The apex of a signed zone, with its validated DNSKEYs.
+    Secure(Vec<ResourceRecord>),
+    /// The apex of an unsigned zone.
+    Insecure,
+    /// Not a zone cut, so the name is in the same zone as its parent.
+    SameZone,
+    /// The name doesn't exist, and so nor does anything beneath it.
+    NxDomain,
+}
+
+#[derive(Debug)]
+pub struct Validator {
+    trust_anchors: Vec<ResourceRecord>,
+    /// Zone cuts by name, so the chain of trust isn't rebuilt for every query.
+    zone_cuts: Mutex<HashMap<DomainName, (Instant, Cut)>>,
+}
+
+/// NSEC and NSEC3 records whose signatures have been checked, for proving names or types don't
+/// exist.
+#[derive(Debug, Default)]
+struct Denial {
+    nsecs: Vec<Nsec>,
+    nsec3s: Vec<Nsec3>,
+    /// Some NSEC3 records used parameters we don't support, so a missing proof isn't bogus.
+    unsupported: bool,
+}
+
+#[derive(Debug)]
+struct Nsec {
+    owner: DomainName,
+    next: DomainName,
+    types: Vec<RecordType>,
+}
+
+#[derive(Debug)]
+struct Nsec3 {
+    zone: DomainName,
+    owner_hash: Vec<u8>,
+    next_hash: Vec<u8>,
+    salt: Vec<u8>,
+    iterations: u16,
+    opt_out: bool,
+    types: Vec<RecordType>,
+}
+
+impl Security {
+    /// The weaker of two results.
+    fn and(self, other: Security) -> Security {
+        match (self, other) {
+            (Security::Bogus(reason), _) | (_, Security::Bogus(reason)) => Security::Bogus(reason),
+            (Security::Insecure, _) | (_, Security::Insecure) => Security::Insecure,
+            _ => Security::Secure,
+        }
+    }
+}
+
+impl Validator {
+    pub fn new(config: &DnssecConfig) -> anyhow::Result<Self> {
+        let root = DomainName::new("")?;
+        let trust_anchors = if config.trust_anchors.is_empty() {
+            zone_file::parse(ROOT_TRUST_ANCHORS, &root)?
+        } else {
+            zone_file::parse(&config.trust_anchors.join("\n"), &root)?
+        };
+        if let Some(record) = trust_anchors
+            .iter()
+            .find(|record| !matches!(record.ty, RecordType::DelegationSigner | RecordType::DnsKey))
+        {
+            anyhow::bail!(
+                "trust anchors should be DS or DNSKEY records, not {:?}",
+                record.ty
+            );
+        }
+        Ok(Validator {
+            trust_anchors,
+            zone_cuts: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Forward `query_message` for `client` and check the answer, unless the client disabled
+    /// checking. Bogus answers become SERVFAIL, and secure ones get the AD bit.
+    pub fn resolve(
+        &self,
+        query_message: &Message,
+        client: Option<IpAddr>,
+        forwarders: &Forwarders,
+    ) -> anyhow::Result<Message> {
+        let dnssec_ok = query_message.edns().is_some_and(|edns| edns.dnssec_ok);
+        let checking_disabled = query_message.header.checking_disabled;
+
+        let mut response_message = forward(&with_dnssec(query_message), client, forwarders)?;
+        // The upstream's opinion doesn't count, only our own
+        response_message.header.authentic_data = false;
+        response_message.header.checking_disabled = checking_disabled;
+        if !checking_disabled {
+            match self.validate(&response_message, forwarders) {
+                Security::Secure => {
+                    // Only for clients which show they understand it (RFC 6840 section 5.8)
+                    response_message.header.authentic_data =
+                        dnssec_ok || query_message.header.authentic_data;
+                }
+                Security::Insecure => {}
+                Security::Bogus(reason) => {
+                    for question in query_message.questions.iter() {
+                        eprintln!(
+                            "dnssec validation failed for {} {:?}: {}",
+                            question.name, question.ty, reason
+                        );
+                    }
+                    let mut response_message =
+                        Message::new_error_reply(query_message, ResponseCode::ServerFailure);
+                    response_message.add_extended_error(ExtendedErrorCode::DnssecBogus, &reason);
+                    return Ok(response_message);
+                }
+            }
+        }
+        if !dnssec_ok {
+            strip_dnssec_records(&mut response_message);
+        }
+        Ok(response_message)
+    }
+
+    fn validate(&self, response_message: &Message, forwarders: &Forwarders) -> Security {
+        if !matches!(
+            response_message.header.response_code,
+            ResponseCode::Ok | ResponseCode::NameError
+        ) {
+            // Nothing to check in a failure
+            return Security::Insecure;
+        }
+
+        let mut security = Security::Secure;
+        let rrsets = rrsets(&response_message.answers);
+        for (rrset, signatures) in rrsets.iter() {
+            let owner = &rrset[0].name;
+            // CNAMEs synthesized from a DNAME aren't signed, but the DNAME is
+            let synthesized = rrset[0].ty == RecordType::CName
+                && signatures.is_empty()
+                && rrsets.iter().any(|(dname, _)| {
+                    dname[0].ty == RecordType::Unknown(39)
+                        && owner.is_subdomain_of(&dname[0].name)
+                        && *owner != dname[0].name
+                });
+            if synthesized {
+                continue;
+            }
+
+            let (rrset_security, wildcard) = self.validate_rrset(rrset, signatures, forwarders);
+            security = security.and(rrset_security);
+            // A wildcard answer is only right if there's no closer match
+            if let Some((signer, labels)) = wildcard {
+                security = security.and(match self.find_zone(&signer, forwarders) {
+                    Zone::Secure { apex, keys } => {
+                        authenticated_denial(&apex, &keys, &response_message.authorities)
+                            .no_closer_match(owner, labels)
+                    }
+                    Zone::Insecure => Security::Insecure,
+                    Zone::Bogus(reason) => Security::Bogus(reason),
+                });
+            }
+        }
+
+        for question in response_message.questions.iter() {
+            if matches!(
+                question.ty,
+                RecordType::Unknown(255) | RecordType::Signature
+            ) {
+                // ANY and RRSIG answers aren't whole RRsets, so there's nothing to deny
+                continue;
+            }
+            let name = final_name(question, &response_message.answers);
+            let answered = response_message
+                .answers
+                .iter()
+                .any(|record| record.name == name && record.ty == question.ty);
+            if !answered {
+                security = security.and(self.validate_denial(
+                    &name,
+                    question.ty,
+                    response_message,
+                    forwarders,
+                ));
+            }
+        }
+        security
+    }
+
+    /// Check that an RRset is signed by its zone. Wildcard expansions also return the signer and
+    /// the number of labels in the wildcard.
+    fn validate_rrset(
+        &self,
+        rrset: &[ResourceRecord],
+        signatures: &[ResourceRecord],
+        forwarders: &Forwarders,
+    ) -> (Security, Option<(DomainName, u8)>) {
+        let owner = &rrset[0].name;
+        if signatures.is_empty() {
+            // Only acceptable outside signed zones
+            let security = match self.find_zone(owner, forwarders) {
+                Zone::Secure { .. } => {
+                    Security::Bogus(format!("no signatures for {} {:?}", owner, rrset[0].ty))
+                }
+                Zone::Insecure => Security::Insecure,
+                Zone::Bogus(reason) => Security::Bogus(reason),
+            };
+            return (security, None);
+        }
+
+        let mut security = Security::Bogus(format!("no usable signatures for {owner}"));
+        for signature in signatures.iter() {
+            let ResourceRecordData::Signature { signer_name, .. } = &signature.data else {
+                continue;
+            };
+            if !owner.is_subdomain_of(signer_name) {
+                continue;
+            }
+            match self.find_zone(signer_name, forwarders) {
+                Zone::Secure { apex, keys } if apex == *signer_name => {
+                    match verify_rrset(rrset, signature, &keys) {
+                        Ok(Some(labels)) => {
+                            return (Security::Secure, Some((signer_name.clone(), labels)))
+                        }
+                        Ok(None) => return (Security::Secure, None),
+                        Err(reason) => security = Security::Bogus(reason),
+                    }
+                }
+                Zone::Secure { apex, .. } => {
+                    security = Security::Bogus(format!(
+                        "{owner} is signed by {signer_name}, but is in {apex}"
+                    ))
+                }
+                Zone::Insecure => return (Security::Insecure, None),
+                Zone::Bogus(reason) => security = Security::Bogus(reason),
+            }
+        }
+        (security, None)
+    }
+
+    /// Check the proof that `name` has no records of type `ty`, or doesn't exist at all.
+    fn validate_denial(
+        &self,
+        name: &DomainName,
+        ty: RecordType,
+        response_message: &Message,
+        forwarders: &Forwarders,
+    ) -> Security {
+        match self.find_zone(name, forwarders) {
+            Zone::Secure { apex, keys } => {
+                let denial = authenticated_denial(&apex, &keys, &response_message.authorities);
+                match response_message.header.response_code {
+                    ResponseCode::NameError => denial.nxdomain(name),
+                    _ => denial.nodata(name, ty),
+                }
+            }
+            Zone::Insecure => Security::Insecure,
+            Zone::Bogus(reason) => Security::Bogus(reason),
+        }
+    }
+
+    /// The zone containing `name`, found by following DS records down from the closest trust
+    /// anchor.
+    fn find_zone(&self, name: &DomainName, forwarders: &Forwarders) -> Zone {
+        let Some(anchor_apex) = self
+            .trust_anchors
+            .iter()
+            .map(|anchor| &anchor.name)
+            .filter(|apex| name.is_subdomain_of(apex))
+            .max_by_key(|apex| apex.label_count())
+        else {
+            return Zone::Insecure;
+        };
+
+        let mut apex = anchor_apex.clone();
+        let mut keys = match self.zone_cut(&apex, || {
+            let anchors = self
+                .trust_anchors
+                .iter()
+                .filter(|anchor| anchor.name == *anchor_apex)
+                .cloned()
+                .collect::<Vec<_>>();
+            self.trusted_keys(anchor_apex, &anchors, forwarders)
+        }) {
+            Ok(Cut::Secure(keys)) => keys,
+            Ok(_) => return Zone::Insecure,
+            Err(reason) => return Zone::Bogus(reason),
+        };
+
+        for label_count in apex.label_count() + 1..=name.label_count() {
+            let child = name.ancestor(label_count);
+            match self.zone_cut(&child, || self.find_cut(&apex, &keys, &child, forwarders)) {
+                Ok(Cut::Secure(child_keys)) => {
+                    apex = child;
+                    keys = child_keys;
+                }
+                Ok(Cut::Insecure) => return Zone::Insecure,
+                Ok(Cut::SameZone) => {}
+                Ok(Cut::NxDomain) => break,
+                Err(reason) => return Zone::Bogus(reason),
+            }
+        }
+        Zone::Secure { apex, keys }
+    }
+
+    /// The cached zone cut at `name`, or else the result of `find`. Failures aren't cached, so
+    /// they're retried next time.
+    fn zone_cut(
+        &self,
+        name: &DomainName,
+        find: impl FnOnce() -> Result<Cut, String>,
+    ) -> Result<Cut, String> {
+        let now = Instant::now();
+        if let Some((expires, cut)) = self.zone_cuts.lock().unwrap().get(name) {
+            if *expires > now {
+                return Ok(cut.clone());
+            }
+        }
+        // Not holding the lock while waiting on the network
+        let cut = find()?;
+        let mut zone_cuts = self.zone_cuts.lock().unwrap();
+        if zone_cuts.len() >= MAX_ZONE_CUTS {
+            zone_cuts.retain(|_, (expires, _)| *expires > now);
+        }
+        zone_cuts.insert(name.clone(), (now + ZONE_CUT_CACHE_TIME, cut.clone()));
+        Ok(cut)
+    }
+
+    /// Whether `child` is the apex of a zone beneath `apex`, from what `apex` says about its DS
+    /// records.
+    fn find_cut(
+        &self,
+        apex: &DomainName,
+        keys: &[ResourceRecord],
+        child: &DomainName,
+        forwarders: &Forwarders,
+    ) -> Result<Cut, String> {
+        let response_message = self.query(child, RecordType::DelegationSigner, forwarders)?;
+        let rrsets = rrsets(&response_message.answers);
+        for (rrset, signatures) in rrsets.iter() {
+            if rrset[0].name != *child {
+                continue;
+            }
+            match rrset[0].ty {
+                RecordType::DelegationSigner => {
+                    verify_signed(apex, keys, rrset, signatures)?;
+                    return self.trusted_keys(child, rrset, forwarders);
+                }
+                // Aliases can't also be zone cuts
+                RecordType::CName => {
+                    verify_signed(apex, keys, rrset, signatures)?;
+                    return Ok(Cut::SameZone);
+                }
+                _ => {}
+            }
+        }
+
+        // Otherwise the parent has to prove there are no DS records
+        let denial = authenticated_denial(apex, keys, &response_message.authorities);
+        if matches!(
+            response_message.header.response_code,
+            ResponseCode::NameError
+        ) {
+            return match denial.nxdomain(child) {
+                Security::Secure => Ok(Cut::NxDomain),
+                Security::Insecure => Ok(Cut::Insecure),
+                Security::Bogus(reason) => Err(reason),
+            };
+        }
+        let types = denial
+            .nsecs
+            .iter()
+            .find(|nsec| nsec.owner == *child)
+            .map(|nsec| &nsec.types)
+            .or_else(|| denial.nsec3_matching(child).map(|nsec3| &nsec3.types));
+        match types {
+            Some(types) if types.contains(&RecordType::DelegationSigner) => {
+                Err(format!("{child} has DS records, but they weren't returned"))
+            }
+            Some(types)
+                if types.contains(&RecordType::NameServer)
+                    && !types.contains(&RecordType::StartOfAuthority) =>
+            {
+                Ok(Cut::Insecure)
+            }
+            Some(_) => Ok(Cut::SameZone),
+            None => match denial.nodata(child, RecordType::DelegationSigner) {
+                Security::Secure => Ok(Cut::SameZone),
+                // Opted out, so there may be an unsigned delegation
+                Security::Insecure => Ok(Cut::Insecure),
+                Security::Bogus(reason) => Err(reason),
+            },
+        }
+    }
+
+    /// The DNSKEYs of `apex`, if one of them matches a DS or DNSKEY in `anchors` and signs the
+    /// rest.
+    fn trusted_keys(
+        &self,
+        apex: &DomainName,
+        anchors: &[ResourceRecord],
+        forwarders: &Forwarders,
+    ) -> Result<Cut, String> {
+        // Without anything we can check, the zone may as well be unsigned (RFC 4035 section 5.2)
+        let supported = anchors.iter().any(|anchor| match &anchor.data {
+            ResourceRecordData::DelegationSigner {
+                algorithm,
+                digest_type,
+                ..
+            } => supports_algorithm(*algorithm) && matches!(digest_type, 1 | 2 | 4),
+            ResourceRecordData::DnsKey { algorithm, .. } => supports_algorithm(*algorithm),
+            _ => false,
+        });
+        if !supported {
+            return Ok(Cut::Insecure);
+        }
+
+        let response_message = self.query(apex, RecordType::DnsKey, forwarders)?;
+        let keys = response_message
+            .answers
+            .iter()
+            .filter(|record| record.name == *apex && record.ty == RecordType::DnsKey)
+            .cloned()
+            .collect::<Vec<_>>();
+        let signatures = signatures_over(&response_message.answers, apex, RecordType::DnsKey);
+        for key in keys.iter() {
+            let anchored = anchors.iter().any(|anchor| matches_anchor(anchor, key));
+            if anchored
+                && verify_signed(apex, std::slice::from_ref(key), &keys, &signatures).is_ok()
+            {
+                return Ok(Cut::Secure(keys));
+            }
+        }
+        Err(format!(
+            "no DNSKEY for {apex} matches its DS records and signs its key set"
+        ))
+    }
+
+    fn query(
+        &self,
+        name: &DomainName,
+        ty: RecordType,
+        forwarders: &Forwarders,
+    ) -> Result<Message, String> {
+        let query_message = with_dnssec(&Message::new_query(vec![Question {
+            name: name.clone(),
+            ty,
+            class: Class::Internet,
+        }]));
+        forward(&query_message, None, forwarders)
+            .map_err(|e| format!("error looking up {name} {ty:?}: {e}"))
+    }
+}
+
+impl Denial {
+    fn nsec_matching(&self, name: &DomainName) -> Option<&Nsec> {
+        self.nsecs.iter().find(|nsec| nsec.owner == *name)
+    }
+
+    /// The NSEC whose owner and next name are either side of `name`.
+    fn nsec_covering(&self, name: &DomainName) -> Option<&Nsec> {
+        self.nsecs.iter().find(|nsec| {
+            let after_owner = nsec.owner.canonical_cmp(name).is_lt();
+            let before_next = name.canonical_cmp(&nsec.next).is_lt();
+            if nsec.owner.canonical_cmp(&nsec.next).is_lt() {
+                after_owner && before_next
+            } else {
+                // The last NSEC in the zone, which points back to the apex
+                after_owner
+            }
+        })
+    }
+
+    /// The deepest existing ancestor of `name`, from the NSEC covering it.
+    fn nsec_closest_encloser(&self, name: &DomainName) -> Option<DomainName> {
+        let nsec = self.nsec_covering(name)?;
+        (0..name.label_count())
+            .rev()
+            .map(|label_count| name.ancestor(label_count))
+            .find(|ancestor| {
+                nsec.owner.is_subdomain_of(ancestor) || nsec.next.is_subdomain_of(ancestor)
+            })
+    }
+
+    fn nsec3_matching(&self, name: &DomainName) -> Option<&Nsec3> {
+        self.nsec3s.iter().find(|nsec3| {
+            name.is_subdomain_of(&nsec3.zone)
+                && nsec3_hash(name, &nsec3.salt, nsec3.iterations) == nsec3.owner_hash
+        })
+    }
+
+    fn nsec3_covering(&self, name: &DomainName) -> Option<&Nsec3> {
+        self.nsec3s.iter().find(|nsec3| {
+            if !name.is_subdomain_of(&nsec3.zone) {
+                return false;
+            }
+            let hash = nsec3_hash(name, &nsec3.salt, nsec3.iterations);
+            if nsec3.owner_hash < nsec3.next_hash {
+                nsec3.owner_hash < hash && hash < nsec3.next_hash
+            } else {
+                // The last NSEC3 in the zone, which wraps around to the first
+                nsec3.owner_hash < hash || hash < nsec3.next_hash
+            }
+        })
+    }
+
+    /// The closest encloser of `name`, with the NSEC3 covering the name one label below it (RFC
+    /// 5155 section 8.3).
+    fn nsec3_closest_encloser(&self, name: &DomainName) -> Option<(DomainName, &Nsec3)> {
+        for label_count in (0..name.label_count()).rev() {
+            let ancestor = name.ancestor(label_count);
+            if self.nsec3_matching(&ancestor).is_some() {
+                let next_closer = name.ancestor(label_count + 1);
+                return self
+                    .nsec3_covering(&next_closer)
+                    .map(|covering| (ancestor, covering));
+            }
+        }
+        None
+    }
+
+    /// Whether `name` exists, but without records of type `ty`.
+    fn nodata(&self, name: &DomainName, ty: RecordType) -> Security {
+        let has_type =
+            |types: &[RecordType]| types.contains(&ty) || types.contains(&RecordType::CName);
+        if let Some(nsec) = self.nsec_matching(name) {
+            return match has_type(&nsec.types) {
+                true => Security::Bogus(format!("NSEC shows {name} has {ty:?} records")),
+                false => Security::Secure,
+            };
+        }
+        if let Some(nsec3) = self.nsec3_matching(name) {
+            return match has_type(&nsec3.types) {
+                true => Security::Bogus(format!("NSEC3 shows {name} has {ty:?} records")),
+                false => Security::Secure,
+            };
+        }
+
+        // An empty non-terminal, with names beneath it but no records of its own
+        if self
+            .nsec_covering(name)
+            .is_some_and(|nsec| nsec.next.is_subdomain_of(name))
+        {
+            return Security::Secure;
+        }
+        // A wildcard match without the type
+        if let Some(closest_encloser) = self.nsec_closest_encloser(name) {
+            if let Some(nsec) = self.nsec_matching(&wildcard(&closest_encloser)) {
+                if !has_type(&nsec.types) {
+                    return Security::Secure;
+                }
+            }
+        }
+        if let Some((closest_encloser, covering)) = self.nsec3_closest_encloser(name) {
+            if covering.opt_out && ty == RecordType::DelegationSigner {
+                return Security::Insecure;
+            }
+            if let Some(nsec3) = self.nsec3_matching(&wildcard(&closest_encloser)) {
+                if !has_type(&nsec3.types) {
+                    return Security::Secure;
+                }
+            }
+        }
+        self.unproven(format!("no proof that {name} has no {ty:?} records"))
+    }
+
+    /// Whether `name` doesn't exist, and nor does a wildcard which would match it.
+    fn nxdomain(&self, name: &DomainName) -> Security {
+        if let Some(closest_encloser) = self.nsec_closest_encloser(name) {
+            if self.nsec_covering(&wildcard(&closest_encloser)).is_some() {
+                return Security::Secure;
+            }
+        }
+        if let Some((closest_encloser, covering)) = self.nsec3_closest_encloser(name) {
+            if self.nsec3_covering(&wildcard(&closest_encloser)).is_some() {
+                return match covering.opt_out {
+                    true => Security::Insecure,
+                    false => Security::Secure,
+                };
+            }
+        }
+        self.unproven(format!("no proof that {name} doesn't exist"))
+    }
+
+    /// Whether `name`, which was answered from a wildcard with `labels` labels, has no closer
+    /// match.
+    fn no_closer_match(&self, name: &DomainName, labels: u8) -> Security {
+        if self.nsec_covering(name).is_some() {
+            return Security::Secure;
+        }
+        let next_closer = name.ancestor(labels as usize + 1);
+        match self.nsec3_covering(&next_closer) {
+            Some(covering) if covering.opt_out => Security::Insecure,
+            Some(_) => Security::Secure,
+            None => self.unproven(format!("no proof that {name} isn't a closer match")),
+        }
+    }
+
+    fn unproven(&self, reason: String) -> Security {
+        match self.unsupported {
+            true => Security::Insecure,
+            false => Security::Bogus(reason),
+        }
+    }
+}
+
+/// The NSEC and NSEC3 records in `authorities` which are signed by the zone at `apex`.
+fn authenticated_denial(
+    apex: &DomainName,
+    keys: &[ResourceRecord],
+    authorities: &[ResourceRecord],
+) -> Denial {
+    let mut denial = Denial::default();
+    for (rrset, signatures) in rrsets(authorities) {
+        if !matches!(
+            rrset[0].ty,
+            RecordType::NextSecure | RecordType::NextSecure3
+        ) || verify_signed(apex, keys, &rrset, &signatures).is_err()
+        {
+            continue;
+        }
+        for record in rrset {
+            match record.data {
+                ResourceRecordData::NextSecure {
+                    next_domain_name,
+                    types,
+                } => denial.nsecs.push(Nsec {
+                    owner: record.name,
+                    next: next_domain_name,
+                    types,
+                }),
+                ResourceRecordData::NextSecure3 {
+                    hash_algorithm,
+                    flags,
+                    iterations,
+                    salt,
+                    next_hashed_owner,
+                    types,
+                } => {
+                    let owner_hash = record
+                        .name
+                        .first_label()
+                        .and_then(|label| decode_base32hex(label).ok());
+                    let (Some(owner_hash), Some(zone)) = (owner_hash, record.name.parent()) else {
+                        continue;
+                    };
+                    // SHA-1 is the only hash algorithm defined
+                    if hash_algorithm != 1 || iterations > MAX_NSEC3_ITERATIONS {
+                        denial.unsupported = true;
+                        continue;
+                    }
+                    denial.nsec3s.push(Nsec3 {
+                        zone,
+                        owner_hash,
+                        next_hash: next_hashed_owner,
+                        salt,
+                        iterations,
+                        opt_out: flags & OPT_OUT_FLAG != 0,
+                        types,
+                    });
+                }
+                _ => {}
+            }
+        }
+    }
+    denial
+}
+
+/// Check that the RRset is signed by one of `keys`, the zone keys of `apex`. Wildcard expansions
+/// return the number of labels in the wildcard.
+fn verify_signed(
+    apex: &DomainName,
+    keys: &[ResourceRecord],
+    rrset: &[ResourceRecord],
+    signatures: &[ResourceRecord],
+) -> Result<Option<u8>, String> {
+    let mut error = format!(
+        "no signatures by {} for {} {:?}",
+        apex, rrset[0].name, rrset[0].ty
+    );
+    for signature in signatures.iter() {
+        match &signature.data {
+            ResourceRecordData::Signature { signer_name, .. } if signer_name == apex => {
+                match verify_rrset(rrset, signature, keys) {
+                    Ok(wildcard) => return Ok(wildcard),
+                    Err(e) => error = e,
+                }
+            }
+            _ => {}
+        }
+    }
+    Err(error)
+}
+
+/// Check one RRSIG over the RRset (RFC 4035 section 5.3).
+fn verify_rrset(
+    rrset: &[ResourceRecord],
+    signature_record: &ResourceRecord,
+    keys: &[ResourceRecord],
+) -> Result<Option<u8>, String> {
+    let ResourceRecordData::Signature {
+        type_covered,
+        algorithm,
+        labels,
+        expiration,
+        inception,
+        key_tag,
+        signer_name,
+        signature,
+        ..
+    } = &signature_record.data
+    else {
+        return Err("not a signature".to_string());
+    };
+    let owner = &rrset[0].name;
+    let ty = rrset[0].ty;
+    if *type_covered != ty {
+        return Err(format!("signature covers {type_covered:?}, not {ty:?}"));
+    }
+
+    // Times are compared with serial number arithmetic, so they work past 2106
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs() as u32);
+    if (now.wrapping_sub(*inception) as i32) < 0 {
+        return Err(format!("signature over {owner} {ty:?} isn't valid yet"));
+    }
+    if (expiration.wrapping_sub(now) as i32) < 0 {
+        return Err(format!("signature over {owner} {ty:?} has expired"));
+    }
+
+    let wildcard = match owner.label_count() {
+        count if *labels as usize == count => None,
+        count if (*labels as usize) < count => Some(*labels),
+        _ => return Err(format!("signature over {owner} has too many labels")),
+    };
+    let signed_data = signed_data(rrset, &signature_record.data).map_err(|e| e.to_string())?;
+
+    for key in keys.iter() {
+        let ResourceRecordData::DnsKey {
+            flags,
+            protocol: 3,
+            algorithm: key_algorithm,
+            public_key,
+        } = &key.data
+        else {
+            continue;
+        };
+        if flags & ZONE_KEY_FLAG == 0
+            || key_algorithm != algorithm
+            || self::key_tag(&key.data) != *key_tag
+        {
+            continue;
+        }
+        if verify_signature(*algorithm, public_key, &signed_data, signature) {
+            return Ok(wildcard);
+        }
+    }
+    Err(format!(
+        "signature over {owner} {ty:?} by {signer_name} key {key_tag} doesn't verify"
+    ))
+}
+
+/// What an RRSIG signs: its own RDATA without the signature, then each record of the RRset in
+/// canonical form and order (RFC 4034 section 3.1.8.1). Wildcard expansions are signed with the
+/// wildcard as the owner name.
+pub fn signed_data(
+    rrset: &[ResourceRecord],
+    signature: &ResourceRecordData,
+) -> anyhow::Result<Vec<u8>> {
+    let ResourceRecordData::Signature {
+        type_covered,
+        algorithm,
+        labels,
+        original_ttl,
+        expiration,
+        inception,
+        key_tag,
+        signer_name,
+        ..
+    } = signature
+    else {
+        anyhow::bail!("not a signature");
+    };
+    let owner = &rrset[0].name;
+    let signed_owner = match (*labels as usize) < owner.label_count() {
+        true => wildcard(&owner.ancestor(*labels as usize)),
+        false => owner.to_lowercase(),
+    };
+
+    let mut signed_data = BytesMut::new();
+    ResourceRecordData::Signature {
+        type_covered: *type_covered,
+        algorithm: *algorithm,
+        labels: *labels,
+        original_ttl: *original_ttl,
+        expiration: *expiration,
+        inception: *inception,
+        key_tag: *key_tag,
+        signer_name: signer_name.to_lowercase(),
+        signature: Vec::new(),
+    }
+    .write(&mut signed_data)?;
+    // Without the RDLENGTH
+    let mut signed_data = signed_data.split_off(2);
+
+    let mut rdatas = Vec::new();
+    for record in rrset.iter() {
+        let mut rdata = BytesMut::new();
+        record.data.to_canonical().write(&mut rdata)?;
+        rdatas.push(rdata.to_vec());
+    }
+    rdatas.sort_by(|a, b| a[2..].cmp(&b[2..]));
+    rdatas.dedup();
+    for rdata in rdatas.iter() {
+        signed_owner.to_lowercase().write(&mut signed_data)?;
+        signed_data.put_u16((*type_covered).into());
+        signed_data.put_u16(rrset[0].class.into());
+        signed_data.put_u32(*original_ttl);
+        signed_data.put_slice(rdata);
+    }
+    Ok(signed_data.to_vec())
+}
+
+/// The DNSSEC algorithm numbers we can verify: RSASHA1, RSASHA1-NSEC3-SHA1, RSASHA256,
+/// RSASHA512, ECDSAP256SHA256 and ED25519.
+fn supports_algorithm(algorithm: u8) -> bool {
+    matches!(algorithm, 5 | 7 | 8 | 10 | 13 | 15)
+}
+
+fn verify_signature(algorithm: u8, public_key: &[u8], data: &[u8], signature: &[u8]) -> bool {
+    match algorithm {
+        5 | 7 => rsa::verify(public_key, rsa::SHA1_PREFIX, &sha1(data), signature),
+        8 => rsa::verify(public_key, rsa::SHA256_PREFIX, &sha256(data), signature),
+        10 => rsa::verify(public_key, rsa::SHA512_PREFIX, &sha512(data), signature),
+        13 => p256::verify(public_key, &sha256(data), signature),
+        15 => ed25519::verify(public_key, data, signature),
+        _ => false,
+    }
+}
+
+/// The short identifier of a DNSKEY used by DS and RRSIG records (RFC 4034 appendix B).
+pub fn key_tag(data: &ResourceRecordData) -> u16 {
+    let mut rdata = BytesMut::new();
+    if data.write(&mut rdata).is_err() {
+        return 0;
+    }
+    let mut sum: u32 = 0;
+    for (i, byte) in rdata[2..].iter().enumerate() {
+        sum += if i & 1 == 0 {
+            (*byte as u32) << 8
+        } else {
+            *byte as u32
+        };
+    }
+    sum += (sum >> 16) & 0xFFFF;
+    sum as u16
+}
+
+/// Whether `key` is the DNSKEY trust anchor `anchor`, or has the digest in the DS `anchor`.
+fn matches_anchor(anchor: &ResourceRecord, key: &ResourceRecord) -> bool {
+    match &anchor.data {
+        ResourceRecordData::DnsKey { public_key, .. } => match &key.data {
+            ResourceRecordData::DnsKey {
+                public_key: key_public_key,
+                ..
+            } => public_key == key_public_key,
+            _ => false,
+        },
+        ResourceRecordData::DelegationSigner {
+            key_tag,
+            algorithm,
+            digest_type,
+            digest,
+        } => {
+            let ResourceRecordData::DnsKey {
+                algorithm: key_algorithm,
+                ..
+            } = &key.data
+            else {
+                return false;
+            };
+            if key_algorithm != algorithm || self::key_tag(&key.data) != *key_tag {
+                return false;
+            }
+            let mut digested = BytesMut::new();
+            if key.name.to_lowercase().write(&mut digested).is_err()
+                || key.data.write(&mut digested).is_err()
+            {
+                return false;
+            }
+            // The owner name and the DNSKEY RDATA, without the RDLENGTH in between
+            let name_length = key.name.length() as usize;
+            let mut digested = digested.to_vec();
+            digested.drain(name_length..name_length + 2);
+            match digest_type {
+                1 => sha1(&digested).as_slice() == digest.as_slice(),
+                2 => sha256(&digested).as_slice() == digest.as_slice(),
+                4 => sha384(&digested).as_slice() == digest.as_slice(),
+                _ => false,
+            }
+        }
+        _ => false,
+    }
+}
+
+/// The NSEC3 hash of a name (RFC 5155 section 5).
+pub fn nsec3_hash(name: &DomainName, salt: &[u8], iterations: u16) -> Vec<u8> {
+    let mut data = BytesMut::new();
+    if name.to_lowercase().write(&mut data).is_err() {
+        return Vec::new();
+    }
+    data.put_slice(salt);
+    let mut hash = sha1(&data);
+    for _ in 0..iterations {
+        let mut data = hash.to_vec();
+        data.extend_from_slice(salt);
+        hash = sha1(&data);
+    }
+    hash.to_vec()
+}
+
+/// `*.` followed by `name`.
+fn wildcard(name: &DomainName) -> DomainName {
+    DomainName::new("*")
+        .expect("* should be a valid label")
+        .join(name)
+}
+
+/// The records grouped into RRsets, each with the RRSIGs covering it.
+fn rrsets(records: &[ResourceRecord]) -> Vec<(Vec<ResourceRecord>, Vec<ResourceRecord>)> {
+    let mut rrsets: Vec<(Vec<ResourceRecord>, Vec<ResourceRecord>)> = Vec::new();
+    for record in records
+        .iter()
+        .filter(|record| record.ty != RecordType::Signature)
+    {
+        match rrsets.iter_mut().find(|(rrset, _)| {
+            rrset[0].name == record.name
+                && rrset[0].ty == record.ty
+                && rrset[0].class == record.class
+        }) {
+            Some((rrset, _)) => rrset.push(record.clone()),
+            None => rrsets.push((
+                vec![record.clone()],
+                signatures_over(records, &record.name, record.ty),
+            )),
+        }
+    }
+    rrsets
+}
+
+fn signatures_over(
+    records: &[ResourceRecord],
+    name: &DomainName,
+    ty: RecordType,
+) -> Vec<ResourceRecord> {
+    records
+        .iter()
+        .filter(|record| {
+            record.name == *name
+                && matches!(
+                    record.data,
+                    ResourceRecordData::Signature { type_covered, .. } if type_covered == ty
+                )
+        })
+        .cloned()
+        .collect()
+}
+
+/// The name whose records answer `question`, after following any CNAMEs in `answers`.
+fn final_name(question: &Question, answers: &[ResourceRecord]) -> DomainName {
+    let mut name = question.name.clone();
+    if question.ty == RecordType::CName {
+        return name;
+    }
+    for _ in 0..MAX_CNAME_CHAIN {
+        let target = answers.iter().find_map(|record| match &record.data {
+            ResourceRecordData::CName(target) if record.name == name => Some(target.clone()),
+            _ => None,
+        });
+        match target {
+            Some(target) => name = target,
+            None => break,
+        }
+    }
+    name
+}
+
+/// A copy of `query_message` asking for DNSSEC records, but not for the upstream to validate.
+/// Its other EDNS options, like the client subnet, still apply.
+fn with_dnssec(query_message: &Message) -> Message {
+    let mut message = query_message.clone();
+    message
+        .additionals
+        .retain(|record| record.ty != RecordType::Opt);
+    let mut edns = Edns::new(EDNS_UDP_PAYLOAD_SIZE);
+    edns.dnssec_ok = true;
+    edns.options = query_message
+        .edns()
+        .map(|edns| edns.options)
+        .unwrap_or_default();
+    message.additionals.push(edns.to_record());
+    message.header.checking_disabled = true;
+    message
+}
+
+/// Remove the DNSSEC records which a client that didn't set DO hasn't asked for, and the DO bit
+/// it was asked for with.
+fn strip_dnssec_records(response_message: &mut Message) {
+    for record in response_message.additionals.iter_mut() {
+        if let Some(mut edns) = Edns::from_record(record) {
+            edns.dnssec_ok = false;
+            *record = edns.to_record();
+        }
+    }
+    let asked_for = response_message
+        .questions
+        .iter()
+        .map(|question| question.ty)
+        .collect::<Vec<_>>();
+    let keep = |record: &ResourceRecord| {
+        !matches!(
+            record.ty,
+            RecordType::Signature | RecordType::NextSecure | RecordType::NextSecure3
+        ) || asked_for.contains(&record.ty)
+    };
+    response_message.answers.retain(keep);
+    response_message.authorities.retain(keep);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        encoding::decode_hex,
+        forward::tests::{forwarders, stand_in_upstream},
+        message::EdnsOption,
+    };
+
+    use super::*;
+
+    /// The key of RFC 8032 section 7.1 test 1, which signs the test zone.
+    const PRIVATE_KEY: &str = "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60";
+
+    fn name(name: &str) -> DomainName {
+        DomainName::new(name).unwrap()
+    }
+
+    fn record(owner: &str, ty: RecordType, data: ResourceRecordData) -> ResourceRecord {
+        ResourceRecord::new(name(owner), ty, Class::Internet, 300, data)
+    }
+
+    fn dnskey() -> ResourceRecordData {
+        let private_key = decode_hex(PRIVATE_KEY).unwrap();
+        ResourceRecordData::DnsKey {
+            flags: 257,
+            protocol: 3,
+            algorithm: 15,
+            public_key: ed25519::public_key(&private_key).unwrap(),
+        }
+    }
+
+    fn nsec(owner: &str, next: &str, types: &[RecordType]) -> ResourceRecord {
+        let mut types = types.to_vec();
+        types.extend([RecordType::Signature, RecordType::NextSecure]);
+        let data = ResourceRecordData::NextSecure {
+            next_domain_name: name(next),
+            types,
+        };
+        record(owner, RecordType::NextSecure, data)
+    }
+
+    /// A signature over `rrset` by `example.`, valid between the given offsets from now in
+    /// seconds.
+    fn sign(rrset: &[ResourceRecord], valid: (i64, i64)) -> ResourceRecord {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let mut data = ResourceRecordData::Signature {
+            type_covered: rrset[0].ty,
+            algorithm: 15,
+            labels: rrset[0].name.label_count() as u8,
+            original_ttl: rrset[0].time_to_live,
+            expiration: (now + valid.1) as u32,
+            inception: (now + valid.0) as u32,
+            key_tag: key_tag(&dnskey()),
+            signer_name: name("example"),
+            signature: Vec::new(),
+        };
+        let signed_data = signed_data(rrset, &data).unwrap();
+        let private_key = decode_hex(PRIVATE_KEY).unwrap();
+        if let ResourceRecordData::Signature { signature, .. } = &mut data {
+            *signature = ed25519::sign(&private_key, &signed_data);
+        }
+        let ttl = rrset[0].time_to_live;
+        ResourceRecord::new(
+            rrset[0].name.clone(),
+            RecordType::Signature,
+            Class::Internet,
+            ttl,
+            data,
+        )
+    }
+
+    /// The zone `example.` signed with NSEC, with an unsigned delegation to `insecure.example.`
+    /// and that zone's records beneath it.
+    fn zone(valid: (i64, i64)) -> Vec<ResourceRecord> {
+        let soa = ResourceRecordData::StartOfAuthority {
+            primary_name_server: name("ns.example"),
+            responsible_mailbox: name("admin.example"),
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: 300,
+        };
+        let signed = [
+            vec![record("example", RecordType::StartOfAuthority, soa)],
+            vec![record("example", RecordType::DnsKey, dnskey())],
+            vec![nsec(
+                "example",
+                "insecure.example",
+                &[RecordType::StartOfAuthority, RecordType::DnsKey],
+            )],
+            vec![nsec(
+                "insecure.example",
+                "www.example",
+                &[RecordType::NameServer],
+            )],
+            vec![
+                record(
+                    "www.example",
+                    RecordType::Address,
+                    ResourceRecordData::IPv4([192, 0, 2, 1]),
+                ),
+                record(
+                    "www.example",
+                    RecordType::Address,
+                    ResourceRecordData::IPv4([192, 0, 2, 2]),
+                ),
+            ],
+            vec![nsec("www.example", "example", &[RecordType::Address])],
+        ];
+        let mut records = Vec::new();
+        for rrset in signed {
+            records.push(sign(&rrset, valid));
+            records.extend(rrset);
+        }
+        records.push(record(
+            "insecure.example",
+            RecordType::NameServer,
+            ResourceRecordData::NameServer(name("ns.insecure.example")),
+        ));
+        records.push(record(
+            "host.insecure.example",
+            RecordType::Address,
+            ResourceRecordData::IPv4([192, 0, 2, 3]),
+        ));
+        records
+    }
+
+    /// What a resolver would answer from `zone`, with NSEC records for missing names and types.
+    fn answer(zone: &[ResourceRecord], query_message: &Message) -> Message {
+        let question = &query_message.questions[0];
+        let at = |owner: &DomainName, ty: RecordType| {
+            zone.iter()
+                .filter(|record| {
+                    record.name == *owner
+                        && (record.ty == ty
+                            || matches!(record.data,
+                                ResourceRecordData::Signature { type_covered, .. }
+                                    if type_covered == ty))
+                })
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        let answers = at(&question.name, question.ty);
+        let mut response_message =
+            Message::new_reply(query_message, query_message.questions.clone(), answers);
+        if !response_message.answers.is_empty() {
+            return response_message;
+        }
+        response_message.authorities = at(&name("example"), RecordType::StartOfAuthority);
+        if zone.iter().any(|record| record.name == question.name) {
+            response_message
+                .authorities
+                .extend(at(&question.name, RecordType::NextSecure));
+            return response_message;
+        }
+        response_message.header.response_code = ResponseCode::NameError;
+        let mut covering = Vec::new();
+        for missing in [question.name.clone(), name("*.example")] {
+            let nsec = zone.iter().find(|record| match &record.data {
+                ResourceRecordData::NextSecure {
+                    next_domain_name, ..
+                } => {
+                    record.name.canonical_cmp(&missing).is_lt()
+                        && (missing.canonical_cmp(next_domain_name).is_lt()
+                            || next_domain_name == &name("example"))
+                }
+                _ => false,
+            });
+            if let Some(nsec) = nsec.filter(|nsec| !covering.contains(&nsec.name)) {
+                covering.push(nsec.name.clone());
+            }
+        }
+        for owner in covering {
+            response_message
+                .authorities
+                .extend(at(&owner, RecordType::NextSecure));
+        }
+        response_message
+    }
+
+    /// A validator trusting the test key, and an upstream answering from `zone`, with `tamper`
+    /// applied to answers other than the DNSKEY and DS lookups which build the chain of trust.
+    fn validator(zone: Vec<ResourceRecord>, tamper: fn(&mut Message)) -> (Validator, Forwarders) {
+        let addr = stand_in_upstream(move |query_message| {
+            let mut response_message = answer(&zone, query_message);
+            if !matches!(
+                query_message.questions[0].ty,
+                RecordType::DnsKey | RecordType::DelegationSigner
+            ) {
+                tamper(&mut response_message);
+            }
+            Some(response_message)
+        });
+        let validator = Validator {
+            trust_anchors: vec![record("example", RecordType::DnsKey, dnskey())],
+            zone_cuts: Mutex::new(HashMap::new()),
+        };
+        (validator, forwarders(addr))
+    }
+
+    fn resolve(
+        (validator, forwarders): &(Validator, Forwarders),
+        qname: &str,
+        ty: RecordType,
+        checking_disabled: bool,
+    ) -> Message {
+        let mut query_message = Message::new_query(vec![Question {
+            name: name(qname),
+            ty,
+            class: Class::Internet,
+        }]);
+        query_message.header.checking_disabled = checking_disabled;
+        let mut edns = Edns::new(EDNS_UDP_PAYLOAD_SIZE);
+        edns.dnssec_ok = true;
+        query_message.additionals.push(edns.to_record());
+        validator.resolve(&query_message, None, forwarders).unwrap()
+    }
+
+    fn is_bogus(response_message: &Message) -> bool {
+        let dnssec_bogus = response_message.edns().is_some_and(|edns| {
+            edns.options.iter().any(|option| {
+                matches!(option, EdnsOption::ExtendedError(error)
+                    if error.info_code == ExtendedErrorCode::DnssecBogus)
+            })
+        });
+        matches!(
+            response_message.header.response_code,
+            ResponseCode::ServerFailure
+        ) && dnssec_bogus
+    }
+
+    const VALID: (i64, i64) = (-3600, 3600);
+
+    #[test]
+    fn secure_answers_are_authentic() {
+        let validator = validator(zone(VALID), |_| {});
+
+        let response_message = resolve(&validator, "www.example", RecordType::Address, false);
+        assert!(response_message.header.authentic_data);
+        assert_eq!(response_message.answers.len(), 3);
+
+        // Proofs of missing names and types are checked too
+        let response_message = resolve(&validator, "nope.example", RecordType::Address, false);
+        assert!(matches!(
+            response_message.header.response_code,
+            ResponseCode::NameError
+        ));
+        assert!(response_message.header.authentic_data);
+        let response_message = resolve(&validator, "www.example", RecordType::MailExchange, false);
+        assert!(matches!(
+            response_message.header.response_code,
+            ResponseCode::Ok
+        ));
+        assert!(response_message.answers.is_empty());
+        assert!(response_message.header.authentic_data);
+    }
+
+    #[test]
+    fn unsigned_delegations_are_insecure() {
+        let validator = validator(zone(VALID), |_| {});
+        let response_message = resolve(
+            &validator,
+            "host.insecure.example",
+            RecordType::Address,
+            false,
+        );
+        assert!(matches!(
+            response_message.header.response_code,
+            ResponseCode::Ok
+        ));
+        assert_eq!(response_message.answers.len(), 1);
+        assert!(!response_message.header.authentic_data);
+    }
+
+    #[test]
+    fn changed_answers_are_bogus() {
+        let validator = validator(zone(VALID), |response_message| {
+            for record in response_message.answers.iter_mut() {
+                if let ResourceRecordData::IPv4(ip) = &mut record.data {
+                    ip[3] = 99;
+                }
+            }
+        });
+        let response_message = resolve(&validator, "www.example", RecordType::Address, false);
+        assert!(is_bogus(&response_message));
+        assert!(response_message.answers.is_empty());
+
+        // Unless the client asked not to check
+        let response_message = resolve(&validator, "www.example", RecordType::Address, true);
+        assert!(matches!(
+            response_message.header.response_code,
+            ResponseCode::Ok
+        ));
+        assert!(!response_message.header.authentic_data);
+        assert_eq!(response_message.answers.len(), 3);
+    }
+
+    #[test]
+    fn missing_signatures_and_proofs_are_bogus() {
+        let validator = validator(zone(VALID), |response_message| {
+            response_message
+                .answers
+                .retain(|record| record.ty != RecordType::Signature);
+            response_message
+                .authorities
+                .retain(|record| record.ty != RecordType::NextSecure);
+        });
+        for (qname, ty) in [
+            ("www.example", RecordType::Address),
+            ("nope.example", RecordType::Address),
+            ("www.example", RecordType::MailExchange),
+        ] {
+            assert!(is_bogus(&resolve(&validator, qname, ty, false)), "{qname}");
+        }
+    }
+
+    #[test]
+    fn a_wrong_proof_is_bogus() {
+        // The NSEC at www.example says it has A records, so it can't prove they're missing
+        let validator = validator(zone(VALID), |response_message| {
+            response_message.answers.clear();
+        });
+        assert!(is_bogus(&resolve(
+            &validator,
+            "www.example",
+            RecordType::Address,
+            false
+        )));
+    }
+
+    #[test]
+    fn signatures_outside_their_validity_are_bogus() {
+        for valid in [(-7200, -3600), (3600, 7200)] {
+            let validator = validator(zone(valid), |_| {});
+            assert!(is_bogus(&resolve(
+                &validator,
+                "www.example",
+                RecordType::Address,
+                false
+            )));
+        }
+    }
+
+    #[test]
+    fn untrusted_keys_are_bogus() {
+        let (_, forwarders) = validator(zone(VALID), |_| {});
+        let mut other_key = dnskey();
+        if let ResourceRecordData::DnsKey { public_key, .. } = &mut other_key {
+            public_key[0] ^= 1;
+        }
+        let validator = Validator {
+            trust_anchors: vec![record("example", RecordType::DnsKey, other_key)],
+            zone_cuts: Mutex::new(HashMap::new()),
+        };
+        let validator = (validator, forwarders);
+        assert!(is_bogus(&resolve(
+            &validator,
+            "www.example",
+            RecordType::Address,
+            false
+        )));
+    }
+}
@@ -0,0 +1,425 @@
+//! DNS-over-HTTPS (RFC 8484) endpoint and upstream client.
+//!
+//! Both speak HTTP/2 in the clear, with prior knowledge (RFC 9113 section 3.3). TLS isn't
+//! available without extra dependencies, so the endpoint is meant to sit behind a proxy which
+//! terminates it, and https upstreams have to be reached through a local proxy which adds it.
+
+use bytes::BytesMut;
+use std::{
+    fmt,
+    io::BufReader,
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::{
+    encoding::decode_base64url,
+    http2,
+    message::Message,
+    server::{Server, Transport},
+};
+
+const PATH: &str = "/dns-query";
+const CONTENT_TYPE: &str = "application/dns-message";
+/// Larger than any DNS message can be.
+const MAX_BODY_LENGTH: usize = 65535;
+/// How many idle connections to keep open to each upstream.
+const MAX_IDLE_CONNECTIONS: usize = 8;
+
+/// A DoH resolver to forward queries to, e.g. `http://127.0.0.1:8053/dns-query`.
+#[derive(Debug, Clone)]
+pub struct HttpUpstream {
+    host: String,
+    addr: SocketAddr,
+    path: String,
+    /// Kept-alive connections, shared between clones.
+    idle: Arc<Mutex<Vec<http2::Connection<TcpStream>>>>,
+}
+
+#[derive(Debug)]
+struct Request {
+    method: String,
+    target: String,
+    content_type: Option<String>,
+    body: Vec<u8>,
+}
+
+#[derive(Debug)]
+struct Response {
+    status: &'static str,
+    /// Besides the body's length, with lowercase names as HTTP/2 needs.
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+}
+
+/// Answer DoH requests on a connection until the client closes it.
+pub fn serve_connection(server: &Server, stream: TcpStream) -> anyhow::Result<()> {
+    let source = stream.peer_addr()?;
+    stream.set_nodelay(true)?;
+    let mut connection = http2::Connection::server(BufReader::new(stream))?;
+    while let Some(message) = connection.receive()? {
+        let response = match (message.header(":method"), message.header(":path")) {
+            (Some(method), Some(target)) => {
+                let request = Request {
+                    method: method.to_string(),
+                    target: target.to_string(),
+                    content_type: message.header("content-type").map(str::to_ascii_lowercase),
+                    body: message.body,
+                };
+                handle(server, &request, source)
+            }
+            _ => Response::error("400 Bad Request"),
+        };
+        let content_length = response.body.len().to_string();
+        let mut headers = vec![(":status", &response.status[..3])];
+        headers.extend(
+            response
+                .headers
+                .iter()
+                .map(|(name, value)| (*name, value.as_str())),
+        );
+        headers.push(("content-length", &content_length));
+        connection.send(message.stream_id, &headers, &response.body)?;
+    }
+    Ok(())
+}
+
+fn handle(server: &Server, request: &Request, source: SocketAddr) -> Response {
+    let (path, query) = request
+        .target
+        .split_once('?')
+        .unwrap_or((&request.target, ""));
+    if path != PATH {
+        return Response::error("404 Not Found");
+    }
+    let msg = match request.method.as_str() {
+        "GET" => {
+            let dns = query
+                .split('&')
+                .find_map(|param| param.strip_prefix("dns="));
+            match dns.map(decode_base64url) {
+                Some(Ok(msg)) => msg,
+                _ => return Response::error("400 Bad Request"),
+            }
+        }
+        "POST" => {
+            if request.content_type.as_deref() != Some(CONTENT_TYPE) {
+                return Response::error("415 Unsupported Media Type");
+            }
+            if request.body.len() > MAX_BODY_LENGTH {
+                return Response::error("413 Content Too Large");
+            }
+            request.body.clone()
+        }
+        _ => {
+            let mut response = Response::error("405 Method Not Allowed");
+            response.headers.push(("allow", "GET, POST".to_string()));
+            return response;
+        }
+    };
+
+    let Ok(query_message) = Message::parse(&msg) else {
+        return Response::error("400 Bad Request");
+    };
+    let response_message = match server.handle(&query_message, source, Transport::Https) {
+        Ok(Some(response_message)) => response_message,
+        Ok(None) => return Response::error("403 Forbidden"),
+        Err(e) => {
+            eprintln!("error handling doh request from {}: {}", source, e);
+            return Response::error("500 Internal Server Error");
+        }
+    };
+    // Caches can keep it as long as its shortest lived record (RFC 8484 section 5.1)
+    let max_age = response_message
+        .answers
+        .iter()
+        .chain(response_message.authorities.iter())
+        .map(|record| record.time_to_live)
+        .min();
+    let mut body = BytesMut::with_capacity(64);
+    if let Err(e) = response_message.write(&mut body) {
+        eprintln!("error writing doh response to {}: {}", source, e);
+        return Response::error("500 Internal Server Error");
+    }
+    Response {
+        status: "200 OK",
+        headers: vec![
+            ("content-type", CONTENT_TYPE.to_string()),
+            ("cache-control", format!("max-age={}", max_age.unwrap_or(0))),
+        ],
+        body: body.to_vec(),
+    }
+}
+
+impl Response {
+    fn error(status: &'static str) -> Self {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+}
+
+impl HttpUpstream {
+    /// POST `msg` and return the response body, reusing an idle connection if there is one.
+    pub fn query(&self, msg: &[u8], timeout: Duration) -> anyhow::Result<Vec<u8>> {
+        let idle = self.idle.lock().unwrap().pop();
+        if let Some(connection) = idle {
+            // The server may have closed it in the meantime, in which case start afresh
+            if let Ok(response) = self.exchange(connection, msg) {
+                return Ok(response);
+            }
+        }
+        let stream = TcpStream::connect_timeout(&self.addr, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        stream.set_nodelay(true)?;
+        self.exchange(http2::Connection::client(stream), msg)
+    }
+
+    fn exchange(
+        &self,
+        mut connection: http2::Connection<TcpStream>,
+        msg: &[u8],
+    ) -> anyhow::Result<Vec<u8>> {
+        let content_length = msg.len().to_string();
+        let headers = [
+            (":method", "POST"),
+            (":scheme", "http"),
+            (":authority", self.host.as_str()),
+            (":path", self.path.as_str()),
+            ("accept", CONTENT_TYPE),
+            ("content-type", CONTENT_TYPE),
+            ("content-length", content_length.as_str()),
+        ];
+        let response = connection.request(&headers, msg)?;
+        let status = response.header(":status").unwrap_or_default();
+        if status != "200" {
+            anyhow::bail!("http status {status}");
+        }
+        let content_type = response.header("content-type").map(str::to_ascii_lowercase);
+        if content_type.as_deref() != Some(CONTENT_TYPE) {
+            anyhow::bail!("unexpected content type {:?}", content_type);
+        }
+
+        // Unless the server has said it's closing it
+        if connection.is_open() {
+            let mut idle = self.idle.lock().unwrap();
+            if idle.len() < MAX_IDLE_CONNECTIONS {
+                idle.push(connection);
+            }
+        }
+        Ok(response.body)
+    }
+}
+
+impl fmt::Display for HttpUpstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "http://{}{}", self.host, self.path)
+    }
+}
+
+impl FromStr for HttpUpstream {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with("https://") {
+            anyhow::bail!("https upstreams aren't supported, use http:// through a TLS proxy");
+        }
+        let rest = s
+            .strip_prefix("http://")
+            .ok_or_else(|| anyhow::format_err!("{s:?} isn't an http url"))?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, PATH),
+        };
+        let addr = if authority.contains(':') && !authority.ends_with(']') {
+            authority.to_socket_addrs()
+        } else {
+            let host = authority.trim_start_matches('[').trim_end_matches(']');
+            (host, 80).to_socket_addrs()
+        }?
+        .next()
+        .ok_or_else(|| anyhow::format_err!("no addresses for {authority}"))?;
+        Ok(HttpUpstream {
+            host: authority.to_string(),
+            addr,
+            path: path.to_string(),
+            idle: Arc::new(Mutex::new(Vec::new())),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+    };
+
+    use crate::{
+        config::Config,
+        message::{Class, DomainName, Question, RecordType, ResourceRecordData},
+    };
+
+    use super::*;
+
+    /// The query for www.example.com's address from RFC 8484 section 4.1.1.
+    const GET_QUERY: &str = "AAABAAABAAAAAAAAA3d3dwdleGFtcGxlA2NvbQAAAQAB";
+
+    /// A server which knows www.example.com's address, and nothing else.
+    fn server() -> Arc<Server> {
+        let args = [
+            "--resolver",
+            "127.0.0.1:9",
+            "--local-record",
+            "www.example.com. A 192.0.2.7",
+            "--local-ttl",
+            "60",
+        ];
+        let args = args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        Arc::new(Server::new(Config::from_args(&args).unwrap()).unwrap())
+    }
+
+    /// Serve DoH on a local port, counting the connections taken.
+    fn serve() -> (u16, Arc<AtomicUsize>) {
+        let server = server();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = connections.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                let server = server.clone();
+                thread::spawn(move || serve_connection(&server, stream));
+            }
+        });
+        (port, connections)
+    }
+
+    fn query() -> Vec<u8> {
+        let query_message = Message::new_query(vec![Question {
+            name: DomainName::new("www.example.com").unwrap(),
+            ty: RecordType::Address,
+            class: Class::Internet,
+        }]);
+        let mut query = BytesMut::new();
+        query_message.write(&mut query).unwrap();
+        query.to_vec()
+    }
+
+    fn assert_answered(body: &[u8]) {
+        let response_message = Message::parse(body).unwrap();
+        assert!(matches!(
+            response_message.answers[0].data,
+            ResourceRecordData::IPv4([192, 0, 2, 7])
+        ));
+    }
+
+    #[test]
+    fn requests() {
+        let (port, _) = serve();
+        let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let mut connection = http2::Connection::client(stream);
+        let mut request = |method: &str, path: &str, content_type: &str, body: &[u8]| {
+            let mut headers = vec![
+                (":method", method),
+                (":scheme", "https"),
+                (":authority", "dns.test"),
+                (":path", path),
+            ];
+            if !content_type.is_empty() {
+                headers.push(("content-type", content_type));
+            }
+            connection.request(&headers, body).unwrap()
+        };
+
+        let path = format!("{PATH}?dns={GET_QUERY}");
+        let response = request("GET", &path, "", &[]);
+        assert_eq!(response.header(":status"), Some("200"));
+        assert_eq!(response.header("content-type"), Some(CONTENT_TYPE));
+        assert_eq!(response.header("cache-control"), Some("max-age=60"));
+        assert_answered(&response.body);
+        let response = request("POST", PATH, CONTENT_TYPE, &query());
+        assert_eq!(response.header(":status"), Some("200"));
+        assert_answered(&response.body);
+
+        // Only malformed requests are bad ones
+        let response = request("POST", PATH, "text/plain", &query());
+        assert_eq!(response.header(":status"), Some("415"));
+        let response = request("POST", PATH, CONTENT_TYPE, &[0; 70000]);
+        assert_eq!(response.header(":status"), Some("413"));
+        let response = request("PUT", PATH, CONTENT_TYPE, &query());
+        assert_eq!(response.header(":status"), Some("405"));
+        assert_eq!(response.header("allow"), Some("GET, POST"));
+        let response = request("GET", "/other", "", &[]);
+        assert_eq!(response.header(":status"), Some("404"));
+        for path in [PATH, &format!("{PATH}?dns=!"), &format!("{PATH}?dns=AAAA")] {
+            let response = request("GET", path, "", &[]);
+            assert_eq!(response.header(":status"), Some("400"), "{path}");
+        }
+    }
+
+    #[test]
+    fn http1_is_refused() {
+        let (port, _) = serve();
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream
+            .write_all(b"GET /dns-query HTTP/1.1\r\nHost: dns.test\r\n\r\n")
+            .unwrap();
+        // The connection is dropped, without an HTTP/1.1 response
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response);
+        assert!(response.is_empty());
+    }
+
+    #[test]
+    fn upstreams_reuse_connections() {
+        let (port, connections) = serve();
+        let upstream = format!("http://127.0.0.1:{port}/dns-query")
+            .parse::<HttpUpstream>()
+            .unwrap();
+        for _ in 0..3 {
+            let response = upstream.query(&query(), Duration::from_secs(5)).unwrap();
+            assert_answered(&response);
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+        assert_eq!(upstream.idle.lock().unwrap().len(), 1);
+
+        // Other paths aren't DoH, so that's an error rather than a response
+        let upstream = format!("http://127.0.0.1:{port}/other")
+            .parse::<HttpUpstream>()
+            .unwrap();
+        let error = upstream
+            .query(&query(), Duration::from_secs(5))
+            .unwrap_err();
+        assert_eq!(error.to_string(), "http status 404");
+    }
+
+    #[test]
+    fn urls() {
+        let upstream = "http://127.0.0.1/dns-query"
+            .parse::<HttpUpstream>()
+            .unwrap();
+        assert_eq!(upstream.to_string(), "http://127.0.0.1/dns-query");
+        assert_eq!(upstream.addr, "127.0.0.1:80".parse().unwrap());
+        let upstream = "http://localhost:8053".parse::<HttpUpstream>().unwrap();
+        assert_eq!(upstream.addr.port(), 8053);
+        assert_eq!(upstream.path, PATH);
+        let upstream = "http://[::1]/query".parse::<HttpUpstream>().unwrap();
+        assert_eq!(upstream.to_string(), "http://[::1]/query");
+        assert_eq!(upstream.addr, "[::1]:80".parse().unwrap());
+        assert!("https://127.0.0.1/dns-query"
+            .parse::<HttpUpstream>()
+            .unwrap_err()
+            .to_string()
+            .contains("TLS proxy"));
+        assert!("tls://127.0.0.1".parse::<HttpUpstream>().is_err());
+    }
+}
@@ -0,0 +1,158 @@
+//! Text encodings of binary data, as used in URLs and zone files.
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const BASE64URL_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+/// The "extended hex" alphabet of RFC 4648, which sorts the same as the data. Used by NSEC3.
+const BASE32HEX_ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHIJKLMNOPQRSTUV";
+
+/// Decode base64, with or without padding.
+pub fn decode_base64(s: &str) -> anyhow::Result<Vec<u8>> {
+    decode_bits(s.trim_end_matches('='), 6, |c| {
+        BASE64_ALPHABET.iter().position(|&a| a == c)
+    })
+}
+
+/// Decode base64url, with or without padding.
+pub fn decode_base64url(s: &str) -> anyhow::Result<Vec<u8>> {
+    decode_bits(s.trim_end_matches('='), 6, |c| {
+        BASE64URL_ALPHABET.iter().position(|&a| a == c)
+    })
+}
+
+/// Decode case-insensitive base32hex, without padding.
+pub fn decode_base32hex(s: &str) -> anyhow::Result<Vec<u8>> {
+    decode_bits(s, 5, |c| {
+        BASE32HEX_ALPHABET
+            .iter()
+            .position(|&a| a == c.to_ascii_uppercase())
+    })
+}
+
+/// Encode as lowercase base32hex, without padding, as in NSEC3 owner names.
+pub fn encode_base32hex(data: &[u8]) -> String {
+    encode_bits(data, 5, |value| {
+        BASE32HEX_ALPHABET[value].to_ascii_lowercase() as char
+    })
+}
+
+pub fn decode_hex(s: &str) -> anyhow::Result<Vec<u8>> {
+    if s.len() % 2 == 1 {
+        anyhow::bail!("odd number of hex digits in {s:?}");
+    }
+    decode_bits(s, 4, |c| (c as char).to_digit(16).map(|d| d as usize))
+}
+
+/// Encode as lowercase hex.
+pub fn encode_hex(data: &[u8]) -> String {
+    encode_bits(data, 4, |value| {
+        char::from_digit(value as u32, 16).expect("4 bits should be a hex digit")
+    })
+}
+
+/// Encode as characters worth `bits_per_char` bits each, with the last one padded with zero bits.
+fn encode_bits(data: &[u8], bits_per_char: u32, char: impl Fn(usize) -> char) -> String {
+    let mask = (1 << bits_per_char) - 1;
+    let mut encoded = String::new();
+    let mut bits: u32 = 0;
+    let mut bit_count = 0;
+    for &byte in data {
+        bits = (bits << 8) | byte as u32;
+        bit_count += 8;
+        while bit_count >= bits_per_char {
+            bit_count -= bits_per_char;
+            encoded.push(char(((bits >> bit_count) & mask) as usize));
+        }
+    }
+    if bit_count > 0 {
+        encoded.push(char(
+            ((bits << (bits_per_char - bit_count)) & mask) as usize,
+        ));
+    }
+    encoded
+}
+
+/// Decode characters worth `bits_per_char` bits each, dropping any leftover bits at the end.
+fn decode_bits(
+    s: &str,
+    bits_per_char: u32,
+    value: impl Fn(u8) -> Option<usize>,
+) -> anyhow::Result<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(s.len() * bits_per_char as usize / 8);
+    let mut bits: u32 = 0;
+    let mut bit_count = 0;
+    for c in s.bytes() {
+        let value = value(c)
+            .ok_or_else(|| anyhow::format_err!("invalid character {:?} in {s:?}", c as char))?;
+        bits = (bits << bits_per_char) | value as u32;
+        bit_count += bits_per_char;
+        if bit_count >= 8 {
+            bit_count -= 8;
+            decoded.push((bits >> bit_count) as u8);
+        }
+    }
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 4648 section 10
+    #[test]
+    fn base64() {
+        let cases = [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ];
+        for (data, encoded) in cases {
+            assert_eq!(decode_base64(encoded).unwrap(), data.as_bytes());
+            assert_eq!(
+                decode_base64(encoded.trim_end_matches('=')).unwrap(),
+                data.as_bytes()
+            );
+        }
+        assert_eq!(decode_base64url("-_8").unwrap(), [0xfb, 0xff]);
+        assert_eq!(decode_base64("+/8=").unwrap(), [0xfb, 0xff]);
+        assert!(decode_base64("-_8").is_err());
+        assert!(decode_base64url("+/8").is_err());
+        assert!(decode_base64("Zm 9v").is_err());
+    }
+
+    #[test]
+    fn base32hex() {
+        let cases = [
+            ("", ""),
+            ("f", "co"),
+            ("fo", "cpng"),
+            ("foo", "cpnmu"),
+            ("foob", "cpnmuog"),
+            ("fooba", "cpnmuoj1"),
+            ("foobar", "cpnmuoj1e8"),
+        ];
+        for (data, encoded) in cases {
+            assert_eq!(encode_base32hex(data.as_bytes()), encoded);
+            assert_eq!(decode_base32hex(encoded).unwrap(), data.as_bytes());
+            assert_eq!(
+                decode_base32hex(&encoded.to_ascii_uppercase()).unwrap(),
+                data.as_bytes()
+            );
+        }
+        assert!(decode_base32hex("cpnmw").is_err());
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(encode_hex(&[0x00, 0x7f, 0xab, 0xff]), "007fabff");
+        assert_eq!(decode_hex("007FabfF").unwrap(), [0x00, 0x7f, 0xab, 0xff]);
+        assert_eq!(decode_hex("").unwrap(), []);
+        assert!(decode_hex("abc").is_err());
+        assert!(decode_hex("0g").is_err());
+    }
+}
@@ -0,0 +1,1268 @@
+use bytes::BytesMut;
+use std::{
+    fmt,
+    io::{Read, Write},
+    net::{IpAddr, SocketAddr, TcpStream, UdpSocket},
+    str::FromStr,
+    sync::mpsc::{self, Receiver},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    acl::Cidr,
+    cache::{Cache, CacheKey},
+    cookie::ClientCookies,
+    doh::HttpUpstream,
+    message::{ClientSubnet, Cookie, DomainName, Edns, EdnsOption, Message, ResponseCode},
+};
+
+/// The UDP payload size advertised to upstreams, small enough to avoid fragmentation.
+pub const EDNS_UDP_PAYLOAD_SIZE: u16 = 1232;
+/// How long to wait for an upstream resolver to answer, unless configured otherwise.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Queries to encrypted upstreams are padded to a multiple of this (RFC 8467 section 4.1).
+const QUERY_PADDING_BLOCK_SIZE: usize = 128;
+/// How long a client waits for an upstream before getting a stale answer, unless configured
+/// otherwise (RFC 8767 section 5).
+const DEFAULT_CLIENT_RESPONSE_TIMEOUT: Duration = Duration::from_millis(1800);
+/// How many prefetches can be under way for an upstream at once, unless configured otherwise.
+const DEFAULT_MAX_PREFETCHES: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Udp,
+    Tcp,
+}
+
+/// Where an upstream resolver can be reached.
+#[derive(Debug, Clone)]
+pub enum UpstreamAddr {
+    /// Plain DNS, over UDP or TCP.
+    Dns(SocketAddr),
+    /// DNS-over-HTTPS.
+    Http(HttpUpstream),
+}
+
+/// A set of equivalent upstream resolvers, tried in order until one answers.
+#[derive(Debug, Clone)]
+pub struct Upstream {
+    pub addrs: Vec<UpstreamAddr>,
+    /// Used for plain DNS addresses.
+    pub protocol: Protocol,
+    pub timeout: Duration,
+    /// A tighter limit on the client subnet sent to these resolvers.
+    pub client_subnet: Option<ClientSubnetLimit>,
+    pub cookies: ClientCookies,
+    pub cache: Cache,
+}
+
+/// Send queries for names beneath `domain` to a different upstream.
+#[derive(Debug, Clone)]
+pub struct ForwardingRule {
+    pub domain: DomainName,
+    /// Written as `*.domain`, matching names beneath the domain but not the domain itself.
+    pub subdomains_only: bool,
+    pub upstream: Upstream,
+}
+
+/// The upstreams to use for a view: the most specific matching rule, or else the default.
+#[derive(Debug, Clone)]
+pub struct Forwarders {
+    pub default: Upstream,
+    pub rules: Vec<ForwardingRule>,
+    pub client_subnet: ClientSubnetConfig,
+    pub serve_stale: ServeStaleConfig,
+    pub prefetch: PrefetchConfig,
+}
+
+/// How many leading bits of a client's address can be passed on, written `<ipv4>/<ipv6>`, e.g.
+/// `24/56`, or `off` for none.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientSubnetLimit {
+    pub ipv4_prefix_len: u8,
+    pub ipv6_prefix_len: u8,
+}
+
+/// How much of a client's address to tell upstreams, so they can pick an answer for where it is
+/// (RFC 7871). Every limit which applies to a query is kept to.
+#[derive(Debug, Clone, Default)]
+pub struct ClientSubnetConfig {
+    /// Nothing is passed on unless this is set.
+    pub limit: Option<ClientSubnetLimit>,
+    /// Tighter limits for names beneath these domains, the most specific of which applies.
+    pub domains: Vec<(DomainName, ClientSubnetLimit)>,
+}
+
+/// Answering from expired cache entries when upstreams can't be reached (RFC 8767).
+#[derive(Debug, Clone)]
+pub struct ServeStaleConfig {
+    /// How long after expiring an answer can still be used, or `None` to never use one.
+    pub max_stale: Option<Duration>,
+    /// How long to wait for an upstream before answering with a stale answer, if there is one.
+    /// The upstream's answer still goes in the cache when it comes.
+    pub client_response_timeout: Duration,
+}
+
+/// Looking up popular answers again shortly before they expire, so that clients don't have to
+/// wait for them.
+#[derive(Debug, Clone)]
+pub struct PrefetchConfig {
+    /// How many times an answer has to be used before it's looked up early, or `None` to never.
+    pub min_hits: Option<u32>,
+    /// The most lookups to have under way in the background for each upstream.
+    pub max_concurrent: usize,
+}
+
+impl Default for ServeStaleConfig {
+    fn default() -> Self {
+        ServeStaleConfig {
+            max_stale: None,
+            client_response_timeout: DEFAULT_CLIENT_RESPONSE_TIMEOUT,
+        }
+    }
+}
+
+impl Default for PrefetchConfig {
+    fn default() -> Self {
+        PrefetchConfig {
+            min_hits: None,
+            max_concurrent: DEFAULT_MAX_PREFETCHES,
+        }
+    }
+}
+
+impl Upstream {
+    pub fn new(addr: UpstreamAddr) -> Self {
+        Upstream {
+            addrs: vec![addr],
+            protocol: Protocol::Udp,
+            timeout: DEFAULT_TIMEOUT,
+            client_subnet: None,
+            cookies: ClientCookies::default(),
+            cache: Cache::default(),
+        }
+    }
+
+    /// Ask what `key` asks on behalf of a client in `client_subnet`, from the cache if possible.
+    ///
+    /// An expired answer is used if the resolvers fail or are too slow, and can be kept around for
+    /// that, as `serve_stale` allows. An answer which is about to expire is looked up again in the
+    /// background, if it's popular enough for `prefetch`.
+    fn query(
+        &self,
+        key: CacheKey,
+        client_subnet: Option<&ClientSubnet>,
+        serve_stale: &ServeStaleConfig,
+        prefetch: &PrefetchConfig,
+    ) -> anyhow::Result<Message> {
+        let keep_stale = serve_stale.max_stale.unwrap_or_default();
+        if let Some(hit) = self.cache.get(&key, client_subnet) {
+            if hit.expiring
+                && prefetch
+                    .min_hits
+                    .is_some_and(|min_hits| hit.hits >= min_hits)
+            {
+                self.refresh(
+                    key,
+                    client_subnet.cloned(),
+                    keep_stale,
+                    Some(prefetch.max_concurrent),
+                );
+            }
+            return Ok(hit.response_message);
+        }
+        let stale_message = serve_stale
+            .max_stale
+            .and_then(|max_stale| self.cache.get_stale(&key, client_subnet, max_stale));
+        let Some(stale_message) = stale_message else {
+            let response_message = self.exchange(&key, client_subnet)?;
+            self.cache
+                .insert(key, client_subnet, &response_message, keep_stale);
+            return Ok(response_message);
+        };
+        // The lookup carries on in the background if the client can't wait for it. If it's
+        // already under way for someone else, or too many are, the stale answer will do.
+        let refresh = self.refresh(
+            key,
+            client_subnet.cloned(),
+            keep_stale,
+            Some(prefetch.max_concurrent),
+        );
+        let Some(refresh) = refresh else {
+            return Ok(stale_message);
+        };
+        match refresh.recv_timeout(serve_stale.client_response_timeout) {
+            Ok(Ok(response_message))
+                if !matches!(
+                    response_message.header.response_code,
+                    ResponseCode::ServerFailure | ResponseCode::Refused
+                ) =>
+            {
+                Ok(response_message)
+            }
+            Ok(Ok(_)) | Ok(Err(_)) | Err(_) => Ok(stale_message),
+        }
+    }
+
+    /// Look up `key` again in a new thread and cache the answer, unless that's already under way
+    /// or `limit` lookups already are. The result is sent back too, for anyone who wants to wait
+    /// for it.
+    fn refresh(
+        &self,
+        key: CacheKey,
+        client_subnet: Option<ClientSubnet>,
+        keep_stale: Duration,
+        limit: Option<usize>,
+    ) -> Option<Receiver<anyhow::Result<Message>>> {
+        if !self
+            .cache
+            .start_refresh(&key, client_subnet.as_ref(), limit)
+        {
+            return None;
+        }
+        let upstream = self.clone();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let result = upstream.exchange(&key, client_subnet.as_ref());
+            if let Ok(response_message) = &result {
+                upstream.cache.insert(
+                    key.clone(),
+                    client_subnet.as_ref(),
+                    response_message,
+                    keep_stale,
+                );
+            }
+            upstream.cache.finish_refresh(&key, client_subnet.as_ref());
+            // Whoever was waiting may have given up
+            let _ = sender.send(result);
+        });
+        Some(receiver)
+    }
+
+    /// Ask what `key` asks, trying each resolver in turn.
+    fn exchange(
+        &self,
+        key: &CacheKey,
+        client_subnet: Option<&ClientSubnet>,
+    ) -> anyhow::Result<Message> {
+        let dnssec_ok = key.dnssec_ok;
+        let mut upstream_query = Message::new_query(vec![key.question()]);
+        upstream_query.header.recursion_desired = true;
+        upstream_query.header.authentic_data = key.authentic_data;
+        upstream_query.header.checking_disabled = key.checking_disabled;
+        // An OPT record is only needed to ask for DNSSEC records or to carry options
+        let write_query = |cookie: Option<Cookie>, padded: bool| {
+            let mut upstream_query = upstream_query.clone();
+            if dnssec_ok || cookie.is_some() || client_subnet.is_some() {
+                let mut edns = Edns::new(EDNS_UDP_PAYLOAD_SIZE);
+                edns.dnssec_ok = dnssec_ok;
+                edns.options.extend(cookie.map(EdnsOption::Cookie));
+                edns.options
+                    .extend(client_subnet.cloned().map(EdnsOption::ClientSubnet));
+                upstream_query.additionals.push(edns.to_record());
+            }
+            if padded {
+                upstream_query.pad(QUERY_PADDING_BLOCK_SIZE, 0)?;
+            }
+            let mut msg = BytesMut::with_capacity(64);
+            upstream_query.write(&mut msg)?;
+            anyhow::Ok(msg)
+        };
+
+        let mut last_error = anyhow::format_err!("no upstream resolvers configured");
+        for addr in self.addrs.iter() {
+            let (packet_id, result) = match addr {
+                UpstreamAddr::Dns(addr) => {
+                    // Each try sends the latest server cookie
+                    let query = |protocol| {
+                        let msg = write_query(Some(self.cookies.cookie(addr.ip())), false)?;
+                        let response = match protocol {
+                            Protocol::Udp => query_udp(&msg, *addr, self.timeout)?,
+                            Protocol::Tcp => query_tcp(&msg, *addr, self.timeout)?,
+                        };
+                        let response_message = Message::parse(&response)?;
+                        self.cookies.check_response(addr.ip(), &response_message)?;
+                        Ok(response_message)
+                    };
+                    let is_bad_cookie = |result: &anyhow::Result<Message>| {
+                        result.as_ref().is_ok_and(|response_message| {
+                            matches!(
+                                response_message.header.response_code,
+                                ResponseCode::BadCookie
+                            )
+                        })
+                    };
+                    let mut result = query(self.protocol);
+                    // Try again with the server cookie that came with BADCOOKIE, and then over
+                    // TCP, which doesn't need one (RFC 7873 section 5.3)
+                    if is_bad_cookie(&result) {
+                        result = query(self.protocol);
+                    }
+                    let result = match result {
+                        // Too big for UDP, so try again over TCP
+                        Ok(response_message) if response_message.header.truncation => {
+                            query(Protocol::Tcp)
+                        }
+                        result if is_bad_cookie(&result) => query(Protocol::Tcp),
+                        result => result,
+                    };
+                    (upstream_query.header.packet_id, result)
+                }
+                UpstreamAddr::Http(upstream) => {
+                    // HTTP matches up responses already, and an ID of 0 keeps them cacheable. TLS
+                    // does the job of cookies, and padding hides the query's length from anyone
+                    // watching (RFC 8467).
+                    let mut msg = write_query(None, true)?.to_vec();
+                    msg[..2].copy_from_slice(&[0, 0]);
+                    let result = upstream
+                        .query(&msg, self.timeout)
+                        .and_then(|response| Message::parse(&response));
+                    (0, result)
+                }
+            };
+            match result {
+                Ok(response_message) if response_message.header.packet_id != packet_id => {
+                    last_error = anyhow::format_err!("response from {} has the wrong id", addr)
+                }
+                // The subnet has to be the one asked about (RFC 7871 section 7.3)
+                Ok(response_message) if !echoes_client_subnet(client_subnet, &response_message) => {
+                    last_error =
+                        anyhow::format_err!("response from {} has the wrong client subnet", addr)
+                }
+                Ok(response_message) => return Ok(response_message),
+                Err(e) => last_error = anyhow::format_err!("error querying {}: {}", addr, e),
+            }
+        }
+        Err(last_error)
+    }
+}
+
+impl FromStr for Protocol {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "udp" => Ok(Protocol::Udp),
+            "tcp" => Ok(Protocol::Tcp),
+            _ => Err(anyhow::format_err!("unknown protocol {s:?}")),
+        }
+    }
+}
+
+impl FromStr for UpstreamAddr {
+    type Err = anyhow::Error;
+
+    /// Either a socket address or a URL.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with("quic://") {
+            anyhow::bail!("DNS-over-QUIC upstreams aren't supported, they need QUIC and TLS 1.3");
+        }
+        if s.contains("://") {
+            Ok(UpstreamAddr::Http(s.parse()?))
+        } else {
+            Ok(UpstreamAddr::Dns(s.parse()?))
+        }
+    }
+}
+
+impl fmt::Display for UpstreamAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpstreamAddr::Dns(addr) => addr.fmt(f),
+            UpstreamAddr::Http(upstream) => upstream.fmt(f),
+        }
+    }
+}
+
+impl FromStr for ForwardingRule {
+    type Err = anyhow::Error;
+
+    /// Parse a comma-separated list of `key=value` settings, e.g.
+    /// `domain=*.consul,upstream=127.0.0.1:8600,protocol=tcp,timeout=500`. `upstream` can be
+    /// repeated, and can be a DoH URL, `timeout` is in milliseconds, and `client-subnet` limits
+    /// the client subnet sent, e.g. `client-subnet=off`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut domain = None;
+        let mut addrs = Vec::new();
+        let mut protocol = Protocol::Udp;
+        let mut timeout = DEFAULT_TIMEOUT;
+        let mut client_subnet = None;
+        for setting in s.split(',') {
+            let (key, value) = setting
+                .split_once('=')
+                .ok_or_else(|| anyhow::format_err!("expected key=value, got {setting:?}"))?;
+            match key {
+                "domain" => domain = Some(value),
+                "upstream" => addrs.push(value.parse()?),
+                "protocol" => protocol = value.parse()?,
+                "timeout" => timeout = Duration::from_millis(value.parse()?),
+                "client-subnet" => client_subnet = Some(value.parse()?),
+                _ => anyhow::bail!("unknown forwarding rule setting {key:?}"),
+            }
+        }
+        let domain = domain.ok_or_else(|| anyhow::format_err!("forwarding rule has no domain"))?;
+        if addrs.is_empty() {
+            anyhow::bail!("forwarding rule for {domain} has no upstream");
+        }
+        let (domain, subdomains_only) = match domain.strip_prefix("*.") {
+            Some(domain) => (domain, true),
+            None => (domain, false),
+        };
+        Ok(ForwardingRule {
+            domain: DomainName::new(domain)?,
+            subdomains_only,
+            upstream: Upstream {
+                addrs,
+                protocol,
+                timeout,
+                client_subnet,
+                cookies: ClientCookies::default(),
+                cache: Cache::default(),
+            },
+        })
+    }
+}
+
+impl FromStr for ClientSubnetLimit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "off" {
+            return Ok(ClientSubnetLimit {
+                ipv4_prefix_len: 0,
+                ipv6_prefix_len: 0,
+            });
+        }
+        let (ipv4_prefix_len, ipv6_prefix_len) = s.split_once('/').ok_or_else(|| {
+            anyhow::format_err!("expected <ipv4 prefix length>/<ipv6 prefix length>, got {s:?}")
+        })?;
+        let limit = ClientSubnetLimit {
+            ipv4_prefix_len: ipv4_prefix_len.parse()?,
+            ipv6_prefix_len: ipv6_prefix_len.parse()?,
+        };
+        if limit.ipv4_prefix_len > 32 || limit.ipv6_prefix_len > 128 {
+            anyhow::bail!("client subnet prefix lengths {s:?} are too long");
+        }
+        Ok(limit)
+    }
+}
+
+impl ClientSubnetLimit {
+    fn prefix_len(&self, addr: IpAddr) -> u8 {
+        match addr {
+            IpAddr::V4(_) => self.ipv4_prefix_len,
+            IpAddr::V6(_) => self.ipv6_prefix_len,
+        }
+    }
+}
+
+impl Forwarders {
+    /// The upstream for the most specific rule matching `name`.
+    pub fn select(&self, name: &DomainName) -> &Upstream {
+        let mut ancestor = Some(name.clone());
+        while let Some(domain) = ancestor {
+            let is_subdomain = domain != *name;
+            let rule = self
+                .rules
+                .iter()
+                .find(|rule| rule.domain == domain && (is_subdomain || !rule.subdomains_only));
+            if let Some(rule) = rule {
+                return &rule.upstream;
+            }
+            ancestor = domain.parent();
+        }
+        &self.default
+    }
+
+    /// What to tell `upstream` about a client in `client_subnet` when asking about `name`: as
+    /// much of the address as every limit allows, or nothing.
+    fn client_subnet(
+        &self,
+        name: &DomainName,
+        upstream: &Upstream,
+        client_subnet: &ClientSubnet,
+    ) -> Option<ClientSubnet> {
+        let mut domain_limit = None;
+        let mut ancestor = Some(name.clone());
+        while let Some(domain) = ancestor {
+            domain_limit = self
+                .client_subnet
+                .domains
+                .iter()
+                .find(|(limit_domain, _)| *limit_domain == domain)
+                .map(|(_, limit)| *limit);
+            if domain_limit.is_some() {
+                break;
+            }
+            ancestor = domain.parent();
+        }
+        let prefix_len = [self.client_subnet.limit?]
+            .into_iter()
+            .chain(upstream.client_subnet)
+            .chain(domain_limit)
+            .map(|limit| limit.prefix_len(client_subnet.addr))
+            .min()?
+            .min(client_subnet.source_prefix_len);
+        if prefix_len == 0 {
+            return None;
+        }
+        Some(ClientSubnet {
+            source_prefix_len: prefix_len,
+            scope_prefix_len: 0,
+            addr: Cidr::new(client_subnet.addr, prefix_len).ok()?.network(),
+        })
+    }
+}
+
+/// Whether a response is for the client subnet that was sent, if it says.
+fn echoes_client_subnet(client_subnet: Option<&ClientSubnet>, response_message: &Message) -> bool {
+    let response_subnet = response_message
+        .edns()
+        .and_then(|edns| edns.client_subnet().cloned());
+    match (client_subnet, response_subnet) {
+        (Some(client_subnet), Some(response_subnet)) => {
+            client_subnet.addr == response_subnet.addr
+                && client_subnet.source_prefix_len == response_subnet.source_prefix_len
+        }
+        _ => true,
+    }
+}
+
+/// Send `msg` to `addr` over UDP and wait for the response to it.
+///
+/// Datagrams from anywhere but `addr`, and responses without the query's ID and question, are
+/// ignored rather than ending the wait, so that someone off-path has to match all of those to
+/// spoof an answer, and can't cut a query short by trying (RFC 5452).
+pub fn query_udp(msg: &[u8], addr: SocketAddr, timeout: Duration) -> anyhow::Result<Vec<u8>> {
+    let query_message = Message::parse(msg)?;
+    let bind_addr = if addr.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let udp_socket = UdpSocket::bind(bind_addr)?;
+    udp_socket.send_to(msg, addr)?;
+    let deadline = Instant::now() + timeout;
+    let mut buf = [0; EDNS_UDP_PAYLOAD_SIZE as usize];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            anyhow::bail!("timed out waiting for a response from {}", addr);
+        }
+        udp_socket.set_read_timeout(Some(remaining))?;
+        let (len, source) = udp_socket.recv_from(&mut buf)?;
+        if source == addr && is_response_to(&query_message, &buf[..len]) {
+            return Ok(buf[..len].to_vec());
+        }
+    }
+}
+
+/// Whether `response` has the ID and question of `query_message`. Errors about the query itself
+/// needn't repeat the question, since the server may not have been able to read it.
+fn is_response_to(query_message: &Message, response: &[u8]) -> bool {
+    let Ok(response_message) = Message::parse(response) else {
+        return false;
+    };
+    let header = &response_message.header;
+    header.packet_id == query_message.header.packet_id
+        && (response_message.questions == query_message.questions
+            || (response_message.questions.is_empty()
+                && matches!(
+                    header.response_code,
+                    ResponseCode::FormatError | ResponseCode::NotImplemented
+                )))
+}
+
+fn query_tcp(msg: &[u8], addr: SocketAddr, timeout: Duration) -> anyhow::Result<Vec<u8>> {
+    let stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    query_stream(msg, stream)
+}
+
+/// Send a length-prefixed query on a stream and read the response, as over TCP or DNS-over-TLS.
+fn query_stream<S>(msg: &[u8], mut stream: S) -> anyhow::Result<Vec<u8>>
+where
+    S: Read + Write,
+{
+    stream.write_all(&(msg.len() as u16).to_be_bytes())?;
+    stream.write_all(msg)?;
+    let mut length = [0; 2];
+    stream.read_exact(&mut length)?;
+    let mut buf = vec![0; u16::from_be_bytes(length) as usize];
+    stream.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Ask the upstream for each of the questions in `query_message`, and collect the answers into
+/// a single reply. `client` is who's asking, unless the upstream shouldn't be told.
+///
+/// The client's CD bit is passed on. The upstream's AD bit is passed back only to clients
+/// which show they understand it by setting DO or AD (RFC 6840 section 5.8), and only if it
+/// was set on every answer.
+///
+/// A client which gives a subnet gets back how much of it the answer depends on (RFC 7871
+/// section 7.2.2). Extended errors from upstream, or saying an answer is stale, are passed on.
+pub fn forward(
+    query_message: &Message,
+    client: Option<IpAddr>,
+    forwarders: &Forwarders,
+) -> anyhow::Result<Message> {
+    let mut answers = Vec::new();
+    let mut authorities = Vec::new();
+    let mut response_code = ResponseCode::Ok;
+    let dnssec_ok = query_message.edns().is_some_and(|edns| edns.dnssec_ok);
+    let authentic_data = dnssec_ok || query_message.header.authentic_data;
+    let checking_disabled = query_message.header.checking_disabled;
+    let mut all_authentic = authentic_data;
+    // The subnet the client gave, or else its own address
+    let query_subnet = query_message
+        .edns()
+        .and_then(|edns| edns.client_subnet().cloned());
+    let client_subnet = query_subnet.clone().or_else(|| {
+        client.map(|addr| ClientSubnet {
+            source_prefix_len: if addr.is_ipv4() { 32 } else { 128 },
+            scope_prefix_len: 0,
+            addr,
+        })
+    });
+    let mut scope_prefix_len = 0;
+    let mut extended_errors = Vec::new();
+    for question in query_message.questions.iter() {
+        let upstream = forwarders.select(&question.name);
+        let upstream_subnet = client_subnet.as_ref().and_then(|client_subnet| {
+            forwarders.client_subnet(&question.name, upstream, client_subnet)
+        });
+        let response_message = upstream.query(
+            CacheKey::new(question, dnssec_ok, authentic_data, checking_disabled),
+            upstream_subnet.as_ref(),
+            &forwarders.serve_stale,
+            &forwarders.prefetch,
+        )?;
+        if !matches!(response_message.header.response_code, ResponseCode::Ok) {
+            response_code = response_message.header.response_code;
+        }
+        if upstream_subnet.is_some() {
+            let response_subnet = response_message
+                .edns()
+                .and_then(|edns| edns.client_subnet().cloned());
+            if let Some(response_subnet) = response_subnet {
+                scope_prefix_len = scope_prefix_len.max(response_subnet.scope_prefix_len);
+            }
+        }
+        extended_errors.extend(
+            response_message
+                .edns()
+                .into_iter()
+                .flat_map(|edns| edns.options)
+                .filter(|option| matches!(option, EdnsOption::ExtendedError(_))),
+        );
+        all_authentic &= response_message.header.authentic_data;
+        answers.extend(response_message.answers);
+        authorities.extend(response_message.authorities);
+    }
+    let mut reply = Message::new_reply(query_message, query_message.questions.clone(), answers);
+    reply.header.authentic_data = all_authentic;
+    reply.authorities = authorities;
+    if matches!(reply.header.response_code, ResponseCode::Ok) {
+        reply.header.response_code = response_code;
+    }
+    if let Some(query_subnet) = query_subnet {
+        reply.add_edns_option(EdnsOption::ClientSubnet(ClientSubnet {
+            scope_prefix_len,
+            ..query_subnet
+        }));
+    }
+    for extended_error in extended_errors {
+        reply.add_edns_option(extended_error);
+    }
+    Ok(reply)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        thread,
+    };
+
+    use crate::message::{
+        Class, ExtendedErrorCode, Question, RecordType, ResourceRecord, ResourceRecordData,
+    };
+
+    use super::*;
+
+    /// An upstream on a local UDP port which answers with `answer`, or not at all if it gives
+    /// nothing.
+    pub(crate) fn stand_in_upstream<F>(answer: F) -> SocketAddr
+    where
+        F: Fn(&Message) -> Option<Message> + Send + 'static,
+    {
+        let udp_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = udp_socket.local_addr().unwrap();
+        thread::spawn(move || loop {
+            let mut buf = [0; 4096];
+            let (len, source) = udp_socket.recv_from(&mut buf).unwrap();
+            let query_message = Message::parse(&buf[..len]).unwrap();
+            if let Some(response_message) = answer(&query_message) {
+                let mut response = BytesMut::new();
+                response_message.write(&mut response).unwrap();
+                udp_socket.send_to(&response, source).unwrap();
+            }
+        });
+        addr
+    }
+
+    /// Forwarders with only `addr` as the upstream.
+    pub(crate) fn forwarders(addr: SocketAddr) -> Forwarders {
+        Forwarders {
+            default: Upstream::new(UpstreamAddr::Dns(addr)),
+            rules: Vec::new(),
+            client_subnet: ClientSubnetConfig::default(),
+            serve_stale: ServeStaleConfig::default(),
+            prefetch: PrefetchConfig::default(),
+        }
+    }
+
+    /// The first address of `upstream`, to tell upstreams apart.
+    fn first_addr(upstream: &Upstream) -> String {
+        upstream.addrs[0].to_string()
+    }
+
+    #[test]
+    fn the_most_specific_rule_is_selected() {
+        let mut forwarders = forwarders("127.0.0.1:53".parse().unwrap());
+        forwarders.rules = [
+            "domain=example.com,upstream=127.0.0.2:53",
+            "domain=a.example.com,upstream=127.0.0.3:53,protocol=tcp,timeout=500",
+            "domain=*.consul,upstream=127.0.0.4:8600",
+        ]
+        .iter()
+        .map(|rule| rule.parse().unwrap())
+        .collect();
+        let select = |name| forwarders.select(&DomainName::new(name).unwrap());
+
+        assert_eq!(first_addr(select("example.com")), "127.0.0.2:53");
+        assert_eq!(first_addr(select("B.Example.com")), "127.0.0.2:53");
+        assert_eq!(first_addr(select("a.example.com")), "127.0.0.3:53");
+        assert_eq!(first_addr(select("www.a.example.com")), "127.0.0.3:53");
+        assert_eq!(first_addr(select("example.org")), "127.0.0.1:53");
+        // `*.` rules are for the names beneath the domain, not the domain itself
+        assert_eq!(first_addr(select("consul")), "127.0.0.1:53");
+        assert_eq!(first_addr(select("web.service.consul")), "127.0.0.4:8600");
+
+        // Each rule has its own protocol and timeout
+        assert_eq!(select("example.com").protocol, Protocol::Udp);
+        assert_eq!(select("example.com").timeout, DEFAULT_TIMEOUT);
+        assert_eq!(select("www.a.example.com").protocol, Protocol::Tcp);
+        assert_eq!(
+            select("www.a.example.com").timeout,
+            Duration::from_millis(500)
+        );
+    }
+
+    #[test]
+    fn udp_responses_must_match_the_query() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = upstream.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (len, source) = upstream.recv_from(&mut buf).unwrap();
+            let query_message = Message::parse(&buf[..len]).unwrap();
+            let send = |udp_socket: &UdpSocket, response_message: &Message| {
+                let mut response = BytesMut::new();
+                response_message.write(&mut response).unwrap();
+                udp_socket.send_to(&response, source).unwrap();
+            };
+            let reply = |answers| {
+                Message::new_reply(&query_message, query_message.questions.clone(), answers)
+            };
+            let answer = || {
+                vec![ResourceRecord::new(
+                    query_message.questions[0].name.clone(),
+                    RecordType::Address,
+                    Class::Internet,
+                    300,
+                    ResourceRecordData::IPv4([192, 0, 2, 1]),
+                )]
+            };
+
+            // The wrong ID
+            let mut spoofed = reply(Vec::new());
+            spoofed.header.packet_id = spoofed.header.packet_id.wrapping_add(1);
+            send(&upstream, &spoofed);
+            // The wrong question
+            let mut spoofed = reply(Vec::new());
+            spoofed.questions[0].ty = RecordType::MailExchange;
+            send(&upstream, &spoofed);
+            // From somewhere else
+            let elsewhere = UdpSocket::bind("127.0.0.1:0").unwrap();
+            send(&elsewhere, &reply(Vec::new()));
+            // Not DNS at all
+            upstream.send_to(b"hello", source).unwrap();
+            send(&upstream, &reply(answer()));
+        });
+
+        let query_message = Message::new_query(vec![Question {
+            name: DomainName::new("example.com").unwrap(),
+            ty: RecordType::Address,
+            class: Class::Internet,
+        }]);
+        let mut msg = BytesMut::new();
+        query_message.write(&mut msg).unwrap();
+        let response = query_udp(&msg, addr, Duration::from_secs(5)).unwrap();
+        assert_eq!(Message::parse(&response).unwrap().answers.len(), 1);
+
+        // Nothing but spoofs means no answer, once the time's up
+        let spoofer = stand_in_upstream(|query_message| {
+            let mut spoofed = Message::new_reply(query_message, Vec::new(), Vec::new());
+            spoofed.header.packet_id = spoofed.header.packet_id.wrapping_add(1);
+            Some(spoofed)
+        });
+        assert!(query_udp(&msg, spoofer, Duration::from_millis(200)).is_err());
+    }
+
+    /// A query for each of `names`, with the given flags.
+    fn query(
+        names: &[&str],
+        dnssec_ok: bool,
+        authentic_data: bool,
+        checking_disabled: bool,
+    ) -> Message {
+        let questions = names
+            .iter()
+            .map(|name| Question {
+                name: DomainName::new(name).unwrap(),
+                ty: RecordType::Address,
+                class: Class::Internet,
+            })
+            .collect();
+        let mut query_message = Message::new_query(questions);
+        query_message.header.authentic_data = authentic_data;
+        query_message.header.checking_disabled = checking_disabled;
+        if dnssec_ok {
+            let mut edns = Edns::new(EDNS_UDP_PAYLOAD_SIZE);
+            edns.dnssec_ok = true;
+            query_message.additionals.push(edns.to_record());
+        }
+        query_message
+    }
+
+    #[test]
+    fn authentic_data_is_passed_back_to_clients_which_understand_it() {
+        // Everything but `insecure.example` is authentic
+        let addr = stand_in_upstream(|query_message| {
+            let mut response_message =
+                Message::new_reply(query_message, query_message.questions.clone(), Vec::new());
+            response_message.header.authentic_data =
+                query_message.questions[0].name != DomainName::new("insecure.example").unwrap();
+            Some(response_message)
+        });
+        let forwarders = forwarders(addr);
+        let authentic_data = |query_message: &Message| {
+            forward(query_message, None, &forwarders)
+                .unwrap()
+                .header
+                .authentic_data
+        };
+
+        assert!(!authentic_data(&query(
+            &["example.com"],
+            false,
+            false,
+            false
+        )));
+        assert!(authentic_data(&query(&["example.com"], true, false, false)));
+        assert!(authentic_data(&query(&["example.com"], false, true, false)));
+        assert!(!authentic_data(&query(
+            &["insecure.example"],
+            true,
+            true,
+            false
+        )));
+        // Only if every answer was authentic
+        assert!(!authentic_data(&query(
+            &["example.com", "insecure.example"],
+            true,
+            true,
+            false
+        )));
+    }
+
+    #[test]
+    fn checking_disabled_is_passed_upstream() {
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        let addr = stand_in_upstream(move |query_message| {
+            let header = &query_message.header;
+            let sent = (header.checking_disabled, header.authentic_data);
+            sender.lock().unwrap().send(sent).unwrap();
+            Some(Message::new_reply(
+                query_message,
+                query_message.questions.clone(),
+                Vec::new(),
+            ))
+        });
+        let forwarders = forwarders(addr);
+
+        forward(
+            &query(&["example.com"], false, false, true),
+            None,
+            &forwarders,
+        )
+        .unwrap();
+        assert_eq!(receiver.recv().unwrap(), (true, false));
+        forward(
+            &query(&["example.com"], false, true, false),
+            None,
+            &forwarders,
+        )
+        .unwrap();
+        assert_eq!(receiver.recv().unwrap(), (false, true));
+        // The same question with different flags isn't answered from the cache
+        forward(
+            &query(&["example.com"], true, false, false),
+            None,
+            &forwarders,
+        )
+        .unwrap();
+        assert_eq!(receiver.recv().unwrap(), (false, true));
+    }
+
+    #[test]
+    fn client_subnets_are_cut_to_every_limit_which_applies() {
+        let mut forwarders = forwarders("127.0.0.1:53".parse().unwrap());
+        let limit = |limit: &str| limit.parse::<ClientSubnetLimit>().unwrap();
+        let name = |name| DomainName::new(name).unwrap();
+        let subnet = |addr: &str, source_prefix_len| ClientSubnet {
+            source_prefix_len,
+            scope_prefix_len: 0,
+            addr: addr.parse().unwrap(),
+        };
+        let client = subnet("198.51.100.77", 32);
+        let client_v6 = subnet("2001:db8:1234:5678::1", 128);
+        let cut = |forwarders: &Forwarders, domain, upstream: &Upstream, client: &ClientSubnet| {
+            forwarders.client_subnet(&name(domain), upstream, client)
+        };
+
+        // Nothing is passed on without a limit, whatever else is set
+        forwarders.default.client_subnet = Some(limit("24/56"));
+        let upstream = forwarders.default.clone();
+        assert_eq!(cut(&forwarders, "example.com", &upstream, &client), None);
+
+        // The address is cut to the prefix, and the scope left to the upstream
+        forwarders.client_subnet.limit = Some(limit("24/64"));
+        forwarders.default.client_subnet = None;
+        let upstream = forwarders.default.clone();
+        assert_eq!(
+            cut(&forwarders, "example.com", &upstream, &client),
+            Some(subnet("198.51.100.0", 24))
+        );
+        assert_eq!(
+            cut(&forwarders, "example.com", &upstream, &client_v6),
+            Some(subnet("2001:db8:1234:5678::", 64))
+        );
+        // Nor is more passed on than the client sent
+        assert_eq!(
+            cut(
+                &forwarders,
+                "example.com",
+                &upstream,
+                &subnet("198.51.100.0", 16)
+            ),
+            Some(subnet("198.51.0.0", 16))
+        );
+
+        // An upstream can ask for less
+        let mut upstream = forwarders.default.clone();
+        upstream.client_subnet = Some(limit("16/48"));
+        assert_eq!(
+            cut(&forwarders, "example.com", &upstream, &client),
+            Some(subnet("198.51.0.0", 16))
+        );
+        upstream.client_subnet = Some(limit("off"));
+        assert_eq!(cut(&forwarders, "example.com", &upstream, &client), None);
+
+        // The most specific domain applies, to the names beneath it too
+        let upstream = forwarders.default.clone();
+        forwarders.client_subnet.domains = vec![
+            (name("example.com"), limit("20/48")),
+            (name("cdn.example.com"), limit("8/32")),
+            (name("private.example.com"), limit("off")),
+        ];
+        assert_eq!(
+            cut(&forwarders, "www.example.com", &upstream, &client),
+            Some(subnet("198.51.96.0", 20))
+        );
+        assert_eq!(
+            cut(&forwarders, "img.cdn.example.com", &upstream, &client),
+            Some(subnet("198.0.0.0", 8))
+        );
+        assert_eq!(
+            cut(&forwarders, "private.example.com", &upstream, &client),
+            None
+        );
+        // A domain can't ask for more than the overall limit
+        forwarders.client_subnet.domains = vec![(name("example.com"), limit("32/128"))];
+        assert_eq!(
+            cut(&forwarders, "example.com", &upstream, &client),
+            Some(subnet("198.51.100.0", 24))
+        );
+        assert_eq!(
+            cut(&forwarders, "example.org", &upstream, &client),
+            Some(subnet("198.51.100.0", 24))
+        );
+    }
+
+    #[test]
+    fn responses_must_echo_the_client_subnet_sent() {
+        let sent = ClientSubnet {
+            source_prefix_len: 24,
+            scope_prefix_len: 0,
+            addr: "198.51.100.0".parse().unwrap(),
+        };
+        let response = |client_subnet: Option<ClientSubnet>| {
+            let query_message = query(&["example.com"], false, false, false);
+            let mut response_message =
+                Message::new_reply(&query_message, query_message.questions.clone(), Vec::new());
+            if let Some(client_subnet) = client_subnet {
+                response_message.add_edns_option(EdnsOption::ClientSubnet(client_subnet));
+            }
+            response_message
+        };
+
+        // The scope is the upstream's to choose
+        let scoped = ClientSubnet {
+            scope_prefix_len: 20,
+            ..sent.clone()
+        };
+        assert!(echoes_client_subnet(Some(&sent), &response(Some(scoped))));
+        // An upstream which doesn't do ECS just leaves it out
+        assert!(echoes_client_subnet(Some(&sent), &response(None)));
+        assert!(echoes_client_subnet(None, &response(Some(sent.clone()))));
+
+        let elsewhere = ClientSubnet {
+            addr: "203.0.113.0".parse().unwrap(),
+            ..sent.clone()
+        };
+        assert!(!echoes_client_subnet(
+            Some(&sent),
+            &response(Some(elsewhere))
+        ));
+        let wider = ClientSubnet {
+            source_prefix_len: 16,
+            addr: "198.51.0.0".parse().unwrap(),
+            ..sent.clone()
+        };
+        assert!(!echoes_client_subnet(Some(&sent), &response(Some(wider))));
+    }
+
+    /// The address in the first answer of `response_message`.
+    fn answer_addr(response_message: &Message) -> [u8; 4] {
+        match response_message.answers[0].data {
+            ResourceRecordData::IPv4(addr) => addr,
+            _ => panic!("expected an address"),
+        }
+    }
+
+    /// An upstream which answers with `192.0.2.<n>` for the nth query it gets, after `delay` for
+    /// all but the first, and the number it's had so far.
+    fn counting_upstream(delay: Duration) -> (SocketAddr, Arc<Mutex<u8>>) {
+        let queries = Arc::new(Mutex::new(0u8));
+        let counted = queries.clone();
+        let addr = stand_in_upstream(move |query_message| {
+            let n = {
+                let mut queries = counted.lock().unwrap();
+                *queries += 1;
+                *queries
+            };
+            if n > 1 {
+                thread::sleep(delay);
+            }
+            let answer = ResourceRecord::new(
+                query_message.questions[0].name.clone(),
+                RecordType::Address,
+                Class::Internet,
+                60,
+                ResourceRecordData::IPv4([192, 0, 2, n]),
+            );
+            Some(Message::new_reply(
+                query_message,
+                query_message.questions.clone(),
+                vec![answer],
+            ))
+        });
+        (addr, queries)
+    }
+
+    fn extended_errors(response_message: &Message) -> Vec<ExtendedErrorCode> {
+        response_message
+            .edns()
+            .into_iter()
+            .flat_map(|edns| edns.options)
+            .filter_map(|option| match option {
+                EdnsOption::ExtendedError(extended_error) => Some(extended_error.info_code),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn stale_answers_are_given_while_a_slow_upstream_refreshes_them() {
+        let (addr, queries) = counting_upstream(Duration::from_millis(300));
+        let mut forwarders = forwarders(addr);
+        forwarders.serve_stale = ServeStaleConfig {
+            max_stale: Some(Duration::from_secs(600)),
+            client_response_timeout: Duration::from_millis(50),
+        };
+        let query_message = query(&["example.com"], false, false, false);
+        let key = CacheKey::new(&query_message.questions[0], false, false, false);
+
+        let response_message = forward(&query_message, None, &forwarders).unwrap();
+        assert_eq!(answer_addr(&response_message), [192, 0, 2, 1]);
+        forwarders.default.cache.age(&key, 61);
+
+        // The upstream's too slow, so the expired answer is used, and says so
+        let response_message = forward(&query_message, None, &forwarders).unwrap();
+        assert_eq!(answer_addr(&response_message), [192, 0, 2, 1]);
+        assert!(response_message.answers[0].time_to_live <= 30);
+        assert_eq!(
+            extended_errors(&response_message),
+            [ExtendedErrorCode::StaleAnswer]
+        );
+
+        // But its answer still replaces the stale one when it comes
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while forwarders.default.cache.get(&key, None).is_none() {
+            assert!(Instant::now() < deadline, "the answer was never refreshed");
+            thread::sleep(Duration::from_millis(10));
+        }
+        let response_message = forward(&query_message, None, &forwarders).unwrap();
+        assert_eq!(answer_addr(&response_message), [192, 0, 2, 2]);
+        assert!(extended_errors(&response_message).is_empty());
+        assert_eq!(*queries.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn stale_refreshes_are_limited_like_prefetches() {
+        let (addr, queries) = counting_upstream(Duration::ZERO);
+        let mut forwarders = forwarders(addr);
+        forwarders.serve_stale.max_stale = Some(Duration::from_secs(600));
+        forwarders.prefetch.max_concurrent = 0;
+        let query_message = query(&["example.com"], false, false, false);
+        let key = CacheKey::new(&query_message.questions[0], false, false, false);
+
+        forward(&query_message, None, &forwarders).unwrap();
+        forwarders.default.cache.age(&key, 61);
+
+        let response_message = forward(&query_message, None, &forwarders).unwrap();
+        assert_eq!(answer_addr(&response_message), [192, 0, 2, 1]);
+        assert_eq!(
+            extended_errors(&response_message),
+            [ExtendedErrorCode::StaleAnswer]
+        );
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(*queries.lock().unwrap(), 1);
+    }
+
+    /// Wait for `queries` to reach `count`, and check it goes no further for a while.
+    fn expect_queries(queries: &Mutex<u8>, count: u8) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while *queries.lock().unwrap() < count {
+            assert!(Instant::now() < deadline, "the upstream wasn't asked");
+            thread::sleep(Duration::from_millis(10));
+        }
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(*queries.lock().unwrap(), count);
+    }
+
+    #[test]
+    fn popular_answers_are_prefetched() {
+        let (addr, queries) = counting_upstream(Duration::ZERO);
+        let mut forwarders = forwarders(addr);
+        forwarders.prefetch.min_hits = Some(3);
+        let query_message = query(&["example.com"], false, false, false);
+        let key = CacheKey::new(&query_message.questions[0], false, false, false);
+
+        forward(&query_message, None, &forwarders).unwrap();
+        forwarders.default.cache.age(&key, 55);
+        for _ in 0..2 {
+            let response_message = forward(&query_message, None, &forwarders).unwrap();
+            assert_eq!(answer_addr(&response_message), [192, 0, 2, 1]);
+        }
+        expect_queries(&queries, 1);
+
+        // The third use makes it popular enough, and the answer is still given from the cache
+        let response_message = forward(&query_message, None, &forwarders).unwrap();
+        assert_eq!(answer_addr(&response_message), [192, 0, 2, 1]);
+        expect_queries(&queries, 2);
+        let response_message = forward(&query_message, None, &forwarders).unwrap();
+        assert_eq!(answer_addr(&response_message), [192, 0, 2, 2]);
+        assert_eq!(response_message.answers[0].time_to_live, 60);
+    }
+
+    #[test]
+    fn answers_are_prefetched_in_the_last_tenth_of_their_ttl() {
+        let (addr, queries) = counting_upstream(Duration::ZERO);
+        let mut forwarders = forwarders(addr);
+        forwarders.prefetch.min_hits = Some(1);
+        let query_message = query(&["example.com"], false, false, false);
+        let key = CacheKey::new(&query_message.questions[0], false, false, false);
+
+        forward(&query_message, None, &forwarders).unwrap();
+        forwarders.default.cache.age(&key, 50);
+        forward(&query_message, None, &forwarders).unwrap();
+        expect_queries(&queries, 1);
+
+        forwarders.default.cache.age(&key, 4);
+        forward(&query_message, None, &forwarders).unwrap();
+        expect_queries(&queries, 2);
+    }
+
+    #[test]
+    fn prefetches_are_limited() {
+        let (addr, queries) = counting_upstream(Duration::from_millis(300));
+        let mut forwarders = forwarders(addr);
+        forwarders.prefetch = PrefetchConfig {
+            min_hits: Some(1),
+            max_concurrent: 1,
+        };
+        let queries_for = ["a.example.com", "b.example.com"].map(|name| {
+            let query_message = query(&[name], false, false, false);
+            let key = CacheKey::new(&query_message.questions[0], false, false, false);
+            (query_message, key)
+        });
+        for (query_message, key) in queries_for.iter() {
+            forward(query_message, None, &forwarders).unwrap();
+            forwarders.default.cache.age(key, 55);
+        }
+        expect_queries(&queries, 2);
+
+        // The second prefetch waits for the first to finish, and isn't made at all meanwhile
+        for (query_message, _) in queries_for.iter() {
+            forward(query_message, None, &forwarders).unwrap();
+        }
+        expect_queries(&queries, 3);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while forwarders
+            .default
+            .cache
+            .get(&queries_for[0].1, None)
+            .map_or(true, |hit| {
+                answer_addr(&hit.response_message) != [192, 0, 2, 3]
+            })
+        {
+            assert!(
+                Instant::now() < deadline,
+                "the first prefetch never finished"
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+        // The slot's given back just after the answer's cached
+        thread::sleep(Duration::from_millis(50));
+        let response_message = forward(&queries_for[1].0, None, &forwarders).unwrap();
+        assert_eq!(answer_addr(&response_message), [192, 0, 2, 2]);
+        expect_queries(&queries, 4);
+    }
+}
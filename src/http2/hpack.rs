@@ -0,0 +1,693 @@
+//! HPACK header compression (RFC 7541).
+//!
+//! Decoding supports everything a peer may send, including Huffman coded strings and the dynamic
+//! table. Encoding only uses the static table and plain literals, which every decoder accepts and
+//! which is all the few headers of a DNS message need.
+
+use std::{collections::VecDeque, sync::OnceLock};
+
+/// The dynamic table size peers can use unless told otherwise, which is all we allow.
+pub const TABLE_SIZE: usize = 4096;
+/// The most a decoded header list can add up to, counted as in RFC 9113 section 6.5.2.
+pub const MAX_HEADER_LIST_SIZE: usize = 16384;
+/// Each table entry's size counts this on top of its name and value.
+const ENTRY_OVERHEAD: usize = 32;
+
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/// The Huffman code for each byte and then end-of-string, as the code and its length in bits
+/// (RFC 7541 appendix B).
+const HUFFMAN_CODES: [(u32, u8); 257] = [
+    (0x1ff8, 13),
+    (0x7fffd8, 23),
+    (0xfffffe2, 28),
+    (0xfffffe3, 28),
+    (0xfffffe4, 28),
+    (0xfffffe5, 28),
+    (0xfffffe6, 28),
+    (0xfffffe7, 28),
+    (0xfffffe8, 28),
+    (0xffffea, 24),
+    (0x3ffffffc, 30),
+    (0xfffffe9, 28),
+    (0xfffffea, 28),
+    (0x3ffffffd, 30),
+    (0xfffffeb, 28),
+    (0xfffffec, 28),
+    (0xfffffed, 28),
+    (0xfffffee, 28),
+    (0xfffffef, 28),
+    (0xffffff0, 28),
+    (0xffffff1, 28),
+    (0xffffff2, 28),
+    (0x3ffffffe, 30),
+    (0xffffff3, 28),
+    (0xffffff4, 28),
+    (0xffffff5, 28),
+    (0xffffff6, 28),
+    (0xffffff7, 28),
+    (0xffffff8, 28),
+    (0xffffff9, 28),
+    (0xffffffa, 28),
+    (0xffffffb, 28),
+    (0x14, 6),
+    (0x3f8, 10),
+    (0x3f9, 10),
+    (0xffa, 12),
+    (0x1ff9, 13),
+    (0x15, 6),
+    (0xf8, 8),
+    (0x7fa, 11),
+    (0x3fa, 10),
+    (0x3fb, 10),
+    (0xf9, 8),
+    (0x7fb, 11),
+    (0xfa, 8),
+    (0x16, 6),
+    (0x17, 6),
+    (0x18, 6),
+    (0x0, 5),
+    (0x1, 5),
+    (0x2, 5),
+    (0x19, 6),
+    (0x1a, 6),
+    (0x1b, 6),
+    (0x1c, 6),
+    (0x1d, 6),
+    (0x1e, 6),
+    (0x1f, 6),
+    (0x5c, 7),
+    (0xfb, 8),
+    (0x7ffc, 15),
+    (0x20, 6),
+    (0xffb, 12),
+    (0x3fc, 10),
+    (0x1ffa, 13),
+    (0x21, 6),
+    (0x5d, 7),
+    (0x5e, 7),
+    (0x5f, 7),
+    (0x60, 7),
+    (0x61, 7),
+    (0x62, 7),
+    (0x63, 7),
+    (0x64, 7),
+    (0x65, 7),
+    (0x66, 7),
+    (0x67, 7),
+    (0x68, 7),
+    (0x69, 7),
+    (0x6a, 7),
+    (0x6b, 7),
+    (0x6c, 7),
+    (0x6d, 7),
+    (0x6e, 7),
+    (0x6f, 7),
+    (0x70, 7),
+    (0x71, 7),
+    (0x72, 7),
+    (0xfc, 8),
+    (0x73, 7),
+    (0xfd, 8),
+    (0x1ffb, 13),
+    (0x7fff0, 19),
+    (0x1ffc, 13),
+    (0x3ffc, 14),
+    (0x22, 6),
+    (0x7ffd, 15),
+    (0x3, 5),
+    (0x23, 6),
+    (0x4, 5),
+    (0x24, 6),
+    (0x5, 5),
+    (0x25, 6),
+    (0x26, 6),
+    (0x27, 6),
+    (0x6, 5),
+    (0x74, 7),
+    (0x75, 7),
+    (0x28, 6),
+    (0x29, 6),
+    (0x2a, 6),
+    (0x7, 5),
+    (0x2b, 6),
+    (0x76, 7),
+    (0x2c, 6),
+    (0x8, 5),
+    (0x9, 5),
+    (0x2d, 6),
+    (0x77, 7),
+    (0x78, 7),
+    (0x79, 7),
+    (0x7a, 7),
+    (0x7b, 7),
+    (0x7ffe, 15),
+    (0x7fc, 11),
+    (0x3ffd, 14),
+    (0x1ffd, 13),
+    (0xffffffc, 28),
+    (0xfffe6, 20),
+    (0x3fffd2, 22),
+    (0xfffe7, 20),
+    (0xfffe8, 20),
+    (0x3fffd3, 22),
+    (0x3fffd4, 22),
+    (0x3fffd5, 22),
+    (0x7fffd9, 23),
+    (0x3fffd6, 22),
+    (0x7fffda, 23),
+    (0x7fffdb, 23),
+    (0x7fffdc, 23),
+    (0x7fffdd, 23),
+    (0x7fffde, 23),
+    (0xffffeb, 24),
+    (0x7fffdf, 23),
+    (0xffffec, 24),
+    (0xffffed, 24),
+    (0x3fffd7, 22),
+    (0x7fffe0, 23),
+    (0xffffee, 24),
+    (0x7fffe1, 23),
+    (0x7fffe2, 23),
+    (0x7fffe3, 23),
+    (0x7fffe4, 23),
+    (0x1fffdc, 21),
+    (0x3fffd8, 22),
+    (0x7fffe5, 23),
+    (0x3fffd9, 22),
+    (0x7fffe6, 23),
+    (0x7fffe7, 23),
+    (0xffffef, 24),
+    (0x3fffda, 22),
+    (0x1fffdd, 21),
+    (0xfffe9, 20),
+    (0x3fffdb, 22),
+    (0x3fffdc, 22),
+    (0x7fffe8, 23),
+    (0x7fffe9, 23),
+    (0x1fffde, 21),
+    (0x7fffea, 23),
+    (0x3fffdd, 22),
+    (0x3fffde, 22),
+    (0xfffff0, 24),
+    (0x1fffdf, 21),
+    (0x3fffdf, 22),
+    (0x7fffeb, 23),
+    (0x7fffec, 23),
+    (0x1fffe0, 21),
+    (0x1fffe1, 21),
+    (0x3fffe0, 22),
+    (0x1fffe2, 21),
+    (0x7fffed, 23),
+    (0x3fffe1, 22),
+    (0x7fffee, 23),
+    (0x7fffef, 23),
+    (0xfffea, 20),
+    (0x3fffe2, 22),
+    (0x3fffe3, 22),
+    (0x3fffe4, 22),
+    (0x7ffff0, 23),
+    (0x3fffe5, 22),
+    (0x3fffe6, 22),
+    (0x7ffff1, 23),
+    (0x3ffffe0, 26),
+    (0x3ffffe1, 26),
+    (0xfffeb, 20),
+    (0x7fff1, 19),
+    (0x3fffe7, 22),
+    (0x7ffff2, 23),
+    (0x3fffe8, 22),
+    (0x1ffffec, 25),
+    (0x3ffffe2, 26),
+    (0x3ffffe3, 26),
+    (0x3ffffe4, 26),
+    (0x7ffffde, 27),
+    (0x7ffffdf, 27),
+    (0x3ffffe5, 26),
+    (0xfffff1, 24),
+    (0x1ffffed, 25),
+    (0x7fff2, 19),
+    (0x1fffe3, 21),
+    (0x3ffffe6, 26),
+    (0x7ffffe0, 27),
+    (0x7ffffe1, 27),
+    (0x3ffffe7, 26),
+    (0x7ffffe2, 27),
+    (0xfffff2, 24),
+    (0x1fffe4, 21),
+    (0x1fffe5, 21),
+    (0x3ffffe8, 26),
+    (0x3ffffe9, 26),
+    (0xffffffd, 28),
+    (0x7ffffe3, 27),
+    (0x7ffffe4, 27),
+    (0x7ffffe5, 27),
+    (0xfffec, 20),
+    (0xfffff3, 24),
+    (0xfffed, 20),
+    (0x1fffe6, 21),
+    (0x3fffe9, 22),
+    (0x1fffe7, 21),
+    (0x1fffe8, 21),
+    (0x7ffff3, 23),
+    (0x3fffea, 22),
+    (0x3fffeb, 22),
+    (0x1ffffee, 25),
+    (0x1ffffef, 25),
+    (0xfffff4, 24),
+    (0xfffff5, 24),
+    (0x3ffffea, 26),
+    (0x7ffff4, 23),
+    (0x3ffffeb, 26),
+    (0x7ffffe6, 27),
+    (0x3ffffec, 26),
+    (0x3ffffed, 26),
+    (0x7ffffe7, 27),
+    (0x7ffffe8, 27),
+    (0x7ffffe9, 27),
+    (0x7ffffea, 27),
+    (0x7ffffeb, 27),
+    (0xffffffe, 28),
+    (0x7ffffec, 27),
+    (0x7ffffed, 27),
+    (0x7ffffee, 27),
+    (0x7ffffef, 27),
+    (0x7fffff0, 27),
+    (0x3ffffee, 26),
+    (0x3fffffff, 30),
+];
+const END_OF_STRING: u16 = 256;
+
+/// A header field, with its name in lowercase.
+pub type Header = (String, String);
+
+/// The state kept between header blocks received on a connection.
+#[derive(Debug)]
+pub struct Decoder {
+    /// Newest first.
+    dynamic_table: VecDeque<Header>,
+    size: usize,
+    max_size: usize,
+}
+
+/// A reader of header block representations.
+struct Reader<'a>(&'a [u8]);
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Decoder {
+            dynamic_table: VecDeque::new(),
+            size: 0,
+            max_size: TABLE_SIZE,
+        }
+    }
+}
+
+impl Decoder {
+    /// The headers in a complete header block.
+    pub fn decode(&mut self, block: &[u8]) -> anyhow::Result<Vec<Header>> {
+        let mut reader = Reader(block);
+        let mut headers = Vec::new();
+        let mut list_size = 0;
+        let mut at_start = true;
+        while let Some(&first) = reader.0.first() {
+            let header = if first & 0x80 != 0 {
+                self.entry(reader.integer(7)?)?
+            } else if first & 0xc0 == 0x40 {
+                let header = self.literal(&mut reader, 6)?;
+                self.insert(header.clone());
+                header
+            } else if first & 0xe0 == 0x20 {
+                let max_size = reader.integer(5)?;
+                if !at_start || max_size > TABLE_SIZE {
+                    anyhow::bail!("invalid dynamic table size update");
+                }
+                self.max_size = max_size;
+                self.evict(0);
+                continue;
+            } else {
+                // Without indexing, or never indexed, which is the same to us
+                self.literal(&mut reader, 4)?
+            };
+            at_start = false;
+            list_size += header.0.len() + header.1.len() + ENTRY_OVERHEAD;
+            if list_size > MAX_HEADER_LIST_SIZE {
+                anyhow::bail!("header list is too large");
+            }
+            headers.push(header);
+        }
+        Ok(headers)
+    }
+
+    fn entry(&self, index: usize) -> anyhow::Result<Header> {
+        match index {
+            0 => anyhow::bail!("header index 0"),
+            1..=61 => {
+                let (name, value) = STATIC_TABLE[index - 1];
+                Ok((name.to_string(), value.to_string()))
+            }
+            _ => self
+                .dynamic_table
+                .get(index - 62)
+                .cloned()
+                .ok_or_else(|| anyhow::format_err!("header index {index} is out of range")),
+        }
+    }
+
+    /// A literal header field, whose name is either indexed or follows as a string.
+    fn literal(&self, reader: &mut Reader, prefix: u32) -> anyhow::Result<Header> {
+        let name = match reader.integer(prefix)? {
+            0 => reader.string()?,
+            index => self.entry(index)?.0,
+        };
+        Ok((name, reader.string()?))
+    }
+
+    fn insert(&mut self, header: Header) {
+        let size = header.0.len() + header.1.len() + ENTRY_OVERHEAD;
+        self.evict(size);
+        // An entry larger than the table empties it, and isn't added itself
+        if size <= self.max_size {
+            self.size += size;
+            self.dynamic_table.push_front(header);
+        }
+    }
+
+    /// Drop the oldest entries until there's room for `size` more.
+    fn evict(&mut self, size: usize) {
+        while self.size + size > self.max_size {
+            let Some((name, value)) = self.dynamic_table.pop_back() else {
+                break;
+            };
+            self.size -= name.len() + value.len() + ENTRY_OVERHEAD;
+        }
+    }
+}
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> anyhow::Result<u8> {
+        let (&byte, rest) = self
+            .0
+            .split_first()
+            .ok_or_else(|| anyhow::format_err!("truncated header block"))?;
+        self.0 = rest;
+        Ok(byte)
+    }
+
+    /// An integer in the low `prefix` bits of the next byte, continuing in later bytes if they're
+    /// all ones (RFC 7541 section 5.1).
+    fn integer(&mut self, prefix: u32) -> anyhow::Result<usize> {
+        let mask = (1 << prefix) - 1;
+        let mut value = (self.byte()? & mask) as usize;
+        if value < mask as usize {
+            return Ok(value);
+        }
+        let mut shift = 0;
+        loop {
+            let byte = self.byte()?;
+            if shift > 21 {
+                anyhow::bail!("header block integer is too large");
+            }
+            value += ((byte & 0x7f) as usize) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn string(&mut self) -> anyhow::Result<String> {
+        let is_huffman = self.0.first().is_some_and(|byte| byte & 0x80 != 0);
+        let length = self.integer(7)?;
+        if length > self.0.len() {
+            anyhow::bail!("truncated header block");
+        }
+        let (data, rest) = self.0.split_at(length);
+        self.0 = rest;
+        let data = match is_huffman {
+            true => huffman_decode(data)?,
+            false => data.to_vec(),
+        };
+        Ok(String::from_utf8(data)?)
+    }
+}
+
+/// A header block with these headers, in order. Names must be lowercase.
+pub fn encode(headers: &[(&str, &str)]) -> Vec<u8> {
+    let mut block = Vec::new();
+    for &(name, value) in headers {
+        if let Some(index) = STATIC_TABLE
+            .iter()
+            .position(|&entry| entry == (name, value))
+        {
+            encode_integer(&mut block, 0x80, 7, index + 1);
+            continue;
+        }
+        // A literal without indexing, naming a static entry if there's one with the name
+        match STATIC_TABLE.iter().position(|&(other, _)| other == name) {
+            Some(index) => encode_integer(&mut block, 0, 4, index + 1),
+            None => {
+                block.push(0);
+                encode_string(&mut block, name);
+            }
+        }
+        encode_string(&mut block, value);
+    }
+    block
+}
+
+fn encode_integer(block: &mut Vec<u8>, flags: u8, prefix: u32, value: usize) {
+    let mask = (1 << prefix) - 1;
+    if value < mask {
+        block.push(flags | value as u8);
+        return;
+    }
+    block.push(flags | mask as u8);
+    let mut value = value - mask;
+    while value >= 0x80 {
+        block.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    block.push(value as u8);
+}
+
+fn encode_string(block: &mut Vec<u8>, s: &str) {
+    encode_integer(block, 0, 7, s.len());
+    block.extend_from_slice(s.as_bytes());
+}
+
+/// Decode a Huffman coded string, which has to end with at most 7 bits of the end-of-string
+/// code's leading ones.
+fn huffman_decode(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let tree = huffman_tree();
+    let mut decoded = Vec::new();
+    let mut node = 0;
+    let mut depth = 0;
+    let mut all_ones = true;
+    for i in 0..data.len() * 8 {
+        let bit = (data[i / 8] >> (7 - i % 8)) & 1;
+        match tree[node][bit as usize] {
+            Node::Branch(next) => {
+                node = next;
+                depth += 1;
+                all_ones &= bit == 1;
+            }
+            Node::Symbol(END_OF_STRING) => anyhow::bail!("end of string in Huffman code"),
+            Node::Symbol(symbol) => {
+                decoded.push(symbol as u8);
+                node = 0;
+                depth = 0;
+                all_ones = true;
+            }
+            Node::None => anyhow::bail!("invalid Huffman code"),
+        }
+    }
+    if depth > 7 || !all_ones {
+        anyhow::bail!("invalid Huffman padding");
+    }
+    Ok(decoded)
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Node {
+    None,
+    Branch(usize),
+    Symbol(u16),
+}
+
+/// A binary tree of the Huffman codes, each node holding what a 0 and a 1 lead to.
+fn huffman_tree() -> &'static [[Node; 2]] {
+    static TREE: OnceLock<Vec<[Node; 2]>> = OnceLock::new();
+    TREE.get_or_init(|| {
+        let mut tree = vec![[Node::None; 2]];
+        for (symbol, &(code, length)) in HUFFMAN_CODES.iter().enumerate() {
+            let mut node = 0;
+            for i in (1..length).rev() {
+                let bit = ((code >> i) & 1) as usize;
+                node = match tree[node][bit] {
+                    Node::Branch(next) => next,
+                    _ => {
+                        tree.push([Node::None; 2]);
+                        tree[node][bit] = Node::Branch(tree.len() - 1);
+                        tree.len() - 1
+                    }
+                };
+            }
+            tree[node][(code & 1) as usize] = Node::Symbol(symbol as u16);
+        }
+        tree
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_hex(s: &str) -> anyhow::Result<Vec<u8>> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| Ok(u8::from_str_radix(&s[i..i + 2], 16)?))
+            .collect()
+    }
+
+    fn headers(headers: &[(&str, &str)]) -> Vec<Header> {
+        headers
+            .iter()
+            .map(|&(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    // RFC 7541 appendix C.4, requests with Huffman coding which add to the dynamic table
+    #[test]
+    fn known_requests_decode() {
+        let mut decoder = Decoder::default();
+        let first = decoder
+            .decode(&decode_hex("828684418cf1e3c2e5f23a6ba0ab90f4ff").unwrap())
+            .unwrap();
+        assert_eq!(
+            first,
+            headers(&[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "www.example.com"),
+            ])
+        );
+        let second = decoder
+            .decode(&decode_hex("828684be5886a8eb10649cbf").unwrap())
+            .unwrap();
+        assert_eq!(second[3], first[3]);
+        assert_eq!(
+            second[4],
+            ("cache-control".to_string(), "no-cache".to_string())
+        );
+        let third = decoder
+            .decode(&decode_hex("828785bf408825a849e95ba97d7f8925a849e95bb8e8b4bf").unwrap())
+            .unwrap();
+        assert_eq!(
+            third,
+            headers(&[
+                (":method", "GET"),
+                (":scheme", "https"),
+                (":path", "/index.html"),
+                (":authority", "www.example.com"),
+                ("custom-key", "custom-value"),
+            ])
+        );
+        assert_eq!(decoder.dynamic_table.len(), 3);
+        assert_eq!(decoder.size, 164);
+
+        // Bad indexes, truncation and padding
+        for block in [
+            "80",
+            "c0",
+            "41",
+            "418cf1e3c2e5f23a6ba0ab90f4",
+            "4181ff",
+            "3fe21f",
+        ] {
+            assert!(
+                Decoder::default()
+                    .decode(&decode_hex(block).unwrap())
+                    .is_err(),
+                "{block}"
+            );
+        }
+    }
+
+    #[test]
+    fn encoded_headers_decode() {
+        let long_value = "x".repeat(300);
+        let list = [
+            (":status", "200"),
+            ("content-type", "application/dns-message"),
+            ("cache-control", "max-age=300"),
+            ("x-custom", long_value.as_str()),
+        ];
+        let block = encode(&list);
+        assert_eq!(block[0], 0x88);
+        assert_eq!(Decoder::default().decode(&block).unwrap(), headers(&list));
+    }
+}
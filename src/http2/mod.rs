@@ -0,0 +1,617 @@
+//! A blocking HTTP/2 (RFC 9113) connection, enough for DNS-over-HTTPS.
+//!
+//! Messages are exchanged whole: `receive` returns the next complete request or response, and
+//! `send` writes one, waiting for the peer's flow control window if it has to. Frames for other
+//! streams that arrive meanwhile are kept, so requests can be pipelined even though they're
+//! answered in turn.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, BufReader, Read, Write},
+};
+
+pub mod hpack;
+
+use hpack::Header;
+
+/// What clients start with, before their SETTINGS.
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+/// The largest body taken, which is far more than DoH needs.
+pub const MAX_BODY_SIZE: usize = 1 << 20;
+/// How many streams a client can have open at once.
+const MAX_CONCURRENT_STREAMS: usize = 100;
+/// The largest frame payload either side can send until told otherwise, which is all we allow.
+const DEFAULT_MAX_FRAME_SIZE: usize = 16384;
+const DEFAULT_WINDOW_SIZE: i64 = 65535;
+const MAX_WINDOW_SIZE: i64 = 0x7fff_ffff;
+const MAX_STREAM_ID: u32 = 0x7fff_ffff;
+
+// Frame types
+const DATA: u8 = 0;
+const HEADERS: u8 = 1;
+const PRIORITY: u8 = 2;
+const RST_STREAM: u8 = 3;
+const SETTINGS: u8 = 4;
+const PUSH_PROMISE: u8 = 5;
+const PING: u8 = 6;
+const GOAWAY: u8 = 7;
+const WINDOW_UPDATE: u8 = 8;
+const CONTINUATION: u8 = 9;
+
+// Frame flags
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY_FLAG: u8 = 0x20;
+
+// Settings
+const SETTINGS_ENABLE_PUSH: u16 = 2;
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 5;
+const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 6;
+
+// Error codes
+const PROTOCOL_ERROR: u32 = 0x1;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+const STREAM_CLOSED: u32 = 0x5;
+const FRAME_SIZE_ERROR: u32 = 0x6;
+const REFUSED_STREAM: u32 = 0x7;
+const COMPRESSION_ERROR: u32 = 0x9;
+const ENHANCE_YOUR_CALM: u32 = 0xb;
+
+/// A complete request or response.
+#[derive(Debug)]
+pub struct Message {
+    pub stream_id: u32,
+    pub headers: Vec<Header>,
+    pub body: Vec<u8>,
+}
+
+/// One end of an HTTP/2 connection over a stream.
+#[derive(Debug)]
+pub struct Connection<S> {
+    reader: BufReader<S>,
+    /// Frames waiting to be written, which go out together before the next read.
+    out: Vec<u8>,
+    is_server: bool,
+    decoder: hpack::Decoder,
+    /// Whether the peer's first SETTINGS have arrived, which have to come before anything else.
+    settings_received: bool,
+    /// A header block waiting for its CONTINUATION frames.
+    header_block: Option<HeaderBlock>,
+    /// Streams whose message is still arriving.
+    streams: HashMap<u32, Incoming>,
+    /// Messages which have arrived but haven't been returned yet.
+    ready: VecDeque<Message>,
+    /// The highest stream the peer has opened.
+    last_peer_stream: u32,
+    /// The stream our next request goes on.
+    next_stream_id: u32,
+    /// How much more we can send on the connection, and on each stream we've still to send on.
+    send_window: i64,
+    stream_send_windows: HashMap<u32, i64>,
+    /// The peer's settings which matter to what we send.
+    initial_window_size: i64,
+    max_frame_size: usize,
+    /// Whether the peer has said it's closing the connection.
+    going_away: bool,
+}
+
+/// A message partly received.
+#[derive(Debug)]
+struct Incoming {
+    /// Missing until a client has the final response headers.
+    headers: Option<Vec<Header>>,
+    body: Vec<u8>,
+}
+
+#[derive(Debug)]
+struct HeaderBlock {
+    stream_id: u32,
+    fragment: Vec<u8>,
+    end_stream: bool,
+}
+
+impl Message {
+    /// The first value of the header with this (lowercase) name.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(other, _)| other == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+impl<S: Read + Write> Connection<S> {
+    /// Take a client's connection, which has to start with the HTTP/2 preface.
+    pub fn server(mut reader: BufReader<S>) -> anyhow::Result<Self> {
+        let mut preface = [0; PREFACE.len()];
+        reader.read_exact(&mut preface)?;
+        if preface != PREFACE {
+            anyhow::bail!("missing HTTP/2 connection preface");
+        }
+        let mut connection = Connection::new(reader, true);
+        connection.write_settings(&[
+            (
+                SETTINGS_MAX_CONCURRENT_STREAMS,
+                MAX_CONCURRENT_STREAMS as u32,
+            ),
+            (
+                SETTINGS_MAX_HEADER_LIST_SIZE,
+                hpack::MAX_HEADER_LIST_SIZE as u32,
+            ),
+        ]);
+        Ok(connection)
+    }
+
+    /// Start a connection to a server, which will be sent with the first request.
+    pub fn client(stream: S) -> Self {
+        let mut connection = Connection::new(BufReader::new(stream), false);
+        connection.out.extend_from_slice(PREFACE);
+        connection.write_settings(&[
+            (SETTINGS_ENABLE_PUSH, 0),
+            (
+                SETTINGS_MAX_HEADER_LIST_SIZE,
+                hpack::MAX_HEADER_LIST_SIZE as u32,
+            ),
+        ]);
+        connection
+    }
+
+    fn new(reader: BufReader<S>, is_server: bool) -> Self {
+        Connection {
+            reader,
+            out: Vec::new(),
+            is_server,
+            decoder: hpack::Decoder::default(),
+            settings_received: false,
+            header_block: None,
+            streams: HashMap::new(),
+            ready: VecDeque::new(),
+            last_peer_stream: 0,
+            next_stream_id: 1,
+            send_window: DEFAULT_WINDOW_SIZE,
+            stream_send_windows: HashMap::new(),
+            initial_window_size: DEFAULT_WINDOW_SIZE,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            going_away: false,
+        }
+    }
+
+    /// Send a request and wait for its response.
+    pub fn request(&mut self, headers: &[(&str, &str)], body: &[u8]) -> anyhow::Result<Message> {
+        if !self.is_open() {
+            anyhow::bail!("connection is closing");
+        }
+        let stream_id = self.next_stream_id;
+        self.next_stream_id += 2;
+        self.streams.insert(
+            stream_id,
+            Incoming {
+                headers: None,
+                body: Vec::new(),
+            },
+        );
+        self.stream_send_windows
+            .insert(stream_id, self.initial_window_size);
+        self.send(stream_id, headers, body)?;
+        loop {
+            if let Some(i) = self.ready.iter().position(|m| m.stream_id == stream_id) {
+                return Ok(self.ready.remove(i).unwrap());
+            }
+            if !self.streams.contains_key(&stream_id) {
+                anyhow::bail!("server reset the request");
+            }
+            if !self.read_frame()? {
+                anyhow::bail!("connection closed");
+            }
+        }
+    }
+
+    /// The next complete message, or `None` once the peer has closed the connection.
+    pub fn receive(&mut self) -> anyhow::Result<Option<Message>> {
+        loop {
+            if let Some(message) = self.ready.pop_front() {
+                return Ok(Some(message));
+            }
+            if self.going_away && self.streams.is_empty() {
+                self.flush()?;
+                return Ok(None);
+            }
+            if !self.read_frame()? {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Send a message on a stream, ending it. Responses to streams the client has since reset
+    /// are dropped.
+    pub fn send(
+        &mut self,
+        stream_id: u32,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> anyhow::Result<()> {
+        if !self.stream_send_windows.contains_key(&stream_id) {
+            return Ok(());
+        }
+        let block = hpack::encode(headers);
+        let mut fragments = block.chunks(self.max_frame_size).collect::<Vec<_>>();
+        if fragments.is_empty() {
+            fragments.push(&[]);
+        }
+        let count = fragments.len();
+        for (i, fragment) in fragments.into_iter().enumerate() {
+            let kind = if i == 0 { HEADERS } else { CONTINUATION };
+            let mut flags = 0;
+            if i == count - 1 {
+                flags |= END_HEADERS;
+            }
+            if i == 0 && body.is_empty() {
+                flags |= END_STREAM;
+            }
+            self.write_frame(kind, flags, stream_id, fragment);
+        }
+
+        let mut sent = 0;
+        while sent < body.len() {
+            let Some(&stream_window) = self.stream_send_windows.get(&stream_id) else {
+                anyhow::bail!("stream {stream_id} was reset");
+            };
+            let window = self.send_window.min(stream_window);
+            if window <= 0 {
+                if !self.read_frame()? {
+                    anyhow::bail!("connection closed");
+                }
+                continue;
+            }
+            let length = (body.len() - sent)
+                .min(window as usize)
+                .min(self.max_frame_size);
+            let flags = match sent + length == body.len() {
+                true => END_STREAM,
+                false => 0,
+            };
+            self.write_frame(DATA, flags, stream_id, &body[sent..sent + length]);
+            self.send_window -= length as i64;
+            self.stream_send_windows
+                .entry(stream_id)
+                .and_modify(|window| *window -= length as i64);
+            sent += length;
+        }
+        self.stream_send_windows.remove(&stream_id);
+        self.flush()?;
+        Ok(())
+    }
+
+    /// Whether the server can still take requests on the connection.
+    pub fn is_open(&self) -> bool {
+        !self.going_away && self.next_stream_id <= MAX_STREAM_ID
+    }
+
+    /// Read and act on a frame, returning false if the peer closed the connection instead.
+    fn read_frame(&mut self) -> anyhow::Result<bool> {
+        self.flush()?;
+        let mut head = [0; 9];
+        // A clean close comes between frames
+        if self.reader.read(&mut head[..1])? == 0 {
+            return Ok(false);
+        }
+        self.reader.read_exact(&mut head[1..])?;
+        let length = u32::from_be_bytes([0, head[0], head[1], head[2]]) as usize;
+        let (kind, flags) = (head[3], head[4]);
+        let stream_id = u32::from_be_bytes([head[5], head[6], head[7], head[8]]) & 0x7fff_ffff;
+        if length > DEFAULT_MAX_FRAME_SIZE {
+            return Err(self.error(FRAME_SIZE_ERROR, "frame is too large"));
+        }
+        let mut payload = vec![0; length];
+        self.reader.read_exact(&mut payload)?;
+
+        if !self.settings_received && (kind != SETTINGS || flags & ACK != 0) {
+            return Err(self.error(PROTOCOL_ERROR, "connection didn't start with settings"));
+        }
+        let continues_block = self
+            .header_block
+            .as_ref()
+            .map(|block| block.stream_id == stream_id);
+        if (kind == CONTINUATION) != continues_block.unwrap_or(false) {
+            return Err(self.error(PROTOCOL_ERROR, "header block interrupted"));
+        }
+        if stream_id == 0 && matches!(kind, DATA | HEADERS | PRIORITY | RST_STREAM) {
+            return Err(self.error(PROTOCOL_ERROR, "stream frame on stream 0"));
+        }
+        if stream_id != 0 && matches!(kind, SETTINGS | PING | GOAWAY) {
+            return Err(self.error(PROTOCOL_ERROR, "connection frame on a stream"));
+        }
+
+        match kind {
+            DATA => {
+                let Some(data) = unpad(flags, &payload) else {
+                    return Err(self.error(PROTOCOL_ERROR, "too much padding"));
+                };
+                // What the peer used of the connection's window is given back straight away
+                if length > 0 {
+                    self.write_frame(WINDOW_UPDATE, 0, 0, &(length as u32).to_be_bytes());
+                }
+                let end_stream = flags & END_STREAM != 0;
+                let is_idle = self.is_idle(stream_id);
+                match self.streams.get_mut(&stream_id) {
+                    Some(Incoming {
+                        headers: Some(_),
+                        body,
+                    }) => {
+                        body.extend_from_slice(data);
+                        if body.len() > MAX_BODY_SIZE {
+                            self.reset(stream_id, ENHANCE_YOUR_CALM);
+                        } else if end_stream {
+                            self.complete(stream_id);
+                        } else if length > 0 {
+                            let increment = (length as u32).to_be_bytes();
+                            self.write_frame(WINDOW_UPDATE, 0, stream_id, &increment);
+                        }
+                    }
+                    Some(_) => self.reset(stream_id, PROTOCOL_ERROR),
+                    None if is_idle => {
+                        return Err(self.error(PROTOCOL_ERROR, "data on an idle stream"));
+                    }
+                    None => self.reset(stream_id, STREAM_CLOSED),
+                }
+            }
+            HEADERS => {
+                let Some(mut fragment) = unpad(flags, &payload) else {
+                    return Err(self.error(PROTOCOL_ERROR, "too much padding"));
+                };
+                if flags & PRIORITY_FLAG != 0 {
+                    if fragment.len() < 5 {
+                        return Err(self.error(FRAME_SIZE_ERROR, "truncated priority"));
+                    }
+                    fragment = &fragment[5..];
+                }
+                self.header_block = Some(HeaderBlock {
+                    stream_id,
+                    fragment: fragment.to_vec(),
+                    end_stream: flags & END_STREAM != 0,
+                });
+                if flags & END_HEADERS != 0 {
+                    self.end_header_block()?;
+                }
+            }
+            CONTINUATION => {
+                let block = self.header_block.as_mut().unwrap();
+                block.fragment.extend_from_slice(&payload);
+                if block.fragment.len() > hpack::MAX_HEADER_LIST_SIZE {
+                    return Err(self.error(ENHANCE_YOUR_CALM, "header block is too large"));
+                }
+                if flags & END_HEADERS != 0 {
+                    self.end_header_block()?;
+                }
+            }
+            PRIORITY if length != 5 => self.reset(stream_id, FRAME_SIZE_ERROR),
+            RST_STREAM => {
+                if length != 4 {
+                    return Err(self.error(FRAME_SIZE_ERROR, "malformed stream reset"));
+                }
+                if self.is_idle(stream_id) {
+                    return Err(self.error(PROTOCOL_ERROR, "reset of an idle stream"));
+                }
+                self.streams.remove(&stream_id);
+                self.stream_send_windows.remove(&stream_id);
+            }
+            SETTINGS if flags & ACK != 0 && length != 0 => {
+                return Err(self.error(FRAME_SIZE_ERROR, "settings ack with a payload"));
+            }
+            SETTINGS if flags & ACK != 0 => {}
+            SETTINGS => {
+                if length % 6 != 0 {
+                    return Err(self.error(FRAME_SIZE_ERROR, "malformed settings"));
+                }
+                for setting in payload.chunks(6) {
+                    let id = u16::from_be_bytes([setting[0], setting[1]]);
+                    let value =
+                        u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+                    match id {
+                        SETTINGS_ENABLE_PUSH if value > 1 => {
+                            return Err(self.error(PROTOCOL_ERROR, "invalid push setting"));
+                        }
+                        SETTINGS_INITIAL_WINDOW_SIZE => {
+                            if value as i64 > MAX_WINDOW_SIZE {
+                                return Err(self.error(FLOW_CONTROL_ERROR, "window is too large"));
+                            }
+                            // Streams already open make up the difference
+                            let change = value as i64 - self.initial_window_size;
+                            for window in self.stream_send_windows.values_mut() {
+                                *window += change;
+                            }
+                            self.initial_window_size = value as i64;
+                        }
+                        SETTINGS_MAX_FRAME_SIZE => {
+                            if !(DEFAULT_MAX_FRAME_SIZE as u32..=0xff_ffff).contains(&value) {
+                                return Err(self.error(PROTOCOL_ERROR, "invalid frame size"));
+                            }
+                            self.max_frame_size = value as usize;
+                        }
+                        _ => {}
+                    }
+                }
+                self.settings_received = true;
+                self.write_frame(SETTINGS, ACK, 0, &[]);
+            }
+            PUSH_PROMISE => {
+                return Err(self.error(PROTOCOL_ERROR, "push isn't enabled"));
+            }
+            PING => {
+                if length != 8 {
+                    return Err(self.error(FRAME_SIZE_ERROR, "malformed ping"));
+                }
+                if flags & ACK == 0 {
+                    self.write_frame(PING, ACK, 0, &payload);
+                }
+            }
+            GOAWAY => {
+                if length < 8 {
+                    return Err(self.error(FRAME_SIZE_ERROR, "malformed goaway"));
+                }
+                // Streams we opened after the last one the peer will process never will be
+                let last_stream_id =
+                    u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]])
+                        & 0x7fff_ffff;
+                let is_server = self.is_server;
+                let kept = |id: &u32| (id % 2 == 1) == is_server || *id <= last_stream_id;
+                self.streams.retain(|id, _| kept(id));
+                self.stream_send_windows.retain(|id, _| kept(id));
+                self.going_away = true;
+            }
+            WINDOW_UPDATE => {
+                if length != 4 {
+                    return Err(self.error(FRAME_SIZE_ERROR, "malformed window update"));
+                }
+                let increment =
+                    (u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]])
+                        & 0x7fff_ffff) as i64;
+                if stream_id == 0 {
+                    self.send_window += increment;
+                    if increment == 0 || self.send_window > MAX_WINDOW_SIZE {
+                        return Err(self.error(FLOW_CONTROL_ERROR, "invalid window update"));
+                    }
+                } else if let Some(window) = self.stream_send_windows.get_mut(&stream_id) {
+                    *window += increment;
+                    if increment == 0 || *window > MAX_WINDOW_SIZE {
+                        self.reset(stream_id, FLOW_CONTROL_ERROR);
+                    }
+                }
+            }
+            // Unknown frame types are ignored, as are priorities
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    /// Decode a complete header block, and start or finish its stream's message with it.
+    fn end_header_block(&mut self) -> anyhow::Result<()> {
+        let block = self.header_block.take().unwrap();
+        let headers = match self.decoder.decode(&block.fragment) {
+            Ok(headers) => headers,
+            Err(e) => return Err(self.error(COMPRESSION_ERROR, &e.to_string())),
+        };
+        let stream_id = block.stream_id;
+        let is_idle = self.is_idle(stream_id);
+        match self.streams.get_mut(&stream_id) {
+            Some(incoming) => {
+                let is_informational = headers
+                    .iter()
+                    .any(|(name, value)| name == ":status" && value.starts_with('1'));
+                if incoming.headers.is_some() && !block.end_stream {
+                    // Trailers have to end the stream
+                    self.reset(stream_id, PROTOCOL_ERROR);
+                    return Ok(());
+                }
+                if incoming.headers.is_none() && !is_informational {
+                    incoming.headers = Some(headers);
+                }
+            }
+            None if self.is_server && stream_id % 2 == 1 && stream_id > self.last_peer_stream => {
+                self.last_peer_stream = stream_id;
+                if self.stream_send_windows.len() >= MAX_CONCURRENT_STREAMS {
+                    self.reset(stream_id, REFUSED_STREAM);
+                    return Ok(());
+                }
+                self.streams.insert(
+                    stream_id,
+                    Incoming {
+                        headers: Some(headers),
+                        body: Vec::new(),
+                    },
+                );
+                self.stream_send_windows
+                    .insert(stream_id, self.initial_window_size);
+            }
+            None if is_idle => {
+                return Err(self.error(PROTOCOL_ERROR, "headers on an idle stream"));
+            }
+            None => {
+                self.reset(stream_id, STREAM_CLOSED);
+                return Ok(());
+            }
+        }
+        let has_headers = self.streams[&stream_id].headers.is_some();
+        if block.end_stream && has_headers {
+            self.complete(stream_id);
+        }
+        Ok(())
+    }
+
+    fn complete(&mut self, stream_id: u32) {
+        let incoming = self.streams.remove(&stream_id).unwrap();
+        self.ready.push_back(Message {
+            stream_id,
+            headers: incoming.headers.unwrap(),
+            body: incoming.body,
+        });
+    }
+
+    /// Whether a stream hasn't been opened yet.
+    fn is_idle(&self, stream_id: u32) -> bool {
+        match (stream_id % 2 == 1) == self.is_server {
+            true => stream_id > self.last_peer_stream,
+            false => stream_id >= self.next_stream_id,
+        }
+    }
+
+    /// Give up on a stream, telling the peer why.
+    fn reset(&mut self, stream_id: u32, code: u32) {
+        self.write_frame(RST_STREAM, 0, stream_id, &code.to_be_bytes());
+        self.streams.remove(&stream_id);
+        self.stream_send_windows.remove(&stream_id);
+    }
+
+    /// Give up on the connection, telling the peer why, and return the error to stop with.
+    fn error(&mut self, code: u32, message: &str) -> anyhow::Error {
+        let mut payload = self.last_peer_stream.to_be_bytes().to_vec();
+        payload.extend_from_slice(&code.to_be_bytes());
+        self.write_frame(GOAWAY, 0, 0, &payload);
+        // The error's more use than one about sending the GOAWAY
+        let _ = self.flush();
+        anyhow::format_err!("http/2 connection error: {message}")
+    }
+
+    fn write_settings(&mut self, settings: &[(u16, u32)]) {
+        let mut payload = Vec::new();
+        for (id, value) in settings {
+            payload.extend_from_slice(&id.to_be_bytes());
+            payload.extend_from_slice(&value.to_be_bytes());
+        }
+        self.write_frame(SETTINGS, 0, 0, &payload);
+    }
+
+    fn write_frame(&mut self, kind: u8, flags: u8, stream_id: u32, payload: &[u8]) {
+        self.out
+            .extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+        self.out.push(kind);
+        self.out.push(flags);
+        self.out.extend_from_slice(&stream_id.to_be_bytes());
+        self.out.extend_from_slice(payload);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.out.is_empty() {
+            return Ok(());
+        }
+        let stream = self.reader.get_mut();
+        stream.write_all(&self.out)?;
+        stream.flush()?;
+        self.out.clear();
+        Ok(())
+    }
+}
+
+/// A DATA or HEADERS frame's payload without its padding, if the padding fits.
+fn unpad(flags: u8, payload: &[u8]) -> Option<&[u8]> {
+    if flags & PADDED == 0 {
+        return Some(payload);
+    }
+    let (&padding, rest) = payload.split_first()?;
+    let length = rest.len().checked_sub(padding as usize)?;
+    Some(&rest[..length])
+}
@@ -0,0 +1,551 @@
+//! Records answered directly rather than forwarded, from `--local-record` zone file lines or
+//! `/etc/hosts` style files. PTR records are synthesized for every address.
+//!
+//! Names under a zone with a signing key form a signed zone, which is answered authoritatively
+//! for every name beneath it, including ones which don't exist.
+
+use std::{
+    collections::HashMap,
+    fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    message::{
+        Class, DomainName, Message, Question, RecordType, ResourceRecord, ResourceRecordData,
+        ResponseCode,
+    },
+    signer::{SigningConfig, ZoneSigner},
+    zone::ZoneAnswer,
+    zone_file,
+};
+
+/// Guards against alias loops.
+const MAX_ALIAS_CHAIN: usize = 8;
+
+#[derive(Debug, Clone)]
+pub struct LocalRecordsConfig {
+    /// Records in zone file syntax, e.g. `printer.lan. A 192.168.1.20`.
+    pub records: Vec<String>,
+    pub hosts_files: Vec<PathBuf>,
+    /// TTL for records which don't give their own.
+    pub ttl: u32,
+    /// Zones to sign with DNSSEC.
+    pub signing: SigningConfig,
+}
+
+#[derive(Debug)]
+pub struct LocalRecords {
+    records: HashMap<DomainName, Vec<ResourceRecord>>,
+    zones: Vec<ZoneSigner>,
+}
+
+impl Default for LocalRecordsConfig {
+    fn default() -> Self {
+        LocalRecordsConfig {
+            records: Vec::new(),
+            hosts_files: Vec::new(),
+            ttl: 300,
+            signing: SigningConfig::default(),
+        }
+    }
+}
+
+impl LocalRecords {
+    pub fn load(config: &LocalRecordsConfig) -> anyhow::Result<Self> {
+        let mut contents = format!("$TTL {}\n", config.ttl);
+        for record in config.records.iter() {
+            contents.push_str(record);
+            contents.push('\n');
+        }
+        let mut records = zone_file::parse(&contents, &DomainName::new("")?)?;
+
+        for path in config.hosts_files.iter() {
+            let hosts = fs::read_to_string(path)?;
+            records.extend(
+                parse_hosts_file(path, &hosts, config.ttl)
+                    .map_err(|e| anyhow::format_err!("{}: {}", path.display(), e))?,
+            );
+        }
+
+        // Reverse lookups for every address, pointing at the first name given for it, as with the
+        // canonical hostname on a hosts line. Taken before the records are grouped by name, which
+        // would lose their order.
+        let addresses = records
+            .iter()
+            .filter_map(|record| {
+                let addr = match record.data {
+                    ResourceRecordData::IPv4(ip) => IpAddr::from(ip),
+                    ResourceRecordData::IPv6(ip) => IpAddr::from(ip),
+                    _ => return None,
+                };
+                Some((addr, record.name.clone(), record.time_to_live))
+            })
+            .collect::<Vec<_>>();
+
+        let mut local_records = LocalRecords {
+            records: HashMap::new(),
+            zones: Vec::new(),
+        };
+        for record in records {
+            local_records.insert(record);
+        }
+
+        // Unless there's already an explicit PTR
+        for (addr, name, ttl) in addresses {
+            let reverse_name = reverse_name(addr);
+            if !local_records.records.contains_key(&reverse_name) {
+                local_records.insert(ResourceRecord::new(
+                    reverse_name,
+                    RecordType::Pointer,
+                    Class::Internet,
+                    ttl,
+                    ResourceRecordData::Pointer(name),
+                ));
+            }
+        }
+
+        // Signed zones need keys and an SOA for negative answers
+        for (apex, path) in config.signing.keys.iter() {
+            let zone = ZoneSigner::load(apex.clone(), path, &config.signing)?;
+            local_records.insert(zone.dnskey(config.ttl));
+            let has_soa = local_records.records.get(apex).is_some_and(|records| {
+                records
+                    .iter()
+                    .any(|record| record.ty == RecordType::StartOfAuthority)
+            });
+            if !has_soa {
+                local_records.insert(ResourceRecord::new(
+                    apex.clone(),
+                    RecordType::StartOfAuthority,
+                    Class::Internet,
+                    config.ttl,
+                    ResourceRecordData::StartOfAuthority {
+                        primary_name_server: apex.clone(),
+                        responsible_mailbox: DomainName::new("hostmaster")?.join(apex),
+                        serial: 1,
+                        refresh: 3600,
+                        retry: 600,
+                        expire: 86400,
+                        minimum: config.ttl,
+                    },
+                ));
+            }
+            local_records.zones.push(zone);
+        }
+        Ok(local_records)
+    }
+
+    fn insert(&mut self, record: ResourceRecord) {
+        self.records
+            .entry(record.name.clone())
+            .or_default()
+            .push(record);
+    }
+
+    /// Whether any of the questions are for local names.
+    pub fn contains(&self, query_message: &Message) -> bool {
+        query_message
+            .questions
+            .iter()
+            .any(|question| self.contains_name(&question.name))
+    }
+
+    pub fn contains_name(&self, name: &DomainName) -> bool {
+        self.records.contains_key(name) || self.zone(name).is_some()
+    }
+
+    /// The signed zone `name` is in, if any.
+    fn zone(&self, name: &DomainName) -> Option<&ZoneSigner> {
+        self.zones
+            .iter()
+            .filter(|zone| name.is_subdomain_of(&zone.apex))
+            .max_by_key(|zone| zone.apex.label_count())
+    }
+
+    /// The answers to `question`, if it's for a local name. Aliases are followed as far as they
+    /// stay within the local records.
+    pub fn answers(&self, question: &Question) -> Option<Vec<ResourceRecord>> {
+        let mut records = self.records.get(&question.name)?;
+        let mut answers = Vec::new();
+        for _ in 0..MAX_ALIAS_CHAIN {
+            let matching = records.iter().filter(|record| record.ty == question.ty);
+            if matching.clone().next().is_some() {
+                answers.extend(matching.cloned());
+                break;
+            }
+            let Some(cname) = records.iter().find(|record| record.ty == RecordType::CName) else {
+                break;
+            };
+            answers.push(cname.clone());
+            match &cname.data {
+                ResourceRecordData::CName(target) if self.records.contains_key(target) => {
+                    records = &self.records[target];
+                }
+                _ => break,
+            }
+        }
+        Some(answers)
+    }
+
+    /// The answer to `question` if it's in a signed zone, with signatures and proof of what
+    /// doesn't exist if the client set DO.
+    pub fn zone_answer(
+        &self,
+        question: &Question,
+        dnssec_ok: bool,
+    ) -> anyhow::Result<Option<ZoneAnswer>> {
+        if self.zone(&question.name).is_none() {
+            return Ok(None);
+        }
+        let mut answers = self.answers(question).unwrap_or_default();
+        let mut authorities = Vec::new();
+        let mut response_code = ResponseCode::Ok;
+
+        // Aliases to names outside of the signed zones are resolved elsewhere
+        let name = match answers.last().map(|answer| &answer.data) {
+            Some(ResourceRecordData::CName(target)) if question.ty != RecordType::CName => target,
+            _ => &question.name,
+        };
+        let answered = answers.iter().any(|answer| answer.ty == question.ty);
+        if let (false, Some(zone)) = (answered, self.zone(name)) {
+            let contents = self.zone_contents(zone);
+            let exists = contents
+                .iter()
+                .any(|(owner, _)| owner.is_subdomain_of(name));
+            let types = contents
+                .iter()
+                .find(|(owner, _)| owner == name)
+                .map(|(_, types)| types.clone())
+                .or_else(|| exists.then(Vec::new));
+            if types.is_none() {
+                response_code = ResponseCode::NameError;
+            }
+
+            let soa = self.records[&zone.apex]
+                .iter()
+                .find(|record| record.ty == RecordType::StartOfAuthority)
+                .expect("signed zones should have an SOA")
+                .clone();
+            // Negative answers are cached for the SOA minimum TTL (RFC 2308 section 5)
+            let negative_ttl = match &soa.data {
+                ResourceRecordData::StartOfAuthority { minimum, .. } => {
+                    soa.time_to_live.min(*minimum)
+                }
+                _ => soa.time_to_live,
+            };
+            let mut soa = soa;
+            soa.time_to_live = negative_ttl;
+            authorities.push(soa);
+            if dnssec_ok {
+                let (denial_response_code, denial) =
+                    zone.deny(name, types.as_deref(), &contents, negative_ttl)?;
+                response_code = denial_response_code;
+                authorities.extend(denial);
+            }
+        }
+
+        if dnssec_ok {
+            answers = self.sign(answers)?;
+            authorities = self.sign(authorities)?;
+        }
+        Ok(Some(ZoneAnswer {
+            response_code,
+            authoritative: true,
+            answers,
+            authorities,
+            additionals: Vec::new(),
+        }))
+    }
+
+    /// Every name in `zone` in canonical order, with the types at each.
+    fn zone_contents(&self, zone: &ZoneSigner) -> Vec<(DomainName, Vec<RecordType>)> {
+        let mut contents = self
+            .records
+            .iter()
+            .filter(|(name, _)| self.zone(name).is_some_and(|z| z.apex == zone.apex))
+            .map(|(name, records)| {
+                let mut types = records.iter().map(|record| record.ty).collect::<Vec<_>>();
+                types.dedup();
+                (name.clone(), types)
+            })
+            .collect::<Vec<_>>();
+        contents.sort_by(|a, b| a.0.canonical_cmp(&b.0));
+        contents
+    }
+
+    /// `records` followed by signatures over those in signed zones.
+    fn sign(&self, records: Vec<ResourceRecord>) -> anyhow::Result<Vec<ResourceRecord>> {
+        let mut signatures = Vec::new();
+        for zone in self.zones.iter() {
+            let zone_records = records
+                .iter()
+                .filter(|record| self.zone(&record.name).is_some_and(|z| z.apex == zone.apex))
+                .cloned()
+                .collect::<Vec<_>>();
+            signatures.extend(zone.sign(&zone_records)?);
+        }
+        let mut records = records;
+        records.extend(signatures);
+        Ok(records)
+    }
+}
+
+/// Address records from a hosts file, with lines like `192.168.1.20 printer.lan printer`.
+fn parse_hosts_file(path: &Path, contents: &str, ttl: u32) -> anyhow::Result<Vec<ResourceRecord>> {
+    let mut records = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap();
+        let mut tokens = line.split_whitespace();
+        let Some(addr) = tokens.next() else {
+            continue;
+        };
+        // Link-local addresses scoped to an interface, e.g. `fe80::1%lo0`, can't go in a record
+        if addr.contains('%') {
+            eprintln!(
+                "local records: skipping line {} of {}, {} has an interface zone",
+                number + 1,
+                path.display(),
+                addr
+            );
+            continue;
+        }
+        let data = match addr.parse::<IpAddr>()? {
+            IpAddr::V4(addr) => ResourceRecordData::IPv4(addr.octets()),
+            IpAddr::V6(addr) => ResourceRecordData::IPv6(addr.octets()),
+        };
+        let ty = match data {
+            ResourceRecordData::IPv4(_) => RecordType::Address,
+            _ => RecordType::Ipv6Address,
+        };
+        for name in tokens {
+            records.push(ResourceRecord::new(
+                DomainName::new(name)?,
+                ty,
+                Class::Internet,
+                ttl,
+                data.clone(),
+            ));
+        }
+    }
+    Ok(records)
+}
+
+/// The name for reverse lookups of `addr`, e.g. `4.3.2.1.in-addr.arpa` for `1.2.3.4`.
+pub fn reverse_name(addr: IpAddr) -> DomainName {
+    let name = match addr {
+        IpAddr::V4(addr) => {
+            let [a, b, c, d] = addr.octets();
+            format!("{d}.{c}.{b}.{a}.in-addr.arpa")
+        }
+        IpAddr::V6(addr) => {
+            let mut name = String::new();
+            for byte in addr.octets().iter().rev() {
+                name.push_str(&format!("{:x}.{:x}.", byte & 0x0F, byte >> 4));
+            }
+            name.push_str("ip6.arpa");
+            name
+        }
+    };
+    DomainName::new(&name).expect("reverse names should be valid")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    fn name(name: &str) -> DomainName {
+        DomainName::new(name).unwrap()
+    }
+
+    fn question(qname: &str, ty: RecordType) -> Question {
+        Question {
+            name: name(qname),
+            ty,
+            class: Class::Internet,
+        }
+    }
+
+    /// The types and data of the answers to a question, if it's for a local name.
+    fn answers(local_records: &LocalRecords, qname: &str, ty: RecordType) -> Option<Vec<String>> {
+        let answers = local_records.answers(&question(qname, ty))?;
+        Some(
+            answers
+                .iter()
+                .map(|answer| format!("{} {:?}", answer.name, answer.data))
+                .collect(),
+        )
+    }
+
+    fn load(records: &[&str], hosts_files: Vec<PathBuf>) -> LocalRecords {
+        LocalRecords::load(&LocalRecordsConfig {
+            records: records.iter().map(|record| record.to_string()).collect(),
+            hosts_files,
+            ..LocalRecordsConfig::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn records_and_hosts_files() {
+        let hosts = env::temp_dir().join(format!("dns-server-test-{}-local-hosts", process::id()));
+        fs::write(
+            &hosts,
+            "192.168.1.20 printer.lan printer # office\n\nfd00::20 printer.lan\nfe80::1%lo0 localhost\n",
+        )
+        .unwrap();
+        let local_records = load(&["nas.lan. 60 A 192.168.1.30"], vec![hosts.clone()]);
+        fs::remove_file(hosts).unwrap();
+
+        assert_eq!(
+            answers(&local_records, "printer.lan", RecordType::Address).unwrap(),
+            ["printer.lan IPv4([192, 168, 1, 20])"]
+        );
+        let records = local_records.answers(&question("nas.lan", RecordType::Address));
+        assert_eq!(records.unwrap()[0].time_to_live, 60);
+        let records = local_records.answers(&question("printer", RecordType::Address));
+        assert_eq!(records.unwrap()[0].time_to_live, 300);
+        assert_eq!(
+            answers(&local_records, "printer.lan", RecordType::Ipv6Address)
+                .unwrap()
+                .len(),
+            1
+        );
+        // Local names with nothing of the type asked for have no data, rather than being
+        // forwarded
+        assert_eq!(
+            answers(&local_records, "nas.lan", RecordType::MailExchange),
+            Some(Vec::new())
+        );
+        assert_eq!(
+            answers(&local_records, "www.lan", RecordType::Address),
+            None
+        );
+
+        // The zoned address is skipped rather than failing the whole file
+        assert_eq!(
+            answers(&local_records, "localhost", RecordType::Ipv6Address),
+            None
+        );
+
+        let query_message = Message::new_query(vec![question("NAS.lan", RecordType::Address)]);
+        assert!(local_records.contains(&query_message));
+        assert!(!local_records.contains_name(&name("lan")));
+    }
+
+    #[test]
+    fn addresses_have_reverse_lookups() {
+        let local_records = load(
+            &[
+                "nas.lan. A 192.168.1.30",
+                "printer.lan. AAAA 2001:db8::20",
+                "router.lan. A 192.168.1.1",
+                "1.1.168.192.in-addr.arpa. PTR gateway.lan.",
+            ],
+            Vec::new(),
+        );
+        let hosts = env::temp_dir().join(format!("dns-server-test-{}-ptr-hosts", process::id()));
+        let mut contents = String::new();
+        for i in 0..16 {
+            contents.push_str(&format!("192.168.2.{i} host{i}.lan host{i} alias{i}.lan\n"));
+        }
+        fs::write(&hosts, contents).unwrap();
+        let hosts_records = load(&[], vec![hosts.clone()]);
+        fs::remove_file(hosts).unwrap();
+        let pointer = |qname: &str| {
+            let answers = local_records.answers(&question(qname, RecordType::Pointer))?;
+            match &answers[..] {
+                [ResourceRecord {
+                    data: ResourceRecordData::Pointer(target),
+                    ..
+                }] => Some(target.to_string()),
+                _ => None,
+            }
+        };
+        assert_eq!(
+            pointer("30.1.168.192.in-addr.arpa").as_deref(),
+            Some("nas.lan")
+        );
+        let reverse = reverse_name("2001:db8::20".parse().unwrap());
+        assert_eq!(
+            reverse.to_string(),
+            "0.2.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa"
+        );
+        assert!(local_records.contains_name(&reverse));
+        // Explicit PTR records aren't added to
+        assert_eq!(
+            pointer("1.1.168.192.in-addr.arpa").as_deref(),
+            Some("gateway.lan")
+        );
+
+        // The first name on a hosts line is the one addresses point back to, whatever order the
+        // names end up in
+        for i in 0..16 {
+            let answers = hosts_records
+                .answers(&question(
+                    &format!("{i}.2.168.192.in-addr.arpa"),
+                    RecordType::Pointer,
+                ))
+                .unwrap();
+            assert_eq!(answers.len(), 1);
+            let ResourceRecordData::Pointer(target) = &answers[0].data else {
+                panic!("expected a PTR");
+            };
+            assert_eq!(target.to_string(), format!("host{i}.lan"));
+        }
+    }
+
+    #[test]
+    fn aliases_are_followed_within_local_records() {
+        let local_records = load(
+            &[
+                "www.lan. CNAME web.lan.",
+                "web.lan. CNAME server.lan.",
+                "server.lan. A 192.168.1.40",
+                "docs.lan. CNAME docs.example.com.",
+                "loop.lan. CNAME loop.lan.",
+            ],
+            Vec::new(),
+        );
+        let types = |qname: &str, ty: RecordType| {
+            local_records
+                .answers(&question(qname, ty))
+                .unwrap()
+                .iter()
+                .map(|answer| answer.ty)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            types("www.lan", RecordType::Address),
+            [RecordType::CName, RecordType::CName, RecordType::Address]
+        );
+        assert_eq!(types("www.lan", RecordType::CName), [RecordType::CName]);
+        assert_eq!(types("docs.lan", RecordType::Address), [RecordType::CName]);
+        assert_eq!(
+            types("loop.lan", RecordType::Address).len(),
+            MAX_ALIAS_CHAIN
+        );
+    }
+
+    #[test]
+    fn invalid_records_fail_to_load() {
+        let config = LocalRecordsConfig {
+            records: vec!["nas.lan. A 192.168.1".to_string()],
+            ..LocalRecordsConfig::default()
+        };
+        assert!(LocalRecords::load(&config).is_err());
+
+        let hosts = env::temp_dir().join(format!("dns-server-test-{}-bad-hosts", process::id()));
+        fs::write(&hosts, "192.168.1 printer.lan\n").unwrap();
+        let config = LocalRecordsConfig {
+            hosts_files: vec![hosts.clone()],
+            ..LocalRecordsConfig::default()
+        };
+        let error = LocalRecords::load(&config).unwrap_err().to_string();
+        fs::remove_file(&hosts).unwrap();
+        assert!(error.starts_with(&hosts.display().to_string()), "{error}");
+    }
+}
@@ -1,74 +1,195 @@
 use bytes::BytesMut;
 use std::{
     env,
-    net::{SocketAddrV4, UdpSocket},
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, UdpSocket},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
 };
 
+use config::Config;
+use server::{Server, Transport};
+
+mod acl;
+mod blocklist;
+mod cache;
+mod config;
+mod cookie;
+mod crypto;
+mod dnssec;
+mod doh;
+mod encoding;
+mod forward;
+mod http2;
+mod local_records;
 mod message;
+mod notify;
+mod rpz;
+mod rrl;
+mod secondary;
+mod server;
+mod signer;
+mod tsig;
+mod update;
+mod zone;
+mod zone_file;
 
-fn forward(
-    query_message: message::Message,
-    resolver_addr: SocketAddrV4,
-    udp_socket: &UdpSocket,
-) -> anyhow::Result<message::Message> {
-    let mut answers = Vec::new();
-    for question in query_message.questions.iter() {
-        let questions = vec![message::Question {
-            name: question.name.decompress(&query_message)?,
-            ty: question.ty,
-            class: question.class,
-        }];
-        let query_message = message::Message::new_query(questions);
+/// How long a connection can go without sending anything before it's closed, so that idle or
+/// stalled clients don't keep their threads (RFC 7766 section 6.2.3).
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How many connections each listener serves at once. Any more are closed as soon as they're
+/// accepted.
+const MAX_CONNECTIONS: usize = 256;
 
-        let mut msg = BytesMut::with_capacity(64);
-        query_message.write(&mut msg)?;
-        udp_socket
-            .send_to(&msg, resolver_addr)
-            .expect("failed to forward question");
-        let mut buf = [0; 512];
-        match udp_socket.recv_from(&mut buf) {
-            Ok(_) => {
-                let response_message = message::Message::parse(&buf)?;
-                for answer in response_message.answers.iter() {
-                    answers.push(answer.decompressed_clone(&response_message)?);
-                }
-            }
-            Err(e) => {
-                anyhow::bail!("error receiving data: {}", e);
+/// Accept connections on `listener` and serve each on its own thread until it closes or idles out.
+fn serve_connections<F>(listener: TcpListener, protocol: &'static str, serve: F)
+where
+    F: Fn(TcpStream) -> anyhow::Result<()> + Send + Sync + 'static,
+{
+    let serve = Arc::new(serve);
+    let open = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming().flatten() {
+        if open.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+            open.fetch_sub(1, Ordering::SeqCst);
+            continue;
+        }
+        let serve = serve.clone();
+        let open = open.clone();
+        thread::spawn(move || {
+            let result = stream
+                .set_read_timeout(Some(IDLE_TIMEOUT))
+                .and_then(|_| stream.set_write_timeout(Some(IDLE_TIMEOUT)))
+                .map_err(anyhow::Error::from)
+                .and_then(|_| serve(stream));
+            if let Err(e) = result {
+                eprintln!("error serving {} connection: {}", protocol, e);
             }
+            open.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+/// Answer queries on a TCP connection until the client closes it.
+fn serve_tcp_connection(server: &Server, stream: TcpStream) -> anyhow::Result<()> {
+    let source = stream.peer_addr()?;
+    serve_stream(server, source, stream)
+}
+
+/// Answer length-prefixed queries on a stream until the client closes it. This framing is shared
+/// by plain TCP and by transports layered on top of it, like DNS-over-TLS.
+fn serve_stream<S>(server: &Server, source: SocketAddr, mut stream: S) -> anyhow::Result<()>
+where
+    S: Read + Write,
+{
+    loop {
+        // Each message is prefixed with its length as a u16
+        let mut length = [0; 2];
+        if stream.read_exact(&mut length).is_err() {
+            return Ok(());
+        }
+        let mut buf = vec![0; u16::from_be_bytes(length) as usize];
+        stream.read_exact(&mut buf)?;
+
+        let query_message = message::Message::parse(&buf)?;
+        for response_message in server.handle_stream(&query_message, source)? {
+            let mut response = BytesMut::with_capacity(64);
+            response_message.write(&mut response)?;
+            stream.write_all(&(response.len() as u16).to_be_bytes())?;
+            stream.write_all(&response)?;
         }
     }
-    Ok(message::Message::new_reply(
-        &query_message,
-        query_message
-            .questions
-            .iter()
-            .map(|q| q.decompressed_clone(&query_message).unwrap())
-            .collect(),
-        answers,
-    ))
 }
 
 fn main() -> anyhow::Result<()> {
     let args: Vec<String> = env::args().collect();
-    let resolver_addr = if args.len() == 3 && args[1] == "--resolver" {
-        args[2].parse::<SocketAddrV4>()?
-    } else {
-        anyhow::bail!("error: no resolver address given")
-    };
+    let config = Config::from_args(&args[1..])?;
+    let server = Arc::new(Server::new(config)?);
+
+    {
+        let server = server.clone();
+        thread::spawn(move || loop {
+            server.maintain_zones();
+            thread::sleep(zone::MAINTENANCE_INTERVAL);
+        });
+    }
+
+    {
+        let server = server.clone();
+        thread::spawn(move || {
+            let mut last_stats = None;
+            let mut last_blocklist_stats = Vec::new();
+            loop {
+                thread::sleep(rrl::STATS_INTERVAL);
+                let stats = server.rrl_stats();
+                if let Some(stats) = stats.filter(|_| stats != last_stats) {
+                    eprintln!(
+                        "rrl: sent {}, dropped {}, slipped {}, logged {}",
+                        stats.sent, stats.dropped, stats.slipped, stats.logged
+                    );
+                }
+                last_stats = stats;
+
+                let blocklist_stats = server.blocklist_stats();
+                for (index, list) in blocklist_stats.iter().enumerate() {
+                    if last_blocklist_stats.get(index) != Some(list) {
+                        eprintln!(
+                            "blocklist: {} has {} entries, {} hits",
+                            list.path.display(),
+                            list.entries,
+                            list.hits
+                        );
+                    }
+                }
+                last_blocklist_stats = blocklist_stats;
+            }
+        });
+    }
+
+    let tcp_listener = TcpListener::bind("127.0.0.1:2053").expect("failed to bind to address");
+    {
+        let server = server.clone();
+        thread::spawn(move || {
+            serve_connections(tcp_listener, "tcp", move |stream| {
+                serve_tcp_connection(&server, stream)
+            })
+        });
+    }
+
+    if let Some(doh_addr) = server.doh_addr() {
+        let doh_listener = TcpListener::bind(doh_addr).expect("failed to bind to address");
+        let server = server.clone();
+        thread::spawn(move || {
+            serve_connections(doh_listener, "doh", move |stream| {
+                doh::serve_connection(&server, stream)
+            })
+        });
+    }
 
     let udp_socket = UdpSocket::bind("127.0.0.1:2053").expect("failed to bind to address");
-    let mut buf = [0; 512];
+    // Clients can send queries as large as their own payload size, which may be more than ours
+    let mut buf = [0; 4096];
     loop {
         match udp_socket.recv_from(&mut buf) {
-            Ok((_, source)) => {
-                let query_message = message::Message::parse(&buf)?;
-                let response_message = forward(query_message, resolver_addr, &udp_socket)?;
-                let mut response = BytesMut::with_capacity(64);
-                response_message.write(&mut response)?;
-                udp_socket
-                    .send_to(&response, source)
-                    .expect("failed to send response");
+            Ok((len, source)) => {
+                let response_message =
+                    message::Message::parse(&buf[..len]).and_then(|query_message| {
+                        server.handle(&query_message, source, Transport::Udp)
+                    });
+                match response_message {
+                    Ok(Some(response_message)) => {
+                        let mut response = BytesMut::with_capacity(64);
+                        response_message.write(&mut response)?;
+                        udp_socket
+                            .send_to(&response, source)
+                            .expect("failed to send response");
+                    }
+                    Ok(None) => {}
+                    Err(e) => eprintln!("error handling query from {}: {}", source, e),
+                }
             }
             Err(e) => {
                 anyhow::bail!("error receiving data: {}", e);
@@ -0,0 +1,336 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use bytes::BufMut;
+use nom::{
+    bytes::complete::take,
+    number::complete::{be_u16, u8},
+    IResult,
+};
+
+use super::{Class, DomainName, RecordType, ResourceRecord, ResourceRecordData};
+
+/// The option code for DNS cookies.
+const COOKIE: u16 = 10;
+/// The option code for padding.
+const PADDING: u16 = 12;
+/// The option code for extended errors.
+const EXTENDED_ERROR: u16 = 15;
+
+/// EDNS(0) parameters, carried in an OPT pseudo-record in the additional section (RFC 6891).
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub struct Edns {
+    /// The largest UDP payload the sender can reassemble.
+    pub udp_payload_size: u16,
+    /// The upper 8 bits of the 12-bit response code. `Message::write` sets it from the header.
+    pub extended_response_code: u8,
+    /// The EDNS version, only 0 is defined.
+    pub version: u8,
+    /// DO: The sender can handle DNSSEC records.
+    pub dnssec_ok: bool,
+    pub options: Vec<EdnsOption>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EdnsOption {
+    /// ECS: The network a query was sent on behalf of (RFC 7871).
+    ClientSubnet(ClientSubnet),
+    /// A DNS cookie, proving the sender has seen earlier replies (RFC 7873).
+    Cookie(Cookie),
+    /// This many zero bytes, to hide the message's length on an encrypted transport (RFC 7830).
+    Padding(u16),
+    /// EDE: Why a query failed, or what's unusual about its answer (RFC 8914).
+    ExtendedError(ExtendedError),
+    /// An option we don't know about, kept so it round-trips.
+    Unknown { code: u16, data: Vec<u8> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ClientSubnet {
+    /// How many leading bits of `addr` are significant in the query.
+    pub source_prefix_len: u8,
+    /// How many leading bits of `addr` the answer applies to. 0 in queries.
+    pub scope_prefix_len: u8,
+    pub addr: IpAddr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cookie {
+    /// Chosen by the client for each server.
+    pub client: [u8; 8],
+    /// Chosen by the server for each client, 8 to 32 bytes. Empty until the client has one.
+    pub server: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExtendedError {
+    pub info_code: ExtendedErrorCode,
+    /// For people rather than programs. May be empty.
+    pub extra_text: String,
+}
+
+/// The extended error codes we send (RFC 8914 section 4).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtendedErrorCode {
+    /// The answer has expired, but the upstream couldn't be reached to refresh it.
+    StaleAnswer,
+    /// DNSSEC validation found the answer was forged or broken.
+    DnssecBogus,
+    /// The zone isn't loaded yet, or has expired.
+    NotReady,
+    /// The name is blocked by the server's own policy.
+    Blocked,
+    /// The client isn't allowed to ask.
+    Prohibited,
+    /// The upstream couldn't be reached.
+    NetworkError,
+    /// A code we don't know about, kept so it round-trips.
+    Unknown(u16),
+}
+
+impl Edns {
+    pub fn new(udp_payload_size: u16) -> Self {
+        Edns {
+            udp_payload_size,
+            extended_response_code: 0,
+            version: 0,
+            dnssec_ok: false,
+            options: Vec::new(),
+        }
+    }
+
+    pub fn from_record(record: &ResourceRecord) -> Option<Self> {
+        let ResourceRecordData::Opt(options) = &record.data else {
+            return None;
+        };
+        Some(Edns {
+            udp_payload_size: record.class.into(),
+            extended_response_code: (record.time_to_live >> 24) as u8,
+            version: (record.time_to_live >> 16) as u8,
+            dnssec_ok: (record.time_to_live >> 15) & 0x01 != 0,
+            options: options.clone(),
+        })
+    }
+
+    pub fn to_record(&self) -> ResourceRecord {
+        let time_to_live = (self.extended_response_code as u32) << 24
+            | (self.version as u32) << 16
+            | (self.dnssec_ok as u32) << 15;
+        ResourceRecord::new(
+            DomainName::new("").expect("the root should be a valid name"),
+            RecordType::Opt,
+            Class::from(self.udp_payload_size),
+            time_to_live,
+            ResourceRecordData::Opt(self.options.clone()),
+        )
+    }
+
+    pub fn client_subnet(&self) -> Option<&ClientSubnet> {
+        self.options.iter().find_map(|option| match option {
+            EdnsOption::ClientSubnet(client_subnet) => Some(client_subnet),
+            _ => None,
+        })
+    }
+
+    pub fn cookie(&self) -> Option<&Cookie> {
+        self.options.iter().find_map(|option| match option {
+            EdnsOption::Cookie(cookie) => Some(cookie),
+            _ => None,
+        })
+    }
+
+    /// Whether there's a cookie option of the wrong length, which a server answers with FORMERR
+    /// (RFC 7873 section 5.2.2).
+    pub fn has_malformed_cookie(&self) -> bool {
+        self.options
+            .iter()
+            .any(|option| matches!(option, EdnsOption::Unknown { code, .. } if *code == COOKIE))
+    }
+
+    /// Whether the sender padded its message, and so wants a padded reply (RFC 7830 section 4).
+    pub fn has_padding(&self) -> bool {
+        self.options
+            .iter()
+            .any(|option| matches!(option, EdnsOption::Padding(_)))
+    }
+}
+
+impl EdnsOption {
+    pub fn parse(input: &[u8]) -> IResult<&[u8], Self> {
+        let (rest, code) = be_u16(input)?;
+        let (rest, length) = be_u16(rest)?;
+        let (rest, data) = take(length)(rest)?;
+        let option = match code {
+            8 => match ClientSubnet::parse(data) {
+                Some(client_subnet) => EdnsOption::ClientSubnet(client_subnet),
+                None => EdnsOption::Unknown {
+                    code,
+                    data: data.to_vec(),
+                },
+            },
+            PADDING => EdnsOption::Padding(length),
+            EXTENDED_ERROR if data.len() >= 2 => EdnsOption::ExtendedError(ExtendedError {
+                info_code: u16::from_be_bytes([data[0], data[1]]).into(),
+                extra_text: String::from_utf8_lossy(&data[2..]).into_owned(),
+            }),
+            COOKIE => match Cookie::parse(data) {
+                Some(cookie) => EdnsOption::Cookie(cookie),
+                None => EdnsOption::Unknown {
+                    code,
+                    data: data.to_vec(),
+                },
+            },
+            _ => EdnsOption::Unknown {
+                code,
+                data: data.to_vec(),
+            },
+        };
+        Ok((rest, option))
+    }
+
+    pub fn length(&self) -> u16 {
+        4 + match self {
+            EdnsOption::ClientSubnet(client_subnet) => client_subnet.length(),
+            EdnsOption::Cookie(cookie) => cookie.length(),
+            EdnsOption::Padding(length) => *length,
+            EdnsOption::ExtendedError(extended_error) => 2 + extended_error.extra_text.len() as u16,
+            EdnsOption::Unknown { data, .. } => data.len() as u16,
+        }
+    }
+
+    pub fn write<B>(&self, buf: &mut B)
+    where
+        B: BufMut,
+    {
+        match self {
+            EdnsOption::ClientSubnet(client_subnet) => {
+                buf.put_u16(8);
+                buf.put_u16(client_subnet.length());
+                client_subnet.write(buf);
+            }
+            EdnsOption::Cookie(cookie) => {
+                buf.put_u16(COOKIE);
+                buf.put_u16(cookie.length());
+                buf.put_slice(&cookie.client);
+                buf.put_slice(&cookie.server);
+            }
+            EdnsOption::Padding(length) => {
+                buf.put_u16(PADDING);
+                buf.put_u16(*length);
+                buf.put_bytes(0, *length as usize);
+            }
+            EdnsOption::ExtendedError(extended_error) => {
+                buf.put_u16(EXTENDED_ERROR);
+                buf.put_u16(2 + extended_error.extra_text.len() as u16);
+                buf.put_u16(extended_error.info_code.into());
+                buf.put_slice(extended_error.extra_text.as_bytes());
+            }
+            EdnsOption::Unknown { code, data } => {
+                buf.put_u16(*code);
+                buf.put_u16(data.len() as u16);
+                buf.put_slice(data);
+            }
+        }
+    }
+}
+
+impl ClientSubnet {
+    fn parse(input: &[u8]) -> Option<Self> {
+        let (rest, family) = be_u16::<_, nom::error::Error<_>>(input).ok()?;
+        let (rest, source_prefix_len) = u8::<_, nom::error::Error<_>>(rest).ok()?;
+        let (address, scope_prefix_len) = u8::<_, nom::error::Error<_>>(rest).ok()?;
+        // Only as many bytes as the prefix needs are sent
+        let addr = match family {
+            1 if address.len() <= 4 => {
+                let mut octets = [0; 4];
+                octets[..address.len()].copy_from_slice(address);
+                IpAddr::V4(Ipv4Addr::from(octets))
+            }
+            2 if address.len() <= 16 => {
+                let mut octets = [0; 16];
+                octets[..address.len()].copy_from_slice(address);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => return None,
+        };
+        Some(ClientSubnet {
+            source_prefix_len,
+            scope_prefix_len,
+            addr,
+        })
+    }
+
+    fn address_length(&self) -> usize {
+        let max_length = if self.addr.is_ipv4() { 4 } else { 16 };
+        // Rounded up to whole bytes
+        ((self.source_prefix_len as usize + 7) >> 3).min(max_length)
+    }
+
+    fn length(&self) -> u16 {
+        4 + self.address_length() as u16
+    }
+
+    fn write<B>(&self, buf: &mut B)
+    where
+        B: BufMut,
+    {
+        let octets = match self.addr {
+            IpAddr::V4(addr) => {
+                buf.put_u16(1);
+                addr.octets().to_vec()
+            }
+            IpAddr::V6(addr) => {
+                buf.put_u16(2);
+                addr.octets().to_vec()
+            }
+        };
+        buf.put_u8(self.source_prefix_len);
+        buf.put_u8(self.scope_prefix_len);
+        buf.put_slice(&octets[..self.address_length()]);
+    }
+}
+
+impl Cookie {
+    /// A client cookie alone, or with a server cookie of a valid length.
+    fn parse(input: &[u8]) -> Option<Self> {
+        if input.len() != 8 && !(16..=40).contains(&input.len()) {
+            return None;
+        }
+        Some(Cookie {
+            client: input[..8].try_into().unwrap(),
+            server: input[8..].to_vec(),
+        })
+    }
+
+    fn length(&self) -> u16 {
+        8 + self.server.len() as u16
+    }
+}
+
+impl From<u16> for ExtendedErrorCode {
+    fn from(value: u16) -> Self {
+        match value {
+            3 => ExtendedErrorCode::StaleAnswer,
+            6 => ExtendedErrorCode::DnssecBogus,
+            14 => ExtendedErrorCode::NotReady,
+            15 => ExtendedErrorCode::Blocked,
+            18 => ExtendedErrorCode::Prohibited,
+            23 => ExtendedErrorCode::NetworkError,
+            _ => ExtendedErrorCode::Unknown(value),
+        }
+    }
+}
+
+impl From<ExtendedErrorCode> for u16 {
+    fn from(info_code: ExtendedErrorCode) -> Self {
+        match info_code {
+            ExtendedErrorCode::StaleAnswer => 3,
+            ExtendedErrorCode::DnssecBogus => 6,
+            ExtendedErrorCode::NotReady => 14,
+            ExtendedErrorCode::Blocked => 15,
+            ExtendedErrorCode::Prohibited => 18,
+            ExtendedErrorCode::NetworkError => 23,
+            ExtendedErrorCode::Unknown(value) => value,
+        }
+    }
+}
@@ -5,7 +5,7 @@ use nom::{
 };
 use rand::Rng;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Header {
     /// ID: A random ID assigned to query packets. Response packets must reply with the same ID.
     pub packet_id: u16,
@@ -15,14 +15,20 @@ pub struct Header {
     pub op_code: OpCode,
     /// AA: 1 if the responding server "owns" the domain queried, i.e., it's authoritative.
     pub authoritative_answer: bool,
-    /// TC: 1 if the message is larger than 512 bytes. Always 0 in UDP responses.
+    /// TC: 1 if the message was cut short to fit the transport, usually a UDP payload size, so the
+    /// client should ask again over TCP.
     pub truncation: bool,
     /// RD: Sender sets this to 1 if the server should recursively resolve this query, 0 otherwise.
     pub recursion_desired: bool,
     /// RA: Server sets this to 1 to indicate that recursion is available.
     pub recursion_available: bool,
-    /// Z: Used by DNSSEC queries. At inception, it was reserved for future use. 3 bits.
-    pub reserved: u8,
+    /// Z: Reserved for future use. Must be 0.
+    pub z: bool,
+    /// AD: In a reply, 1 if every record in the answer has been validated with DNSSEC. In a
+    /// query, 1 if the client understands the AD bit in replies.
+    pub authentic_data: bool,
+    /// CD: 1 if the client doesn't want DNSSEC validation done for it.
+    pub checking_disabled: bool,
     /// RCODE: Response code indicating the status of the response. 4 bits.
     pub response_code: ResponseCode,
     /// QDCOUNT: Number of questions in the Question section.
@@ -35,39 +41,65 @@ pub struct Header {
     pub additional_record_count: u16,
 }
 
-#[derive(Debug, Clone, Copy)]
-#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum OpCode {
     /// QUERY: A standard query.
-    Query = 0,
+    Query,
     /// IQUERY: An inverse query.
-    IQuery = 1,
+    IQuery,
     /// STATUS: A server status request.
-    Status = 2,
-    Invalid,
+    Status,
+    /// NOTIFY: A primary telling a secondary that a zone has changed (RFC 1996).
+    Notify,
+    /// UPDATE: A request to add or remove records in a zone (RFC 2136).
+    Update,
+    /// An opcode we don't know about, kept so it round-trips.
+    Unknown(u8),
 }
 
-#[derive(Debug, Clone, Copy)]
-#[repr(u8)]
+/// The 12-bit response code. The lower 4 bits are in the header, and the upper 8 in the OPT
+/// record if there is one (RFC 6891 section 6.1.3).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ResponseCode {
     /// No error condition.
-    Ok = 0,
+    Ok,
     /// Format error - The name server was unable to interpret the query.
-    FormatError = 1,
+    FormatError,
     /// Server failure - The name server was unable to process this query due to a
     ///                  problem with the name server.
-    ServerFailure = 2,
+    ServerFailure,
     /// Name Error - Meaningful only for responses from an authoritative name server,
     /// this code signifies that the domain name referenced in the query does not exist.
-    NameError = 3,
+    NameError,
     /// Not Implemented - The name server does not support the requested kind of query.
-    NotImplemented = 4,
+    NotImplemented,
     /// Refused - The name server refuses to perform the specified operation for policy
     ///           reasons. For example, a name server may not wish to provide the
     ///           information to the particular requester, or a name server may not wish
     ///           to perform a particular operation (e.g., zone transfer) for particular data.
-    Refused = 5,
-    Invalid,
+    Refused,
+    /// YXDOMAIN: A name exists when it should not (RFC 2136).
+    NameExists,
+    /// YXRRSET: An RRset exists when it should not (RFC 2136).
+    RrSetExists,
+    /// NXRRSET: An RRset that should exist does not (RFC 2136).
+    RrSetMissing,
+    /// NOTAUTH: The server isn't authoritative for the zone, or the request isn't authorized
+    /// (RFC 2136, RFC 8945).
+    NotAuthoritative,
+    /// NOTZONE: A name used in an update isn't in the zone (RFC 2136).
+    NotZone,
+    /// BADVERS: The server doesn't implement the EDNS version asked for (RFC 6891). BADSIG has
+    /// the same value, but only in a TSIG record's error field, which has its own type.
+    BadVersion,
+    /// BADKEY: A TSIG key isn't recognized (RFC 8945).
+    BadKey,
+    /// BADTIME: A TSIG signature is outside the time window (RFC 8945).
+    BadTime,
+    /// BADCOOKIE: A bad or missing server cookie (RFC 7873).
+    BadCookie,
+    /// A response code we don't know about, kept so it round-trips.
+    Unknown(u16),
 }
 
 impl Header {
@@ -81,7 +113,9 @@ impl Header {
             truncation: false,
             recursion_desired: false,
             recursion_available: false,
-            reserved: 0,
+            z: false,
+            authentic_data: false,
+            checking_disabled: false,
             response_code: ResponseCode::Ok,
             question_count,
             answer_record_count: 0,
@@ -95,15 +129,18 @@ impl Header {
 
         let (rest, byte2) = u8(rest)?;
         let is_response = (byte2 >> 7) & 0x01 != 0;
-        let op_code = OpCode::parse((byte2 >> 3) & 0x0F);
+        let op_code = OpCode::from((byte2 >> 3) & 0x0F);
         let authoritative_answer = (byte2 >> 2) & 0x01 != 0;
         let truncation = (byte2 >> 1) & 0x01 != 0;
         let recursion_desired = byte2 & 0x01 != 0;
 
         let (rest, byte3) = u8(rest)?;
         let recursion_available = (byte3 >> 7) & 0x01 != 0;
-        let reserved = (byte3 >> 4) & 0x07;
-        let response_code = ResponseCode::parse(byte3 & 0x0F);
+        let z = (byte3 >> 6) & 0x01 != 0;
+        let authentic_data = (byte3 >> 5) & 0x01 != 0;
+        let checking_disabled = (byte3 >> 4) & 0x01 != 0;
+        // Only the lower 4 bits; `Message::parse` adds the rest from the OPT record
+        let response_code = ResponseCode::from((byte3 & 0x0F) as u16);
 
         let (rest, question_count) = be_u16(rest)?;
         let (rest, answer_record_count) = be_u16(rest)?;
@@ -120,7 +157,9 @@ impl Header {
                 truncation,
                 recursion_desired,
                 recursion_available,
-                reserved,
+                z,
+                authentic_data,
+                checking_disabled,
                 response_code,
                 question_count,
                 answer_record_count,
@@ -138,7 +177,8 @@ impl Header {
 
         let mut byte2 = 0;
         byte2 |= (self.is_response as u8) << 7;
-        byte2 |= (self.op_code as u8) << 3;
+        // Only 4 bits wide, so an out of range `OpCode::Unknown` can't spill into the other flags
+        byte2 |= (u8::from(self.op_code) & 0x0F) << 3;
         byte2 |= (self.authoritative_answer as u8) << 2;
         byte2 |= (self.truncation as u8) << 1;
         byte2 |= self.recursion_desired as u8;
@@ -146,8 +186,10 @@ impl Header {
 
         let mut byte3 = 0;
         byte3 |= (self.recursion_available as u8) << 7;
-        byte3 |= self.reserved << 4;
-        byte3 |= self.response_code as u8;
+        byte3 |= (self.z as u8) << 6;
+        byte3 |= (self.authentic_data as u8) << 5;
+        byte3 |= (self.checking_disabled as u8) << 4;
+        byte3 |= (u16::from(self.response_code) & 0x0F) as u8;
         buf.put_u8(byte3);
 
         buf.put_u16(self.question_count);
@@ -157,27 +199,143 @@ impl Header {
     }
 }
 
-impl OpCode {
-    fn parse(input: u8) -> Self {
-        match input {
+impl From<u8> for OpCode {
+    fn from(value: u8) -> Self {
+        match value {
             0 => OpCode::Query,
             1 => OpCode::IQuery,
             2 => OpCode::Status,
-            _ => OpCode::Invalid,
+            4 => OpCode::Notify,
+            5 => OpCode::Update,
+            _ => OpCode::Unknown(value),
+        }
+    }
+}
+
+impl From<OpCode> for u8 {
+    fn from(op_code: OpCode) -> Self {
+        match op_code {
+            OpCode::Query => 0,
+            OpCode::IQuery => 1,
+            OpCode::Status => 2,
+            OpCode::Notify => 4,
+            OpCode::Update => 5,
+            OpCode::Unknown(value) => value,
         }
     }
 }
 
 impl ResponseCode {
-    fn parse(input: u8) -> Self {
-        match input {
+    /// The upper 8 bits, which go in the OPT record.
+    pub fn extended_bits(self) -> u8 {
+        (u16::from(self) >> 4) as u8
+    }
+
+    /// Whether the code needs an OPT record to carry it.
+    pub fn is_extended(self) -> bool {
+        self.extended_bits() != 0
+    }
+}
+
+impl From<u16> for ResponseCode {
+    fn from(value: u16) -> Self {
+        match value {
             0 => ResponseCode::Ok,
             1 => ResponseCode::FormatError,
             2 => ResponseCode::ServerFailure,
             3 => ResponseCode::NameError,
             4 => ResponseCode::NotImplemented,
             5 => ResponseCode::Refused,
-            _ => ResponseCode::Invalid,
+            6 => ResponseCode::NameExists,
+            7 => ResponseCode::RrSetExists,
+            8 => ResponseCode::RrSetMissing,
+            9 => ResponseCode::NotAuthoritative,
+            10 => ResponseCode::NotZone,
+            16 => ResponseCode::BadVersion,
+            17 => ResponseCode::BadKey,
+            18 => ResponseCode::BadTime,
+            23 => ResponseCode::BadCookie,
+            _ => ResponseCode::Unknown(value),
+        }
+    }
+}
+
+impl From<ResponseCode> for u16 {
+    fn from(response_code: ResponseCode) -> Self {
+        match response_code {
+            ResponseCode::Ok => 0,
+            ResponseCode::FormatError => 1,
+            ResponseCode::ServerFailure => 2,
+            ResponseCode::NameError => 3,
+            ResponseCode::NotImplemented => 4,
+            ResponseCode::Refused => 5,
+            ResponseCode::NameExists => 6,
+            ResponseCode::RrSetExists => 7,
+            ResponseCode::RrSetMissing => 8,
+            ResponseCode::NotAuthoritative => 9,
+            ResponseCode::NotZone => 10,
+            ResponseCode::BadVersion => 16,
+            ResponseCode::BadKey => 17,
+            ResponseCode::BadTime => 18,
+            ResponseCode::BadCookie => 23,
+            ResponseCode::Unknown(value) => value,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_round_trip() {
+        let mut header = Header::new_query(1);
+        header.is_response = true;
+        header.op_code = OpCode::Update;
+        header.truncation = true;
+        header.authentic_data = true;
+        header.checking_disabled = true;
+        header.response_code = ResponseCode::NameError;
+        let mut buf = Vec::new();
+        header.write(&mut buf);
+        assert_eq!(buf[2..4], [0xaa, 0x33]);
+
+        let (rest, parsed) = Header::parse(&buf).unwrap();
+        assert!(rest.is_empty());
+        assert!(parsed.is_response && parsed.truncation);
+        assert!(!parsed.z && parsed.authentic_data && parsed.checking_disabled);
+        assert_eq!(parsed.op_code, OpCode::Update);
+        assert_eq!(parsed.response_code, ResponseCode::NameError);
+        assert_eq!(parsed.packet_id, header.packet_id);
+    }
+
+    #[test]
+    fn response_codes_round_trip() {
+        for value in 0..4096 {
+            assert_eq!(u16::from(ResponseCode::from(value)), value);
+        }
+        assert_eq!(ResponseCode::from(16), ResponseCode::BadVersion);
+        assert_eq!(ResponseCode::BadCookie.extended_bits(), 1);
+        assert!(!ResponseCode::Refused.is_extended());
+    }
+
+    #[test]
+    fn unknown_op_codes_round_trip() {
+        for value in 0..16 {
+            assert_eq!(u8::from(OpCode::from(value)), value);
+        }
+    }
+
+    #[test]
+    fn op_codes_stay_in_their_bits() {
+        let mut header = Header::new_query(1);
+        header.op_code = OpCode::Unknown(0xFF);
+        let mut buf = Vec::new();
+        header.write(&mut buf);
+        // QR and the flags after OPCODE are untouched
+        assert_eq!(buf[2], 0x78);
+        let (_, parsed) = Header::parse(&buf).unwrap();
+        assert!(!parsed.is_response && !parsed.authoritative_answer && !parsed.truncation);
+        assert_eq!(parsed.op_code, OpCode::Unknown(15));
+    }
+}
@@ -1,21 +1,31 @@
 use bytes::BufMut;
 use nom::multi::count;
 
-pub use header::Header;
+pub use edns::{ClientSubnet, Cookie, Edns, EdnsOption, ExtendedError, ExtendedErrorCode};
+pub use header::{Header, OpCode, ResponseCode};
 pub use question_answer::{
     Class, DomainName, Question, RecordType, ResourceRecord, ResourceRecordData,
 };
 
+mod edns;
 mod header;
 mod question_answer;
 
-#[derive(Debug)]
+/// The UDP payload size in OPT records the server adds to replies. It's the most a plain DNS
+/// message can be, and all the server reads over UDP.
+pub const MINIMUM_UDP_PAYLOAD_SIZE: u16 = 512;
+
+#[derive(Debug, Clone)]
 pub struct Message {
     pub header: Header,
     pub questions: Vec<Question>,
     pub answers: Vec<ResourceRecord>,
     pub authorities: Vec<ResourceRecord>,
     pub additionals: Vec<ResourceRecord>,
+    /// For a parsed message ending in a TSIG record, the message as it arrived up to that record,
+    /// with the record left out of the count. This is what the TSIG MAC covers (RFC 8945 section
+    /// 4.3.3), and it can't be rebuilt from the parsed message if names were compressed.
+    pub tsig_data: Option<Vec<u8>>,
 }
 
 impl Message {
@@ -26,6 +36,7 @@ impl Message {
             answers: Vec::new(),
             authorities: Vec::new(),
             additionals: Vec::new(),
+            tsig_data: None,
         }
     }
 
@@ -43,7 +54,11 @@ impl Message {
                 truncation: false,
                 recursion_desired: query_message.header.recursion_desired,
                 recursion_available: false,
-                reserved: 0,
+                z: false,
+                // Set by whoever checks the answer
+                authentic_data: false,
+                // Copied so the client can tell whether checking was done (RFC 4035 section 3.1.6)
+                checking_disabled: query_message.header.checking_disabled,
                 response_code: match query_message.header.op_code {
                     header::OpCode::Query => header::ResponseCode::Ok,
                     _ => header::ResponseCode::NotImplemented,
@@ -57,9 +72,26 @@ impl Message {
             answers,
             authorities: Vec::new(),
             additionals: Vec::new(),
+            tsig_data: None,
         }
     }
 
+    /// A reply to `query_message` with no answers and the given response code.
+    pub fn new_error_reply(query_message: &Message, response_code: ResponseCode) -> Self {
+        let mut message =
+            Message::new_reply(query_message, query_message.questions.clone(), Vec::new());
+        message.header.response_code = response_code;
+        message
+    }
+
+    /// An empty reply with the truncation flag set, telling the client to retry over TCP.
+    pub fn new_truncated_reply(query_message: &Message) -> Self {
+        let mut message =
+            Message::new_reply(query_message, query_message.questions.clone(), Vec::new());
+        message.header.truncation = true;
+        message
+    }
+
     pub fn parse(input: &[u8]) -> anyhow::Result<Self> {
         let (rest, header) = Header::parse(input).map_err(|e| e.map_input(|s| s.to_owned()))?;
         let (rest, questions) = count(Question::parse, header.question_count as usize)(rest)
@@ -72,25 +104,206 @@ impl Message {
             header.authority_record_count as usize,
         )(rest)
         .map_err(|e| e.map_input(|s| s.to_owned()))?;
-        let (_rest, additionals) = count(
-            ResourceRecord::parse,
-            header.additional_record_count as usize,
-        )(rest)
-        .map_err(|e| e.map_input(|s| s.to_owned()))?;
-        Ok(Message {
+        let mut additionals = Vec::new();
+        let mut rest = rest;
+        let mut last_offset = 0;
+        for _ in 0..header.additional_record_count {
+            last_offset = input.len() - rest.len();
+            let (remainder, additional) =
+                ResourceRecord::parse(rest).map_err(|e| e.map_input(|s| s.to_owned()))?;
+            additionals.push(additional);
+            rest = remainder;
+        }
+        let tsig_data = additionals
+            .last()
+            .filter(|record| record.ty == RecordType::TransactionSignature)
+            .map(|_| {
+                let mut data = input[..last_offset].to_vec();
+                let additional_record_count = header.additional_record_count - 1;
+                data[10..12].copy_from_slice(&additional_record_count.to_be_bytes());
+                data
+            });
+        let mut message = Message {
             header,
             questions,
             answers,
             authorities,
             additionals,
-        })
+            tsig_data,
+        };
+
+        // Resolve compression pointers up front, so the rest of the server never sees them
+        for question in message.questions.iter_mut() {
+            question.decompress(input)?;
+        }
+        for record in message
+            .answers
+            .iter_mut()
+            .chain(message.authorities.iter_mut())
+            .chain(message.additionals.iter_mut())
+        {
+            record.decompress(input)?;
+        }
+
+        if let Some(edns) = message.edns() {
+            message.header.response_code = ResponseCode::from(
+                u16::from(edns.extended_response_code) << 4
+                    | u16::from(message.header.response_code),
+            );
+        }
+        Ok(message)
+    }
+
+    /// The EDNS parameters from the OPT record, if there is one.
+    pub fn edns(&self) -> Option<Edns> {
+        self.additionals.iter().find_map(Edns::from_record)
+    }
+
+    /// Add an option to the OPT record, adding the record if there isn't one.
+    pub fn add_edns_option(&mut self, option: EdnsOption) {
+        let position = self
+            .additionals
+            .iter()
+            .position(|record| record.ty == RecordType::Opt);
+        let mut edns = position
+            .and_then(|position| Edns::from_record(&self.additionals[position]))
+            .unwrap_or_else(|| Edns::new(MINIMUM_UDP_PAYLOAD_SIZE));
+        edns.options.push(option);
+        match position {
+            Some(position) => self.additionals[position] = edns.to_record(),
+            None => self.additionals.push(edns.to_record()),
+        }
+    }
+
+    pub fn add_extended_error(&mut self, info_code: ExtendedErrorCode, extra_text: &str) {
+        self.add_edns_option(EdnsOption::ExtendedError(ExtendedError {
+            info_code,
+            extra_text: extra_text.to_string(),
+        }));
+    }
+
+    /// Pad the message to a multiple of `block_size` bytes, which hides its exact length on an
+    /// encrypted transport (RFC 8467 section 4.1). `reserved` is the length of anything still to
+    /// be added, such as a TSIG record, which has to be counted but can only go on afterwards.
+    /// Any padding the message already has is replaced.
+    pub fn pad(&mut self, block_size: usize, reserved: usize) -> anyhow::Result<()> {
+        let position = match self
+            .additionals
+            .iter()
+            .position(|record| record.ty == RecordType::Opt)
+        {
+            Some(position) => position,
+            None => {
+                // A TSIG record has to stay last
+                let position = self
+                    .additionals
+                    .iter()
+                    .position(|record| record.ty == RecordType::TransactionSignature)
+                    .unwrap_or(self.additionals.len());
+                self.additionals
+                    .insert(position, Edns::new(MINIMUM_UDP_PAYLOAD_SIZE).to_record());
+                position
+            }
+        };
+        let mut edns = Edns::from_record(&self.additionals[position])
+            .expect("the OPT record should have EDNS parameters");
+        edns.options
+            .retain(|option| !matches!(option, EdnsOption::Padding(_)));
+        self.additionals[position] = edns.to_record();
+        // The option's own code and length count too
+        let length = self.size()? + reserved + 4;
+        edns.options.push(EdnsOption::Padding(
+            ((block_size - length % block_size) % block_size) as u16,
+        ));
+        self.additionals[position] = edns.to_record();
+        Ok(())
+    }
+
+    /// Make the message fit in `limit` bytes, as a UDP response must. The additional records go
+    /// first, since a client can do without them, then the answers and authority records, with
+    /// the truncation flag telling the client to retry over TCP (RFC 2181 section 9). The OPT
+    /// record stays, so the client still gets the server's EDNS settings, but if even that's too
+    /// big, the text of any extended errors goes, then its options, then the record itself.
+    pub fn truncate(&mut self, limit: usize) -> anyhow::Result<()> {
+        if self.size()? <= limit {
+            return Ok(());
+        }
+        self.additionals
+            .retain(|record| record.ty == RecordType::Opt);
+        if self.size()? <= limit {
+            return Ok(());
+        }
+        self.answers.clear();
+        self.authorities.clear();
+        self.header.truncation = true;
+        let shrink_options: [fn(&mut Vec<EdnsOption>); 2] = [
+            |options| {
+                for option in options.iter_mut() {
+                    if let EdnsOption::ExtendedError(extended_error) = option {
+                        extended_error.extra_text.clear();
+                    }
+                }
+            },
+            |options| options.clear(),
+        ];
+        for shrink in shrink_options {
+            if self.size()? <= limit {
+                return Ok(());
+            }
+            for record in self.additionals.iter_mut() {
+                if let ResourceRecordData::Opt(options) = &mut record.data {
+                    shrink(options);
+                }
+            }
+        }
+        if self.size()? > limit {
+            self.additionals.clear();
+        }
+        Ok(())
+    }
+
+    /// The length of the message once written.
+    pub fn size(&self) -> anyhow::Result<usize> {
+        let mut buf = Vec::new();
+        self.write(&mut buf)?;
+        Ok(buf.len())
     }
 
     pub fn write<B>(&self, buf: &mut B) -> anyhow::Result<()>
     where
         B: BufMut,
     {
-        self.header.write(buf);
+        // Keep the counts in sync with the sections, however they've been modified
+        let mut header = self.header.clone();
+        header.question_count = self.questions.len() as u16;
+        header.answer_record_count = self.answers.len() as u16;
+        header.authority_record_count = self.authorities.len() as u16;
+        header.additional_record_count = self.additionals.len() as u16;
+
+        // The upper bits of the response code go in the OPT record, which must be added for them
+        // if there isn't one. A TSIG record has to stay last.
+        let extended_bits = self.header.response_code.extended_bits();
+        let mut additionals = self.additionals.clone();
+        if self.header.response_code.is_extended()
+            && !additionals
+                .iter()
+                .any(|record| record.ty == RecordType::Opt)
+        {
+            let position = additionals
+                .iter()
+                .position(|record| record.ty == RecordType::TransactionSignature)
+                .unwrap_or(additionals.len());
+            additionals.insert(position, Edns::new(MINIMUM_UDP_PAYLOAD_SIZE).to_record());
+            header.additional_record_count += 1;
+        }
+        for record in additionals.iter_mut() {
+            if record.ty == RecordType::Opt {
+                record.time_to_live =
+                    record.time_to_live & 0x00FF_FFFF | (extended_bits as u32) << 24;
+            }
+        }
+        header.write(buf);
+
         for question in self.questions.iter() {
             question.write(buf)?;
         }
@@ -100,25 +313,226 @@ impl Message {
         for authority in self.authorities.iter() {
             authority.write(buf)?;
         }
-        for additional in self.additionals.iter() {
+        for additional in additionals.iter() {
             additional.write(buf)?;
         }
         Ok(())
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+
+    fn name(name: &str) -> DomainName {
+        DomainName::new(name).unwrap()
+    }
+
+    fn sample_message() -> Message {
+        let question = Question {
+            name: name("example.com"),
+            ty: RecordType::Address,
+            class: Class::Internet,
+        };
+        let mut message = Message::new_query(vec![question.clone()]);
+        let mut reply = Message::new_reply(
+            &message,
+            vec![question],
+            vec![
+                ResourceRecord::new(
+                    name("example.com"),
+                    RecordType::CName,
+                    Class::Internet,
+                    300,
+                    ResourceRecordData::CName(name("www.example.com")),
+                ),
+                ResourceRecord::new(
+                    name("www.example.com"),
+                    RecordType::Address,
+                    Class::Internet,
+                    300,
+                    ResourceRecordData::IPv4([192, 0, 2, 1]),
+                ),
+            ],
+        );
+        reply.authorities.push(ResourceRecord::new(
+            name("example.com"),
+            RecordType::StartOfAuthority,
+            Class::Internet,
+            300,
+            ResourceRecordData::StartOfAuthority {
+                primary_name_server: name("ns.example.com"),
+                responsible_mailbox: name("admin.example.com"),
+                serial: 1,
+                refresh: 2,
+                retry: 3,
+                expire: 4,
+                minimum: 5,
+            },
+        ));
+        reply.add_extended_error(ExtendedErrorCode::Blocked, "test");
+        message.header.packet_id = reply.header.packet_id;
+        reply
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let message = sample_message();
+        let mut buf = Vec::new();
+        message.write(&mut buf).unwrap();
+        let parsed = Message::parse(&buf).unwrap();
+        assert_eq!(parsed.answers.len(), 2);
+        assert_eq!(parsed.authorities.len(), 1);
+        let extended_error = parsed.edns().unwrap().options.pop().unwrap();
+        assert!(matches!(
+            extended_error,
+            EdnsOption::ExtendedError(ExtendedError { info_code: ExtendedErrorCode::Blocked, ref extra_text })
+                if extra_text == "test"
+        ));
+        let mut rewritten = Vec::new();
+        parsed.write(&mut rewritten).unwrap();
+        assert_eq!(rewritten, buf);
+    }
+
+    #[test]
+    fn extended_response_codes_round_trip() {
+        let mut message = sample_message();
+        message.additionals.clear();
+        message.header.response_code = ResponseCode::BadCookie;
+        let mut buf = Vec::new();
+        message.write(&mut buf).unwrap();
+        let parsed = Message::parse(&buf).unwrap();
+        assert_eq!(parsed.header.response_code, ResponseCode::BadCookie);
+        assert!(parsed.edns().is_some());
+    }
 
-    pub fn get_labels(&self, offset: u16) -> anyhow::Result<Vec<String>> {
-        if offset < 12 {
-            anyhow::bail!("invalid label offset (in header)");
+    #[test]
+    fn malformed_messages_are_errors() {
+        let mut buf = Vec::new();
+        sample_message().write(&mut buf).unwrap();
+        for len in 0..buf.len() {
+            assert!(Message::parse(&buf[..len]).is_err(), "{len} bytes");
         }
-        let mut msg_offset = 12;
-        for question in self.questions.iter() {
-            if offset < msg_offset + question.length() {
-                return question.get_labels(offset - msg_offset);
+        // Anything at all can arrive, and none of it should panic
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..10_000 {
+            let mut mutated = buf.clone();
+            for _ in 0..rng.gen_range(1..4) {
+                let i = rng.gen_range(0..mutated.len());
+                mutated[i] = rng.gen();
             }
-            msg_offset += question.length();
+            let _ = Message::parse(&mutated);
         }
-        Err(anyhow::format_err!(
-            "invalid label offset (after questions)"
-        ))
+    }
+
+    #[test]
+    fn padding_rounds_up_to_the_block_size() {
+        let mut message = sample_message();
+        message.pad(128, 0).unwrap();
+        let mut buf = Vec::new();
+        message.write(&mut buf).unwrap();
+        assert_eq!(buf.len() % 128, 0);
+        assert!(message.edns().unwrap().has_padding());
+
+        // Padding again replaces the padding there was
+        message.pad(128, 0).unwrap();
+        assert_eq!(message.size().unwrap(), buf.len());
+        let edns = message.edns().unwrap();
+        let paddings = edns
+            .options
+            .iter()
+            .filter(|option| matches!(option, EdnsOption::Padding(_)))
+            .count();
+        assert_eq!(paddings, 1);
+
+        // Room is left for a signature to come
+        message.pad(128, 61).unwrap();
+        assert_eq!((message.size().unwrap() + 61) % 128, 0);
+    }
+
+    #[test]
+    fn padding_goes_before_a_tsig_record() {
+        let mut message = Message::new_query(vec![Question {
+            name: name("example.com"),
+            ty: RecordType::Address,
+            class: Class::Internet,
+        }]);
+        message.additionals.push(ResourceRecord::new(
+            name("key.example"),
+            RecordType::TransactionSignature,
+            Class::from(255),
+            0,
+            ResourceRecordData::TransactionSignature {
+                algorithm: name("hmac-sha256"),
+                time_signed: 0,
+                fudge: 300,
+                mac: vec![0; 32],
+                original_id: 0,
+                error: 0,
+                other_data: Vec::new(),
+            },
+        ));
+        message.pad(128, 0).unwrap();
+        assert_eq!(message.size().unwrap() % 128, 0);
+        assert_eq!(message.additionals[0].ty, RecordType::Opt);
+        assert_eq!(message.additionals[1].ty, RecordType::TransactionSignature);
+    }
+
+    #[test]
+    fn truncation_drops_additionals_first() {
+        let mut message = sample_message();
+        let size = message.size().unwrap();
+        let additionals = message.additionals.len();
+        message.truncate(size).unwrap();
+        assert!(!message.header.truncation);
+        assert_eq!(message.additionals.len(), additionals);
+
+        message.additionals.push(ResourceRecord::new(
+            name("ns.example.com"),
+            RecordType::Address,
+            Class::Internet,
+            300,
+            ResourceRecordData::IPv4([192, 0, 2, 53]),
+        ));
+        message.truncate(size).unwrap();
+        assert!(!message.header.truncation);
+        assert_eq!(message.additionals.len(), additionals);
+        assert!(message.size().unwrap() <= size);
+
+        message.truncate(size - 1).unwrap();
+        assert!(message.header.truncation);
+        assert!(message.answers.is_empty() && message.authorities.is_empty());
+        assert_eq!(message.questions.len(), 1);
+        // The OPT record stays
+        assert!(message.edns().is_some());
+    }
+
+    #[test]
+    fn truncation_sheds_edns_options_if_it_has_to() {
+        let mut message = sample_message();
+        message.add_extended_error(ExtendedErrorCode::Blocked, &"x".repeat(200));
+        let size = message.size().unwrap();
+
+        message.truncate(size - 150).unwrap();
+        assert!(message.header.truncation);
+        assert!(message.size().unwrap() <= size - 150);
+        let edns = message.edns().unwrap();
+        assert!(!edns.options.is_empty());
+        assert!(edns.options.iter().all(|option| matches!(
+            option,
+            EdnsOption::ExtendedError(extended_error) if extended_error.extra_text.is_empty()
+        )));
+
+        let mut bare = message.clone();
+        bare.additionals.clear();
+        let limit = bare.size().unwrap() + 12;
+        message.truncate(limit).unwrap();
+        assert!(message.size().unwrap() <= limit);
+        assert!(message.edns().unwrap().options.is_empty());
+
+        message.truncate(limit - 12).unwrap();
+        assert!(message.size().unwrap() <= limit - 12);
     }
 }
@@ -1,64 +1,94 @@
+use std::{
+    cmp::Ordering,
+    fmt,
+    hash::{Hash, Hasher},
+    str::FromStr,
+};
+
 use bytes::BufMut;
 use nom::{
     bytes::complete::take,
+    error::{Error, ErrorKind},
+    multi::many0,
     number::complete::{be_u16, be_u32, u8},
     IResult,
 };
 
-use super::Message;
+use super::EdnsOption;
 
 const MAX_LABEL_SIZE: usize = 63;
+/// The longest a name can be on the wire, counting length bytes and the final null byte.
+const MAX_NAME_SIZE: usize = 255;
+/// Guards against compression pointer loops.
+const MAX_POINTER_HOPS: usize = 16;
 
-#[derive(Debug, Clone, Copy)]
-#[repr(u16)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum RecordType {
     /// A: A host address.
-    Address = 1,
+    Address,
     /// NS: An authoritative name server.
-    NameServer = 2,
+    NameServer,
     /// MD: A mail destination (Obsolete - use MX).
-    MailDestination = 3,
+    MailDestination,
     /// MF: A mail forwarder (Obsolete - use MX).
-    MailForwarder = 4,
+    MailForwarder,
     /// CNAME: The canonical name for an alias.
-    CName = 5,
+    CName,
     /// SOA: Marks the start of a zone of authority.
-    StartOfAuthority = 6,
+    StartOfAuthority,
     /// MB: A mailbox domain name (EXPERIMENTAL).
-    Mailbox = 7,
+    Mailbox,
     /// MG: A mail group member (EXPERIMENTAL).
-    MailGroup = 8,
+    MailGroup,
     /// MR: A mail rename domain name (EXPERIMENTAL).
-    MailRename = 9,
+    MailRename,
     /// NULL: A null RR (EXPERIMENTAL).
-    Null = 10,
+    Null,
     /// WKS: A well known service description.
-    WellKnownService = 11,
+    WellKnownService,
     /// PTR: A domain name pointer.
-    Pointer = 12,
+    Pointer,
     /// HINFO: Host information.
-    HostInfo = 13,
+    HostInfo,
     /// MINFO: Mailbox or mail list information.
-    MailboxInfo = 14,
+    MailboxInfo,
     /// MX: Mail exchange.
-    MailExchange = 15,
+    MailExchange,
     /// TXT: Text strings.
-    Text = 16,
-    Invalid,
+    Text,
+    /// AAAA: An IPv6 host address.
+    Ipv6Address,
+    /// OPT: An EDNS pseudo-record.
+    Opt,
+    /// DS: A delegation signer, the digest of a key in a child zone.
+    DelegationSigner,
+    /// RRSIG: A signature over an RRset.
+    Signature,
+    /// NSEC: The next owner name in a zone, for authenticated denial of existence.
+    NextSecure,
+    /// DNSKEY: A public key used to sign a zone.
+    DnsKey,
+    /// NSEC3: Like NSEC, but between hashed owner names.
+    NextSecure3,
+    /// TSIG: A transaction signature, authenticating a message with a shared key.
+    TransactionSignature,
+    /// A type we don't know about, kept so it round-trips.
+    Unknown(u16),
 }
 
-#[derive(Debug, Clone, Copy)]
-#[repr(u16)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Class {
     /// IN: The internet.
-    Internet = 1,
+    Internet,
     /// CS: The CSNET class (Obsolete - used only for examples in some obsolete RFCs).
-    CSNet = 2,
+    CSNet,
     /// CH: The CHAOS class.
-    Chaos = 3,
+    Chaos,
     /// HS: Hesiod [Dyer 87].
-    Hesiod = 4,
-    Invalid,
+    Hesiod,
+    /// A class we don't know about, kept so it round-trips. OPT records use this field for the
+    /// UDP payload size.
+    Unknown(u16),
 }
 
 /// A domain name encoded as a sequence of labels.
@@ -75,7 +105,7 @@ pub enum Label {
     Pointer(u16),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Question {
     /// A domain name.
     pub name: DomainName,
@@ -85,7 +115,7 @@ pub struct Question {
     pub class: Class,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct ResourceRecord {
     /// The domain name.
     pub name: DomainName,
@@ -101,16 +131,118 @@ pub struct ResourceRecord {
     pub data: ResourceRecordData,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum ResourceRecordData {
     /// An IPv4 address.
     IPv4([u8; 4]),
+    /// An IPv6 address.
+    IPv6([u8; 16]),
+    /// A host which should be authoritative for the domain.
+    NameServer(DomainName),
+    /// The canonical name for the owner, which is an alias.
+    CName(DomainName),
+    /// A pointer to some other location in the domain name space.
+    Pointer(DomainName),
+    /// A host willing to act as a mail exchange for the owner.
+    MailExchange {
+        /// Lower values are preferred.
+        preference: u16,
+        exchange: DomainName,
+    },
+    /// One or more character strings.
+    Text(Vec<Vec<u8>>),
+    /// The start of a zone of authority.
+    StartOfAuthority {
+        /// MNAME: The primary name server for the zone.
+        primary_name_server: DomainName,
+        /// RNAME: The mailbox of the person responsible for the zone.
+        responsible_mailbox: DomainName,
+        /// The version number of the zone.
+        serial: u32,
+        /// Seconds before the zone should be refreshed.
+        refresh: u32,
+        /// Seconds before a failed refresh should be retried.
+        retry: u32,
+        /// Seconds after which the zone is no longer authoritative if it can't be refreshed.
+        expire: u32,
+        /// The TTL for negative responses.
+        minimum: u32,
+    },
+    /// The options in an OPT pseudo-record.
+    Opt(Vec<EdnsOption>),
+    /// A digest of a DNSKEY in the child zone, linking it into the chain of trust.
+    DelegationSigner {
+        key_tag: u16,
+        algorithm: u8,
+        digest_type: u8,
+        digest: Vec<u8>,
+    },
+    /// A signature over the RRset of the owner and `type_covered`.
+    Signature {
+        type_covered: RecordType,
+        algorithm: u8,
+        /// The number of labels in the signed owner name, fewer for wildcard expansions.
+        labels: u8,
+        original_ttl: u32,
+        /// Seconds since the epoch, in serial number arithmetic.
+        expiration: u32,
+        inception: u32,
+        key_tag: u16,
+        /// The zone which made the signature.
+        signer_name: DomainName,
+        signature: Vec<u8>,
+    },
+    /// The next owner name in canonical order, and the types which exist at this owner.
+    NextSecure {
+        next_domain_name: DomainName,
+        types: Vec<RecordType>,
+    },
+    DnsKey {
+        /// Bit 7 marks a zone key, and bit 15 a secure entry point.
+        flags: u16,
+        /// Always 3.
+        protocol: u8,
+        algorithm: u8,
+        public_key: Vec<u8>,
+    },
+    /// The next hashed owner name, and the types which exist at this hashed owner.
+    NextSecure3 {
+        hash_algorithm: u8,
+        /// Bit 0 is opt-out, meaning unsigned delegations may lie between the two hashes.
+        flags: u8,
+        iterations: u16,
+        salt: Vec<u8>,
+        next_hashed_owner: Vec<u8>,
+        types: Vec<RecordType>,
+    },
+    /// A MAC over the message it ends, made with the key the record is named after.
+    TransactionSignature {
+        algorithm: DomainName,
+        /// Seconds since the epoch, as 48 bits.
+        time_signed: u64,
+        /// How many seconds either side of `time_signed` the signature is good for.
+        fudge: u16,
+        mac: Vec<u8>,
+        /// The message ID when it was signed, which forwarders may have changed since.
+        original_id: u16,
+        /// An extended response code for errors with the signature itself.
+        error: u16,
+        other_data: Vec<u8>,
+    },
+    /// The data of a record type we don't understand, as-is.
+    Unknown(Vec<u8>),
 }
 
 impl RecordType {
     fn parse(input: &[u8]) -> IResult<&[u8], Self> {
-        let (rest, byte) = be_u16(input)?;
-        let ty = match byte {
+        let (rest, value) = be_u16(input)?;
+        Ok((rest, RecordType::from(value)))
+    }
+}
+
+impl From<u16> for RecordType {
+    fn from(value: u16) -> Self {
+        match value {
             1 => RecordType::Address,
             2 => RecordType::NameServer,
             3 => RecordType::MailDestination,
@@ -127,36 +259,274 @@ impl RecordType {
             14 => RecordType::MailboxInfo,
             15 => RecordType::MailExchange,
             16 => RecordType::Text,
-            _ => RecordType::Invalid,
-        };
-        Ok((rest, ty))
+            28 => RecordType::Ipv6Address,
+            41 => RecordType::Opt,
+            43 => RecordType::DelegationSigner,
+            46 => RecordType::Signature,
+            47 => RecordType::NextSecure,
+            48 => RecordType::DnsKey,
+            50 => RecordType::NextSecure3,
+            250 => RecordType::TransactionSignature,
+            _ => RecordType::Unknown(value),
+        }
+    }
+}
+
+impl From<RecordType> for u16 {
+    fn from(ty: RecordType) -> Self {
+        match ty {
+            RecordType::Address => 1,
+            RecordType::NameServer => 2,
+            RecordType::MailDestination => 3,
+            RecordType::MailForwarder => 4,
+            RecordType::CName => 5,
+            RecordType::StartOfAuthority => 6,
+            RecordType::Mailbox => 7,
+            RecordType::MailGroup => 8,
+            RecordType::MailRename => 9,
+            RecordType::Null => 10,
+            RecordType::WellKnownService => 11,
+            RecordType::Pointer => 12,
+            RecordType::HostInfo => 13,
+            RecordType::MailboxInfo => 14,
+            RecordType::MailExchange => 15,
+            RecordType::Text => 16,
+            RecordType::Ipv6Address => 28,
+            RecordType::Opt => 41,
+            RecordType::DelegationSigner => 43,
+            RecordType::Signature => 46,
+            RecordType::NextSecure => 47,
+            RecordType::DnsKey => 48,
+            RecordType::NextSecure3 => 50,
+            RecordType::TransactionSignature => 250,
+            RecordType::Unknown(value) => value,
+        }
     }
 }
 
 impl Class {
     fn parse(input: &[u8]) -> IResult<&[u8], Self> {
-        let (rest, byte) = be_u16(input)?;
-        let class = match byte {
+        let (rest, value) = be_u16(input)?;
+        Ok((rest, Class::from(value)))
+    }
+}
+
+impl From<u16> for Class {
+    fn from(value: u16) -> Self {
+        match value {
             1 => Class::Internet,
             2 => Class::CSNet,
             3 => Class::Chaos,
             4 => Class::Hesiod,
-            _ => Class::Invalid,
+            _ => Class::Unknown(value),
+        }
+    }
+}
+
+impl From<Class> for u16 {
+    fn from(class: Class) -> Self {
+        match class {
+            Class::Internet => 1,
+            Class::CSNet => 2,
+            Class::Chaos => 3,
+            Class::Hesiod => 4,
+            Class::Unknown(value) => value,
+        }
+    }
+}
+
+impl FromStr for RecordType {
+    type Err = anyhow::Error;
+
+    /// Parse a type mnemonic, as used in zone files.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let ty = match s.to_ascii_uppercase().as_str() {
+            "A" => RecordType::Address,
+            "NS" => RecordType::NameServer,
+            "MD" => RecordType::MailDestination,
+            "MF" => RecordType::MailForwarder,
+            "CNAME" => RecordType::CName,
+            "SOA" => RecordType::StartOfAuthority,
+            "MB" => RecordType::Mailbox,
+            "MG" => RecordType::MailGroup,
+            "MR" => RecordType::MailRename,
+            "NULL" => RecordType::Null,
+            "WKS" => RecordType::WellKnownService,
+            "PTR" => RecordType::Pointer,
+            "HINFO" => RecordType::HostInfo,
+            "MINFO" => RecordType::MailboxInfo,
+            "MX" => RecordType::MailExchange,
+            "TXT" => RecordType::Text,
+            "AAAA" => RecordType::Ipv6Address,
+            "OPT" => RecordType::Opt,
+            "DS" => RecordType::DelegationSigner,
+            "RRSIG" => RecordType::Signature,
+            "NSEC" => RecordType::NextSecure,
+            "DNSKEY" => RecordType::DnsKey,
+            "NSEC3" => RecordType::NextSecure3,
+            "TSIG" => RecordType::TransactionSignature,
+            upper => match upper.strip_prefix("TYPE").map(str::parse::<u16>) {
+                // RFC 3597 generic type names
+                Some(Ok(value)) => RecordType::from(value),
+                _ => anyhow::bail!("unknown record type {s:?}"),
+            },
         };
-        Ok((rest, class))
+        Ok(ty)
+    }
+}
+
+impl FromStr for Class {
+    type Err = anyhow::Error;
+
+    /// Parse a class mnemonic, as used in zone files.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let class = match s.to_ascii_uppercase().as_str() {
+            "IN" => Class::Internet,
+            "CS" => Class::CSNet,
+            "CH" => Class::Chaos,
+            "HS" => Class::Hesiod,
+            upper => match upper.strip_prefix("CLASS").map(str::parse::<u16>) {
+                // RFC 3597 generic class names
+                Some(Ok(value)) => Class::from(value),
+                _ => anyhow::bail!("unknown class {s:?}"),
+            },
+        };
+        Ok(class)
     }
 }
 
 impl DomainName {
-    pub fn _new(name: &str) -> anyhow::Result<Self> {
+    /// Parse a dotted name such as `www.example.com`. The trailing dot is optional.
+    pub fn new(name: &str) -> anyhow::Result<Self> {
+        let name = name.strip_suffix('.').unwrap_or(name);
         let mut labels = Vec::new();
+        if name.is_empty() {
+            // The root domain
+            return Ok(DomainName { labels });
+        }
         for label in name.split('.') {
-            if label.len() > MAX_LABEL_SIZE {
+            if label.is_empty() {
+                anyhow::bail!("empty label in domain name {name:?}");
+            } else if label.len() > MAX_LABEL_SIZE {
                 anyhow::bail!("label cannot be longer than {MAX_LABEL_SIZE} bytes");
             }
             labels.push(Label::Value(label.to_string()));
         }
-        Ok(DomainName { labels })
+        let name = DomainName { labels };
+        if name.length() as usize > MAX_NAME_SIZE {
+            anyhow::bail!("domain name {name} is longer than {MAX_NAME_SIZE} bytes");
+        }
+        Ok(name)
+    }
+
+    /// Whether this name is `other` or anywhere beneath it.
+    pub fn is_subdomain_of(&self, other: &DomainName) -> bool {
+        self.labels.len() >= other.labels.len()
+            && self
+                .labels
+                .iter()
+                .rev()
+                .zip(other.labels.iter().rev())
+                .all(|(a, b)| a.eq_ignore_case(b))
+    }
+
+    /// This name with `suffix` removed from the end, e.g. `www` for `www.example.com` and
+    /// `example.com`. `None` if this name isn't beneath `suffix`.
+    pub fn strip_suffix(&self, suffix: &DomainName) -> Option<Self> {
+        if self.is_subdomain_of(suffix) {
+            Some(DomainName {
+                labels: self.labels[..self.labels.len() - suffix.labels.len()].to_vec(),
+            })
+        } else {
+            None
+        }
+    }
+
+    /// This name with the labels of `prefix` removed from the start, e.g. `example.com` for
+    /// `*.example.com` and `*`. `None` if this name doesn't start with `prefix`.
+    pub fn strip_prefix(&self, prefix: &DomainName) -> Option<Self> {
+        if self.labels.len() >= prefix.labels.len()
+            && self
+                .labels
+                .iter()
+                .zip(prefix.labels.iter())
+                .all(|(a, b)| a.eq_ignore_case(b))
+        {
+            Some(DomainName {
+                labels: self.labels[prefix.labels.len()..].to_vec(),
+            })
+        } else {
+            None
+        }
+    }
+
+    /// This name followed by `suffix`, e.g. `www.example.com` for `www` and `example.com`.
+    pub fn join(&self, suffix: &DomainName) -> Self {
+        DomainName {
+            labels: self
+                .labels
+                .iter()
+                .chain(suffix.labels.iter())
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// The domain one level up, e.g. `example.com` for `www.example.com`.
+    pub fn parent(&self) -> Option<Self> {
+        if self.labels.is_empty() {
+            None
+        } else {
+            Some(DomainName {
+                labels: self.labels[1..].to_vec(),
+            })
+        }
+    }
+
+    pub fn label_count(&self) -> usize {
+        self.labels.len()
+    }
+
+    /// The last `label_count` labels, e.g. `example.com` for `www.example.com` and 2.
+    pub fn ancestor(&self, label_count: usize) -> Self {
+        let start = self.labels.len().saturating_sub(label_count);
+        DomainName {
+            labels: self.labels[start..].to_vec(),
+        }
+    }
+
+    pub fn first_label(&self) -> Option<&str> {
+        match self.labels.first() {
+            Some(Label::Value(string)) => Some(string),
+            _ => None,
+        }
+    }
+
+    pub fn to_lowercase(&self) -> Self {
+        DomainName {
+            labels: self
+                .labels
+                .iter()
+                .map(|label| match label {
+                    Label::Value(string) => Label::Value(string.to_ascii_lowercase()),
+                    Label::Pointer(offset) => Label::Pointer(*offset),
+                })
+                .collect(),
+        }
+    }
+
+    /// The DNSSEC ordering of names (RFC 4034 section 6.1), comparing labels case-insensitively
+    /// from the right, so that names sort after their ancestors.
+    pub fn canonical_cmp(&self, other: &DomainName) -> Ordering {
+        let label_bytes = |label: &Label| match label {
+            Label::Value(string) => string.to_ascii_lowercase().into_bytes(),
+            Label::Pointer(_) => Vec::new(),
+        };
+        self.labels
+            .iter()
+            .rev()
+            .map(label_bytes)
+            .cmp(other.labels.iter().rev().map(label_bytes))
     }
 
     pub fn length(&self) -> u16 {
@@ -167,79 +537,79 @@ impl DomainName {
                 Label::Pointer(_) => 2,
             };
         }
-        if matches!(self.labels.last().unwrap(), Label::Value(_)) {
+        if !matches!(self.labels.last(), Some(Label::Pointer(_))) {
             // Final null byte
             length += 1;
         }
         length
     }
 
-    pub fn get_labels(&self, offset: u16) -> anyhow::Result<Vec<String>> {
-        let mut name_offset = 0;
-        for (i, label) in self.labels.iter().enumerate() {
-            if offset == name_offset {
-                let mut labels = Vec::new();
-                for label in self.labels.iter().skip(i) {
-                    match label {
-                        Label::Value(string) => labels.push(string.clone()),
-                        Label::Pointer(_) => {
-                            return Err(anyhow::format_err!("invalid label offset (pointer)"))
-                        }
-                    }
-                }
-                return Ok(labels);
-            }
-            name_offset += match label {
-                Label::Value(string) => 1 + string.len() as u16,
-                Label::Pointer(_) => 2,
-            };
-        }
-        Err(anyhow::format_err!(
-            "invalid label offset (not start of a label)"
-        ))
-    }
-
-    pub fn decompress(&self, message: &Message) -> anyhow::Result<Self> {
+    /// Resolve compression pointers against `packet`, the whole message this name was parsed from.
+    pub fn decompress(&self, packet: &[u8]) -> anyhow::Result<Self> {
         let mut labels = Vec::new();
-        for label in self.labels.iter() {
-            match label {
-                Label::Value(string) => labels.push(Label::Value(string.to_owned())),
-                Label::Pointer(offset) => {
-                    labels.extend(message.get_labels(*offset)?.drain(..).map(Label::Value));
+        let mut name = self;
+        let mut pointed_to;
+        for _ in 0..MAX_POINTER_HOPS {
+            let mut pointer = None;
+            for label in name.labels.iter() {
+                match label {
+                    Label::Value(string) => labels.push(Label::Value(string.to_owned())),
+                    Label::Pointer(offset) => pointer = Some(*offset as usize),
                 }
             }
+            let Some(offset) = pointer else {
+                let name = DomainName { labels };
+                if name.length() as usize > MAX_NAME_SIZE {
+                    anyhow::bail!("compressed name is longer than {MAX_NAME_SIZE} bytes");
+                }
+                return Ok(name);
+            };
+            let input = packet
+                .get(offset..)
+                .ok_or_else(|| anyhow::format_err!("invalid label offset {offset}"))?;
+            (_, pointed_to) =
+                DomainName::parse(input).map_err(|e| e.map_input(|s| s.to_owned()))?;
+            name = &pointed_to;
         }
-        Ok(DomainName { labels })
+        anyhow::bail!("too many compression pointers")
     }
 
+    /// Parse a name as it's written in a message. Compression pointers are kept, to be resolved
+    /// with [`DomainName::decompress`]. Names this can't represent are parse errors: extended
+    /// label types, labels which aren't UTF-8, and names which are too long.
     pub fn parse(input: &[u8]) -> IResult<&[u8], Self> {
         let mut rest = input;
         let mut labels = Vec::new();
         loop {
+            if input.len() - rest.len() >= MAX_NAME_SIZE {
+                return Err(nom::Err::Failure(Error::new(rest, ErrorKind::TooLarge)));
+            }
             let (remainder, label_length) = u8(rest)?;
-            rest = remainder;
             if label_length == 0 {
+                rest = remainder;
                 break;
             } else if (label_length >> 6) == 0x03 {
                 // Pointer
-                let (remainder, pointer_remainder) = u8(rest)?;
+                let (remainder, pointer_remainder) = u8(remainder)?;
                 rest = remainder;
                 let pointer = ((label_length & 0x3F) as u16) << 8 | (pointer_remainder as u16);
                 labels.push(Label::Pointer(pointer));
                 break;
             } else if label_length as usize > MAX_LABEL_SIZE {
-                panic!("label cannot be longer than {MAX_LABEL_SIZE} bytes");
+                // The 0x40 and 0x80 label types, which are obsolete or never defined
+                return Err(nom::Err::Failure(Error::new(rest, ErrorKind::Tag)));
             }
-            let (remainder, label) = take(label_length)(rest)?;
+            let (remainder, label) = take(label_length)(remainder)?;
+            let Ok(label) = String::from_utf8(label.to_owned()) else {
+                return Err(nom::Err::Failure(Error::new(rest, ErrorKind::Char)));
+            };
             rest = remainder;
-            labels.push(Label::Value(
-                String::from_utf8(label.to_owned()).expect("labels should be valid utf-8"),
-            ));
+            labels.push(Label::Value(label));
         }
         Ok((rest, DomainName { labels }))
     }
 
-    fn write<B>(&self, buf: &mut B) -> anyhow::Result<()>
+    pub fn write<B>(&self, buf: &mut B) -> anyhow::Result<()>
     where
         B: BufMut,
     {
@@ -252,7 +622,9 @@ impl DomainName {
                     buf.put_u8(string.len() as u8);
                     buf.put_slice(string.as_bytes());
                 }
-                Label::Pointer(_) => todo!(),
+                Label::Pointer(_) => {
+                    anyhow::bail!("compressed name {self} has to be decompressed to be written")
+                }
             }
         }
         buf.put_u8(0);
@@ -261,6 +633,64 @@ impl DomainName {
     }
 }
 
+impl PartialEq for DomainName {
+    fn eq(&self, other: &Self) -> bool {
+        self.labels.len() == other.labels.len()
+            && self
+                .labels
+                .iter()
+                .zip(other.labels.iter())
+                .all(|(a, b)| a.eq_ignore_case(b))
+    }
+}
+
+impl Eq for DomainName {}
+
+impl Hash for DomainName {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for label in self.labels.iter() {
+            match label {
+                Label::Value(string) => {
+                    for byte in string.bytes() {
+                        state.write_u8(byte.to_ascii_lowercase());
+                    }
+                    state.write_u8(b'.');
+                }
+                Label::Pointer(offset) => state.write_u16(*offset),
+            }
+        }
+    }
+}
+
+impl Label {
+    /// Domain names are compared case-insensitively.
+    fn eq_ignore_case(&self, other: &Label) -> bool {
+        match (self, other) {
+            (Label::Value(a), Label::Value(b)) => a.eq_ignore_ascii_case(b),
+            (Label::Pointer(a), Label::Pointer(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for DomainName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.labels.is_empty() {
+            return write!(f, ".");
+        }
+        for (i, label) in self.labels.iter().enumerate() {
+            if i > 0 {
+                write!(f, ".")?;
+            }
+            match label {
+                Label::Value(string) => write!(f, "{string}")?,
+                Label::Pointer(offset) => write!(f, "<pointer {offset}>")?,
+            }
+        }
+        Ok(())
+    }
+}
+
 impl Question {
     pub fn parse(input: &[u8]) -> IResult<&[u8], Self> {
         let (rest, name) = DomainName::parse(input)?;
@@ -269,23 +699,9 @@ impl Question {
         Ok((rest, Question { name, ty, class }))
     }
 
-    pub fn decompressed_clone(&self, message: &Message) -> anyhow::Result<Self> {
-        Ok(Question {
-            name: self.name.decompress(message)?,
-            ty: self.ty,
-            class: self.class,
-        })
-    }
-
-    pub fn length(&self) -> u16 {
-        self.name.length() + 4
-    }
-
-    pub fn get_labels(&self, offset: u16) -> anyhow::Result<Vec<String>> {
-        if offset >= self.name.length() {
-            anyhow::bail!("invalid label offset (in type/class enums)");
-        }
-        self.name.get_labels(offset)
+    pub fn decompress(&mut self, packet: &[u8]) -> anyhow::Result<()> {
+        self.name = self.name.decompress(packet)?;
+        Ok(())
     }
 
     pub fn write<B>(&self, buf: &mut B) -> anyhow::Result<()>
@@ -293,15 +709,14 @@ impl Question {
         B: BufMut,
     {
         self.name.write(buf)?;
-        buf.put_u16(self.ty as u16);
-        buf.put_u16(self.class as u16);
+        buf.put_u16(self.ty.into());
+        buf.put_u16(self.class.into());
 
         Ok(())
     }
 }
 
 impl ResourceRecord {
-    #[allow(dead_code)]
     pub fn new(
         name: DomainName,
         ty: RecordType,
@@ -319,15 +734,11 @@ impl ResourceRecord {
         }
     }
 
-    pub fn decompressed_clone(&self, message: &Message) -> anyhow::Result<Self> {
-        Ok(ResourceRecord {
-            name: self.name.decompress(message)?,
-            ty: self.ty,
-            class: self.class,
-            time_to_live: self.time_to_live,
-            length: self.length,
-            data: self.data.clone(),
-        })
+    pub fn decompress(&mut self, packet: &[u8]) -> anyhow::Result<()> {
+        self.name = self.name.decompress(packet)?;
+        self.data.decompress(packet)?;
+        self.length = self.data.length();
+        Ok(())
     }
 
     pub fn parse(input: &[u8]) -> IResult<&[u8], Self> {
@@ -354,8 +765,8 @@ impl ResourceRecord {
         B: BufMut,
     {
         self.name.write(buf)?;
-        buf.put_u16(self.ty as u16);
-        buf.put_u16(self.class as u16);
+        buf.put_u16(self.ty.into());
+        buf.put_u16(self.class.into());
         buf.put_u32(self.time_to_live);
         self.data.write(buf)?;
 
@@ -364,22 +775,261 @@ impl ResourceRecord {
 }
 
 impl ResourceRecordData {
+    /// This data with its domain names lowercased, as it's signed (RFC 4034 section 6.2, less
+    /// NSEC as updated by RFC 6840).
+    pub fn to_canonical(&self) -> Self {
+        let mut data = self.clone();
+        match &mut data {
+            ResourceRecordData::NameServer(name)
+            | ResourceRecordData::CName(name)
+            | ResourceRecordData::Pointer(name)
+            | ResourceRecordData::MailExchange { exchange: name, .. }
+            | ResourceRecordData::Signature {
+                signer_name: name, ..
+            } => *name = name.to_lowercase(),
+            ResourceRecordData::StartOfAuthority {
+                primary_name_server,
+                responsible_mailbox,
+                ..
+            } => {
+                *primary_name_server = primary_name_server.to_lowercase();
+                *responsible_mailbox = responsible_mailbox.to_lowercase();
+            }
+            _ => {}
+        }
+        data
+    }
+
     fn length(&self) -> u16 {
         match self {
             ResourceRecordData::IPv4(_) => 4,
+            ResourceRecordData::IPv6(_) => 16,
+            ResourceRecordData::NameServer(name)
+            | ResourceRecordData::CName(name)
+            | ResourceRecordData::Pointer(name) => name.length(),
+            ResourceRecordData::MailExchange { exchange, .. } => 2 + exchange.length(),
+            ResourceRecordData::Text(strings) => {
+                strings.iter().map(|string| 1 + string.len() as u16).sum()
+            }
+            ResourceRecordData::StartOfAuthority {
+                primary_name_server,
+                responsible_mailbox,
+                ..
+            } => primary_name_server.length() + responsible_mailbox.length() + 20,
+            ResourceRecordData::Opt(options) => options.iter().map(EdnsOption::length).sum(),
+            ResourceRecordData::DelegationSigner { digest, .. } => 4 + digest.len() as u16,
+            ResourceRecordData::Signature {
+                signer_name,
+                signature,
+                ..
+            } => 18 + signer_name.length() + signature.len() as u16,
+            ResourceRecordData::NextSecure {
+                next_domain_name,
+                types,
+            } => next_domain_name.length() + encode_type_bitmap(types).len() as u16,
+            ResourceRecordData::DnsKey { public_key, .. } => 4 + public_key.len() as u16,
+            ResourceRecordData::NextSecure3 {
+                salt,
+                next_hashed_owner,
+                types,
+                ..
+            } => {
+                6 + salt.len() as u16
+                    + next_hashed_owner.len() as u16
+                    + encode_type_bitmap(types).len() as u16
+            }
+            ResourceRecordData::TransactionSignature {
+                algorithm,
+                mac,
+                other_data,
+                ..
+            } => algorithm.length() + 16 + mac.len() as u16 + other_data.len() as u16,
+            ResourceRecordData::Unknown(data) => data.len() as u16,
         }
     }
 
-    pub fn parse(input: &[u8], ty: RecordType) -> IResult<&[u8], Self> {
-        match ty {
-            RecordType::Address => {
-                let (rest, length) = be_u16(input)?;
-                assert_eq!(length, 4);
-                let (rest, ip) = take(4usize)(rest)?;
-                Ok((rest, ResourceRecordData::IPv4([ip[0], ip[1], ip[2], ip[3]])))
+    fn decompress(&mut self, packet: &[u8]) -> anyhow::Result<()> {
+        match self {
+            ResourceRecordData::NameServer(name)
+            | ResourceRecordData::CName(name)
+            | ResourceRecordData::Pointer(name)
+            | ResourceRecordData::MailExchange { exchange: name, .. }
+            | ResourceRecordData::Signature {
+                signer_name: name, ..
+            }
+            | ResourceRecordData::NextSecure {
+                next_domain_name: name,
+                ..
+            }
+            | ResourceRecordData::TransactionSignature {
+                algorithm: name, ..
+            } => {
+                *name = name.decompress(packet)?;
+            }
+            ResourceRecordData::StartOfAuthority {
+                primary_name_server,
+                responsible_mailbox,
+                ..
+            } => {
+                *primary_name_server = primary_name_server.decompress(packet)?;
+                *responsible_mailbox = responsible_mailbox.decompress(packet)?;
             }
-            _ => unimplemented!(),
+            ResourceRecordData::IPv4(_)
+            | ResourceRecordData::IPv6(_)
+            | ResourceRecordData::Text(_)
+            | ResourceRecordData::Opt(_)
+            | ResourceRecordData::DelegationSigner { .. }
+            | ResourceRecordData::DnsKey { .. }
+            | ResourceRecordData::NextSecure3 { .. }
+            | ResourceRecordData::Unknown(_) => {}
         }
+        Ok(())
+    }
+
+    pub fn parse(input: &[u8], ty: RecordType) -> IResult<&[u8], Self> {
+        let (rest, length) = be_u16(input)?;
+        let (rest, data) = take(length)(rest)?;
+        let data = match ty {
+            // UPDATE deletes by type with no data (RFC 2136 section 2.5.2)
+            _ if length == 0 && ty != RecordType::Opt => ResourceRecordData::Unknown(Vec::new()),
+            RecordType::Address if length == 4 => {
+                ResourceRecordData::IPv4([data[0], data[1], data[2], data[3]])
+            }
+            RecordType::Ipv6Address if length == 16 => {
+                ResourceRecordData::IPv6(data.try_into().unwrap())
+            }
+            RecordType::NameServer => ResourceRecordData::NameServer(DomainName::parse(data)?.1),
+            RecordType::CName => ResourceRecordData::CName(DomainName::parse(data)?.1),
+            RecordType::Pointer => ResourceRecordData::Pointer(DomainName::parse(data)?.1),
+            RecordType::MailExchange => {
+                let (data, preference) = be_u16(data)?;
+                let (_, exchange) = DomainName::parse(data)?;
+                ResourceRecordData::MailExchange {
+                    preference,
+                    exchange,
+                }
+            }
+            RecordType::Text => {
+                let mut strings = Vec::new();
+                let mut data = data;
+                while !data.is_empty() {
+                    let (remainder, string_length) = u8(data)?;
+                    let (remainder, string) = take(string_length)(remainder)?;
+                    strings.push(string.to_vec());
+                    data = remainder;
+                }
+                ResourceRecordData::Text(strings)
+            }
+            RecordType::StartOfAuthority => {
+                let (data, primary_name_server) = DomainName::parse(data)?;
+                let (data, responsible_mailbox) = DomainName::parse(data)?;
+                let (data, serial) = be_u32(data)?;
+                let (data, refresh) = be_u32(data)?;
+                let (data, retry) = be_u32(data)?;
+                let (data, expire) = be_u32(data)?;
+                let (_, minimum) = be_u32(data)?;
+                ResourceRecordData::StartOfAuthority {
+                    primary_name_server,
+                    responsible_mailbox,
+                    serial,
+                    refresh,
+                    retry,
+                    expire,
+                    minimum,
+                }
+            }
+            RecordType::Opt => ResourceRecordData::Opt(many0(EdnsOption::parse)(data)?.1),
+            RecordType::DelegationSigner => {
+                let (data, key_tag) = be_u16(data)?;
+                let (data, algorithm) = u8(data)?;
+                let (digest, digest_type) = u8(data)?;
+                ResourceRecordData::DelegationSigner {
+                    key_tag,
+                    algorithm,
+                    digest_type,
+                    digest: digest.to_vec(),
+                }
+            }
+            RecordType::Signature => {
+                let (data, type_covered) = RecordType::parse(data)?;
+                let (data, algorithm) = u8(data)?;
+                let (data, labels) = u8(data)?;
+                let (data, original_ttl) = be_u32(data)?;
+                let (data, expiration) = be_u32(data)?;
+                let (data, inception) = be_u32(data)?;
+                let (data, key_tag) = be_u16(data)?;
+                let (signature, signer_name) = DomainName::parse(data)?;
+                ResourceRecordData::Signature {
+                    type_covered,
+                    algorithm,
+                    labels,
+                    original_ttl,
+                    expiration,
+                    inception,
+                    key_tag,
+                    signer_name,
+                    signature: signature.to_vec(),
+                }
+            }
+            RecordType::NextSecure => {
+                let (data, next_domain_name) = DomainName::parse(data)?;
+                ResourceRecordData::NextSecure {
+                    next_domain_name,
+                    types: parse_type_bitmap(data)?.1,
+                }
+            }
+            RecordType::DnsKey => {
+                let (data, flags) = be_u16(data)?;
+                let (data, protocol) = u8(data)?;
+                let (public_key, algorithm) = u8(data)?;
+                ResourceRecordData::DnsKey {
+                    flags,
+                    protocol,
+                    algorithm,
+                    public_key: public_key.to_vec(),
+                }
+            }
+            RecordType::NextSecure3 => {
+                let (data, hash_algorithm) = u8(data)?;
+                let (data, flags) = u8(data)?;
+                let (data, iterations) = be_u16(data)?;
+                let (data, salt_length) = u8(data)?;
+                let (data, salt) = take(salt_length)(data)?;
+                let (data, hash_length) = u8(data)?;
+                let (data, next_hashed_owner) = take(hash_length)(data)?;
+                ResourceRecordData::NextSecure3 {
+                    hash_algorithm,
+                    flags,
+                    iterations,
+                    salt: salt.to_vec(),
+                    next_hashed_owner: next_hashed_owner.to_vec(),
+                    types: parse_type_bitmap(data)?.1,
+                }
+            }
+            RecordType::TransactionSignature => {
+                let (data, algorithm) = DomainName::parse(data)?;
+                let (data, time_signed_high) = be_u16(data)?;
+                let (data, time_signed_low) = be_u32(data)?;
+                let (data, fudge) = be_u16(data)?;
+                let (data, mac_length) = be_u16(data)?;
+                let (data, mac) = take(mac_length)(data)?;
+                let (data, original_id) = be_u16(data)?;
+                let (data, error) = be_u16(data)?;
+                let (data, other_length) = be_u16(data)?;
+                let (_, other_data) = take(other_length)(data)?;
+                ResourceRecordData::TransactionSignature {
+                    algorithm,
+                    time_signed: u64::from(time_signed_high) << 32 | u64::from(time_signed_low),
+                    fudge,
+                    mac: mac.to_vec(),
+                    original_id,
+                    error,
+                    other_data: other_data.to_vec(),
+                }
+            }
+            _ => ResourceRecordData::Unknown(data.to_vec()),
+        };
+        Ok((rest, data))
     }
 
     pub fn write<B>(&self, buf: &mut B) -> anyhow::Result<()>
@@ -391,7 +1041,304 @@ impl ResourceRecordData {
             ResourceRecordData::IPv4(ip) => {
                 buf.put_slice(ip);
             }
+            ResourceRecordData::IPv6(ip) => {
+                buf.put_slice(ip);
+            }
+            ResourceRecordData::NameServer(name)
+            | ResourceRecordData::CName(name)
+            | ResourceRecordData::Pointer(name) => {
+                name.write(buf)?;
+            }
+            ResourceRecordData::MailExchange {
+                preference,
+                exchange,
+            } => {
+                buf.put_u16(*preference);
+                exchange.write(buf)?;
+            }
+            ResourceRecordData::Text(strings) => {
+                for string in strings.iter() {
+                    if string.len() > u8::MAX as usize {
+                        anyhow::bail!("character string cannot be longer than 255 bytes");
+                    }
+                    buf.put_u8(string.len() as u8);
+                    buf.put_slice(string);
+                }
+            }
+            ResourceRecordData::StartOfAuthority {
+                primary_name_server,
+                responsible_mailbox,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => {
+                primary_name_server.write(buf)?;
+                responsible_mailbox.write(buf)?;
+                buf.put_u32(*serial);
+                buf.put_u32(*refresh);
+                buf.put_u32(*retry);
+                buf.put_u32(*expire);
+                buf.put_u32(*minimum);
+            }
+            ResourceRecordData::Opt(options) => {
+                for option in options.iter() {
+                    option.write(buf);
+                }
+            }
+            ResourceRecordData::DelegationSigner {
+                key_tag,
+                algorithm,
+                digest_type,
+                digest,
+            } => {
+                buf.put_u16(*key_tag);
+                buf.put_u8(*algorithm);
+                buf.put_u8(*digest_type);
+                buf.put_slice(digest);
+            }
+            ResourceRecordData::Signature {
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                expiration,
+                inception,
+                key_tag,
+                signer_name,
+                signature,
+            } => {
+                buf.put_u16((*type_covered).into());
+                buf.put_u8(*algorithm);
+                buf.put_u8(*labels);
+                buf.put_u32(*original_ttl);
+                buf.put_u32(*expiration);
+                buf.put_u32(*inception);
+                buf.put_u16(*key_tag);
+                signer_name.write(buf)?;
+                buf.put_slice(signature);
+            }
+            ResourceRecordData::NextSecure {
+                next_domain_name,
+                types,
+            } => {
+                next_domain_name.write(buf)?;
+                buf.put_slice(&encode_type_bitmap(types));
+            }
+            ResourceRecordData::DnsKey {
+                flags,
+                protocol,
+                algorithm,
+                public_key,
+            } => {
+                buf.put_u16(*flags);
+                buf.put_u8(*protocol);
+                buf.put_u8(*algorithm);
+                buf.put_slice(public_key);
+            }
+            ResourceRecordData::NextSecure3 {
+                hash_algorithm,
+                flags,
+                iterations,
+                salt,
+                next_hashed_owner,
+                types,
+            } => {
+                buf.put_u8(*hash_algorithm);
+                buf.put_u8(*flags);
+                buf.put_u16(*iterations);
+                buf.put_u8(salt.len() as u8);
+                buf.put_slice(salt);
+                buf.put_u8(next_hashed_owner.len() as u8);
+                buf.put_slice(next_hashed_owner);
+                buf.put_slice(&encode_type_bitmap(types));
+            }
+            ResourceRecordData::TransactionSignature {
+                algorithm,
+                time_signed,
+                fudge,
+                mac,
+                original_id,
+                error,
+                other_data,
+            } => {
+                algorithm.write(buf)?;
+                buf.put_u16((time_signed >> 32) as u16);
+                buf.put_u32(*time_signed as u32);
+                buf.put_u16(*fudge);
+                buf.put_u16(mac.len() as u16);
+                buf.put_slice(mac);
+                buf.put_u16(*original_id);
+                buf.put_u16(*error);
+                buf.put_u16(other_data.len() as u16);
+                buf.put_slice(other_data);
+            }
+            ResourceRecordData::Unknown(data) => {
+                buf.put_slice(data);
+            }
         }
         Ok(())
     }
 }
+
+/// The types present at an NSEC or NSEC3 owner, as bitmaps of each 256 type window in use.
+fn parse_type_bitmap(input: &[u8]) -> IResult<&[u8], Vec<RecordType>> {
+    let mut types = Vec::new();
+    let mut rest = input;
+    while !rest.is_empty() {
+        let (remainder, window) = u8(rest)?;
+        let (remainder, length) = u8(remainder)?;
+        // 32 bytes covers the 256 types in a window (RFC 4034 section 4.1.2)
+        if length == 0 || length > 32 {
+            return Err(nom::Err::Failure(Error::new(
+                remainder,
+                ErrorKind::LengthValue,
+            )));
+        }
+        let (remainder, bitmap) = take(length)(remainder)?;
+        for (i, byte) in bitmap.iter().enumerate() {
+            for bit in 0..8 {
+                if byte & (0x80 >> bit) != 0 {
+                    let value = (window as u16) << 8 | (i * 8 + bit) as u16;
+                    types.push(RecordType::from(value));
+                }
+            }
+        }
+        rest = remainder;
+    }
+    Ok((rest, types))
+}
+
+fn encode_type_bitmap(types: &[RecordType]) -> Vec<u8> {
+    let mut values = types.iter().map(|&ty| u16::from(ty)).collect::<Vec<_>>();
+    values.sort_unstable();
+    values.dedup();
+
+    let mut encoded = Vec::new();
+    let mut rest = values.as_slice();
+    while let Some(first) = rest.first() {
+        let window = first >> 8;
+        let count = rest
+            .iter()
+            .take_while(|value| *value >> 8 == window)
+            .count();
+        let (values, remainder) = rest.split_at(count);
+        let length = (values[count - 1] & 0xFF) as usize / 8 + 1;
+        let mut bitmap = vec![0u8; length];
+        for value in values {
+            let low = (value & 0xFF) as usize;
+            bitmap[low / 8] |= 0x80 >> (low % 8);
+        }
+        encoded.push(window as u8);
+        encoded.push(length as u8);
+        encoded.extend(bitmap);
+        rest = remainder;
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_name(input: &[u8]) -> Option<DomainName> {
+        DomainName::parse(input).ok().map(|(_, name)| name)
+    }
+
+    #[test]
+    fn names_round_trip() {
+        let name = DomainName::new("www.Example.com.").unwrap();
+        let mut buf = Vec::new();
+        name.write(&mut buf).unwrap();
+        assert_eq!(buf, b"\x03www\x07Example\x03com\x00");
+        assert_eq!(buf.len(), name.length() as usize);
+        assert_eq!(
+            parse_name(&buf),
+            Some(DomainName::new("www.example.com").unwrap())
+        );
+        assert_eq!(name.to_string(), "www.Example.com");
+        assert_eq!(DomainName::new(".").unwrap().to_string(), ".");
+    }
+
+    #[test]
+    fn bad_names_are_errors() {
+        // The 0x40 and 0x80 label types
+        assert_eq!(parse_name(b"\x40abc\x00"), None);
+        assert_eq!(parse_name(b"\x80abc\x00"), None);
+        assert_eq!(parse_name(b"\x02\xff\xfe\x00"), None);
+        assert_eq!(parse_name(b"\x05abc"), None);
+        assert_eq!(parse_name(b"\xc0"), None);
+        let long = [[63].as_slice(), &[b'a'; 63]].concat().repeat(5);
+        assert_eq!(parse_name(&[long.as_slice(), &[0]].concat()), None);
+
+        assert!(DomainName::new("a..b").is_err());
+        assert!(DomainName::new(&"a".repeat(64)).is_err());
+        assert!(DomainName::new(&vec!["a".repeat(63); 4].join(".")).is_err());
+        assert!(DomainName::new(
+            &[
+                "a".repeat(63),
+                "b".repeat(63),
+                "c".repeat(63),
+                "d".repeat(61)
+            ]
+            .join(".")
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn pointers_are_resolved_or_rejected() {
+        let packet = b"\x07example\x03com\x00\x03www\xc0\x00";
+        let (_, name) = DomainName::parse(&packet[13..]).unwrap();
+        assert!(name.write(&mut Vec::new()).is_err());
+        let name = name.decompress(packet).unwrap();
+        assert_eq!(name, DomainName::new("www.example.com").unwrap());
+
+        // A pointer to itself
+        let (_, name) = DomainName::parse(b"\xc0\x00").unwrap();
+        assert!(name.decompress(b"\xc0\x00").is_err());
+        // A pointer past the end
+        let (_, name) = DomainName::parse(b"\xc0\x10").unwrap();
+        assert!(name.decompress(b"\xc0\x10").is_err());
+    }
+
+    #[test]
+    fn names_compare_canonically() {
+        let names = [
+            "example",
+            "a.example",
+            "yljkjljk.a.example",
+            "Z.a.example",
+            "zABC.a.EXAMPLE",
+        ]
+        .map(|name| DomainName::new(name).unwrap());
+        for pair in names.windows(2) {
+            assert_eq!(
+                pair[0].canonical_cmp(&pair[1]),
+                Ordering::Less,
+                "{} < {}",
+                pair[0],
+                pair[1]
+            );
+        }
+        let name = DomainName::new("www.example.com").unwrap();
+        let zone = DomainName::new("EXAMPLE.com").unwrap();
+        assert!(name.is_subdomain_of(&zone));
+        assert_eq!(name.strip_suffix(&zone).unwrap().to_string(), "www");
+        assert_eq!(name.parent().unwrap(), zone);
+    }
+
+    #[test]
+    fn type_bitmaps_round_trip() {
+        let types = vec![
+            RecordType::Address,
+            RecordType::Signature,
+            RecordType::from(1234),
+        ];
+        let encoded = encode_type_bitmap(&types);
+        assert_eq!(parse_type_bitmap(&encoded).unwrap().1, types);
+        assert!(parse_type_bitmap(&[0, 33]).is_err());
+        assert!(parse_type_bitmap(&[0, 0]).is_err());
+    }
+}
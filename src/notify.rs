@@ -0,0 +1,53 @@
+//! Sending NOTIFY (RFC 1996), which tells secondaries a zone has changed so they needn't wait for
+//! their next refresh.
+
+use std::{net::SocketAddr, time::Duration};
+
+use bytes::BytesMut;
+
+use crate::{
+    forward::query_udp,
+    message::{Class, Message, OpCode, Question, ResourceRecord, ResponseCode},
+    tsig::{Key, Session},
+};
+
+/// How long to wait for a secondary to acknowledge a NOTIFY.
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Tell `secondary` that the zone `soa` is the SOA of has changed, returning once it's
+/// acknowledged. With a key, the NOTIFY is signed and the acknowledgement must be.
+pub fn send(secondary: SocketAddr, soa: &ResourceRecord, key: Option<&Key>) -> anyhow::Result<()> {
+    let mut query_message = Message::new_query(vec![Question {
+        name: soa.name.clone(),
+        ty: soa.ty,
+        class: Class::Internet,
+    }]);
+    query_message.header.op_code = OpCode::Notify;
+    query_message.header.authoritative_answer = true;
+    query_message.header.recursion_desired = false;
+    // The new SOA, as a hint (RFC 1996 section 3.7)
+    query_message.answers.push(soa.clone());
+    let mut session = key.cloned().map(Session::new);
+    if let Some(session) = &mut session {
+        session.sign(&mut query_message)?;
+    }
+    let mut msg = BytesMut::with_capacity(64);
+    query_message.write(&mut msg)?;
+
+    let response_message = Message::parse(&query_udp(&msg, secondary, NOTIFY_TIMEOUT)?)?;
+    if response_message.header.packet_id != query_message.header.packet_id
+        || response_message.header.op_code != OpCode::Notify
+    {
+        anyhow::bail!("mismatched response from {secondary}");
+    }
+    if let Some(session) = &mut session {
+        session.verify_response(&response_message)?;
+    }
+    if response_message.header.response_code != ResponseCode::Ok {
+        anyhow::bail!(
+            "{secondary} rejected notify: {:?}",
+            response_message.header.response_code
+        );
+    }
+    Ok(())
+}
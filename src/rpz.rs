@@ -0,0 +1,727 @@
+//! Response Policy Zones, as described in draft-vixie-dnsop-dns-rpz.
+//!
+//! Each record's owner name is a trigger relative to the zone's origin:
+//!
+//! - `example.com` or `*.example.com` matches the query name.
+//! - `32.1.0.0.10.rpz-client-ip` matches the client address, here `10.0.0.1/32`.
+//! - `24.0.2.0.192.rpz-ip` matches addresses in the answer, here `192.0.2.0/24`.
+//! - `ns.example.com.rpz-nsdname` matches name servers in the answer or authority sections.
+//! - `32.1.2.0.192.rpz-nsip` matches the addresses of those name servers, when the response
+//!   carries them as glue.
+//!
+//! The records at the trigger give the action. A CNAME to `.` means NXDOMAIN, to `*.` means NODATA,
+//! and to `rpz-passthru.`, `rpz-drop.` or `rpz-tcp-only.` means just that. Any other records are
+//! local data to answer with instead, and a CNAME to `*.garden.example` rewrites the query name
+//! to be beneath `garden.example`.
+//!
+//! Policy zones come from zone files, or by zone transfer from a primary, in which case they're
+//! refreshed like secondary zones.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    sync::{Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    acl::Cidr,
+    message::{DomainName, Message, Question, RecordType, ResourceRecord, ResourceRecordData},
+    secondary::{self, Transfer},
+    zone::{record_key, serial, serial_lt, INITIAL_RETRY},
+    zone_file,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PolicyAction {
+    /// Answer that the name doesn't exist.
+    NxDomain,
+    /// Answer that the name has no records of the queried type.
+    NoData,
+    /// Answer as normal, ignoring any later policies.
+    Passthru,
+    /// Don't answer at all.
+    Drop,
+    /// Answer over TCP as normal, but only with a truncated response over UDP.
+    TcpOnly,
+    /// Answer with these records instead.
+    LocalData(Vec<ResourceRecord>),
+}
+
+/// Where a policy zone is loaded from.
+#[derive(Debug, Clone)]
+pub enum PolicySource {
+    File(PathBuf),
+    /// The primary to transfer the zone from.
+    Primary(SocketAddr),
+}
+
+#[derive(Debug, Clone)]
+pub struct RpzConfig {
+    /// The zone's origin, and where to load it from.
+    pub zones: Vec<(DomainName, PolicySource)>,
+}
+
+#[derive(Debug, Default)]
+struct PolicyZone {
+    qname: HashMap<DomainName, PolicyAction>,
+    /// Wildcard query name triggers, stored without the leading `*`.
+    qname_wildcard: HashMap<DomainName, PolicyAction>,
+    nsdname: HashMap<DomainName, PolicyAction>,
+    nsdname_wildcard: HashMap<DomainName, PolicyAction>,
+    client_ip: Vec<(Cidr, PolicyAction)>,
+    response_ip: Vec<(Cidr, PolicyAction)>,
+    nsip: Vec<(Cidr, PolicyAction)>,
+}
+
+/// A policy zone, and the primary it's kept up to date from if it isn't from a file.
+#[derive(Debug)]
+struct LoadedZone {
+    origin: DomainName,
+    triggers: RwLock<PolicyZone>,
+    primary: Option<Mutex<Primary>>,
+}
+
+/// A transferred policy zone's copy of the primary's records.
+#[derive(Debug)]
+struct Primary {
+    addr: SocketAddr,
+    soa: Option<ResourceRecord>,
+    /// Everything but the SOA, for incremental transfers to apply to.
+    records: Vec<ResourceRecord>,
+    refresh_at: Instant,
+}
+
+/// Policy zones in order of precedence.
+#[derive(Debug)]
+pub struct ResponsePolicy {
+    zones: Vec<LoadedZone>,
+}
+
+impl ResponsePolicy {
+    /// Load the policy zones from files. Those from a primary start out empty, and are
+    /// transferred by the first `maintain`.
+    pub fn load(config: &RpzConfig) -> anyhow::Result<Self> {
+        let mut zones = Vec::new();
+        for (origin, source) in config.zones.iter() {
+            let (triggers, primary) = match source {
+                PolicySource::File(path) => {
+                    let contents = fs::read_to_string(path)?;
+                    let records = zone_file::parse(&contents, origin)
+                        .map_err(|e| anyhow::format_err!("{}: {}", path.display(), e))?;
+                    (PolicyZone::new(origin, records)?, None)
+                }
+                PolicySource::Primary(addr) => {
+                    let primary = Primary {
+                        addr: *addr,
+                        soa: None,
+                        records: Vec::new(),
+                        refresh_at: Instant::now(),
+                    };
+                    (PolicyZone::default(), Some(Mutex::new(primary)))
+                }
+            };
+            zones.push(LoadedZone {
+                origin: origin.clone(),
+                triggers: RwLock::new(triggers),
+                primary,
+            });
+        }
+        Ok(ResponsePolicy { zones })
+    }
+
+    /// Refresh the transferred policy zones which are due. A zone keeps its last policies if
+    /// the primary can't be reached, however long that goes on, rather than let through what
+    /// it blocks.
+    pub fn maintain(&self) {
+        for zone in self.zones.iter() {
+            if let Err(e) = zone.refresh() {
+                eprintln!("error refreshing policy zone {}: {e}", zone.origin);
+            }
+        }
+    }
+
+    /// The policy for a query, based on the client and question alone.
+    ///
+    /// Triggers which need the answer are checked later by `check_response`, so a query name
+    /// trigger takes effect even if an earlier zone would have matched the answer.
+    pub fn check_query(&self, client: IpAddr, questions: &[Question]) -> Option<PolicyAction> {
+        for zone in self.zones.iter() {
+            let zone = zone.triggers.read().unwrap();
+            if let Some(action) = longest_match(&zone.client_ip, client) {
+                return Some(action.clone());
+            }
+            for question in questions.iter() {
+                if let Some(action) = lookup(&zone.qname, &zone.qname_wildcard, &question.name) {
+                    return Some(action.clone());
+                }
+            }
+        }
+        None
+    }
+
+    /// The policy for an answer, based on the addresses and name servers in it.
+    pub fn check_response(&self, response: &Message) -> Option<PolicyAction> {
+        let records = || {
+            response
+                .answers
+                .iter()
+                .chain(response.authorities.iter())
+                .chain(response.additionals.iter())
+        };
+        let name_servers = records()
+            .filter_map(|record| match &record.data {
+                ResourceRecordData::NameServer(name) => Some(name),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        for zone in self.zones.iter() {
+            let zone = zone.triggers.read().unwrap();
+            for addr in response.answers.iter().filter_map(address) {
+                if let Some(action) = longest_match(&zone.response_ip, addr) {
+                    return Some(action.clone());
+                }
+            }
+            for name in name_servers.iter() {
+                if let Some(action) = lookup(&zone.nsdname, &zone.nsdname_wildcard, name) {
+                    return Some(action.clone());
+                }
+            }
+            let glue = records().filter(|record| name_servers.contains(&&record.name));
+            for addr in glue.filter_map(address) {
+                if let Some(action) = longest_match(&zone.nsip, addr) {
+                    return Some(action.clone());
+                }
+            }
+        }
+        None
+    }
+}
+
+impl LoadedZone {
+    /// Bring a transferred zone up to date if it's due a refresh, keeping the old policies if
+    /// the new version can't be had or has a bad trigger.
+    fn refresh(&self) -> anyhow::Result<()> {
+        let Some(primary) = &self.primary else {
+            return Ok(());
+        };
+        let mut primary = primary.lock().unwrap();
+        if primary.refresh_at > Instant::now() {
+            return Ok(());
+        }
+        let result = primary.fetch(&self.origin).and_then(|update| {
+            let Some((soa, records)) = update else {
+                return Ok(());
+            };
+            *self.triggers.write().unwrap() = PolicyZone::new(&self.origin, records.clone())?;
+            primary.soa = Some(soa);
+            primary.records = records;
+            Ok(())
+        });
+        let wait = match &primary.soa {
+            Some(soa) => soa_timer(soa, result.is_ok()),
+            None => INITIAL_RETRY,
+        };
+        primary.refresh_at = Instant::now() + wait;
+        result
+    }
+}
+
+impl Primary {
+    /// The primary's SOA and records, if they're newer than ours.
+    fn fetch(
+        &self,
+        origin: &DomainName,
+    ) -> anyhow::Result<Option<(ResourceRecord, Vec<ResourceRecord>)>> {
+        let primary_soa = secondary::query_soa(self.addr, origin, None)?;
+        if let Some(soa) = &self.soa {
+            if !serial_lt(serial(soa), serial(&primary_soa)) {
+                return Ok(None);
+            }
+        }
+        match secondary::transfer(self.addr, origin, self.soa.as_ref(), None)? {
+            Transfer::UpToDate => Ok(None),
+            Transfer::Full(mut records) => {
+                let soa = records.remove(0);
+                Ok(Some((soa, records)))
+            }
+            Transfer::Incremental(changes) => {
+                let mut soa = primary_soa;
+                let mut records = self.records.clone();
+                for change in changes {
+                    let removed = change
+                        .removed
+                        .iter()
+                        .map(record_key)
+                        .collect::<HashSet<_>>();
+                    records.retain(|record| !removed.contains(&record_key(record)));
+                    records.extend(change.added);
+                    soa = change.new_soa;
+                }
+                Ok(Some((soa, records)))
+            }
+        }
+    }
+}
+
+impl PolicyZone {
+    fn new(origin: &DomainName, records: Vec<ResourceRecord>) -> anyhow::Result<Self> {
+        // Records grouped by owner, in the order each owner first appears
+        let mut triggers: Vec<(DomainName, Vec<ResourceRecord>)> = Vec::new();
+        let mut owners: HashMap<DomainName, usize> = HashMap::new();
+        for record in records {
+            if matches!(
+                record.ty,
+                RecordType::StartOfAuthority | RecordType::NameServer
+            ) && record.name == *origin
+            {
+                // The zone's own apex records aren't policies
+                continue;
+            }
+            match owners.get(&record.name) {
+                Some(&index) => triggers[index].1.push(record),
+                None => {
+                    owners.insert(record.name.clone(), triggers.len());
+                    triggers.push((record.name.clone(), vec![record]));
+                }
+            }
+        }
+
+        let client_ip_suffix = DomainName::new("rpz-client-ip")?;
+        let ip_suffix = DomainName::new("rpz-ip")?;
+        let nsdname_suffix = DomainName::new("rpz-nsdname")?;
+        let nsip_suffix = DomainName::new("rpz-nsip")?;
+        let wildcard = DomainName::new("*")?;
+
+        let mut zone = PolicyZone::default();
+        for (name, records) in triggers {
+            let Some(trigger) = name.strip_suffix(origin) else {
+                anyhow::bail!("{name} is outside of policy zone {origin}");
+            };
+            let action = PolicyAction::from_records(records);
+            if let Some(ip) = trigger.strip_suffix(&client_ip_suffix) {
+                zone.client_ip.push((parse_ip_trigger(&ip)?, action));
+            } else if let Some(ip) = trigger.strip_suffix(&ip_suffix) {
+                zone.response_ip.push((parse_ip_trigger(&ip)?, action));
+            } else if let Some(name) = trigger.strip_suffix(&nsdname_suffix) {
+                match name.strip_prefix(&wildcard) {
+                    Some(name) => zone.nsdname_wildcard.insert(name, action),
+                    None => zone.nsdname.insert(name, action),
+                };
+            } else if let Some(ip) = trigger.strip_suffix(&nsip_suffix) {
+                zone.nsip.push((parse_ip_trigger(&ip)?, action));
+            } else {
+                match trigger.strip_prefix(&wildcard) {
+                    Some(name) => zone.qname_wildcard.insert(name, action),
+                    None => zone.qname.insert(trigger, action),
+                };
+            }
+        }
+        Ok(zone)
+    }
+}
+
+impl PolicyAction {
+    fn from_records(records: Vec<ResourceRecord>) -> Self {
+        if let [ResourceRecord {
+            data: ResourceRecordData::CName(target),
+            ..
+        }] = records.as_slice()
+        {
+            // Names compare case-insensitively, like any other
+            match target.to_string().to_ascii_lowercase().as_str() {
+                "." => return PolicyAction::NxDomain,
+                "*" => return PolicyAction::NoData,
+                "rpz-passthru" => return PolicyAction::Passthru,
+                "rpz-drop" => return PolicyAction::Drop,
+                "rpz-tcp-only" => return PolicyAction::TcpOnly,
+                _ => {}
+            }
+        }
+        PolicyAction::LocalData(records)
+    }
+}
+
+/// Local data records which answer `question`, renamed to the query name.
+pub fn local_answers(records: &[ResourceRecord], question: &Question) -> Vec<ResourceRecord> {
+    let wildcard = DomainName::new("*").unwrap();
+    records
+        .iter()
+        .filter(|record| record.ty == question.ty || record.ty == RecordType::CName)
+        .map(|record| {
+            let data = match &record.data {
+                ResourceRecordData::CName(target) => match target.strip_prefix(&wildcard) {
+                    Some(suffix) => ResourceRecordData::CName(question.name.join(&suffix)),
+                    None => record.data.clone(),
+                },
+                data => data.clone(),
+            };
+            ResourceRecord::new(
+                question.name.clone(),
+                record.ty,
+                record.class,
+                record.time_to_live,
+                data,
+            )
+        })
+        .collect()
+}
+
+fn lookup<'a>(
+    exact: &'a HashMap<DomainName, PolicyAction>,
+    wildcard: &'a HashMap<DomainName, PolicyAction>,
+    name: &DomainName,
+) -> Option<&'a PolicyAction> {
+    if let Some(action) = exact.get(name) {
+        return Some(action);
+    }
+    // The closest enclosing wildcard wins
+    let mut ancestor = name.parent();
+    while let Some(domain) = ancestor {
+        if let Some(action) = wildcard.get(&domain) {
+            return Some(action);
+        }
+        ancestor = domain.parent();
+    }
+    None
+}
+
+/// The address of an A or AAAA record.
+fn address(record: &ResourceRecord) -> Option<IpAddr> {
+    match record.data {
+        ResourceRecordData::IPv4(ip) => Some(IpAddr::V4(ip.into())),
+        ResourceRecordData::IPv6(ip) => Some(IpAddr::V6(ip.into())),
+        _ => None,
+    }
+}
+
+/// How long until the next refresh of a zone with `soa`, after a refresh which worked or didn't.
+fn soa_timer(soa: &ResourceRecord, refreshed: bool) -> Duration {
+    match soa.data {
+        ResourceRecordData::StartOfAuthority { refresh, retry, .. } => {
+            Duration::from_secs(if refreshed { refresh } else { retry }.into())
+        }
+        _ => INITIAL_RETRY,
+    }
+}
+
+/// The most specific matching rule wins.
+fn longest_match(rules: &[(Cidr, PolicyAction)], addr: IpAddr) -> Option<&PolicyAction> {
+    rules
+        .iter()
+        .filter(|(cidr, _)| cidr.contains(addr))
+        .max_by_key(|(cidr, _)| cidr.prefix_len())
+        .map(|(_, action)| action)
+}
+
+/// Parse an address trigger such as `24.0.2.0.192` or `48.zz.db8.2001`, which are a prefix
+/// length followed by the address in reverse, with `zz` standing in for `::` in IPv6.
+fn parse_ip_trigger(trigger: &DomainName) -> anyhow::Result<Cidr> {
+    let trigger = trigger.to_string();
+    let mut labels = trigger.split('.');
+    let prefix_len = labels
+        .next()
+        .ok_or_else(|| anyhow::format_err!("empty address trigger"))?
+        .parse::<u8>()?;
+    let mut parts = labels.collect::<Vec<_>>();
+    parts.reverse();
+
+    let addr = if parts.len() == 4 && parts.iter().all(|p| p.parse::<u8>().is_ok()) {
+        IpAddr::V4(parts.join(".").parse::<Ipv4Addr>()?)
+    } else {
+        let addr = parts
+            .iter()
+            .map(|p| if *p == "zz" { "" } else { p })
+            .collect::<Vec<_>>()
+            .join(":");
+        // A leading or trailing `zz` needs a doubled colon to be valid
+        let addr = match (addr.starts_with(':'), addr.ends_with(':')) {
+            (true, _) => format!(":{addr}"),
+            (_, true) => format!("{addr}:"),
+            _ => addr,
+        };
+        IpAddr::V6(addr.parse::<Ipv6Addr>()?)
+    };
+    Cidr::new(addr, prefix_len)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        message::Class,
+        secondary::tests::StandInPrimary,
+        zone::{AXFR, IXFR},
+        zone_file,
+    };
+
+    use super::*;
+
+    const POLICY_ZONE: &str = "
+$TTL 300
+@                           SOA   localhost. admin.localhost. 1 3600 600 86400 60
+                            NS    localhost.
+nx.test                     CNAME .
+nodata.test                 CNAME *.
+*.wild.test                 CNAME rpz-drop.
+ok.wild.test                CNAME rpz-passthru.
+*.deep.wild.test            CNAME rpz-tcp-only.
+local.test                  A     10.9.8.7
+garden.test                 CNAME *.garden.example.
+24.0.2.0.192.rpz-client-ip  CNAME rpz-drop.
+32.1.2.0.192.rpz-client-ip  CNAME rpz-passthru.
+48.zz.db8.2001.rpz-client-ip CNAME .
+32.4.3.2.1.rpz-ip           CNAME *.
+ns.bad.example.rpz-nsdname  CNAME .
+*.worse.example.rpz-nsdname CNAME rpz-drop.
+24.0.113.0.203.rpz-nsip     CNAME rpz-tcp-only.
+local.test                  A     10.9.8.6
+upper.test                  CNAME RPZ-PASSTHRU.
+";
+
+    fn name(name: &str) -> DomainName {
+        DomainName::new(name).unwrap()
+    }
+
+    fn policy_zone(contents: &str) -> PolicyZone {
+        let origin = name("rpz.local");
+        PolicyZone::new(&origin, zone_file::parse(contents, &origin).unwrap()).unwrap()
+    }
+
+    fn policy(contents: &str) -> ResponsePolicy {
+        ResponsePolicy {
+            zones: vec![LoadedZone {
+                origin: name("rpz.local"),
+                triggers: RwLock::new(policy_zone(contents)),
+                primary: None,
+            }],
+        }
+    }
+
+    fn question(qname: &str) -> Question {
+        Question {
+            name: name(qname),
+            ty: RecordType::Address,
+            class: Class::Internet,
+        }
+    }
+
+    fn record(owner: &str, data: ResourceRecordData) -> ResourceRecord {
+        let ty = match data {
+            ResourceRecordData::IPv4(_) => RecordType::Address,
+            ResourceRecordData::NameServer(_) => RecordType::NameServer,
+            _ => RecordType::CName,
+        };
+        ResourceRecord::new(name(owner), ty, Class::Internet, 300, data)
+    }
+
+    fn query_action(policy: &ResponsePolicy, client: &str, qname: &str) -> Option<PolicyAction> {
+        policy.check_query(client.parse().unwrap(), &[question(qname)])
+    }
+
+    #[test]
+    fn query_triggers_match() {
+        use PolicyAction::*;
+        let policy = policy(POLICY_ZONE);
+        let action = |qname| query_action(&policy, "10.0.0.1", qname);
+        assert_eq!(action("nx.test"), Some(NxDomain));
+        assert_eq!(action("nodata.test"), Some(NoData));
+        assert_eq!(action("a.nx.test"), None);
+        assert_eq!(action("a.wild.test"), Some(Drop));
+        assert_eq!(action("ok.wild.test"), Some(Passthru));
+        // The closest wildcard wins
+        assert_eq!(action("a.deep.wild.test"), Some(TcpOnly));
+        assert_eq!(action("wild.test"), None);
+        // Records for the same owner are kept together, even when they aren't next to each other
+        assert_eq!(
+            action("local.test"),
+            Some(LocalData(vec![
+                record(
+                    "local.test.rpz.local",
+                    ResourceRecordData::IPv4([10, 9, 8, 7])
+                ),
+                record(
+                    "local.test.rpz.local",
+                    ResourceRecordData::IPv4([10, 9, 8, 6])
+                ),
+            ]))
+        );
+        // Actions are names, so they compare case-insensitively
+        assert_eq!(action("upper.test"), Some(Passthru));
+
+        // The most specific client address wins, and comes before the query name
+        let action = |client| query_action(&policy, client, "nx.test");
+        assert_eq!(action("192.0.2.7"), Some(Drop));
+        assert_eq!(action("192.0.2.1"), Some(Passthru));
+        assert_eq!(action("2001:db8::1"), Some(NxDomain));
+        assert_eq!(action("2001:db9::1"), Some(NxDomain));
+    }
+
+    #[test]
+    fn response_triggers_match() {
+        use PolicyAction::*;
+        let policy = policy(POLICY_ZONE);
+        let query_message = Message::new_query(vec![question("example.com")]);
+        let response = |answers, authorities, additionals| {
+            let mut response =
+                Message::new_reply(&query_message, query_message.questions.clone(), answers);
+            response.authorities = authorities;
+            response.additionals = additionals;
+            response
+        };
+        let address = |owner, ip| record(owner, ResourceRecordData::IPv4(ip));
+        let name_server = |owner, ns| record(owner, ResourceRecordData::NameServer(name(ns)));
+
+        let clean = response(
+            vec![address("example.com", [1, 2, 3, 5])],
+            vec![name_server("example.com", "ns.example.com")],
+            vec![address("ns.example.com", [203, 0, 114, 1])],
+        );
+        assert_eq!(policy.check_response(&clean), None);
+
+        let bad_answer = response(vec![address("example.com", [1, 2, 3, 4])], vec![], vec![]);
+        assert_eq!(policy.check_response(&bad_answer), Some(NoData));
+
+        let bad_name_server = response(
+            vec![],
+            vec![name_server("example.com", "ns.bad.example")],
+            vec![],
+        );
+        assert_eq!(policy.check_response(&bad_name_server), Some(NxDomain));
+        let worse_name_server = response(
+            vec![],
+            vec![name_server("example.com", "a.ns.worse.example")],
+            vec![],
+        );
+        assert_eq!(policy.check_response(&worse_name_server), Some(Drop));
+
+        let bad_glue = response(
+            vec![address("example.com", [1, 2, 3, 5])],
+            vec![name_server("example.com", "ns.example.com")],
+            vec![address("ns.example.com", [203, 0, 113, 53])],
+        );
+        assert_eq!(policy.check_response(&bad_glue), Some(TcpOnly));
+        // Only the addresses of name servers are name server addresses
+        let not_glue = response(
+            vec![],
+            vec![name_server("example.com", "ns.example.com")],
+            vec![address("www.example.com", [203, 0, 113, 53])],
+        );
+        assert_eq!(policy.check_response(&not_glue), None);
+    }
+
+    #[test]
+    fn local_data_is_renamed() {
+        let policy = policy(POLICY_ZONE);
+        let Some(PolicyAction::LocalData(records)) =
+            policy.check_query("10.0.0.1".parse().unwrap(), &[question("garden.test")])
+        else {
+            panic!("expected local data");
+        };
+        let answers = local_answers(&records, &question("garden.test"));
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0].name, name("garden.test"));
+        let ResourceRecordData::CName(target) = &answers[0].data else {
+            panic!("expected a CNAME");
+        };
+        assert_eq!(*target, name("garden.test.garden.example"));
+    }
+
+    #[test]
+    fn ip_triggers_parse() {
+        let cidr = |trigger| parse_ip_trigger(&name(trigger));
+        let expected =
+            |addr: &str, prefix_len| Cidr::new(addr.parse().unwrap(), prefix_len).unwrap();
+        assert_eq!(cidr("24.0.2.0.192").unwrap(), expected("192.0.2.0", 24));
+        assert_eq!(cidr("48.zz.db8.2001").unwrap(), expected("2001:db8::", 48));
+        assert_eq!(
+            cidr("128.1.zz.db8.2001").unwrap(),
+            expected("2001:db8::1", 128)
+        );
+        assert_eq!(cidr("128.zz.1").unwrap(), expected("1::", 128));
+        assert!(cidr("33.1.2.0.192").is_err());
+        assert!(cidr("24.2.0.192").is_err());
+        assert!(cidr("x.1.2.0.192").is_err());
+    }
+
+    #[test]
+    fn triggers_outside_the_zone_are_errors() {
+        let origin = name("rpz.local");
+        let records = zone_file::parse("nx.test.other. CNAME .", &origin).unwrap();
+        assert!(PolicyZone::new(&origin, records).is_err());
+    }
+
+    #[test]
+    fn policy_zones_are_transferred_and_refreshed() {
+        use PolicyAction::*;
+        let origin = name("rpz.local");
+        let version = |serial: u32, extra: &str| {
+            let contents = POLICY_ZONE.replace(
+                "1 3600 600 86400 60",
+                &format!("{serial} 3600 600 86400 60"),
+            );
+            zone_file::parse(&format!("{contents}{extra}\n"), &origin).unwrap()
+        };
+        let primary = StandInPrimary::start(version(1, ""));
+        let config = RpzConfig {
+            zones: vec![(origin.clone(), PolicySource::Primary(primary.addr))],
+        };
+        let policy = ResponsePolicy::load(&config).unwrap();
+        let refresh_now = || {
+            let zone = &policy.zones[0];
+            zone.primary.as_ref().unwrap().lock().unwrap().refresh_at = Instant::now();
+        };
+        let action = |qname| query_action(&policy, "10.0.0.1", qname);
+
+        // Nothing until the first transfer
+        assert_eq!(action("nx.test"), None);
+        policy.maintain();
+        assert_eq!(action("nx.test"), Some(NxDomain));
+        assert_eq!(
+            primary.state.lock().unwrap().queries,
+            vec![RecordType::StartOfAuthority, AXFR]
+        );
+
+        // Not due yet
+        policy.maintain();
+        assert_eq!(primary.state.lock().unwrap().queries.len(), 2);
+
+        // Up to date, so only the SOA is asked for
+        refresh_now();
+        policy.maintain();
+        assert_eq!(primary.state.lock().unwrap().queries.len(), 3);
+
+        // A new version, with IXFR refused so that it falls back to AXFR
+        primary.state.lock().unwrap().records = version(2, "new.test CNAME .");
+        refresh_now();
+        policy.maintain();
+        assert_eq!(action("new.test"), Some(NxDomain));
+        assert_eq!(
+            primary.state.lock().unwrap().queries[3..],
+            [RecordType::StartOfAuthority, IXFR, AXFR]
+        );
+
+        // An incremental change
+        let new_records = version(3, "");
+        let mut ixfr = vec![new_records[0].clone(), version(2, "")[0].clone()];
+        ixfr.extend(zone_file::parse("$TTL 300\nnew.test CNAME .", &origin).unwrap());
+        ixfr.push(new_records[0].clone());
+        ixfr.extend(zone_file::parse("$TTL 300\nnewer.test CNAME .", &origin).unwrap());
+        ixfr.push(new_records[0].clone());
+        {
+            let mut state = primary.state.lock().unwrap();
+            state.records = new_records;
+            state.ixfr = Some(ixfr);
+        }
+        refresh_now();
+        policy.maintain();
+        assert_eq!(action("new.test"), None);
+        assert_eq!(action("newer.test"), Some(NxDomain));
+        assert_eq!(action("nx.test"), Some(NxDomain));
+        assert_eq!(
+            primary.state.lock().unwrap().queries[6..],
+            [RecordType::StartOfAuthority, IXFR]
+        );
+    }
+}
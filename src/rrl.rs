@@ -0,0 +1,518 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use crate::message::{Message, RecordType, ResponseCode};
+
+/// The most buckets kept at once. Past this the least recently used bucket is forgotten, so a
+/// flood from spoofed sources can't grow the table without bound.
+const MAX_BUCKETS: usize = 10_000;
+/// At most one line is logged per this interval when clients start being limited. The rest are
+/// only counted, and show up in the periodic stats.
+const LOG_INTERVAL: Duration = Duration::from_secs(1);
+/// How often the counters are logged, if they've changed.
+pub const STATS_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct RrlConfig {
+    /// Identical responses allowed per second to a single client network.
+    pub responses_per_second: u32,
+    /// Answer every Nth limited response with TC=1 instead of dropping it. 0 never slips.
+    pub slip: u32,
+    /// How far back a client's excess responses are remembered, in seconds.
+    pub window: u32,
+    /// Only log what would have been limited, without changing any responses.
+    pub log_only: bool,
+}
+
+/// What to do with a response after rate limiting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RrlAction {
+    /// Send the response as normal.
+    Send,
+    /// Don't send anything.
+    Drop,
+    /// Send an empty truncated response, so legitimate clients retry over TCP.
+    Slip,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RrlStats {
+    /// Responses sent unchanged.
+    pub sent: u64,
+    /// Responses dropped.
+    pub dropped: u64,
+    /// Responses replaced with a truncated response.
+    pub slipped: u64,
+    /// Responses which would have been limited in log-only mode.
+    pub logged: u64,
+}
+
+/// Which responses are counted together: the client network plus what was answered, or the zone
+/// for NXDOMAIN and NODATA answers.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct BucketKey {
+    network: IpAddr,
+    name: String,
+    ty: Option<RecordType>,
+    response_code: u16,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Remaining responses, negative when the client is being limited.
+    balance: f64,
+    last_update: Instant,
+    /// Limited responses since the bucket was last under its limit.
+    limited: u32,
+    /// Position in `ResponseRateLimiter::by_use`.
+    last_use: u64,
+}
+
+#[derive(Debug)]
+pub struct ResponseRateLimiter {
+    config: RrlConfig,
+    buckets: HashMap<BucketKey, Bucket>,
+    /// Bucket keys from least to most recently used.
+    by_use: BTreeMap<u64, BucketKey>,
+    next_use: u64,
+    stats: RrlStats,
+    last_log: Option<Instant>,
+    /// Clients which started being limited since the last line was logged.
+    unlogged: u64,
+}
+
+impl Default for RrlConfig {
+    fn default() -> Self {
+        RrlConfig {
+            responses_per_second: 0,
+            slip: 2,
+            window: 15,
+            log_only: false,
+        }
+    }
+}
+
+impl ResponseRateLimiter {
+    pub fn new(config: RrlConfig) -> Self {
+        ResponseRateLimiter {
+            config,
+            buckets: HashMap::new(),
+            by_use: BTreeMap::new(),
+            next_use: 0,
+            stats: RrlStats::default(),
+            last_log: None,
+            unlogged: 0,
+        }
+    }
+
+    /// The counters, or nothing if rate limiting is off.
+    pub fn stats(&self) -> Option<RrlStats> {
+        (self.config.responses_per_second != 0).then_some(self.stats)
+    }
+
+    /// Account for sending `response` to `client` at time `now`.
+    ///
+    /// Time is passed in rather than read from the system clock so that the limiter can be driven
+    /// by a simulated clock.
+    pub fn check(&mut self, now: Instant, client: IpAddr, response: &Message) -> RrlAction {
+        let rate = self.config.responses_per_second as f64;
+        if rate == 0.0 {
+            self.stats.sent += 1;
+            return RrlAction::Send;
+        }
+
+        self.prune(now);
+
+        let key = BucketKey::new(client, response);
+        if !self.buckets.contains_key(&key) && self.buckets.len() >= MAX_BUCKETS {
+            self.evict_oldest();
+        }
+        let last_use = self.next_use;
+        self.next_use += 1;
+        let bucket = self.buckets.entry(key.clone()).or_insert(Bucket {
+            balance: rate,
+            last_update: now,
+            limited: 0,
+            last_use,
+        });
+        self.by_use.remove(&bucket.last_use);
+        self.by_use.insert(last_use, key.clone());
+        bucket.last_use = last_use;
+        let elapsed = now.saturating_duration_since(bucket.last_update);
+        bucket.last_update = now;
+        bucket.balance = (bucket.balance + elapsed.as_secs_f64() * rate).min(rate) - 1.0;
+        // Don't let a client dig a hole deeper than the window, so it recovers within that time
+        bucket.balance = bucket.balance.max(-rate * self.config.window as f64);
+
+        if bucket.balance >= 0.0 {
+            bucket.limited = 0;
+            self.stats.sent += 1;
+            return RrlAction::Send;
+        }
+
+        bucket.limited += 1;
+        let limited = bucket.limited;
+        if limited == 1 {
+            self.log_limiting(now, &key);
+        }
+        if self.config.log_only {
+            self.stats.logged += 1;
+            RrlAction::Send
+        } else if limited.checked_rem(self.config.slip) == Some(0) {
+            self.stats.slipped += 1;
+            RrlAction::Slip
+        } else {
+            self.stats.dropped += 1;
+            RrlAction::Drop
+        }
+    }
+
+    /// Log that responses for `key` have started being limited, unless a line was logged recently.
+    fn log_limiting(&mut self, now: Instant, key: &BucketKey) {
+        let recent = self
+            .last_log
+            .is_some_and(|last_log| now.saturating_duration_since(last_log) < LOG_INTERVAL);
+        if recent {
+            self.unlogged += 1;
+            return;
+        }
+        eprintln!(
+            "rrl: {}limiting responses to {}/{} for {} ({} more since the last message; sent {}, dropped {}, slipped {}, logged {})",
+            if self.config.log_only {
+                "would start "
+            } else {
+                ""
+            },
+            key.network,
+            if key.network.is_ipv4() { 24 } else { 56 },
+            key.name,
+            self.unlogged,
+            self.stats.sent,
+            self.stats.dropped,
+            self.stats.slipped,
+            self.stats.logged,
+        );
+        self.last_log = Some(now);
+        self.unlogged = 0;
+    }
+
+    /// Forget the least recently used buckets while they've fully recovered their balance.
+    ///
+    /// Each bucket is only looked at once it's the least recently used, so the work per response
+    /// stays small however large the table is.
+    fn prune(&mut self, now: Instant) {
+        let rate = self.config.responses_per_second as f64;
+        let window = Duration::from_secs(self.config.window as u64);
+        while let Some((_, key)) = self.by_use.first_key_value() {
+            let bucket = &self.buckets[key];
+            let elapsed = now.saturating_duration_since(bucket.last_update);
+            if elapsed < window && bucket.balance + elapsed.as_secs_f64() * rate < rate {
+                break;
+            }
+            self.evict_oldest();
+        }
+    }
+
+    /// Forget the least recently used bucket.
+    fn evict_oldest(&mut self) {
+        if let Some((_, key)) = self.by_use.pop_first() {
+            self.buckets.remove(&key);
+        }
+    }
+}
+
+impl BucketKey {
+    fn new(client: IpAddr, response: &Message) -> Self {
+        let network = match client {
+            IpAddr::V4(addr) => IpAddr::V4((u32::from(addr) & 0xFFFF_FF00).into()),
+            IpAddr::V6(addr) => IpAddr::V6((u128::from(addr) & !((1u128 << 72) - 1)).into()),
+        };
+        let question = response.questions.first();
+        let code = response.header.response_code;
+        let negative = code == ResponseCode::NameError
+            || (code == ResponseCode::Ok && response.answers.is_empty());
+        // Negative answers are counted per zone rather than per name, as in BIND, so that a flood
+        // of queries for random names under one zone is limited together
+        let zone = response
+            .authorities
+            .iter()
+            .find(|record| record.ty == RecordType::StartOfAuthority)
+            .filter(|_| negative)
+            .map(|record| &record.name);
+        BucketKey {
+            network,
+            name: zone
+                .or(question.map(|q| &q.name))
+                .map(|name| name.to_string().to_ascii_lowercase())
+                .unwrap_or_default(),
+            // Errors are grouped together regardless of type, as in BIND
+            ty: match code {
+                ResponseCode::Ok if zone.is_none() => question.map(|q| q.ty),
+                _ => None,
+            },
+            response_code: response.header.response_code.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::message::{Class, DomainName, Question, ResourceRecord, ResourceRecordData};
+
+    use super::*;
+
+    fn config(responses_per_second: u32, slip: u32) -> RrlConfig {
+        RrlConfig {
+            responses_per_second,
+            slip,
+            window: 3,
+            log_only: false,
+        }
+    }
+
+    fn response(qname: &str, ty: RecordType, response_code: ResponseCode) -> Message {
+        let query_message = Message::new_query(vec![Question {
+            name: DomainName::new(qname).unwrap(),
+            ty,
+            class: Class::Internet,
+        }]);
+        let mut response =
+            Message::new_reply(&query_message, query_message.questions.clone(), Vec::new());
+        response.header.response_code = response_code;
+        response
+    }
+
+    /// A negative response for `qname` from the zone `zone`.
+    fn negative_response(qname: &str, zone: &str, response_code: ResponseCode) -> Message {
+        let mut response = response(qname, RecordType::Address, response_code);
+        response.authorities.push(ResourceRecord::new(
+            DomainName::new(zone).unwrap(),
+            RecordType::StartOfAuthority,
+            Class::Internet,
+            300,
+            ResourceRecordData::StartOfAuthority {
+                primary_name_server: DomainName::new("ns.example").unwrap(),
+                responsible_mailbox: DomainName::new("admin.example").unwrap(),
+                serial: 1,
+                refresh: 2,
+                retry: 3,
+                expire: 4,
+                minimum: 5,
+            },
+        ));
+        response
+    }
+
+    fn addr(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    /// The actions for `count` identical responses to `client` at `now`.
+    fn check_many(
+        limiter: &mut ResponseRateLimiter,
+        now: Instant,
+        client: &str,
+        count: usize,
+    ) -> Vec<RrlAction> {
+        let response = response("example.com", RecordType::Address, ResponseCode::Ok);
+        (0..count)
+            .map(|_| limiter.check(now, addr(client), &response))
+            .collect()
+    }
+
+    #[test]
+    fn disabled_sends_everything() {
+        let mut limiter = ResponseRateLimiter::new(config(0, 2));
+        let actions = check_many(&mut limiter, Instant::now(), "192.0.2.1", 100);
+        assert!(actions.iter().all(|action| *action == RrlAction::Send));
+        assert_eq!(limiter.stats(), None);
+    }
+
+    #[test]
+    fn tokens_refill_over_time() {
+        use RrlAction::*;
+        let start = Instant::now();
+        let mut limiter = ResponseRateLimiter::new(config(2, 0));
+        assert_eq!(
+            check_many(&mut limiter, start, "192.0.2.1", 3),
+            [Send, Send, Drop]
+        );
+        // Half a second earns one response back, which the limited one already owes
+        let now = start + Duration::from_millis(500);
+        assert_eq!(check_many(&mut limiter, now, "192.0.2.1", 1), [Drop]);
+        // Time only ever refills up to the limit
+        let now = start + Duration::from_secs(60);
+        assert_eq!(
+            check_many(&mut limiter, now, "192.0.2.1", 3),
+            [Send, Send, Drop]
+        );
+        // A clock going backwards doesn't refill or drain anything
+        let now = start + Duration::from_secs(30);
+        assert_eq!(check_many(&mut limiter, now, "192.0.2.1", 1), [Drop]);
+    }
+
+    #[test]
+    fn every_nth_limited_response_slips() {
+        use RrlAction::*;
+        let now = Instant::now();
+        let mut limiter = ResponseRateLimiter::new(config(1, 2));
+        assert_eq!(
+            check_many(&mut limiter, now, "192.0.2.1", 6),
+            [Send, Drop, Slip, Drop, Slip, Drop]
+        );
+        let mut limiter = ResponseRateLimiter::new(config(1, 1));
+        assert_eq!(
+            check_many(&mut limiter, now, "192.0.2.1", 3),
+            [Send, Slip, Slip]
+        );
+        assert_eq!(
+            limiter.stats(),
+            Some(RrlStats {
+                sent: 1,
+                dropped: 0,
+                slipped: 2,
+                logged: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn clients_recover_within_the_window() {
+        use RrlAction::*;
+        let start = Instant::now();
+        let mut limiter = ResponseRateLimiter::new(config(2, 0));
+        // A flood far beyond what the window remembers
+        check_many(&mut limiter, start, "192.0.2.1", 1000);
+        // The debt is capped at the window's worth, so it's paid off in about three seconds
+        // rather than the five hundred the flood would otherwise take
+        let now = start + Duration::from_millis(2900);
+        assert_eq!(check_many(&mut limiter, now, "192.0.2.1", 1), [Drop]);
+        let now = start + Duration::from_millis(5000);
+        assert_eq!(check_many(&mut limiter, now, "192.0.2.1", 1), [Send]);
+    }
+
+    #[test]
+    fn log_only_counts_without_limiting() {
+        let now = Instant::now();
+        let mut limiter = ResponseRateLimiter::new(RrlConfig {
+            log_only: true,
+            ..config(1, 2)
+        });
+        let actions = check_many(&mut limiter, now, "192.0.2.1", 5);
+        assert!(actions.iter().all(|action| *action == RrlAction::Send));
+        assert_eq!(
+            limiter.stats(),
+            Some(RrlStats {
+                sent: 1,
+                dropped: 0,
+                slipped: 0,
+                logged: 4,
+            })
+        );
+    }
+
+    #[test]
+    fn responses_are_grouped_by_network_and_answer() {
+        use RrlAction::*;
+        let now = Instant::now();
+        let mut limiter = ResponseRateLimiter::new(config(1, 0));
+        assert_eq!(check_many(&mut limiter, now, "192.0.2.1", 1), [Send]);
+        // Same /24
+        assert_eq!(check_many(&mut limiter, now, "192.0.2.200", 1), [Drop]);
+        assert_eq!(check_many(&mut limiter, now, "192.0.3.1", 1), [Send]);
+        assert_eq!(check_many(&mut limiter, now, "2001:db8:0:1::1", 1), [Send]);
+        // Same /56
+        assert_eq!(check_many(&mut limiter, now, "2001:db8:0:2::1", 1), [Drop]);
+        assert_eq!(
+            check_many(&mut limiter, now, "2001:db8:0:100::1", 1),
+            [Send]
+        );
+
+        let client = addr("198.51.100.1");
+        let mut check = |qname, ty, response_code| {
+            limiter.check(now, client, &response(qname, ty, response_code))
+        };
+        assert_eq!(
+            check("a.example", RecordType::Address, ResponseCode::Ok),
+            Send
+        );
+        assert_eq!(
+            check("A.EXAMPLE", RecordType::Address, ResponseCode::Ok),
+            Drop
+        );
+        assert_eq!(
+            check("a.example", RecordType::MailExchange, ResponseCode::Ok),
+            Send
+        );
+        assert_eq!(
+            check("b.example", RecordType::Address, ResponseCode::Ok),
+            Send
+        );
+        // Errors for a name are counted together whatever the type
+        assert_eq!(
+            check("c.example", RecordType::Address, ResponseCode::NameError),
+            Send
+        );
+        assert_eq!(
+            check(
+                "c.example",
+                RecordType::MailExchange,
+                ResponseCode::NameError
+            ),
+            Drop
+        );
+    }
+
+    #[test]
+    fn negative_answers_are_grouped_by_zone() {
+        use RrlAction::*;
+        let now = Instant::now();
+        let mut limiter = ResponseRateLimiter::new(config(1, 0));
+        let client = addr("192.0.2.1");
+        let mut check = |qname, zone, response_code| {
+            limiter.check(now, client, &negative_response(qname, zone, response_code))
+        };
+        assert_eq!(check("a.example", "example", ResponseCode::NameError), Send);
+        // Random names under the same zone share a bucket
+        assert_eq!(check("b.example", "EXAMPLE", ResponseCode::NameError), Drop);
+        assert_eq!(check("a.example", "example", ResponseCode::Ok), Send);
+        assert_eq!(check("c.example", "example", ResponseCode::Ok), Drop);
+        assert_eq!(check("a.test", "test", ResponseCode::NameError), Send);
+    }
+
+    #[test]
+    fn idle_buckets_are_pruned() {
+        let start = Instant::now();
+        let mut limiter = ResponseRateLimiter::new(config(1, 0));
+        check_many(&mut limiter, start, "192.0.2.1", 5);
+        check_many(&mut limiter, start, "192.0.3.1", 1);
+        assert_eq!(limiter.buckets.len(), 2);
+        // The first client still owes responses, but both have recovered after the window
+        let now = start + Duration::from_secs(2);
+        check_many(&mut limiter, now, "192.0.4.1", 1);
+        assert_eq!(limiter.buckets.len(), 3);
+        let now = start + Duration::from_secs(4);
+        check_many(&mut limiter, now, "192.0.4.1", 1);
+        assert_eq!(limiter.buckets.len(), 1);
+    }
+
+    #[test]
+    fn the_table_is_capped() {
+        use RrlAction::*;
+        let now = Instant::now();
+        let mut limiter = ResponseRateLimiter::new(config(1, 0));
+        assert_eq!(check_many(&mut limiter, now, "192.0.2.1", 2), [Send, Drop]);
+        for i in 0..MAX_BUCKETS as u32 {
+            let client = IpAddr::V4((0x0A00_0000 + (i << 8)).into());
+            let response = response("example.com", RecordType::Address, ResponseCode::Ok);
+            limiter.check(now, client, &response);
+        }
+        assert_eq!(limiter.buckets.len(), MAX_BUCKETS);
+        assert_eq!(limiter.by_use.len(), MAX_BUCKETS);
+        // The least recently used bucket was forgotten to make room
+        assert_eq!(check_many(&mut limiter, now, "192.0.2.1", 1), [Send]);
+    }
+}
@@ -0,0 +1,334 @@
+//! The client side of zone transfers, for secondary zones: asking the primary for its SOA, then
+//! fetching what's changed by IXFR, or the whole zone by AXFR if IXFR doesn't work out. With a
+//! TSIG key, every request is signed and every reply must be.
+
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream},
+    time::Duration,
+};
+
+use bytes::BytesMut;
+
+use crate::{
+    forward::query_udp,
+    message::{Class, DomainName, Message, Question, RecordType, ResourceRecord, ResponseCode},
+    tsig::{Key, Session},
+    zone::{serial, Change, AXFR, IXFR},
+};
+
+/// How long to wait for the primary, per message.
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What a transfer from the primary brought.
+#[derive(Debug)]
+pub enum Transfer {
+    /// The zone hasn't changed since the serial we have.
+    UpToDate,
+    /// The changes since the serial we have, oldest first.
+    Incremental(Vec<Change>),
+    /// The whole zone, SOA included.
+    Full(Vec<ResourceRecord>),
+}
+
+/// The primary's current SOA for `origin`.
+pub fn query_soa(
+    primary: SocketAddr,
+    origin: &DomainName,
+    key: Option<&Key>,
+) -> anyhow::Result<ResourceRecord> {
+    let mut query_message = Message::new_query(vec![Question {
+        name: origin.clone(),
+        ty: RecordType::StartOfAuthority,
+        class: Class::Internet,
+    }]);
+    let mut session = key.cloned().map(Session::new);
+    if let Some(session) = &mut session {
+        session.sign(&mut query_message)?;
+    }
+    let mut msg = BytesMut::with_capacity(64);
+    query_message.write(&mut msg)?;
+    let response_message = Message::parse(&query_udp(&msg, primary, TRANSFER_TIMEOUT)?)?;
+    if response_message.header.packet_id != query_message.header.packet_id {
+        anyhow::bail!("mismatched response id from {primary}");
+    }
+    if let Some(session) = &mut session {
+        session
+            .verify_response(&response_message)
+            .map_err(|e| anyhow::format_err!("{primary}: {e}"))?;
+    }
+    if !matches!(response_message.header.response_code, ResponseCode::Ok)
+        || !response_message.header.authoritative_answer
+    {
+        anyhow::bail!(
+            "{primary} isn't authoritative for {origin}: {:?}",
+            response_message.header.response_code
+        );
+    }
+    response_message
+        .answers
+        .into_iter()
+        .find(|record| record.ty == RecordType::StartOfAuthority && record.name == *origin)
+        .ok_or_else(|| anyhow::format_err!("no SOA for {origin} from {primary}"))
+}
+
+/// Fetch the changes to `origin` since `current_soa` by IXFR, or the whole zone by AXFR if there's
+/// no SOA yet or IXFR fails.
+pub fn transfer(
+    primary: SocketAddr,
+    origin: &DomainName,
+    current_soa: Option<&ResourceRecord>,
+    key: Option<&Key>,
+) -> anyhow::Result<Transfer> {
+    if let Some(current_soa) = current_soa {
+        match transfer_records(primary, origin, Some(current_soa), key) {
+            Ok(records) => return parse_transfer(records, true),
+            Err(e) => eprintln!("ixfr of {origin} from {primary} failed, trying axfr: {e}"),
+        }
+    }
+    parse_transfer(transfer_records(primary, origin, None, key)?, false)
+}
+
+/// The answers from every message of a transfer, IXFR if `current_soa` is given.
+fn transfer_records(
+    primary: SocketAddr,
+    origin: &DomainName,
+    current_soa: Option<&ResourceRecord>,
+    key: Option<&Key>,
+) -> anyhow::Result<Vec<ResourceRecord>> {
+    let mut query_message = Message::new_query(vec![Question {
+        name: origin.clone(),
+        ty: if current_soa.is_some() { IXFR } else { AXFR },
+        class: Class::Internet,
+    }]);
+    query_message.authorities.extend(current_soa.cloned());
+    let mut session = key.cloned().map(Session::new);
+    if let Some(session) = &mut session {
+        session.sign(&mut query_message)?;
+    }
+    let mut msg = BytesMut::with_capacity(64);
+    query_message.write(&mut msg)?;
+
+    let mut stream = TcpStream::connect_timeout(&primary, TRANSFER_TIMEOUT)?;
+    stream.set_read_timeout(Some(TRANSFER_TIMEOUT))?;
+    stream.set_write_timeout(Some(TRANSFER_TIMEOUT))?;
+    stream.write_all(&(msg.len() as u16).to_be_bytes())?;
+    stream.write_all(&msg)?;
+
+    let mut records = Vec::new();
+    let mut first_message = true;
+    loop {
+        let mut length = [0; 2];
+        stream.read_exact(&mut length)?;
+        let mut buf = vec![0; u16::from_be_bytes(length) as usize];
+        stream.read_exact(&mut buf)?;
+        let response_message = Message::parse(&buf)?;
+        if response_message.header.packet_id != query_message.header.packet_id {
+            anyhow::bail!("mismatched response id");
+        }
+        if let Some(session) = &mut session {
+            session.verify_response(&response_message)?;
+        }
+        if !matches!(response_message.header.response_code, ResponseCode::Ok) {
+            anyhow::bail!("{:?}", response_message.header.response_code);
+        }
+        records.extend(response_message.answers);
+        // An IXFR reply of just the SOA says we're up to date (RFC 1995 section 4)
+        let up_to_date = current_soa.is_some() && first_message && records.len() == 1;
+        if up_to_date || is_complete(&records) {
+            return Ok(records);
+        }
+        first_message = false;
+    }
+}
+
+/// Whether `records` make up a whole transfer, ending with the first SOA again.
+fn is_complete(records: &[ResourceRecord]) -> bool {
+    let Some(first) = records.first() else {
+        return false;
+    };
+    if records.len() < 2 {
+        return false;
+    }
+    let final_serial = serial(first);
+    let soas = records
+        .iter()
+        .skip(1)
+        .filter(|record| record.ty == RecordType::StartOfAuthority)
+        .map(serial)
+        .collect::<Vec<_>>();
+    if records[1].ty != RecordType::StartOfAuthority || serial(&records[1]) == final_serial {
+        // AXFR style, where the SOA only comes again at the end
+        return !soas.is_empty();
+    }
+    // IXFR style, with the old and new SOA of each change, then the final SOA where the next old
+    // SOA would be
+    soas.len() % 2 == 1
+        && soas.last() == Some(&final_serial)
+        && records
+            .last()
+            .is_some_and(|record| record.ty == RecordType::StartOfAuthority)
+}
+
+fn parse_transfer(records: Vec<ResourceRecord>, incremental: bool) -> anyhow::Result<Transfer> {
+    let Some(first) = records.first() else {
+        anyhow::bail!("empty transfer");
+    };
+    if first.ty != RecordType::StartOfAuthority {
+        anyhow::bail!("transfer should start with an SOA");
+    }
+    if records.len() == 1 && incremental {
+        return Ok(Transfer::UpToDate);
+    }
+    let is_axfr =
+        records[1].ty != RecordType::StartOfAuthority || serial(&records[1]) == serial(first);
+    if is_axfr {
+        // Everything but the closing SOA
+        let mut records = records;
+        records.pop();
+        return Ok(Transfer::Full(records));
+    }
+
+    let mut changes = Vec::new();
+    let mut records = records.into_iter().skip(1).peekable();
+    loop {
+        let old_soa = records
+            .next()
+            .ok_or_else(|| anyhow::format_err!("incomplete ixfr"))?;
+        if records.peek().is_none() {
+            // The closing SOA
+            break;
+        }
+        let mut removed = Vec::new();
+        while let Some(record) = records.next_if(|record| record.ty != RecordType::StartOfAuthority)
+        {
+            removed.push(record);
+        }
+        let new_soa = records
+            .next()
+            .ok_or_else(|| anyhow::format_err!("incomplete ixfr"))?;
+        let mut added = Vec::new();
+        while let Some(record) = records.next_if(|record| record.ty != RecordType::StartOfAuthority)
+        {
+            added.push(record);
+        }
+        changes.push(Change {
+            old_soa,
+            removed,
+            new_soa,
+            added,
+        });
+    }
+    Ok(Transfer::Incremental(changes))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        net::{TcpListener, UdpSocket},
+        sync::{Arc, Mutex},
+        thread,
+    };
+
+    use super::*;
+
+    /// What a `StandInPrimary` serves, which tests can change as they go.
+    #[derive(Debug, Default)]
+    pub(crate) struct PrimaryState {
+        /// The zone, SOA first.
+        pub records: Vec<ResourceRecord>,
+        /// The answers to IXFR, SOA first and last, or NOTIMP if there are none.
+        pub ixfr: Option<Vec<ResourceRecord>>,
+        /// The type of every question asked so far.
+        pub queries: Vec<RecordType>,
+        /// Answer everything with REFUSED, as if the primary had stopped serving the zone.
+        pub refuse: bool,
+    }
+
+    /// A primary on a local port, answering SOA queries over UDP and transfers over TCP.
+    pub(crate) struct StandInPrimary {
+        pub addr: SocketAddr,
+        pub state: Arc<Mutex<PrimaryState>>,
+    }
+
+    impl StandInPrimary {
+        pub(crate) fn start(records: Vec<ResourceRecord>) -> Self {
+            let (udp_socket, tcp_listener) = loop {
+                let udp_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+                if let Ok(tcp_listener) = TcpListener::bind(udp_socket.local_addr().unwrap()) {
+                    break (udp_socket, tcp_listener);
+                }
+            };
+            let addr = udp_socket.local_addr().unwrap();
+            let state = Arc::new(Mutex::new(PrimaryState {
+                records,
+                ..Default::default()
+            }));
+
+            let udp_state = state.clone();
+            thread::spawn(move || loop {
+                let mut buf = [0; 512];
+                let (len, source) = udp_socket.recv_from(&mut buf).unwrap();
+                let query_message = Message::parse(&buf[..len]).unwrap();
+                let mut state = udp_state.lock().unwrap();
+                state.queries.push(query_message.questions[0].ty);
+                let soa = state.records[0].clone();
+                let mut response_message =
+                    Message::new_reply(&query_message, query_message.questions.clone(), vec![soa]);
+                response_message.header.authoritative_answer = true;
+                if state.refuse {
+                    response_message =
+                        Message::new_error_reply(&query_message, ResponseCode::Refused);
+                }
+                let mut response = BytesMut::new();
+                response_message.write(&mut response).unwrap();
+                udp_socket.send_to(&response, source).unwrap();
+            });
+
+            let tcp_state = state.clone();
+            thread::spawn(move || {
+                for mut stream in tcp_listener.incoming().flatten() {
+                    let mut length = [0; 2];
+                    stream.read_exact(&mut length).unwrap();
+                    let mut buf = vec![0; u16::from_be_bytes(length) as usize];
+                    stream.read_exact(&mut buf).unwrap();
+                    let query_message = Message::parse(&buf).unwrap();
+                    let mut state = tcp_state.lock().unwrap();
+                    let ty = query_message.questions[0].ty;
+                    state.queries.push(ty);
+                    let answers = match (ty, &state.ixfr) {
+                        (AXFR, _) => {
+                            let mut answers = state.records.clone();
+                            answers.push(state.records[0].clone());
+                            Some(answers)
+                        }
+                        (IXFR, Some(ixfr)) => Some(ixfr.clone()),
+                        _ => None,
+                    };
+                    let mut response_message = match answers {
+                        _ if state.refuse => {
+                            Message::new_error_reply(&query_message, ResponseCode::Refused)
+                        }
+                        Some(answers) => Message::new_reply(
+                            &query_message,
+                            query_message.questions.clone(),
+                            answers,
+                        ),
+                        None => {
+                            Message::new_error_reply(&query_message, ResponseCode::NotImplemented)
+                        }
+                    };
+                    response_message.header.authoritative_answer = true;
+                    let mut response = BytesMut::new();
+                    response_message.write(&mut response).unwrap();
+                    stream
+                        .write_all(&(response.len() as u16).to_be_bytes())
+                        .unwrap();
+                    stream.write_all(&response).unwrap();
+                }
+            });
+
+            StandInPrimary { addr, state }
+        }
+    }
+}
@@ -0,0 +1,909 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Mutex,
+    time::Instant,
+};
+
+use crate::{
+    acl::{AccessControl, AclAction, Cidr},
+    blocklist::{Blocklists, ListStats},
+    config::Config,
+    cookie::ServerCookies,
+    dnssec::Validator,
+    forward::{forward, Forwarders, Upstream},
+    local_records::LocalRecords,
+    message::{
+        Edns, ExtendedErrorCode, Message, OpCode, Question, RecordType, ResourceRecord,
+        ResourceRecordData, ResponseCode, MINIMUM_UDP_PAYLOAD_SIZE,
+    },
+    rpz::{self, PolicyAction, ResponsePolicy},
+    rrl::{ResponseRateLimiter, RrlAction, RrlStats},
+    tsig::{self, Session},
+    zone::{Zones, AXFR, IXFR},
+};
+
+/// How a query reached the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+    /// DNS-over-HTTPS, which is encrypted even if TLS ends at a proxy in front of the server.
+    Https,
+}
+
+/// The largest UDP response the server sends, and the size it advertises. It avoids
+/// fragmentation on almost every path (the DNS Flag Day 2020 recommendation).
+const MAX_UDP_PAYLOAD_SIZE: u16 = 1232;
+
+/// Replies to padded queries on encrypted transports are padded to a multiple of this (RFC 8467
+/// section 4.1).
+const RESPONSE_PADDING_BLOCK_SIZE: usize = 468;
+
+/// State shared between all of the listeners.
+#[derive(Debug)]
+pub struct Server {
+    config: Config,
+    rate_limiter: Mutex<ResponseRateLimiter>,
+    cookies: ServerCookies,
+    views: Vec<View>,
+}
+
+/// The settings used for a particular set of clients.
+#[derive(Debug)]
+struct View {
+    match_clients: Vec<Cidr>,
+    forwarders: Forwarders,
+    access_control: AccessControl,
+    response_policy: ResponsePolicy,
+    local_records: LocalRecords,
+    blocklists: Blocklists,
+    /// Authoritative zones, which only this view's clients can query, transfer or update.
+    zones: Zones,
+    /// Checks forwarded answers, if DNSSEC validation is on. Each view has its own, since views
+    /// can have different upstreams.
+    validator: Option<Validator>,
+}
+
+impl Server {
+    pub fn new(config: Config) -> anyhow::Result<Self> {
+        let views = config
+            .views
+            .iter()
+            .map(|view| {
+                let context = |e: anyhow::Error| anyhow::format_err!("view {}: {}", view.name, e);
+                Ok(View {
+                    match_clients: view.match_clients.clone(),
+                    forwarders: Forwarders {
+                        default: Upstream::new(
+                            view.resolver_addr
+                                .clone()
+                                .unwrap_or_else(|| config.resolver_addr.clone()),
+                        ),
+                        rules: view.forwarding_rules.clone(),
+                        client_subnet: config.client_subnet.clone(),
+                        serve_stale: config.serve_stale.clone(),
+                        prefetch: config.prefetch.clone(),
+                    },
+                    access_control: view.access_control.clone(),
+                    response_policy: ResponsePolicy::load(&view.rpz).map_err(context)?,
+                    local_records: LocalRecords::load(&view.local_records).map_err(context)?,
+                    blocklists: Blocklists::load(view.blocklists.clone()).map_err(context)?,
+                    zones: Zones::load(&view.zones, &config.tsig_keys).map_err(context)?,
+                    validator: match config.dnssec.validate {
+                        true => Some(Validator::new(&config.dnssec)?),
+                        false => None,
+                    },
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Server {
+            rate_limiter: Mutex::new(ResponseRateLimiter::new(config.rrl.clone())),
+            cookies: ServerCookies::new(config.cookies.clone()),
+            views,
+            config,
+        })
+    }
+
+    pub fn doh_addr(&self) -> Option<SocketAddr> {
+        self.config.doh_addr
+    }
+
+    /// Reload changed zone files, and refresh secondary and policy zones which are due.
+    pub fn maintain_zones(&self) {
+        for view in self.views.iter() {
+            view.zones.maintain();
+            view.response_policy.maintain();
+        }
+    }
+
+    /// The response rate limiting counters, if it's on.
+    pub fn rrl_stats(&self) -> Option<RrlStats> {
+        self.rate_limiter.lock().unwrap().stats()
+    }
+
+    /// The counters for every view's blocklists and allowlists.
+    pub fn blocklist_stats(&self) -> Vec<ListStats> {
+        self.views
+            .iter()
+            .flat_map(|view| view.blocklists.stats())
+            .collect()
+    }
+
+    /// The first view matching the client, or the client subnet it's asking on behalf of.
+    fn select_view(&self, query_message: &Message, client: IpAddr) -> &View {
+        let client_subnet = query_message
+            .edns()
+            .filter(|_| self.config.match_client_subnet)
+            .and_then(|edns| edns.client_subnet().map(|client_subnet| client_subnet.addr));
+        let addr = client_subnet.unwrap_or(client);
+        self.views
+            .iter()
+            .find(|view| {
+                view.match_clients.is_empty()
+                    || view.match_clients.iter().any(|cidr| cidr.contains(addr))
+            })
+            .expect("the default view should match every client")
+    }
+
+    /// The response to send to `source` for `query_message`, if any. A request signed with TSIG
+    /// gets a signed response, one with a cookie gets a server cookie back, and one which was
+    /// padded over an encrypted transport gets a padded response.
+    pub fn handle(
+        &self,
+        query_message: &Message,
+        source: SocketAddr,
+        transport: Transport,
+    ) -> anyhow::Result<Option<Message>> {
+        let mut session = match tsig::verify_request(&self.config.tsig_keys, query_message) {
+            Ok(session) => session,
+            Err(response_message) => return Ok(Some(*response_message)),
+        };
+        let cookie = self.cookies.check(query_message, source.ip());
+        let valid_cookie = cookie.as_ref().is_some_and(|cookie| cookie.valid);
+        let response_message = if query_message
+            .edns()
+            .is_some_and(|edns| edns.has_malformed_cookie())
+        {
+            // RFC 7873 section 5.2.2
+            Some(Message::new_error_reply(
+                query_message,
+                ResponseCode::FormatError,
+            ))
+        } else if cookie.is_some()
+            && !valid_cookie
+            && self.cookies.required()
+            && transport == Transport::Udp
+            && session.is_none()
+        {
+            // A client which sends cookies can retry with the server cookie it's given here. TCP
+            // and TSIG already show the client is who it says it is.
+            Some(Message::new_error_reply(
+                query_message,
+                ResponseCode::BadCookie,
+            ))
+        } else {
+            self.handle_verified(
+                query_message,
+                source,
+                transport,
+                session.as_ref(),
+                valid_cookie,
+            )?
+        };
+        let Some(mut response_message) = response_message else {
+            return Ok(None);
+        };
+        match_edns(query_message, &mut response_message);
+        if let Some(cookie) = &cookie {
+            self.cookies.add(&mut response_message, cookie, source.ip());
+        }
+        let signature_size = session.as_ref().map_or(0, Session::signature_size);
+        if transport == Transport::Udp {
+            response_message.truncate(udp_payload_limit(query_message) - signature_size)?;
+        }
+        if transport == Transport::Https
+            && query_message.edns().is_some_and(|edns| edns.has_padding())
+        {
+            response_message.pad(RESPONSE_PADDING_BLOCK_SIZE, signature_size)?;
+        }
+        // The signature has to cover everything else, so it goes on last
+        if let Some(session) = &mut session {
+            session.sign(&mut response_message)?;
+        }
+        Ok(Some(response_message))
+    }
+
+    /// The response to a request whose TSIG and cookie, if any, have been checked.
+    fn handle_verified(
+        &self,
+        query_message: &Message,
+        source: SocketAddr,
+        transport: Transport,
+        session: Option<&Session>,
+        valid_cookie: bool,
+    ) -> anyhow::Result<Option<Message>> {
+        let view = self.select_view(query_message, source.ip());
+        let key = session.map(Session::key);
+        match query_message.header.op_code {
+            OpCode::Notify => return Ok(Some(view.zones.notify(query_message, source, key))),
+            OpCode::Update => return Ok(Some(view.zones.update(query_message, source, key))),
+            _ => {}
+        }
+        if is_transfer(query_message) {
+            // A transfer takes several messages, which needs a stream (RFC 5936 section 4.2)
+            return Ok(Some(Message::new_error_reply(
+                query_message,
+                ResponseCode::FormatError,
+            )));
+        }
+        // Local names are answered authoritatively, without recursion
+        let acl =
+            if view.local_records.contains(query_message) || view.zones.contains(query_message) {
+                &view.access_control.authoritative
+            } else {
+                &view.access_control.recursion
+            };
+        let response_message = match acl.check(source.ip()) {
+            // Only EDNS version 0 is defined (RFC 6891 section 6.1.3)
+            AclAction::Allow if query_message.edns().is_some_and(|edns| edns.version > 0) => {
+                Message::new_error_reply(query_message, ResponseCode::BadVersion)
+            }
+            AclAction::Allow => match view.resolve(query_message, source.ip(), transport)? {
+                Some(response_message) => response_message,
+                None => return Ok(None),
+            },
+            AclAction::Refuse => refused_reply(query_message),
+            AclAction::Deny => return Ok(None),
+        };
+
+        // Amplification only works over UDP, and only with a spoofed address, which a valid
+        // cookie rules out
+        if transport != Transport::Udp || valid_cookie {
+            return Ok(Some(response_message));
+        }
+        let action =
+            self.rate_limiter
+                .lock()
+                .unwrap()
+                .check(Instant::now(), source.ip(), &response_message);
+        Ok(match action {
+            RrlAction::Send => Some(response_message),
+            RrlAction::Drop => None,
+            RrlAction::Slip => Some(Message::new_truncated_reply(&response_message)),
+        })
+    }
+
+    /// The responses to send on a TCP connection for `query_message`, which for a zone transfer
+    /// can be several.
+    pub fn handle_stream(
+        &self,
+        query_message: &Message,
+        source: SocketAddr,
+    ) -> anyhow::Result<Vec<Message>> {
+        if !is_transfer(query_message) {
+            return Ok(self
+                .handle(query_message, source, Transport::Tcp)?
+                .into_iter()
+                .collect());
+        }
+        let mut session = match tsig::verify_request(&self.config.tsig_keys, query_message) {
+            Ok(session) => session,
+            Err(response_message) => return Ok(vec![*response_message]),
+        };
+        // A zone's transfer keys are allowed from any address, but only to the zones of the view
+        // that address matches
+        let view = self.select_view(query_message, source.ip());
+        let allowed_by_key = session
+            .as_ref()
+            .is_some_and(|session| view.zones.allows_transfer(query_message, session.key()));
+        let mut response_messages = match view.access_control.transfer.check(source.ip()) {
+            _ if allowed_by_key => view.zones.transfer(query_message)?,
+            AclAction::Allow => view.zones.transfer(query_message)?,
+            AclAction::Refuse => vec![refused_reply(query_message)],
+            AclAction::Deny => Vec::new(),
+        };
+        // Each message's MAC covers the one before, so they have to be signed in order
+        if let Some(session) = &mut session {
+            for response_message in response_messages.iter_mut() {
+                session.sign(response_message)?;
+            }
+        }
+        Ok(response_messages)
+    }
+}
+
+/// A REFUSED reply for a client the ACLs don't allow.
+fn refused_reply(query_message: &Message) -> Message {
+    let mut response_message = Message::new_error_reply(query_message, ResponseCode::Refused);
+    response_message.add_extended_error(ExtendedErrorCode::Prohibited, "");
+    response_message
+}
+
+/// Give the response an OPT record if the query had one, and only then (RFC 6891 section 7),
+/// with the query's DO bit (RFC 3225 section 3) and our own payload size. One is always added for
+/// an extended response code though, when it's written.
+fn match_edns(query_message: &Message, response_message: &mut Message) {
+    let response_edns = response_message.edns();
+    response_message
+        .additionals
+        .retain(|record| record.ty != RecordType::Opt);
+    if let Some(query_edns) = query_message.edns() {
+        let mut edns = response_edns.unwrap_or_else(|| Edns::new(MAX_UDP_PAYLOAD_SIZE));
+        edns.udp_payload_size = MAX_UDP_PAYLOAD_SIZE;
+        edns.dnssec_ok = query_edns.dnssec_ok;
+        response_message.additionals.push(edns.to_record());
+    }
+}
+
+/// The most a UDP response to `query_message` can be: what the client says it can take, but not
+/// less than the 512 bytes every client can, nor more than we send (RFC 6891 section 6.2.5).
+fn udp_payload_limit(query_message: &Message) -> usize {
+    let payload_size = query_message
+        .edns()
+        .map_or(MINIMUM_UDP_PAYLOAD_SIZE, |edns| edns.udp_payload_size);
+    payload_size.clamp(MINIMUM_UDP_PAYLOAD_SIZE, MAX_UDP_PAYLOAD_SIZE) as usize
+}
+
+/// Whether `query_message` asks for a zone transfer.
+fn is_transfer(query_message: &Message) -> bool {
+    query_message
+        .questions
+        .iter()
+        .any(|question| question.ty == AXFR || question.ty == IXFR)
+}
+
+impl View {
+    fn resolve(
+        &self,
+        query_message: &Message,
+        client: IpAddr,
+        transport: Transport,
+    ) -> anyhow::Result<Option<Message>> {
+        let answer = || -> anyhow::Result<Message> {
+            match self.answer_locally(query_message, client)? {
+                Some(local_message) => Ok(local_message),
+                None => self.answer_remotely(query_message, client),
+            }
+        };
+
+        // A policy for the query itself means the answer isn't checked again, so a passthru
+        // exempts the name from later policies without skipping the zones or blocklist
+        if let Some(action) = self
+            .response_policy
+            .check_query(client, &query_message.questions)
+        {
+            return self.apply_policy(action, query_message, client, transport, answer);
+        }
+
+        let response_message = answer()?;
+        match self.response_policy.check_response(&response_message) {
+            Some(action) => self.apply_policy(action, query_message, client, transport, || {
+                Ok(response_message)
+            }),
+            None => Ok(Some(response_message)),
+        }
+    }
+
+    /// Answer from the zones and local records, if every question is for a name in them.
+    fn answer_locally(
+        &self,
+        query_message: &Message,
+        client: IpAddr,
+    ) -> anyhow::Result<Option<Message>> {
+        let dnssec_ok = query_message.edns().is_some_and(|edns| edns.dnssec_ok);
+        let mut answers = Vec::new();
+        let mut authorities = Vec::new();
+        let mut additionals = Vec::new();
+        let mut response_code = ResponseCode::Ok;
+        let mut authoritative = true;
+        for question in query_message.questions.iter() {
+            let zone_answer = match self.zones.answer(question) {
+                Some(zone_answer) => Some(zone_answer),
+                None => self.local_records.zone_answer(question, dnssec_ok)?,
+            };
+            let mut local_answers = match zone_answer {
+                Some(zone_answer) => {
+                    if !matches!(zone_answer.response_code, ResponseCode::Ok) {
+                        response_code = zone_answer.response_code;
+                    }
+                    authoritative &= zone_answer.authoritative;
+                    authorities.extend(zone_answer.authorities);
+                    additionals.extend(zone_answer.additionals);
+                    zone_answer.answers
+                }
+                None => match self.local_records.answers(question) {
+                    Some(local_answers) => local_answers,
+                    None => return Ok(None),
+                },
+            };
+            self.follow_alias(question, client, &mut local_answers)?;
+            answers.extend(local_answers);
+        }
+        let mut reply = Message::new_reply(query_message, query_message.questions.clone(), answers);
+        reply.header.authoritative_answer = authoritative;
+        if !matches!(response_code, ResponseCode::Ok) {
+            reply.header.response_code = response_code;
+        }
+        reply.authorities = authorities;
+        reply.additionals = additionals;
+        // Zones only fail before they're loaded or once they've expired
+        if matches!(response_code, ResponseCode::ServerFailure) {
+            reply.add_extended_error(ExtendedErrorCode::NotReady, "");
+        }
+        Ok(Some(reply))
+    }
+
+    fn answer_remotely(&self, query_message: &Message, client: IpAddr) -> anyhow::Result<Message> {
+        self.blocklists.refresh(Instant::now());
+        match self.blocklists.filter(query_message) {
+            Some(mut blocked_message) => {
+                blocked_message.add_extended_error(ExtendedErrorCode::Blocked, "blocklist");
+                Ok(blocked_message)
+            }
+            None => self.forward(query_message, client),
+        }
+    }
+
+    /// Forward to the upstream for `client`, validating the answer if DNSSEC validation is on.
+    /// If no upstream answers, the client gets SERVFAIL saying so.
+    fn forward(&self, query_message: &Message, client: IpAddr) -> anyhow::Result<Message> {
+        let result = match &self.validator {
+            Some(validator) => validator.resolve(query_message, Some(client), &self.forwarders),
+            None => forward(query_message, Some(client), &self.forwarders),
+        };
+        result.or_else(|e| {
+            eprintln!("error forwarding query from {}: {}", client, e);
+            let mut response_message =
+                Message::new_error_reply(query_message, ResponseCode::ServerFailure);
+            response_message.add_extended_error(ExtendedErrorCode::NetworkError, &e.to_string());
+            Ok(response_message)
+        })
+    }
+
+    /// Resolve the target of an alias which ends `answers`, unless the client asked for the CNAME
+    /// itself or the target is local.
+    fn follow_alias(
+        &self,
+        question: &Question,
+        client: IpAddr,
+        answers: &mut Vec<ResourceRecord>,
+    ) -> anyhow::Result<()> {
+        let last_answer = answers
+            .iter()
+            .rev()
+            .find(|answer| answer.ty != RecordType::Signature);
+        let Some(ResourceRecordData::CName(target)) = last_answer.map(|answer| &answer.data) else {
+            return Ok(());
+        };
+        if question.ty == RecordType::CName || self.local_records.contains_name(target) {
+            return Ok(());
+        }
+        let target_query = Message::new_query(vec![Question {
+            name: target.clone(),
+            ty: question.ty,
+            class: question.class,
+        }]);
+        answers.extend(self.forward(&target_query, client)?.answers);
+        Ok(())
+    }
+
+    /// The response for `action`, where `answer` gives the response the query would get
+    /// without it.
+    fn apply_policy(
+        &self,
+        action: PolicyAction,
+        query_message: &Message,
+        client: IpAddr,
+        transport: Transport,
+        answer: impl FnOnce() -> anyhow::Result<Message>,
+    ) -> anyhow::Result<Option<Message>> {
+        let response_message = match action {
+            PolicyAction::NxDomain => {
+                let mut response_message =
+                    Message::new_error_reply(query_message, ResponseCode::NameError);
+                response_message.add_extended_error(ExtendedErrorCode::Blocked, "policy");
+                response_message
+            }
+            PolicyAction::NoData => {
+                let mut response_message =
+                    Message::new_reply(query_message, query_message.questions.clone(), Vec::new());
+                response_message.add_extended_error(ExtendedErrorCode::Blocked, "policy");
+                response_message
+            }
+            PolicyAction::Passthru => answer()?,
+            PolicyAction::Drop => return Ok(None),
+            PolicyAction::TcpOnly => match transport {
+                Transport::Udp => Message::new_truncated_reply(query_message),
+                Transport::Tcp | Transport::Https => answer()?,
+            },
+            PolicyAction::LocalData(records) => {
+                let mut answers = Vec::new();
+                for question in query_message.questions.iter() {
+                    let mut local_answers = rpz::local_answers(&records, question);
+                    self.follow_alias(question, client, &mut local_answers)?;
+                    answers.extend(local_answers);
+                }
+                Message::new_reply(query_message, query_message.questions.clone(), answers)
+            }
+        };
+        Ok(Some(response_message))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::PathBuf, process};
+
+    use crate::{
+        forward::tests::stand_in_upstream,
+        message::{Class, DomainName, ResourceRecord},
+    };
+
+    use super::*;
+
+    const POLICY_ZONE: &str = "
+$TTL 300
+@                      SOA   localhost. admin.localhost. 1 3600 600 86400 60
+passthru.lan           CNAME rpz-passthru.
+ads.example            CNAME rpz-passthru.
+32.20.2.0.192.rpz-ip   CNAME .
+32.21.2.0.192.rpz-ip   CNAME rpz-tcp-only.
+";
+
+    /// The key of RFC 8032 section 7.1 test 1, as a BIND private key file and as a trust anchor.
+    const PRIVATE_KEY_FILE: &str = "\
+Private-key-format: v1.3
+Algorithm: 15 (ED25519)
+PrivateKey: nWGxne/9WmC6hEr0kuwsxERJxWl7MmkZcDusAxyuf2A=
+";
+    const TRUST_ANCHOR: &str =
+        "example. DNSKEY 257 3 15 11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=";
+
+    /// A file in the temporary directory, named for this test process.
+    fn temp_file(name: &str, contents: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("dns-server-test-{}-{name}", process::id()));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn server(args: &[&str]) -> Server {
+        // Nothing listens on the discard port, so anything forwarded fails
+        let mut all_args = vec!["--resolver".to_string(), "127.0.0.1:9".to_string()];
+        all_args.extend(args.iter().map(|arg| arg.to_string()));
+        Server::new(Config::from_args(&all_args).unwrap()).unwrap()
+    }
+
+    fn query(server: &Server, qname: &str, transport: Transport) -> Option<Message> {
+        query_with_edns(server, qname, RecordType::Address, None, transport)
+    }
+
+    fn query_with_edns(
+        server: &Server,
+        qname: &str,
+        ty: RecordType,
+        udp_payload_size: Option<u16>,
+        transport: Transport,
+    ) -> Option<Message> {
+        let mut query_message = Message::new_query(vec![Question {
+            name: DomainName::new(qname).unwrap(),
+            ty,
+            class: Class::Internet,
+        }]);
+        if let Some(udp_payload_size) = udp_payload_size {
+            query_message
+                .additionals
+                .push(Edns::new(udp_payload_size).to_record());
+        }
+        let source = "127.0.0.1:5300".parse().unwrap();
+        server.handle(&query_message, source, transport).unwrap()
+    }
+
+    /// A query with DO set, from a client which can take `udp_payload_size` bytes.
+    fn query_with_dnssec(
+        server: &Server,
+        qname: &str,
+        ty: RecordType,
+        udp_payload_size: u16,
+    ) -> Message {
+        let mut query_message = Message::new_query(vec![Question {
+            name: DomainName::new(qname).unwrap(),
+            ty,
+            class: Class::Internet,
+        }]);
+        let mut edns = Edns::new(udp_payload_size);
+        edns.dnssec_ok = true;
+        query_message.additionals.push(edns.to_record());
+        let source = "127.0.0.1:5300".parse().unwrap();
+        server
+            .handle(&query_message, source, Transport::Udp)
+            .unwrap()
+            .unwrap()
+    }
+
+    /// A server signing `example.` with `denial`, with `records` in it as well as an empty
+    /// non-terminal at `b.example.`.
+    fn signing_server(denial: &str, records: &[&str]) -> Server {
+        let key_file = temp_file(&format!("{denial}.private"), PRIVATE_KEY_FILE);
+        let mut args = vec![
+            "--dnssec-key".to_string(),
+            format!("example={}", key_file.display()),
+            "--dnssec-denial".to_string(),
+            denial.to_string(),
+            "--local-record".to_string(),
+            "a.b.example. TXT \"hello\"".to_string(),
+        ];
+        for record in records {
+            args.extend(["--local-record".to_string(), record.to_string()]);
+        }
+        let server = server(&args.iter().map(String::as_str).collect::<Vec<_>>());
+        fs::remove_file(key_file).unwrap();
+        server
+    }
+
+    /// A query for `qname` from `source`, which decides the view.
+    fn query_from(server: &Server, qname: &str, ty: RecordType, source: &str) -> Message {
+        let query_message = Message::new_query(vec![Question {
+            name: DomainName::new(qname).unwrap(),
+            ty,
+            class: Class::Internet,
+        }]);
+        let source = format!("{source}:5300").parse().unwrap();
+        match ty {
+            AXFR => server
+                .handle_stream(&query_message, source)
+                .unwrap()
+                .remove(0),
+            _ => server
+                .handle(&query_message, source, Transport::Udp)
+                .unwrap()
+                .unwrap(),
+        }
+    }
+
+    fn addresses(response_message: &Message) -> Vec<[u8; 4]> {
+        response_message
+            .answers
+            .iter()
+            .filter_map(|record| match record.data {
+                ResourceRecordData::IPv4(ip) => Some(ip),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn response_policies_keep_local_answers() {
+        let policy_zone = temp_file("rpz.zone", POLICY_ZONE);
+        let blocklist = temp_file("blocklist", "ads.example\n");
+        let server = server(&[
+            "--rpz-zone",
+            &format!("rpz.local={}", policy_zone.display()),
+            "--blocklist",
+            blocklist.to_str().unwrap(),
+            "--local-record",
+            "passthru.lan. A 192.0.2.20",
+            "--local-record",
+            "blocked.lan. A 192.0.2.20",
+            "--local-record",
+            "tcp.lan. A 192.0.2.21",
+        ]);
+
+        // Passthru still answers from local records, and skips the address trigger
+        let response_message = query(&server, "passthru.lan", Transport::Udp).unwrap();
+        assert!(matches!(
+            response_message.header.response_code,
+            ResponseCode::Ok
+        ));
+        assert_eq!(addresses(&response_message), vec![[192, 0, 2, 20]]);
+
+        let response_message = query(&server, "blocked.lan", Transport::Udp).unwrap();
+        assert!(matches!(
+            response_message.header.response_code,
+            ResponseCode::NameError
+        ));
+
+        // Passthru doesn't get around the blocklist
+        let response_message = query(&server, "ads.example", Transport::Udp).unwrap();
+        assert_eq!(addresses(&response_message), vec![[0, 0, 0, 0]]);
+
+        // TCP-only truncates over UDP, and leaves the answer alone over TCP
+        let response_message = query(&server, "tcp.lan", Transport::Udp).unwrap();
+        assert!(response_message.header.truncation);
+        assert!(response_message.answers.is_empty());
+        let response_message = query(&server, "tcp.lan", Transport::Tcp).unwrap();
+        assert!(!response_message.header.truncation);
+        assert_eq!(addresses(&response_message), vec![[192, 0, 2, 21]]);
+
+        fs::remove_file(policy_zone).unwrap();
+        fs::remove_file(blocklist).unwrap();
+    }
+
+    #[test]
+    fn udp_responses_are_truncated_to_fit() {
+        let text = format!("\"{}\"", "x".repeat(100));
+        let records = (0..6)
+            .map(|i| format!("big.lan. TXT \"{i}\" {text}"))
+            .collect::<Vec<_>>();
+        let mut args = Vec::new();
+        for record in records.iter() {
+            args.extend(["--local-record", record.as_str()]);
+        }
+        let server = server(&args);
+        let query = |udp_payload_size, transport| {
+            query_with_edns(
+                &server,
+                "big.lan",
+                RecordType::Text,
+                udp_payload_size,
+                transport,
+            )
+            .unwrap()
+        };
+
+        // 512 bytes without EDNS, or with EDNS asking for less
+        for udp_payload_size in [None, Some(256), Some(600)] {
+            let response_message = query(udp_payload_size, Transport::Udp);
+            assert!(response_message.header.truncation);
+            assert!(response_message.answers.is_empty());
+            assert_eq!(response_message.questions.len(), 1);
+            assert!(response_message.size().unwrap() <= 600);
+            // The client still learns what we can take
+            if udp_payload_size.is_some() {
+                let edns = response_message.edns().unwrap();
+                assert_eq!(edns.udp_payload_size, MAX_UDP_PAYLOAD_SIZE);
+            }
+        }
+
+        // Up to our own limit with EDNS, and anything over TCP
+        for (udp_payload_size, transport) in [
+            (Some(1232), Transport::Udp),
+            (Some(4096), Transport::Udp),
+            (None, Transport::Tcp),
+        ] {
+            let response_message = query(udp_payload_size, transport);
+            assert!(!response_message.header.truncation);
+            assert_eq!(response_message.answers.len(), 6);
+        }
+    }
+
+    #[test]
+    fn signed_answers_validate() {
+        for denial in ["nsec", "nsec3", "compact"] {
+            let signing_server = signing_server(denial, &["www.example. A 192.0.2.1"]);
+            let addr = stand_in_upstream(move |query_message| {
+                let source = "127.0.0.1:5300".parse().unwrap();
+                signing_server
+                    .handle(query_message, source, Transport::Udp)
+                    .unwrap()
+            });
+            let validating_server = server(&[
+                "--resolver",
+                &addr.to_string(),
+                "--dnssec-validation",
+                "--trust-anchor",
+                TRUST_ANCHOR,
+            ]);
+
+            // Compact denial answers NODATA for missing names too
+            let missing = match denial {
+                "compact" => ResponseCode::Ok,
+                _ => ResponseCode::NameError,
+            };
+            for (qname, ty, response_code, answers) in [
+                ("www.example", RecordType::Address, ResponseCode::Ok, 1),
+                ("www.example", RecordType::MailExchange, ResponseCode::Ok, 0),
+                ("b.example", RecordType::Address, ResponseCode::Ok, 0),
+                ("nope.example", RecordType::Address, missing, 0),
+                ("x.nope.example", RecordType::Address, missing, 0),
+            ] {
+                let response_message = query_with_dnssec(&validating_server, qname, ty, 1232);
+                let context = format!("{denial} {qname} {ty:?}");
+                assert_eq!(
+                    response_message.header.response_code, response_code,
+                    "{context}"
+                );
+                assert!(response_message.header.authentic_data, "{context}");
+                let found = response_message
+                    .answers
+                    .iter()
+                    .filter(|record| record.ty == ty)
+                    .count();
+                assert_eq!(found, answers, "{context}");
+            }
+        }
+    }
+
+    #[test]
+    fn signed_udp_responses_are_truncated_to_fit() {
+        let text = format!("\"{}\"", "x".repeat(100));
+        let records = (0..4)
+            .map(|i| format!("big.example. TXT \"{i}\" {text}"))
+            .collect::<Vec<_>>();
+        let records = records.iter().map(String::as_str).collect::<Vec<_>>();
+        let server = signing_server("nsec", &records);
+
+        // The signatures make it too big for 512 bytes
+        let response_message = query_with_dnssec(&server, "big.example", RecordType::Text, 512);
+        assert!(response_message.header.truncation);
+        assert!(response_message.answers.is_empty());
+        assert!(response_message.size().unwrap() <= 512);
+
+        let response_message = query_with_dnssec(&server, "big.example", RecordType::Text, 1232);
+        assert!(!response_message.header.truncation);
+        let signatures = response_message
+            .answers
+            .iter()
+            .filter(|record| record.ty == RecordType::Signature)
+            .count();
+        assert_eq!((response_message.answers.len(), signatures), (5, 1));
+    }
+
+    #[test]
+    fn views_have_their_own_zones_and_blocklists() {
+        let soa = "@ SOA ns.example. admin.example. 1 3600 600 86400 60";
+        let external_zone = temp_file("external.zone", &format!("{soa}\nwww A 192.0.2.1\n"));
+        let internal_zone = temp_file("internal.zone", &format!("{soa}\nwww A 10.0.0.1\n"));
+        let blocklist = temp_file("views.blocklist", "ads.test\n");
+        let external_zone_arg = format!("example={}", external_zone.display());
+        let internal_zone_arg = format!("example={}", internal_zone.display());
+        let internal_directory = env::temp_dir().join(format!("dns-server-test-{}", process::id()));
+        let internal_resolver = stand_in_upstream(|query_message| {
+            let question = &query_message.questions[0];
+            let data = ResourceRecordData::IPv4([10, 0, 0, 2]);
+            let answer =
+                ResourceRecord::new(question.name.clone(), question.ty, question.class, 60, data);
+            Some(Message::new_reply(
+                query_message,
+                query_message.questions.clone(),
+                vec![answer],
+            ))
+        })
+        .to_string();
+        let mut args = vec![
+            "--zone",
+            &external_zone_arg,
+            "--blocklist",
+            blocklist.to_str().unwrap(),
+            "--view",
+            "internal",
+            "--match-clients",
+            "10.0.0.0/8",
+            "--zone",
+            &internal_zone_arg,
+            "--acl",
+            "transfer:allow:10.0.0.0/8",
+            "--resolver",
+            &internal_resolver,
+        ];
+
+        // Both views would save their copy of example. to the same place
+        let mut all_args = vec!["--resolver", "127.0.0.1:9"];
+        all_args.extend(args.iter());
+        let all_args = all_args
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect::<Vec<_>>();
+        assert!(Config::from_args(&all_args).is_err());
+
+        args.extend(["--zone-directory", internal_directory.to_str().unwrap()]);
+        let server = server(&args);
+        let address =
+            |qname, source| addresses(&query_from(&server, qname, RecordType::Address, source));
+        assert_eq!(address("www.example", "127.0.0.1"), vec![[192, 0, 2, 1]]);
+        assert_eq!(address("www.example", "10.1.2.3"), vec![[10, 0, 0, 1]]);
+        assert_eq!(address("ads.test", "127.0.0.1"), vec![[0, 0, 0, 0]]);
+        assert_eq!(address("ads.test", "10.1.2.3"), vec![[10, 0, 0, 2]]);
+
+        // Transfers only reach the zones of the client's own view
+        let response_message = query_from(&server, "example", AXFR, "10.1.2.3");
+        assert!(response_message.answers.iter().any(|record| {
+            matches!(record.data, ResourceRecordData::IPv4(ip) if ip == [10, 0, 0, 1])
+        }));
+        let response_message = query_from(&server, "example", AXFR, "127.0.0.1");
+        assert_eq!(response_message.header.response_code, ResponseCode::Refused);
+
+        for path in [external_zone, internal_zone, blocklist] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
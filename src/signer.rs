@@ -0,0 +1,627 @@
+//! Online DNSSEC signing of local zones (RFC 4035). RRsets are signed as they're answered rather
+//! than ahead of time, and negative answers are proven with NSEC, NSEC3 (RFC 5155) or compact
+//! denial of existence (RFC 9824), which answers NXDOMAIN as NODATA so each proof only needs one
+//! record.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    crypto::{ed25519, p256, sha2::sha256},
+    dnssec::{key_tag, nsec3_hash, signed_data},
+    encoding::{decode_base64, encode_base32hex},
+    message::{Class, DomainName, RecordType, ResourceRecord, ResourceRecordData, ResponseCode},
+};
+
+/// Signatures start at a multiple of this fraction of the validity period, so an RRset signs the
+/// same way for a while and the signature can be cached.
+const SIGNATURE_REFRESH_FRACTION: u32 = 4;
+/// The signature cache is cleared when it gets this big.
+const MAX_CACHED_SIGNATURES: usize = 10_000;
+/// A zone key which is also a secure entry point, since each zone has one combined key.
+const COMBINED_KEY_FLAGS: u16 = 0x0101;
+/// NSEC3 with SHA-1, no salt and no extra iterations, as RFC 9276 recommends.
+const NSEC3_HASH_ALGORITHM: u8 = 1;
+/// The meta type which marks a compact denial NODATA answer as really being NXDOMAIN.
+const NXNAME: RecordType = RecordType::Unknown(128);
+
+#[derive(Debug, Clone)]
+pub struct SigningConfig {
+    /// Local zones to sign, each with the BIND style private key file to sign it with.
+    pub keys: Vec<(DomainName, PathBuf)>,
+    pub denial: DenialMethod,
+    /// Seconds before now that signatures become valid, for clients with slow clocks.
+    pub inception_offset: u32,
+    /// Seconds that signatures stay valid for.
+    pub validity: u32,
+}
+
+/// How negative answers are proven.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DenialMethod {
+    /// NSEC records between the real names in the zone.
+    Nsec,
+    /// NSEC3 records between the hashes of the real names in the zone.
+    Nsec3,
+    /// An NSEC record covering just the name asked about.
+    Compact,
+}
+
+#[derive(Debug)]
+pub struct ZoneSigner {
+    pub apex: DomainName,
+    algorithm: u8,
+    private_key: Vec<u8>,
+    dnskey: ResourceRecordData,
+    key_tag: u16,
+    denial: DenialMethod,
+    inception_offset: u32,
+    validity: u32,
+    /// Signatures by the data they sign.
+    signatures: Mutex<HashMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl Default for SigningConfig {
+    fn default() -> Self {
+        SigningConfig {
+            keys: Vec::new(),
+            denial: DenialMethod::Compact,
+            inception_offset: 3600,
+            validity: 7 * 86400,
+        }
+    }
+}
+
+impl FromStr for DenialMethod {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nsec" => Ok(DenialMethod::Nsec),
+            "nsec3" => Ok(DenialMethod::Nsec3),
+            "compact" => Ok(DenialMethod::Compact),
+            _ => anyhow::bail!("unknown denial method {s:?}, expected nsec, nsec3 or compact"),
+        }
+    }
+}
+
+impl ZoneSigner {
+    pub fn load(apex: DomainName, path: &Path, config: &SigningConfig) -> anyhow::Result<Self> {
+        let (algorithm, private_key) =
+            load_private_key(path).map_err(|e| anyhow::format_err!("{}: {}", path.display(), e))?;
+        let public_key = match algorithm {
+            13 => p256::public_key(&private_key),
+            15 => ed25519::public_key(&private_key),
+            _ => anyhow::bail!(
+                "{}: only ECDSAP256SHA256 (13) and ED25519 (15) keys can sign",
+                path.display()
+            ),
+        }
+        .ok_or_else(|| anyhow::format_err!("{}: invalid private key", path.display()))?;
+        let dnskey = ResourceRecordData::DnsKey {
+            flags: COMBINED_KEY_FLAGS,
+            protocol: 3,
+            algorithm,
+            public_key,
+        };
+        Ok(ZoneSigner {
+            apex,
+            algorithm,
+            private_key,
+            key_tag: key_tag(&dnskey),
+            dnskey,
+            denial: config.denial,
+            inception_offset: config.inception_offset,
+            validity: config.validity,
+            signatures: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn dnskey(&self, ttl: u32) -> ResourceRecord {
+        ResourceRecord::new(
+            self.apex.clone(),
+            RecordType::DnsKey,
+            Class::Internet,
+            ttl,
+            self.dnskey.clone(),
+        )
+    }
+
+    /// An RRSIG for each RRset in `records`.
+    pub fn sign(&self, records: &[ResourceRecord]) -> anyhow::Result<Vec<ResourceRecord>> {
+        let mut rrsets: Vec<Vec<ResourceRecord>> = Vec::new();
+        for record in records.iter() {
+            match rrsets
+                .iter_mut()
+                .find(|rrset| rrset[0].name == record.name && rrset[0].ty == record.ty)
+            {
+                Some(rrset) => rrset.push(record.clone()),
+                None => rrsets.push(vec![record.clone()]),
+            }
+        }
+
+        let (inception, expiration) = self.signature_window();
+        let mut signatures = Vec::new();
+        for rrset in rrsets {
+            let owner = &rrset[0].name;
+            // Wildcards don't count towards the labels
+            let labels = owner.label_count() - (owner.first_label() == Some("*")) as usize;
+            let mut data = ResourceRecordData::Signature {
+                type_covered: rrset[0].ty,
+                algorithm: self.algorithm,
+                labels: labels as u8,
+                original_ttl: rrset[0].time_to_live,
+                expiration,
+                inception,
+                key_tag: self.key_tag,
+                signer_name: self.apex.clone(),
+                signature: Vec::new(),
+            };
+            let signature = self.sign_data(signed_data(&rrset, &data)?);
+            if let ResourceRecordData::Signature {
+                signature: signature_field,
+                ..
+            } = &mut data
+            {
+                *signature_field = signature;
+            }
+            signatures.push(ResourceRecord::new(
+                owner.clone(),
+                RecordType::Signature,
+                rrset[0].class,
+                rrset[0].time_to_live,
+                data,
+            ));
+        }
+        Ok(signatures)
+    }
+
+    /// The inception and expiration times for signatures made now.
+    fn signature_window(&self) -> (u32, u32) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs() as u32);
+        let refresh = (self.validity / SIGNATURE_REFRESH_FRACTION).max(1);
+        let start = now - now % refresh;
+        (
+            start.wrapping_sub(self.inception_offset),
+            start.wrapping_add(self.validity),
+        )
+    }
+
+    fn sign_data(&self, data: Vec<u8>) -> Vec<u8> {
+        if let Some(signature) = self.signatures.lock().unwrap().get(&data) {
+            return signature.clone();
+        }
+        let signature = match self.algorithm {
+            13 => p256::sign(&self.private_key, &sha256(&data)),
+            _ => ed25519::sign(&self.private_key, &data),
+        };
+        let mut signatures = self.signatures.lock().unwrap();
+        if signatures.len() >= MAX_CACHED_SIGNATURES {
+            signatures.clear();
+        }
+        signatures.insert(data, signature.clone());
+        signature
+    }
+
+    /// The records proving that `name` has none of the records asked for, and the response
+    /// code to send with them. `types` are those at `name` if it exists, and `zone` is every
+    /// name in the zone in canonical order with the types at each.
+    pub fn deny(
+        &self,
+        name: &DomainName,
+        types: Option<&[RecordType]>,
+        zone: &[(DomainName, Vec<RecordType>)],
+        ttl: u32,
+    ) -> anyhow::Result<(ResponseCode, Vec<ResourceRecord>)> {
+        let response_code = match types {
+            Some(_) => ResponseCode::Ok,
+            None => ResponseCode::NameError,
+        };
+        let nsec = |owner: &DomainName, next: &DomainName, types: &[RecordType]| {
+            let mut types = types.to_vec();
+            types.extend([RecordType::Signature, RecordType::NextSecure]);
+            ResourceRecord::new(
+                owner.clone(),
+                RecordType::NextSecure,
+                Class::Internet,
+                ttl,
+                ResourceRecordData::NextSecure {
+                    next_domain_name: next.clone(),
+                    types,
+                },
+            )
+        };
+
+        match self.denial {
+            DenialMethod::Compact => {
+                // Nothing exists between the name and its first possible child
+                let next = DomainName::new("\0")?.join(name);
+                let record = match types {
+                    Some(types) => nsec(name, &next, types),
+                    None => nsec(name, &next, &[NXNAME]),
+                };
+                Ok((ResponseCode::Ok, vec![record]))
+            }
+            DenialMethod::Nsec => {
+                let at = |name: &DomainName| zone.iter().position(|(owner, _)| owner == name);
+                // The NSEC for the name before `name`, which covers it
+                let covering = |name: &DomainName| {
+                    let index = zone
+                        .iter()
+                        .rposition(|(owner, _)| owner.canonical_cmp(name).is_lt())
+                        .unwrap_or(zone.len() - 1);
+                    let next = &zone[(index + 1) % zone.len()].0;
+                    nsec(&zone[index].0, next, &zone[index].1)
+                };
+                let mut records = Vec::new();
+                match (types, at(name)) {
+                    (Some(_), Some(index)) => {
+                        let next = &zone[(index + 1) % zone.len()].0;
+                        records.push(nsec(name, next, &zone[index].1));
+                    }
+                    // An empty non-terminal, which has no NSEC of its own
+                    (Some(_), None) => records.push(covering(name)),
+                    (None, _) => {
+                        records.push(covering(name));
+                        let closest_encloser = closest_encloser(name, zone);
+                        let wildcard = covering(&DomainName::new("*")?.join(&closest_encloser));
+                        if wildcard.name != records[0].name {
+                            records.push(wildcard);
+                        }
+                    }
+                }
+                Ok((response_code, records))
+            }
+            DenialMethod::Nsec3 => {
+                let chain = self.nsec3_chain(zone);
+                let matching = |name: &DomainName| {
+                    let hash = nsec3_hash(name, &[], 0);
+                    chain.iter().position(|(owner_hash, _)| *owner_hash == hash)
+                };
+                let covering = |name: &DomainName| {
+                    let hash = nsec3_hash(name, &[], 0);
+                    chain
+                        .iter()
+                        .rposition(|(owner_hash, _)| *owner_hash < hash)
+                        .unwrap_or(chain.len() - 1)
+                };
+                let mut indexes = Vec::new();
+                match types {
+                    Some(_) => indexes.extend(matching(name)),
+                    None => {
+                        let closest_encloser = closest_encloser(name, zone);
+                        let next_closer = name.ancestor(closest_encloser.label_count() + 1);
+                        indexes.extend(matching(&closest_encloser));
+                        indexes.push(covering(&next_closer));
+                        indexes.push(covering(&DomainName::new("*")?.join(&closest_encloser)));
+                    }
+                }
+
+                let mut records = Vec::new();
+                for (position, &index) in indexes.iter().enumerate() {
+                    // One record can serve as more than one of the proofs
+                    if indexes[..position].contains(&index) {
+                        continue;
+                    }
+                    let (owner_hash, types) = &chain[index];
+                    let mut types = types.clone();
+                    if !types.is_empty() {
+                        types.push(RecordType::Signature);
+                    }
+                    let owner = DomainName::new(&encode_base32hex(owner_hash))?.join(&self.apex);
+                    records.push(ResourceRecord::new(
+                        owner,
+                        RecordType::NextSecure3,
+                        Class::Internet,
+                        ttl,
+                        ResourceRecordData::NextSecure3 {
+                            hash_algorithm: NSEC3_HASH_ALGORITHM,
+                            flags: 0,
+                            iterations: 0,
+                            salt: Vec::new(),
+                            next_hashed_owner: chain[(index + 1) % chain.len()].0.clone(),
+                            types,
+                        },
+                    ));
+                }
+                Ok((response_code, records))
+            }
+        }
+    }
+
+    /// The hashes of every name in the zone, including empty non-terminals, in order with the
+    /// types at each.
+    fn nsec3_chain(
+        &self,
+        zone: &[(DomainName, Vec<RecordType>)],
+    ) -> Vec<(Vec<u8>, Vec<RecordType>)> {
+        let mut chain = Vec::new();
+        let mut names = Vec::new();
+        for (name, types) in zone.iter() {
+            for label_count in self.apex.label_count() + 1..name.label_count() {
+                let ancestor = name.ancestor(label_count);
+                if !zone.iter().any(|(owner, _)| *owner == ancestor) && !names.contains(&ancestor) {
+                    chain.push((nsec3_hash(&ancestor, &[], 0), Vec::new()));
+                    names.push(ancestor);
+                }
+            }
+            chain.push((nsec3_hash(name, &[], 0), types.clone()));
+        }
+        chain.sort_by(|a, b| a.0.cmp(&b.0));
+        chain
+    }
+}
+
+/// The deepest existing ancestor of `name`, which doesn't exist itself.
+fn closest_encloser(name: &DomainName, zone: &[(DomainName, Vec<RecordType>)]) -> DomainName {
+    (0..name.label_count())
+        .rev()
+        .map(|label_count| name.ancestor(label_count))
+        .find(|ancestor| {
+            zone.iter()
+                .any(|(owner, _)| owner.is_subdomain_of(ancestor))
+        })
+        .unwrap_or_else(|| name.ancestor(0))
+}
+
+/// The algorithm and private key from a BIND private key file, with lines like `Algorithm: 13
+/// (ECDSAP256SHA256)` and `PrivateKey: <base64>`.
+fn load_private_key(path: &Path) -> anyhow::Result<(u8, Vec<u8>)> {
+    let contents = fs::read_to_string(path)?;
+    let mut algorithm = None;
+    let mut private_key = None;
+    for line in contents.lines() {
+        let Some((field, value)) = line.split_once(':') else {
+            continue;
+        };
+        match field.trim() {
+            "Algorithm" => {
+                let number = value.split_whitespace().next().unwrap_or_default();
+                algorithm = Some(number.parse::<u8>()?);
+            }
+            "PrivateKey" => private_key = Some(decode_base64(value.trim())?),
+            _ => {}
+        }
+    }
+    match (algorithm, private_key) {
+        (Some(algorithm), Some(private_key)) => Ok((algorithm, private_key)),
+        _ => anyhow::bail!("no Algorithm or PrivateKey"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use crate::{dnssec::nsec3_hash, encoding::decode_base32hex};
+
+    use super::*;
+
+    /// The key of RFC 8032 section 7.1 test 1.
+    const PRIVATE_KEY_FILE: &str = "\
+Private-key-format: v1.3
+Algorithm: 15 (ED25519)
+PrivateKey: nWGxne/9WmC6hEr0kuwsxERJxWl7MmkZcDusAxyuf2A=
+";
+
+    fn name(name: &str) -> DomainName {
+        DomainName::new(name).unwrap()
+    }
+
+    fn signer(denial: DenialMethod) -> ZoneSigner {
+        let path = env::temp_dir().join(format!(
+            "dns-server-test-{}-{denial:?}.private",
+            process::id()
+        ));
+        fs::write(&path, PRIVATE_KEY_FILE).unwrap();
+        let config = SigningConfig {
+            denial,
+            ..SigningConfig::default()
+        };
+        let signer = ZoneSigner::load(name("example"), &path, &config).unwrap();
+        fs::remove_file(path).unwrap();
+        signer
+    }
+
+    /// `example.` with an empty non-terminal at `b.example.`.
+    fn zone() -> Vec<(DomainName, Vec<RecordType>)> {
+        vec![
+            (
+                name("example"),
+                vec![RecordType::StartOfAuthority, RecordType::DnsKey],
+            ),
+            (name("a.b.example"), vec![RecordType::Text]),
+            (name("www.example"), vec![RecordType::Address]),
+        ]
+    }
+
+    /// The owner, next name and types of NSEC records.
+    fn nsecs(records: &[ResourceRecord]) -> Vec<(String, String, Vec<RecordType>)> {
+        records
+            .iter()
+            .map(|record| match &record.data {
+                ResourceRecordData::NextSecure {
+                    next_domain_name,
+                    types,
+                } => (
+                    record.name.to_string(),
+                    next_domain_name.to_string(),
+                    types.clone(),
+                ),
+                _ => panic!("expected an NSEC record, not {:?}", record.ty),
+            })
+            .collect()
+    }
+
+    /// The owner hash, next hash and types of NSEC3 records.
+    fn nsec3s(records: &[ResourceRecord]) -> Vec<(Vec<u8>, Vec<u8>, Vec<RecordType>)> {
+        records
+            .iter()
+            .map(|record| match &record.data {
+                ResourceRecordData::NextSecure3 {
+                    next_hashed_owner,
+                    types,
+                    ..
+                } => {
+                    assert_eq!(record.name.parent(), Some(name("example")));
+                    let owner_hash = decode_base32hex(record.name.first_label().unwrap()).unwrap();
+                    (owner_hash, next_hashed_owner.clone(), types.clone())
+                }
+                _ => panic!("expected an NSEC3 record, not {:?}", record.ty),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn nsec_proofs() {
+        let signer = signer(DenialMethod::Nsec);
+        let zone = zone();
+        let with_nsec = |types: &[RecordType]| {
+            let mut types = types.to_vec();
+            types.extend([RecordType::Signature, RecordType::NextSecure]);
+            types
+        };
+
+        // The name's own NSEC, which wraps around to the apex at the end
+        let types = [RecordType::Address];
+        let (response_code, records) = signer
+            .deny(&name("www.example"), Some(&types), &zone, 300)
+            .unwrap();
+        assert!(matches!(response_code, ResponseCode::Ok));
+        assert_eq!(
+            nsecs(&records),
+            vec![(
+                "www.example".to_string(),
+                "example".to_string(),
+                with_nsec(&types)
+            )]
+        );
+
+        // An empty non-terminal is covered by the NSEC before it
+        let (_, records) = signer
+            .deny(&name("b.example"), Some(&[]), &zone, 300)
+            .unwrap();
+        assert_eq!(
+            nsecs(&records),
+            vec![(
+                "example".to_string(),
+                "a.b.example".to_string(),
+                with_nsec(&zone[0].1)
+            )]
+        );
+
+        // A missing name needs the name and the wildcard covered
+        let (response_code, records) = signer
+            .deny(&name("nope.example"), None, &zone, 300)
+            .unwrap();
+        assert!(matches!(response_code, ResponseCode::NameError));
+        let owners = nsecs(&records)
+            .into_iter()
+            .map(|(owner, next, _)| (owner, next))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            owners,
+            vec![
+                ("a.b.example".to_string(), "www.example".to_string()),
+                ("example".to_string(), "a.b.example".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn nsec3_proofs() {
+        let signer = signer(DenialMethod::Nsec3);
+        let zone = zone();
+        let hash = |owner: &str| nsec3_hash(&name(owner), &[], 0);
+        let covers = |(owner, next, _): &(Vec<u8>, Vec<u8>, Vec<RecordType>), hash: &[u8]| {
+            match owner < next {
+                true => owner.as_slice() < hash && hash < next.as_slice(),
+                false => owner.as_slice() < hash || hash < next.as_slice(),
+            }
+        };
+
+        let types = [RecordType::Address];
+        let (response_code, records) = signer
+            .deny(&name("www.example"), Some(&types), &zone, 300)
+            .unwrap();
+        assert!(matches!(response_code, ResponseCode::Ok));
+        let records = nsec3s(&records);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].0, hash("www.example"));
+        assert_eq!(
+            records[0].2,
+            vec![RecordType::Address, RecordType::Signature]
+        );
+
+        // Empty non-terminals have NSEC3 records of their own, without types
+        let (_, records) = signer
+            .deny(&name("b.example"), Some(&[]), &zone, 300)
+            .unwrap();
+        let records = nsec3s(&records);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].0, hash("b.example"));
+        assert!(records[0].2.is_empty());
+
+        // A missing name needs the closest encloser to match, and the next closer name and the
+        // wildcard covered
+        let (response_code, records) = signer
+            .deny(&name("x.nope.example"), None, &zone, 300)
+            .unwrap();
+        assert!(matches!(response_code, ResponseCode::NameError));
+        let records = nsec3s(&records);
+        assert!(records.iter().any(|record| record.0 == hash("example")));
+        assert!(records
+            .iter()
+            .any(|record| covers(record, &hash("nope.example"))));
+        assert!(records
+            .iter()
+            .any(|record| covers(record, &hash("*.example"))));
+        assert!(records
+            .iter()
+            .all(|record| record.0 != hash("nope.example")));
+    }
+
+    #[test]
+    fn compact_proofs() {
+        let signer = signer(DenialMethod::Compact);
+        let zone = zone();
+
+        // Missing names are NODATA, with the NXNAME type to say what they really are
+        for (qname, types, expected_types) in [
+            ("nope.example", None, vec![NXNAME]),
+            (
+                "www.example",
+                Some(&[RecordType::Address][..]),
+                vec![RecordType::Address],
+            ),
+        ] {
+            let (response_code, records) = signer.deny(&name(qname), types, &zone, 300).unwrap();
+            assert!(matches!(response_code, ResponseCode::Ok));
+            let mut expected_types = expected_types;
+            expected_types.extend([RecordType::Signature, RecordType::NextSecure]);
+            assert_eq!(records.len(), 1);
+            assert_eq!(records[0].name, name(qname));
+            let ResourceRecordData::NextSecure {
+                next_domain_name,
+                types,
+            } = &records[0].data
+            else {
+                panic!("expected an NSEC record");
+            };
+            // Nothing can sort between the name and its first possible child
+            assert_eq!(
+                *next_domain_name,
+                DomainName::new("\0").unwrap().join(&name(qname))
+            );
+            assert_eq!(*types, expected_types);
+        }
+    }
+}
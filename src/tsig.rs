@@ -0,0 +1,677 @@
+//! TSIG (RFC 8945): authenticating messages with a secret key shared by two servers, for zone
+//! transfers, UPDATE and NOTIFY.
+//!
+//! Each MAC covers the MAC before it in the conversation, so a reply is tied to its request and
+//! each message of a multi-message transfer to the last. Every message we send is signed, and
+//! every message we receive must be, rather than allowing unsigned messages mid-transfer.
+
+use std::{
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bytes::{BufMut, BytesMut};
+
+use crate::{
+    crypto::hmac::{hmac_sha256, hmac_sha512},
+    encoding::decode_base64,
+    message::{
+        Class, DomainName, Message, RecordType, ResourceRecord, ResourceRecordData, ResponseCode,
+    },
+};
+
+/// How many seconds either side of the time signed a signature is good for, allowing for clock
+/// skew (RFC 8945 section 10).
+const FUDGE: u16 = 300;
+/// TSIG records are class ANY.
+const CLASS_ANY: Class = Class::Unknown(255);
+
+/// The error field of a TSIG record. Its values are response codes, but BADSIG shares 16 with
+/// BADVERS, so it's kept apart from [`ResponseCode`] (RFC 8945 section 4.2).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TsigError {
+    NoError,
+    /// BADSIG: the MAC didn't verify.
+    BadSignature,
+    /// BADKEY: the key isn't one we know.
+    BadKey,
+    /// BADTIME: the time signed is outside the fudge.
+    BadTime,
+    Unknown(u16),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    HmacSha256,
+    HmacSha512,
+}
+
+/// A key shared with another server.
+#[derive(Debug, Clone)]
+pub struct Key {
+    /// Both sides name the key the same, and the name is how it's found.
+    pub name: DomainName,
+    algorithm: Algorithm,
+    secret: Vec<u8>,
+}
+
+/// One side of a signed conversation: a request and its replies, signed or checked in order.
+#[derive(Debug, Clone)]
+pub struct Session {
+    key: Key,
+    /// The MAC of the last message, which the next one's covers.
+    previous_mac: Option<Vec<u8>>,
+    /// Whether the next message starts a request or reply, and so has a MAC over every TSIG
+    /// field rather than just the timers (RFC 8945 section 5.3.1).
+    first: bool,
+}
+
+impl From<u16> for TsigError {
+    fn from(value: u16) -> Self {
+        match value {
+            0 => TsigError::NoError,
+            16 => TsigError::BadSignature,
+            17 => TsigError::BadKey,
+            18 => TsigError::BadTime,
+            _ => TsigError::Unknown(value),
+        }
+    }
+}
+
+impl From<TsigError> for u16 {
+    fn from(error: TsigError) -> Self {
+        match error {
+            TsigError::NoError => 0,
+            TsigError::BadSignature => 16,
+            TsigError::BadKey => 17,
+            TsigError::BadTime => 18,
+            TsigError::Unknown(value) => value,
+        }
+    }
+}
+
+impl Algorithm {
+    fn name(self) -> &'static str {
+        match self {
+            Algorithm::HmacSha256 => "hmac-sha256",
+            Algorithm::HmacSha512 => "hmac-sha512",
+        }
+    }
+
+    fn mac_size(self) -> usize {
+        match self {
+            Algorithm::HmacSha256 => 32,
+            Algorithm::HmacSha512 => 64,
+        }
+    }
+
+    fn mac(self, secret: &[u8], data: &[u8]) -> Vec<u8> {
+        match self {
+            Algorithm::HmacSha256 => hmac_sha256(secret, data).to_vec(),
+            Algorithm::HmacSha512 => hmac_sha512(secret, data).to_vec(),
+        }
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim_end_matches('.').to_ascii_lowercase().as_str() {
+            "hmac-sha256" => Ok(Algorithm::HmacSha256),
+            "hmac-sha512" => Ok(Algorithm::HmacSha512),
+            _ => anyhow::bail!("unsupported tsig algorithm {s:?}"),
+        }
+    }
+}
+
+impl Key {
+    /// A key from its name, algorithm and base64 secret.
+    pub fn new(name: &str, algorithm: &str, secret: &str) -> anyhow::Result<Self> {
+        Ok(Key {
+            name: DomainName::new(name)?,
+            algorithm: algorithm.parse()?,
+            secret: decode_base64(secret)?,
+        })
+    }
+
+    fn algorithm_name(&self) -> DomainName {
+        DomainName::new(self.algorithm.name()).expect("algorithm names should be valid")
+    }
+}
+
+/// Check the TSIG on a request, if it has one, returning the session to sign replies with. A
+/// request which fails gets an error reply, signed only if the key was right (RFC 8945 section
+/// 5.2).
+pub fn verify_request(
+    keys: &[Key],
+    query_message: &Message,
+) -> Result<Option<Session>, Box<Message>> {
+    let tsig_count = query_message
+        .answers
+        .iter()
+        .chain(query_message.authorities.iter())
+        .chain(query_message.additionals.iter())
+        .filter(|record| record.ty == RecordType::TransactionSignature)
+        .count();
+    let (Some(record), Some(data)) = (query_message.additionals.last(), &query_message.tsig_data)
+    else {
+        if tsig_count > 0 {
+            // It has to be the last record (RFC 8945 section 5.1)
+            return Err(Box::new(Message::new_error_reply(
+                query_message,
+                ResponseCode::FormatError,
+            )));
+        }
+        return Ok(None);
+    };
+    if tsig_count > 1 {
+        return Err(Box::new(Message::new_error_reply(
+            query_message,
+            ResponseCode::FormatError,
+        )));
+    }
+    let ResourceRecordData::TransactionSignature {
+        algorithm,
+        time_signed,
+        fudge,
+        mac,
+        original_id,
+        ..
+    } = &record.data
+    else {
+        unreachable!("tsig_data is only set for a TSIG record");
+    };
+
+    let key = keys.iter().find(|key| {
+        key.name == record.name && key.algorithm_name().to_lowercase() == algorithm.to_lowercase()
+    });
+    let Some(key) = key else {
+        return Err(Box::new(unsigned_error_reply(
+            query_message,
+            record,
+            TsigError::BadKey,
+        )));
+    };
+    let expected_mac = key.algorithm.mac(
+        &key.secret,
+        &[
+            with_id(data, *original_id),
+            variables(&record.name, key, *time_signed, *fudge, 0, &[]),
+        ]
+        .concat(),
+    );
+    if !equal_macs(mac, &expected_mac) {
+        return Err(Box::new(unsigned_error_reply(
+            query_message,
+            record,
+            TsigError::BadSignature,
+        )));
+    }
+
+    let mut session = Session {
+        key: key.clone(),
+        previous_mac: Some(mac.clone()),
+        first: true,
+    };
+    let now = now();
+    if now.abs_diff(*time_signed) > u64::from(*fudge) {
+        // Signed, with our time so the client can see how far off it is
+        let mut response_message =
+            Message::new_error_reply(query_message, ResponseCode::NotAuthoritative);
+        session
+            .sign_with(
+                &mut response_message,
+                TsigError::BadTime,
+                &now.to_be_bytes()[2..],
+            )
+            .map_err(|_| unsigned_error_reply(query_message, record, TsigError::BadTime))?;
+        return Err(Box::new(response_message));
+    }
+    Ok(Some(session))
+}
+
+/// An error reply with a TSIG record carrying the error but no MAC, for when the request's
+/// signature can't be trusted.
+fn unsigned_error_reply(
+    query_message: &Message,
+    request_tsig: &ResourceRecord,
+    error: TsigError,
+) -> Message {
+    let mut response_message =
+        Message::new_error_reply(query_message, ResponseCode::NotAuthoritative);
+    let mut data = request_tsig.data.clone();
+    if let ResourceRecordData::TransactionSignature {
+        mac,
+        error: record_error,
+        other_data,
+        ..
+    } = &mut data
+    {
+        mac.clear();
+        *record_error = error.into();
+        other_data.clear();
+    }
+    response_message.additionals.push(ResourceRecord::new(
+        request_tsig.name.clone(),
+        RecordType::TransactionSignature,
+        CLASS_ANY,
+        0,
+        data,
+    ));
+    response_message
+}
+
+impl Session {
+    /// A session for a request we're about to send.
+    pub fn new(key: Key) -> Self {
+        Session {
+            key,
+            previous_mac: None,
+            first: true,
+        }
+    }
+
+    pub fn key(&self) -> &Key {
+        &self.key
+    }
+
+    /// How much signing a message adds to it, to leave room for when it has to fit a limit.
+    pub fn signature_size(&self) -> usize {
+        let record = ResourceRecord::new(
+            self.key.name.clone(),
+            RecordType::TransactionSignature,
+            CLASS_ANY,
+            0,
+            ResourceRecordData::TransactionSignature {
+                algorithm: self.key.algorithm_name(),
+                time_signed: 0,
+                fudge: FUDGE,
+                mac: vec![0; self.key.algorithm.mac_size()],
+                original_id: 0,
+                error: 0,
+                other_data: Vec::new(),
+            },
+        );
+        let mut buf = Vec::new();
+        record
+            .write(&mut buf)
+            .expect("tsig records should be writable");
+        buf.len()
+    }
+
+    /// Sign a message, which must then be sent without changes.
+    pub fn sign(&mut self, message: &mut Message) -> anyhow::Result<()> {
+        self.sign_with(message, TsigError::NoError, &[])
+    }
+
+    fn sign_with(
+        &mut self,
+        message: &mut Message,
+        error: TsigError,
+        other_data: &[u8],
+    ) -> anyhow::Result<()> {
+        let time_signed = now();
+        let mut data = BytesMut::with_capacity(512);
+        message.write(&mut data)?;
+        let mac = self.mac(
+            &self.key.name,
+            &data,
+            time_signed,
+            FUDGE,
+            error.into(),
+            other_data,
+        );
+        message.additionals.push(ResourceRecord::new(
+            self.key.name.clone(),
+            RecordType::TransactionSignature,
+            CLASS_ANY,
+            0,
+            ResourceRecordData::TransactionSignature {
+                algorithm: self.key.algorithm_name(),
+                time_signed,
+                fudge: FUDGE,
+                mac: mac.clone(),
+                original_id: message.header.packet_id,
+                error: error.into(),
+                other_data: other_data.to_vec(),
+            },
+        ));
+
+        self.previous_mac = Some(mac);
+        // A request is followed by the first message of its reply
+        self.first = !message.header.is_response;
+        Ok(())
+    }
+
+    /// Check the signature on a reply to a request signed in this session.
+    pub fn verify_response(&mut self, message: &Message) -> anyhow::Result<()> {
+        let (Some(record), Some(data)) = (message.additionals.last(), &message.tsig_data) else {
+            anyhow::bail!("unsigned response");
+        };
+        let ResourceRecordData::TransactionSignature {
+            algorithm,
+            time_signed,
+            fudge,
+            mac,
+            original_id,
+            error,
+            other_data,
+        } = &record.data
+        else {
+            unreachable!("tsig_data is only set for a TSIG record");
+        };
+        if record.name != self.key.name
+            || algorithm.to_lowercase() != self.key.algorithm_name().to_lowercase()
+        {
+            anyhow::bail!("response signed with another key");
+        }
+        if *error != 0 {
+            anyhow::bail!("tsig error {:?}", TsigError::from(*error));
+        }
+        let expected_mac = self.mac(
+            &record.name,
+            &with_id(data, *original_id),
+            *time_signed,
+            *fudge,
+            *error,
+            other_data,
+        );
+        if !equal_macs(mac, &expected_mac) {
+            anyhow::bail!("bad tsig signature");
+        }
+        if now().abs_diff(*time_signed) > u64::from(*fudge) {
+            anyhow::bail!("tsig signature outside the time window");
+        }
+        self.previous_mac = Some(mac.clone());
+        self.first = false;
+        Ok(())
+    }
+
+    /// The MAC over a message, chained to the one before it.
+    fn mac(
+        &self,
+        name: &DomainName,
+        message_data: &[u8],
+        time_signed: u64,
+        fudge: u16,
+        error: u16,
+        other_data: &[u8],
+    ) -> Vec<u8> {
+        let mut data = Vec::new();
+        if let Some(previous_mac) = &self.previous_mac {
+            data.put_u16(previous_mac.len() as u16);
+            data.put_slice(previous_mac);
+        }
+        data.put_slice(message_data);
+        if self.first {
+            data.put_slice(&variables(
+                name,
+                &self.key,
+                time_signed,
+                fudge,
+                error,
+                other_data,
+            ));
+        } else {
+            data.put_slice(&timers(time_signed, fudge));
+        }
+        self.key.algorithm.mac(&self.key.secret, &data)
+    }
+}
+
+/// The TSIG fields a MAC covers, in canonical form (RFC 8945 section 4.3.3).
+fn variables(
+    name: &DomainName,
+    key: &Key,
+    time_signed: u64,
+    fudge: u16,
+    error: u16,
+    other_data: &[u8],
+) -> Vec<u8> {
+    let mut data = Vec::new();
+    name.to_lowercase()
+        .write(&mut data)
+        .expect("a parsed name should be writable");
+    data.put_u16(CLASS_ANY.into());
+    data.put_u32(0);
+    key.algorithm_name()
+        .write(&mut data)
+        .expect("algorithm names should be writable");
+    data.put_slice(&timers(time_signed, fudge));
+    data.put_u16(error);
+    data.put_u16(other_data.len() as u16);
+    data.put_slice(other_data);
+    data
+}
+
+fn timers(time_signed: u64, fudge: u16) -> Vec<u8> {
+    let mut data = time_signed.to_be_bytes()[2..].to_vec();
+    data.put_u16(fudge);
+    data
+}
+
+/// A message's data with the ID it was signed with, in case a forwarder has changed it.
+fn with_id(data: &[u8], original_id: u16) -> Vec<u8> {
+    let mut data = data.to_vec();
+    data[..2].copy_from_slice(&original_id.to_be_bytes());
+    data
+}
+
+/// Compare MACs in time independent of where they differ.
+fn equal_macs(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::message::Question;
+
+    use super::*;
+
+    const SECRET: &str = "c2VjcmV0IHNoYXJlZCBieSB0aGUgdHdvIHNlcnZlcnM=";
+
+    fn key(name: &str) -> Key {
+        Key::new(name, "hmac-sha256", SECRET).unwrap()
+    }
+
+    fn query() -> Message {
+        Message::new_query(vec![Question {
+            name: DomainName::new("example").unwrap(),
+            ty: RecordType::StartOfAuthority,
+            class: Class::Internet,
+        }])
+    }
+
+    fn reply(query_message: &Message) -> Message {
+        Message::new_reply(query_message, query_message.questions.clone(), Vec::new())
+    }
+
+    /// `message` as it arrives on the other side.
+    fn send(message: &Message) -> Message {
+        Message::parse(&write(message)).unwrap()
+    }
+
+    fn write(message: &Message) -> BytesMut {
+        let mut buf = BytesMut::new();
+        message.write(&mut buf).unwrap();
+        buf
+    }
+
+    /// The error and MAC of the TSIG record on `message`.
+    fn tsig(message: &Message) -> (TsigError, Vec<u8>) {
+        match &message.additionals.last().unwrap().data {
+            ResourceRecordData::TransactionSignature { error, mac, .. } => {
+                ((*error).into(), mac.clone())
+            }
+            _ => panic!("no tsig record"),
+        }
+    }
+
+    /// A request signed with `key` at `time_signed`.
+    fn signed_at(key: &Key, time_signed: u64) -> Message {
+        let mut query_message = query();
+        let mac = key.algorithm.mac(
+            &key.secret,
+            &[
+                write(&query_message).to_vec(),
+                variables(&key.name, key, time_signed, FUDGE, 0, &[]),
+            ]
+            .concat(),
+        );
+        query_message.additionals.push(ResourceRecord::new(
+            key.name.clone(),
+            RecordType::TransactionSignature,
+            CLASS_ANY,
+            0,
+            ResourceRecordData::TransactionSignature {
+                algorithm: key.algorithm_name(),
+                time_signed,
+                fudge: FUDGE,
+                mac,
+                original_id: query_message.header.packet_id,
+                error: 0,
+                other_data: Vec::new(),
+            },
+        ));
+        send(&query_message)
+    }
+
+    #[test]
+    fn tsig_errors_round_trip() {
+        for value in 0..=u16::from(u8::MAX) {
+            assert_eq!(u16::from(TsigError::from(value)), value);
+        }
+        assert_eq!(TsigError::from(0), TsigError::NoError);
+        assert_eq!(TsigError::from(16), TsigError::BadSignature);
+        assert_eq!(TsigError::from(17), TsigError::BadKey);
+        assert_eq!(TsigError::from(18), TsigError::BadTime);
+        assert_eq!(TsigError::from(19), TsigError::Unknown(19));
+    }
+
+    #[test]
+    fn keys() {
+        assert_eq!(
+            "HMAC-SHA256.".parse::<Algorithm>().unwrap(),
+            Algorithm::HmacSha256
+        );
+        assert_eq!(
+            "hmac-sha512".parse::<Algorithm>().unwrap(),
+            Algorithm::HmacSha512
+        );
+        assert!("hmac-md5.sig-alg.reg.int".parse::<Algorithm>().is_err());
+        assert!(Key::new("transfer.key", "hmac-sha256", "not base64!").is_err());
+    }
+
+    #[test]
+    fn signed_conversations_verify() {
+        for algorithm in ["hmac-sha256", "hmac-sha512"] {
+            let transfer_key = Key::new("transfer.key", algorithm, SECRET).unwrap();
+            let mut client = Session::new(transfer_key.clone());
+            let mut query_message = query();
+            let unsigned_size = write(&query_message).len();
+            client.sign(&mut query_message).unwrap();
+            assert_eq!(
+                write(&query_message).len() - unsigned_size,
+                client.signature_size()
+            );
+            let query_message = send(&query_message);
+
+            let mut server = verify_request(&[key("other.key"), transfer_key], &query_message)
+                .unwrap()
+                .expect("the request should be signed");
+            // Every message of a multi-message reply is signed and chained to the last
+            for _ in 0..3 {
+                let mut response_message = reply(&query_message);
+                server.sign(&mut response_message).unwrap();
+                client.verify_response(&send(&response_message)).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn unsigned_requests_have_no_session() {
+        assert!(verify_request(&[key("transfer.key")], &send(&query()))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn requests_with_bad_signatures_are_rejected() {
+        let mut client = Session::new(key("transfer.key"));
+        let mut query_message = query();
+        client.sign(&mut query_message).unwrap();
+
+        let error_reply = verify_request(&[key("other.key")], &send(&query_message)).unwrap_err();
+        assert_eq!(
+            error_reply.header.response_code,
+            ResponseCode::NotAuthoritative
+        );
+        assert_eq!(tsig(&error_reply), (TsigError::BadKey, Vec::new()));
+
+        query_message.header.recursion_desired = !query_message.header.recursion_desired;
+        let error_reply =
+            verify_request(&[key("transfer.key")], &send(&query_message)).unwrap_err();
+        assert_eq!(tsig(&error_reply), (TsigError::BadSignature, Vec::new()));
+        assert!(client.verify_response(&send(&error_reply)).is_err());
+    }
+
+    #[test]
+    fn requests_signed_outside_the_fudge_get_a_signed_error() {
+        let key = key("transfer.key");
+        let error_reply =
+            verify_request(std::slice::from_ref(&key), &signed_at(&key, now() - 301)).unwrap_err();
+        let (error, mac) = tsig(&error_reply);
+        assert_eq!(error, TsigError::BadTime);
+        assert_eq!(mac.len(), 32);
+
+        assert!(
+            verify_request(std::slice::from_ref(&key), &signed_at(&key, now() - 299))
+                .unwrap()
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn responses_must_follow_on_from_the_last_message() {
+        let mut client = Session::new(key("transfer.key"));
+        let mut query_message = query();
+        client.sign(&mut query_message).unwrap();
+        let query_message = send(&query_message);
+        let mut server = verify_request(&[key("transfer.key")], &query_message)
+            .unwrap()
+            .unwrap();
+
+        // Changed in transit
+        let mut response_message = reply(&query_message);
+        server.sign(&mut response_message).unwrap();
+        response_message.header.authoritative_answer = true;
+        assert!(client
+            .clone()
+            .verify_response(&send(&response_message))
+            .is_err());
+
+        // One missing
+        let mut skipped = reply(&query_message);
+        server.sign(&mut skipped).unwrap();
+        let mut response_message = reply(&query_message);
+        server.sign(&mut response_message).unwrap();
+        assert!(client
+            .clone()
+            .verify_response(&send(&response_message))
+            .is_err());
+
+        // Unsigned, or signed with another key
+        assert!(client.verify_response(&reply(&query_message)).is_err());
+        let mut other = Session::new(key("other.key"));
+        let mut response_message = reply(&query_message);
+        other.sign(&mut response_message).unwrap();
+        assert!(client.verify_response(&send(&response_message)).is_err());
+    }
+}
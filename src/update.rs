@@ -0,0 +1,458 @@
+//! Dynamic updates (RFC 2136): checking an UPDATE's prerequisites against a zone's records, then
+//! applying its additions and deletions. Locking, serials and the journal are up to the zone.
+
+use std::collections::HashMap;
+
+use crate::{
+    message::{Class, DomainName, RecordType, ResourceRecord, ResourceRecordData, ResponseCode},
+    zone::{serial, serial_lt, AXFR, IXFR},
+};
+
+/// ANY: In prerequisites, an RRset or name which must exist, and in updates, an RRset or name to
+/// delete.
+const CLASS_ANY: Class = Class::Unknown(255);
+/// NONE: In prerequisites, an RRset or name which mustn't exist, and in updates, a record to
+/// delete.
+const CLASS_NONE: Class = Class::Unknown(254);
+/// ANY: Every type at a name.
+const TYPE_ANY: RecordType = RecordType::Unknown(255);
+
+/// Every record in a zone by name, as kept by the zone.
+pub type Records = HashMap<DomainName, Vec<ResourceRecord>>;
+
+/// Check the prerequisite section (RFC 2136 section 3.2).
+pub fn check_prerequisites(
+    records: &Records,
+    origin: &DomainName,
+    prerequisites: &[ResourceRecord],
+) -> Result<(), ResponseCode> {
+    // Records which must exist are grouped into RRsets, which must match exactly
+    let mut rrsets = HashMap::<(DomainName, RecordType), Vec<&ResourceRecord>>::new();
+    for prerequisite in prerequisites {
+        if prerequisite.time_to_live != 0 {
+            return Err(ResponseCode::FormatError);
+        }
+        if !prerequisite.name.is_subdomain_of(origin) {
+            return Err(ResponseCode::NotZone);
+        }
+        let name_in_use = records
+            .get(&prerequisite.name)
+            .is_some_and(|records| !records.is_empty());
+        let rrset_exists = records
+            .get(&prerequisite.name)
+            .is_some_and(|records| records.iter().any(|record| record.ty == prerequisite.ty));
+        match prerequisite.class {
+            CLASS_ANY | CLASS_NONE if prerequisite.length != 0 => {
+                return Err(ResponseCode::FormatError)
+            }
+            CLASS_ANY if prerequisite.ty == TYPE_ANY && !name_in_use => {
+                return Err(ResponseCode::NameError)
+            }
+            CLASS_ANY if prerequisite.ty != TYPE_ANY && !rrset_exists => {
+                return Err(ResponseCode::RrSetMissing)
+            }
+            CLASS_NONE if prerequisite.ty == TYPE_ANY && name_in_use => {
+                return Err(ResponseCode::NameExists)
+            }
+            CLASS_NONE if prerequisite.ty != TYPE_ANY && rrset_exists => {
+                return Err(ResponseCode::RrSetExists)
+            }
+            CLASS_ANY | CLASS_NONE => {}
+            Class::Internet => rrsets
+                .entry((prerequisite.name.clone(), prerequisite.ty))
+                .or_default()
+                .push(prerequisite),
+            _ => return Err(ResponseCode::FormatError),
+        }
+    }
+
+    for ((name, ty), expected) in rrsets {
+        let actual = records
+            .get(&name)
+            .into_iter()
+            .flatten()
+            .filter(|record| record.ty == ty)
+            .collect::<Vec<_>>();
+        let matches = actual.len() == expected.len()
+            && expected
+                .iter()
+                .all(|expected| actual.iter().any(|record| same_data(record, expected)));
+        if !matches {
+            return Err(ResponseCode::RrSetMissing);
+        }
+    }
+    Ok(())
+}
+
+/// Check the update section before touching anything, so that a bad update changes nothing
+/// (RFC 2136 section 3.4.1).
+pub fn check_updates(origin: &DomainName, updates: &[ResourceRecord]) -> Result<(), ResponseCode> {
+    for update in updates {
+        if !update.name.is_subdomain_of(origin) {
+            return Err(ResponseCode::NotZone);
+        }
+        let is_meta_type = matches!(
+            update.ty,
+            TYPE_ANY | AXFR | IXFR | RecordType::Unknown(253..)
+        ) || update.ty == RecordType::Opt;
+        let valid = match update.class {
+            Class::Internet => !is_meta_type,
+            CLASS_ANY => {
+                update.time_to_live == 0
+                    && update.length == 0
+                    && (!is_meta_type || update.ty == TYPE_ANY)
+            }
+            CLASS_NONE => update.time_to_live == 0 && !is_meta_type,
+            _ => false,
+        };
+        if !valid {
+            return Err(ResponseCode::FormatError);
+        }
+    }
+    Ok(())
+}
+
+/// Apply the update section, which `check_updates` has passed (RFC 2136 section 3.4.2). The SOA
+/// is only ever replaced by one with a later serial, and the zone's own SOA and NS records are
+/// never deleted.
+pub fn apply_updates(records: &mut Records, origin: &DomainName, updates: &[ResourceRecord]) {
+    for update in updates {
+        let at_apex = update.name == *origin;
+        let protected = |record: &ResourceRecord| {
+            at_apex
+                && matches!(
+                    record.ty,
+                    RecordType::StartOfAuthority | RecordType::NameServer
+                )
+        };
+        match update.class {
+            Class::Internet => add(records, update),
+            CLASS_ANY => {
+                if let Some(records) = records.get_mut(&update.name) {
+                    records.retain(|record| {
+                        protected(record) || (update.ty != TYPE_ANY && record.ty != update.ty)
+                    });
+                }
+            }
+            _ => {
+                let is_last_apex_name_server = at_apex
+                    && update.ty == RecordType::NameServer
+                    && records.get(&update.name).is_some_and(|records| {
+                        records
+                            .iter()
+                            .filter(|record| record.ty == RecordType::NameServer)
+                            .count()
+                            == 1
+                    });
+                if update.ty == RecordType::StartOfAuthority || is_last_apex_name_server {
+                    continue;
+                }
+                if let Some(records) = records.get_mut(&update.name) {
+                    records.retain(|record| record.ty != update.ty || !same_data(record, update));
+                }
+            }
+        }
+    }
+    records.retain(|_, records| !records.is_empty());
+}
+
+/// Add a record, unless it's already there or would break the rule that a CNAME stands alone.
+fn add(records: &mut Records, update: &ResourceRecord) {
+    let mut record = update.clone();
+    record.class = Class::Internet;
+    let existing = records.entry(update.name.clone()).or_default();
+    let has_cname = existing.iter().any(|r| r.ty == RecordType::CName);
+    let has_other = existing
+        .iter()
+        .any(|r| r.ty != RecordType::CName && !is_dnssec_type(r.ty));
+    match update.ty {
+        RecordType::CName if has_other => {}
+        RecordType::CName => {
+            // A new CNAME replaces the old one
+            existing.retain(|r| r.ty != RecordType::CName);
+            existing.push(record);
+        }
+        _ if has_cname && !is_dnssec_type(update.ty) => {}
+        RecordType::StartOfAuthority => {
+            let newer = existing.iter().any(|r| {
+                r.ty == RecordType::StartOfAuthority && serial_lt(serial(r), serial(update))
+            });
+            if newer {
+                existing.retain(|r| r.ty != RecordType::StartOfAuthority);
+                existing.push(record);
+            }
+        }
+        _ => match existing
+            .iter_mut()
+            .find(|r| r.ty == update.ty && same_data(r, update))
+        {
+            // A duplicate just updates the TTL
+            Some(duplicate) => duplicate.time_to_live = update.time_to_live,
+            None => existing.push(record),
+        },
+    }
+}
+
+/// Types which can sit alongside a CNAME (RFC 4035 section 2.5).
+fn is_dnssec_type(ty: RecordType) -> bool {
+    matches!(ty, RecordType::Signature | RecordType::NextSecure)
+}
+
+/// Whether two records of the same type hold the same data, comparing names case-insensitively.
+fn same_data(a: &ResourceRecord, b: &ResourceRecord) -> bool {
+    let wire = |data: &ResourceRecordData| {
+        let mut buf = Vec::new();
+        data.to_canonical().write(&mut buf).ok().map(|()| buf)
+    };
+    wire(&a.data) == wire(&b.data)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::zone_file;
+
+    use super::*;
+
+    fn name(name: &str) -> DomainName {
+        DomainName::new(name).unwrap()
+    }
+
+    fn records() -> Records {
+        let contents = "\
+$ORIGIN example.
+@ 3600 SOA ns1 hostmaster 10 3600 600 86400 300
+@ 3600 NS ns1
+ns1 3600 A 192.0.2.53
+www 300 A 192.0.2.80
+www 300 A 192.0.2.81
+alias 300 CNAME www
+";
+        let mut records = Records::new();
+        for record in zone_file::parse(contents, &name("example")).unwrap() {
+            records.entry(record.name.clone()).or_default().push(record);
+        }
+        records
+    }
+
+    /// A record in zone file syntax, with its class and TTL replaced.
+    fn record(class: Class, ttl: u32, line: &str) -> ResourceRecord {
+        let mut record = zone_file::parse(line, &name("example")).unwrap().remove(0);
+        record.class = class;
+        record.time_to_live = ttl;
+        record
+    }
+
+    /// A record with no data, for prerequisites and deletions by type or name.
+    fn empty(class: Class, owner: &str, ty: RecordType) -> ResourceRecord {
+        ResourceRecord::new(
+            name(owner),
+            ty,
+            class,
+            0,
+            ResourceRecordData::Unknown(Vec::new()),
+        )
+    }
+
+    /// The TTL and data of each record of type `ty` at `owner`.
+    fn data(records: &Records, owner: &str, ty: RecordType) -> Vec<String> {
+        let mut data = records
+            .get(&name(owner))
+            .into_iter()
+            .flatten()
+            .filter(|record| record.ty == ty)
+            .map(|record| format!("{} {:?}", record.time_to_live, record.data))
+            .collect::<Vec<_>>();
+        data.sort();
+        data
+    }
+
+    #[test]
+    fn prerequisites() {
+        let records = records();
+        let origin = name("example");
+        let check = |prerequisites: &[ResourceRecord]| {
+            check_prerequisites(&records, &origin, prerequisites)
+        };
+        let www_a = [
+            record(Class::Internet, 0, "www A 192.0.2.81"),
+            record(Class::Internet, 0, "www A 192.0.2.80"),
+        ];
+
+        assert_eq!(check(&[]), Ok(()));
+        assert_eq!(check(&[empty(CLASS_ANY, "www.example", TYPE_ANY)]), Ok(()));
+        assert_eq!(
+            check(&[empty(CLASS_ANY, "mail.example", TYPE_ANY)]),
+            Err(ResponseCode::NameError)
+        );
+        assert_eq!(
+            check(&[empty(CLASS_ANY, "www.example", RecordType::Address)]),
+            Ok(())
+        );
+        assert_eq!(
+            check(&[empty(CLASS_ANY, "www.example", RecordType::Ipv6Address)]),
+            Err(ResponseCode::RrSetMissing)
+        );
+        assert_eq!(
+            check(&[empty(CLASS_NONE, "mail.example", TYPE_ANY)]),
+            Ok(())
+        );
+        assert_eq!(
+            check(&[empty(CLASS_NONE, "www.example", TYPE_ANY)]),
+            Err(ResponseCode::NameExists)
+        );
+        assert_eq!(
+            check(&[empty(CLASS_NONE, "www.example", RecordType::Address)]),
+            Err(ResponseCode::RrSetExists)
+        );
+        assert_eq!(check(&www_a), Ok(()));
+        assert_eq!(check(&www_a[..1]), Err(ResponseCode::RrSetMissing));
+        assert_eq!(
+            check(&[record(Class::Internet, 0, "www A 192.0.2.82")]),
+            Err(ResponseCode::RrSetMissing)
+        );
+        assert_eq!(
+            check(&[record(Class::Internet, 300, "www A 192.0.2.80")]),
+            Err(ResponseCode::FormatError)
+        );
+        assert_eq!(
+            check(&[record(CLASS_ANY, 0, "www A 192.0.2.80")]),
+            Err(ResponseCode::FormatError)
+        );
+        assert_eq!(
+            check(&[empty(CLASS_ANY, "www.example.net", TYPE_ANY)]),
+            Err(ResponseCode::NotZone)
+        );
+    }
+
+    #[test]
+    fn updates_are_checked_before_any_are_applied() {
+        let origin = name("example");
+        let valid = [
+            record(Class::Internet, 300, "mail A 192.0.2.25"),
+            empty(CLASS_ANY, "www.example", RecordType::Address),
+            empty(CLASS_ANY, "www.example", TYPE_ANY),
+            record(CLASS_NONE, 0, "www A 192.0.2.80"),
+        ];
+        assert_eq!(check_updates(&origin, &valid), Ok(()));
+        let invalid = [
+            (
+                empty(Class::Internet, "www.example", TYPE_ANY),
+                ResponseCode::FormatError,
+            ),
+            (
+                record(CLASS_ANY, 0, "www A 192.0.2.80"),
+                ResponseCode::FormatError,
+            ),
+            (
+                empty(CLASS_NONE, "www.example", AXFR),
+                ResponseCode::FormatError,
+            ),
+            (
+                record(CLASS_NONE, 300, "www A 192.0.2.80"),
+                ResponseCode::FormatError,
+            ),
+            (
+                record(Class::Unknown(3), 300, "www A 192.0.2.80"),
+                ResponseCode::FormatError,
+            ),
+            (
+                record(Class::Internet, 300, "www.example.net. A 192.0.2.80"),
+                ResponseCode::NotZone,
+            ),
+        ];
+        for (update, response_code) in invalid {
+            let updates = [valid[0].clone(), update];
+            assert_eq!(check_updates(&origin, &updates), Err(response_code));
+        }
+    }
+
+    #[test]
+    fn additions() {
+        let mut records = records();
+        let origin = name("example");
+        apply_updates(
+            &mut records,
+            &origin,
+            &[
+                record(Class::Internet, 300, "mail A 192.0.2.25"),
+                // A duplicate only changes the TTL
+                record(Class::Internet, 60, "www A 192.0.2.80"),
+                // A CNAME can't join other data, nor other data a CNAME
+                record(Class::Internet, 300, "www CNAME mail"),
+                record(Class::Internet, 300, "alias A 192.0.2.1"),
+                // Another CNAME replaces it
+                record(Class::Internet, 300, "alias CNAME mail"),
+                // Older SOAs are ignored
+                record(
+                    Class::Internet,
+                    3600,
+                    "@ SOA ns1 hostmaster 9 3600 600 86400 300",
+                ),
+            ],
+        );
+        assert_eq!(data(&records, "mail.example", RecordType::Address).len(), 1);
+        assert_eq!(
+            data(&records, "www.example", RecordType::Address),
+            ["300 IPv4([192, 0, 2, 81])", "60 IPv4([192, 0, 2, 80])",]
+        );
+        assert!(data(&records, "www.example", RecordType::CName).is_empty());
+        assert!(data(&records, "alias.example", RecordType::Address).is_empty());
+        assert_eq!(
+            data(&records, "alias.example", RecordType::CName),
+            [format!(
+                "300 {:?}",
+                ResourceRecordData::CName(name("mail.example"))
+            )]
+        );
+        let soa = &records[&origin][0];
+        assert_eq!(serial(soa), 10);
+
+        let newer_soa = record(
+            Class::Internet,
+            3600,
+            "@ SOA ns1 hostmaster 11 3600 600 86400 300",
+        );
+        apply_updates(&mut records, &origin, &[newer_soa]);
+        let soas = records[&origin]
+            .iter()
+            .filter(|record| record.ty == RecordType::StartOfAuthority)
+            .collect::<Vec<_>>();
+        assert_eq!(soas.len(), 1);
+        assert_eq!(serial(soas[0]), 11);
+    }
+
+    #[test]
+    fn deletions() {
+        let mut records = records();
+        let origin = name("example");
+        apply_updates(
+            &mut records,
+            &origin,
+            &[
+                record(CLASS_NONE, 0, "www A 192.0.2.80"),
+                empty(CLASS_ANY, "alias.example", TYPE_ANY),
+                // The apex SOA and NS records stay
+                empty(CLASS_ANY, "example", TYPE_ANY),
+                record(CLASS_NONE, 0, "@ NS ns1"),
+                record(CLASS_NONE, 0, "@ SOA ns1 hostmaster 10 3600 600 86400 300"),
+            ],
+        );
+        assert_eq!(
+            data(&records, "www.example", RecordType::Address),
+            ["300 IPv4([192, 0, 2, 81])"]
+        );
+        assert!(!records.contains_key(&name("alias.example")));
+        assert_eq!(data(&records, "example", RecordType::NameServer).len(), 1);
+        assert_eq!(
+            data(&records, "example", RecordType::StartOfAuthority).len(),
+            1
+        );
+
+        apply_updates(
+            &mut records,
+            &origin,
+            &[empty(CLASS_ANY, "www.example", RecordType::Address)],
+        );
+        assert!(!records.contains_key(&name("www.example")));
+    }
+}
@@ -0,0 +1,1715 @@
+//! Authoritative zones, answered directly and served to secondaries by zone transfer: AXFR
+//! (RFC 5936) for the whole zone, and IXFR (RFC 1995) for the changes since a serial the
+//! secondary already has.
+
+use std::{
+    cmp::Ordering,
+    collections::{BTreeSet, HashMap, HashSet, VecDeque},
+    fs,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{
+    acl::Cidr,
+    message::{
+        DomainName, ExtendedErrorCode, Message, Question, RecordType, ResourceRecord,
+        ResourceRecordData, ResponseCode,
+    },
+    notify,
+    secondary::{self, Transfer},
+    tsig::Key,
+    update, zone_file,
+};
+
+/// IXFR: A transfer of the changes since a serial (RFC 1995).
+pub const IXFR: RecordType = RecordType::Unknown(251);
+/// AXFR: A transfer of the whole zone (RFC 5936).
+pub const AXFR: RecordType = RecordType::Unknown(252);
+
+/// How often to check whether zones need reloading or refreshing.
+pub const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(1);
+/// Zone transfer messages are filled up to this size. It's well under the 64 KiB a TCP message
+/// can be, so that a large record can't push one over.
+const MAX_TRANSFER_MESSAGE_SIZE: usize = 16 * 1024;
+/// How many versions of each zone to keep changes for. Secondaries further behind get the whole
+/// zone instead.
+const MAX_JOURNAL_ENTRIES: usize = 100;
+/// Guards against alias loops.
+const MAX_ALIAS_CHAIN: usize = 8;
+/// How long to wait before retrying a secondary zone which has never been transferred, and so
+/// has no SOA to give a retry time.
+pub const INITIAL_RETRY: Duration = Duration::from_secs(60);
+/// How long to wait before sending an unacknowledged NOTIFY again, doubling with each attempt.
+const NOTIFY_RETRY: Duration = Duration::from_secs(1);
+/// The longest to wait between NOTIFY attempts.
+const MAX_NOTIFY_RETRY: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct ZonesConfig {
+    /// Zones this server is the primary for, and the zone files they're loaded from.
+    pub primaries: Vec<(DomainName, PathBuf)>,
+    /// Zones this server is a secondary for, and the primaries they're transferred from.
+    pub secondaries: Vec<(DomainName, SocketAddr)>,
+    /// Where secondary zones, and primary zones changed by UPDATE, are saved.
+    pub directory: PathBuf,
+    /// Secondaries to send NOTIFY to when a zone changes.
+    pub notify: Vec<(DomainName, SocketAddr)>,
+    /// Clients allowed to UPDATE each zone.
+    pub allow_update: Vec<(DomainName, Cidr)>,
+    /// The names of keys allowed to transfer each zone. The first also signs NOTIFY messages.
+    pub transfer_keys: Vec<(DomainName, DomainName)>,
+    /// The names of keys allowed to UPDATE each zone.
+    pub update_keys: Vec<(DomainName, DomainName)>,
+    /// The names of keys to use with each secondary zone's primary.
+    pub primary_keys: Vec<(DomainName, DomainName)>,
+}
+
+/// The zones of one view. Transfers, UPDATE and NOTIFY can be authenticated with TSIG keys as
+/// well as by address.
+#[derive(Debug)]
+pub struct Zones {
+    zones: Vec<(DomainName, Arc<RwLock<Zone>>)>,
+}
+
+#[derive(Debug)]
+struct Zone {
+    origin: DomainName,
+    source: Source,
+    /// None for a secondary zone which hasn't been transferred yet, or has expired.
+    data: Option<ZoneData>,
+    /// The changes between successive versions of the zone, oldest first, for IXFR.
+    journal: VecDeque<Change>,
+    /// Secondaries to send NOTIFY (RFC 1996) to when the zone changes, until they acknowledge the
+    /// new serial.
+    notify: Vec<SocketAddr>,
+    /// NOTIFY messages which haven't been acknowledged yet.
+    pending_notifies: Vec<PendingNotify>,
+    /// Clients allowed to UPDATE the zone.
+    allow_update: Vec<Cidr>,
+    /// Keys allowed to transfer the zone.
+    transfer_keys: Vec<Key>,
+    /// Keys allowed to UPDATE the zone.
+    update_keys: Vec<Key>,
+    /// The key to use with a secondary zone's primary. It signs everything sent to the primary,
+    /// and the primary's replies must be signed with it to be believed.
+    primary_key: Option<Key>,
+    /// Where the zone is saved when it's changed by a transfer or UPDATE, so it's there on
+    /// restart.
+    saved_path: PathBuf,
+}
+
+#[derive(Debug, Clone)]
+struct PendingNotify {
+    secondary: SocketAddr,
+    /// The serial being announced, so an acknowledgement for an older one isn't mistaken for it.
+    serial: u32,
+    attempts: u32,
+    next_at: Instant,
+    /// Whether a NOTIFY is waiting to be acknowledged, so another isn't sent meanwhile.
+    in_flight: bool,
+}
+
+#[derive(Debug)]
+enum Source {
+    /// A zone file, reloaded when it's modified.
+    File {
+        path: PathBuf,
+        modified: Option<SystemTime>,
+    },
+    /// A primary server the zone is transferred from whenever its serial moves on, on the
+    /// schedule set by the SOA timers.
+    Primary {
+        addr: SocketAddr,
+        /// When to next check the primary's serial.
+        refresh_at: Instant,
+        /// When to stop answering for the zone if the primary can't be reached.
+        expire_at: Option<Instant>,
+        /// How long to wait after a failed refresh, kept from the last SOA so it still applies
+        /// once the zone has expired.
+        retry: Duration,
+    },
+}
+
+#[derive(Debug)]
+struct ZoneData {
+    soa: ResourceRecord,
+    /// Every record by name, the SOA included.
+    records: HashMap<DomainName, Vec<ResourceRecord>>,
+    /// The names in `records`, in canonical order, so that the names beneath one can be found
+    /// without looking at every name in the zone.
+    names: BTreeSet<CanonicalName>,
+}
+
+/// A name ordered canonically (RFC 4034 section 6.1), which puts every name beneath it straight
+/// after it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CanonicalName(DomainName);
+
+/// The difference between two versions of a zone, in the order IXFR sends it.
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub old_soa: ResourceRecord,
+    pub removed: Vec<ResourceRecord>,
+    pub new_soa: ResourceRecord,
+    pub added: Vec<ResourceRecord>,
+}
+
+/// An authoritative answer, which unlike other local answers can be negative or a referral.
+#[derive(Debug)]
+pub struct ZoneAnswer {
+    pub response_code: ResponseCode,
+    /// False for referrals to a delegated child zone.
+    pub authoritative: bool,
+    pub answers: Vec<ResourceRecord>,
+    pub authorities: Vec<ResourceRecord>,
+    pub additionals: Vec<ResourceRecord>,
+}
+
+impl Default for ZonesConfig {
+    fn default() -> Self {
+        ZonesConfig {
+            primaries: Vec::new(),
+            secondaries: Vec::new(),
+            directory: PathBuf::from("."),
+            notify: Vec::new(),
+            allow_update: Vec::new(),
+            transfer_keys: Vec::new(),
+            update_keys: Vec::new(),
+            primary_keys: Vec::new(),
+        }
+    }
+}
+
+impl ZonesConfig {
+    /// The origins of every zone, primary or secondary.
+    pub fn origins(&self) -> impl Iterator<Item = &DomainName> {
+        let primaries = self.primaries.iter().map(|(origin, _)| origin);
+        primaries.chain(self.secondaries.iter().map(|(origin, _)| origin))
+    }
+}
+
+impl Zones {
+    /// The zones in `config`, whose keys are found by name among `keys`.
+    pub fn load(config: &ZonesConfig, keys: &[Key]) -> anyhow::Result<Self> {
+        let mut zones = Vec::new();
+        let saved_path = |origin: &DomainName| config.directory.join(format!("{origin}.zone"));
+        for (origin, path) in config.primaries.iter() {
+            let zone = Zone::load(origin.clone(), path.clone(), saved_path(origin))
+                .map_err(|e| anyhow::format_err!("zone {}: {}", origin, e))?;
+            zones.push(zone);
+        }
+        for (origin, primary) in config.secondaries.iter() {
+            zones.push(Zone::load_secondary(
+                origin.clone(),
+                *primary,
+                saved_path(origin),
+            ));
+        }
+        let find_keys = |zone_keys: &[(DomainName, DomainName)], zone: &DomainName| {
+            zone_keys
+                .iter()
+                .filter(|(origin, _)| origin == zone)
+                .map(|(_, name)| {
+                    keys.iter()
+                        .find(|key| key.name == *name)
+                        .cloned()
+                        .ok_or_else(|| anyhow::format_err!("unknown tsig key {}", name))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()
+        };
+        let zones = zones
+            .into_iter()
+            .map(|mut zone| {
+                zone.notify = config
+                    .notify
+                    .iter()
+                    .filter(|(origin, _)| *origin == zone.origin)
+                    .map(|(_, secondary)| *secondary)
+                    .collect();
+                zone.allow_update = config
+                    .allow_update
+                    .iter()
+                    .filter(|(origin, _)| *origin == zone.origin)
+                    .map(|(_, cidr)| *cidr)
+                    .collect();
+                zone.transfer_keys = find_keys(&config.transfer_keys, &zone.origin)?;
+                zone.update_keys = find_keys(&config.update_keys, &zone.origin)?;
+                zone.primary_key = find_keys(&config.primary_keys, &zone.origin)?
+                    .into_iter()
+                    .next();
+                Ok((zone.origin.clone(), Arc::new(RwLock::new(zone))))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Zones { zones })
+    }
+
+    /// Whether `key` may transfer the zone asked for in `query_message`.
+    pub fn allows_transfer(&self, query_message: &Message, key: &Key) -> bool {
+        let [question] = query_message.questions.as_slice() else {
+            return false;
+        };
+        self.zones
+            .iter()
+            .find(|(origin, _)| *origin == question.name)
+            .is_some_and(|(_, zone)| {
+                zone.read()
+                    .unwrap()
+                    .transfer_keys
+                    .iter()
+                    .any(|transfer_key| transfer_key.name == key.name)
+            })
+    }
+
+    /// The zone `name` is in, if any.
+    fn find(&self, name: &DomainName) -> Option<&Arc<RwLock<Zone>>> {
+        self.zones
+            .iter()
+            .filter(|(origin, _)| name.is_subdomain_of(origin))
+            .max_by_key(|(origin, _)| origin.label_count())
+            .map(|(_, zone)| zone)
+    }
+
+    /// Whether any of the questions are for names in a zone.
+    pub fn contains(&self, query_message: &Message) -> bool {
+        query_message
+            .questions
+            .iter()
+            .any(|question| self.find(&question.name).is_some())
+    }
+
+    /// The answer to `question`, if it's for a name in a zone.
+    pub fn answer(&self, question: &Question) -> Option<ZoneAnswer> {
+        let zone = self.find(&question.name)?;
+        Some(zone.read().unwrap().answer(question))
+    }
+
+    /// The messages making up the zone transfer asked for in `query_message`, or an error reply.
+    /// The caller checks the client is allowed to transfer zones.
+    pub fn transfer(&self, query_message: &Message) -> anyhow::Result<Vec<Message>> {
+        let error_reply =
+            |response_code| Ok(vec![Message::new_error_reply(query_message, response_code)]);
+        let [question] = query_message.questions.as_slice() else {
+            return error_reply(ResponseCode::FormatError);
+        };
+        let Some(zone) = self.find(&question.name) else {
+            return error_reply(ResponseCode::NotAuthoritative);
+        };
+        let zone = zone.read().unwrap();
+        if zone.origin != question.name {
+            // Only whole zones can be transferred (RFC 5936 section 2.2.1)
+            return error_reply(ResponseCode::NotAuthoritative);
+        }
+        let Some(data) = &zone.data else {
+            let mut response_message =
+                Message::new_error_reply(query_message, ResponseCode::ServerFailure);
+            response_message.add_extended_error(ExtendedErrorCode::NotReady, "");
+            return Ok(vec![response_message]);
+        };
+
+        let records = if question.ty == IXFR {
+            // The client's SOA is in the authority section
+            let client_serial = query_message
+                .authorities
+                .iter()
+                .find(|record| record.ty == RecordType::StartOfAuthority)
+                .map(serial);
+            let Some(client_serial) = client_serial else {
+                return error_reply(ResponseCode::FormatError);
+            };
+            zone.changes_since(data, client_serial)
+                .unwrap_or_else(|| data.contents())
+        } else {
+            data.contents()
+        };
+        Ok(transfer_messages(query_message, records))
+    }
+
+    /// The reply to a NOTIFY, which is only accepted from a secondary zone's primary, or signed
+    /// with its key. The zone is refreshed by the next `maintain`.
+    pub fn notify(
+        &self,
+        query_message: &Message,
+        source: SocketAddr,
+        key: Option<&Key>,
+    ) -> Message {
+        let [question] = query_message.questions.as_slice() else {
+            return Message::new_error_reply(query_message, ResponseCode::FormatError);
+        };
+        let zone = self
+            .zones
+            .iter()
+            .find(|(origin, _)| *origin == question.name)
+            .map(|(_, zone)| zone);
+        let Some(zone) = zone else {
+            return Message::new_error_reply(query_message, ResponseCode::NotAuthoritative);
+        };
+        let mut zone = zone.write().unwrap();
+        let signed_by_primary = key.is_some_and(|key| {
+            zone.primary_key
+                .as_ref()
+                .is_some_and(|primary_key| primary_key.name == key.name)
+        });
+        let Source::Primary {
+            addr, refresh_at, ..
+        } = &mut zone.source
+        else {
+            return Message::new_error_reply(query_message, ResponseCode::NotAuthoritative);
+        };
+        // The primary may send from another port than it listens on
+        if addr.ip() != source.ip() && !signed_by_primary {
+            return Message::new_error_reply(query_message, ResponseCode::Refused);
+        }
+        *refresh_at = Instant::now();
+
+        let mut response_message =
+            Message::new_reply(query_message, query_message.questions.clone(), Vec::new());
+        response_message.header.response_code = ResponseCode::Ok;
+        response_message.header.authoritative_answer = true;
+        response_message
+    }
+
+    /// The reply to a dynamic UPDATE (RFC 2136), which is applied all at once or not at all. It
+    /// bumps the serial, and the zone is saved alongside secondary zones rather than over the zone
+    /// file.
+    pub fn update(
+        &self,
+        query_message: &Message,
+        source: SocketAddr,
+        key: Option<&Key>,
+    ) -> Message {
+        // The zone section takes the place of the question
+        let zone = match query_message.questions.as_slice() {
+            [question] if question.ty == RecordType::StartOfAuthority => self
+                .zones
+                .iter()
+                .find(|(origin, _)| *origin == question.name)
+                .map(|(_, zone)| zone),
+            _ => return Message::new_error_reply(query_message, ResponseCode::FormatError),
+        };
+        let response_code = match zone {
+            Some(zone) => {
+                let mut zone = zone.write().unwrap();
+                match zone.update(query_message, source, key) {
+                    Ok(()) => ResponseCode::Ok,
+                    Err(response_code) => response_code,
+                }
+            }
+            None => ResponseCode::NotAuthoritative,
+        };
+        Message::new_error_reply(query_message, response_code)
+    }
+
+    /// Reload zone files which have changed, refresh secondary zones which are due, and send
+    /// NOTIFY to secondaries which are due it. Called every `MAINTENANCE_INTERVAL`.
+    pub fn maintain(&self) {
+        for (origin, zone) in self.zones.iter() {
+            if let Err(e) = reload(zone).and_then(|()| refresh(zone)) {
+                eprintln!("error maintaining zone {origin}: {e}");
+            }
+            send_notifies(zone);
+        }
+    }
+}
+
+/// Send the NOTIFY messages which are due, each from a thread of its own so that slow or absent
+/// secondaries don't hold up maintenance. The next attempt is scheduled as each is sent, and
+/// cancelled once it's acknowledged.
+fn send_notifies(zone: &Arc<RwLock<Zone>>) {
+    let now = Instant::now();
+    let (origin, soa, key, due) = {
+        let mut zone = zone.write().unwrap();
+        let Some(data) = &zone.data else {
+            return;
+        };
+        let soa = data.soa.clone();
+        let mut due = Vec::new();
+        for pending in zone.pending_notifies.iter_mut() {
+            if pending.in_flight || pending.next_at > now {
+                continue;
+            }
+            pending.attempts += 1;
+            let backoff = NOTIFY_RETRY.saturating_mul(1 << pending.attempts.min(16));
+            pending.next_at = now + backoff.min(MAX_NOTIFY_RETRY);
+            pending.in_flight = true;
+            due.push((pending.secondary, pending.serial));
+        }
+        if due.is_empty() {
+            return;
+        }
+        let key = zone.transfer_keys.first().cloned();
+        (zone.origin.clone(), soa, key, due)
+    };
+    for (secondary, pending_serial) in due {
+        let zone = zone.clone();
+        let (origin, soa, key) = (origin.clone(), soa.clone(), key.clone());
+        thread::spawn(move || {
+            let acknowledged = match notify::send(secondary, &soa, key.as_ref()) {
+                Ok(()) => true,
+                Err(e) => {
+                    eprintln!("error notifying {} of zone {}: {}", secondary, origin, e);
+                    false
+                }
+            };
+            // The zone may have moved on to a newer serial meanwhile, which is still to be sent
+            let mut zone = zone.write().unwrap();
+            zone.pending_notifies.retain_mut(|pending| {
+                if pending.secondary != secondary || pending.serial != pending_serial {
+                    return true;
+                }
+                pending.in_flight = false;
+                !acknowledged
+            });
+        });
+    }
+}
+
+/// Reload a primary zone if its file has changed.
+fn reload(zone: &RwLock<Zone>) -> anyhow::Result<()> {
+    let modified = {
+        let zone = zone.read().unwrap();
+        let Source::File { path, modified } = &zone.source else {
+            return Ok(());
+        };
+        match fs::metadata(path).and_then(|metadata| metadata.modified()) {
+            Ok(new_modified) if Some(new_modified) != *modified => new_modified,
+            _ => return Ok(()),
+        }
+    };
+    zone.write().unwrap().reload(modified)
+}
+
+/// Bring a secondary zone up to date with its primary if it's due a refresh and the primary's
+/// serial has moved on. The zone isn't locked while talking to the primary.
+fn refresh(zone: &RwLock<Zone>) -> anyhow::Result<()> {
+    let (origin, primary, key, current_soa) = {
+        let zone = zone.read().unwrap();
+        let Source::Primary {
+            addr, refresh_at, ..
+        } = zone.source
+        else {
+            return Ok(());
+        };
+        if refresh_at > Instant::now() {
+            return Ok(());
+        }
+        let current_soa = zone.data.as_ref().map(|data| data.soa.clone());
+        (
+            zone.origin.clone(),
+            addr,
+            zone.primary_key.clone(),
+            current_soa,
+        )
+    };
+    let key = key.as_ref();
+    let transfer =
+        secondary::query_soa(primary, &origin, key).and_then(|primary_soa| match &current_soa {
+            Some(current_soa) if !serial_lt(serial(current_soa), serial(&primary_soa)) => {
+                Ok(Transfer::UpToDate)
+            }
+            _ => secondary::transfer(primary, &origin, current_soa.as_ref(), key),
+        });
+
+    let mut zone = zone.write().unwrap();
+    let result = transfer.and_then(|transfer| zone.apply(transfer));
+    zone.schedule_refresh(result.is_ok());
+    result
+}
+
+impl Zone {
+    /// A primary zone, from its zone file or the copy saved after an UPDATE if that's later.
+    fn load(origin: DomainName, path: PathBuf, saved_path: PathBuf) -> anyhow::Result<Self> {
+        let modified = fs::metadata(&path)?.modified().ok();
+        let mut data = read_zone_file(&origin, &path)?;
+        if let Some(saved_data) = read_saved_zone(&origin, &saved_path) {
+            if serial_lt(serial(&data.soa), serial(&saved_data.soa)) {
+                data = saved_data;
+            }
+        }
+        Ok(Zone {
+            origin,
+            source: Source::File { path, modified },
+            data: Some(data),
+            journal: VecDeque::new(),
+            notify: Vec::new(),
+            pending_notifies: Vec::new(),
+            allow_update: Vec::new(),
+            transfer_keys: Vec::new(),
+            update_keys: Vec::new(),
+            primary_key: None,
+            saved_path,
+        })
+    }
+
+    /// A secondary zone, starting from the copy saved last time if there is one. It's refreshed
+    /// straight away either way.
+    fn load_secondary(origin: DomainName, addr: SocketAddr, saved_path: PathBuf) -> Self {
+        let data = read_saved_zone(&origin, &saved_path);
+        let expire_at = data
+            .as_ref()
+            .map(|data| Instant::now() + data.timer(Timer::Expire));
+        let retry = data
+            .as_ref()
+            .map_or(INITIAL_RETRY, |data| data.timer(Timer::Retry));
+        Zone {
+            origin,
+            source: Source::Primary {
+                addr,
+                refresh_at: Instant::now(),
+                expire_at,
+                retry,
+            },
+            data,
+            journal: VecDeque::new(),
+            notify: Vec::new(),
+            pending_notifies: Vec::new(),
+            allow_update: Vec::new(),
+            transfer_keys: Vec::new(),
+            update_keys: Vec::new(),
+            primary_key: None,
+            saved_path,
+        }
+    }
+
+    /// Read the zone file again, noting what's changed in the journal. A reload which doesn't
+    /// increase the serial is ignored, since secondaries would never see it.
+    fn reload(&mut self, modified: SystemTime) -> anyhow::Result<()> {
+        let Source::File {
+            path,
+            modified: last_modified,
+        } = &mut self.source
+        else {
+            return Ok(());
+        };
+        *last_modified = Some(modified);
+        let data = read_zone_file(&self.origin, path)?;
+        if let Some(old_data) = &self.data {
+            let old_serial = serial(&old_data.soa);
+            let new_serial = serial(&data.soa);
+            if !serial_lt(old_serial, new_serial) {
+                // Secondaries would never see the change, so keep serving what they have
+                anyhow::bail!(
+                    "serial went from {old_serial} to {new_serial}, keeping the old zone"
+                );
+            }
+        }
+        self.replace(data);
+        self.queue_notifies();
+        Ok(())
+    }
+
+    /// Switch to a whole new version of the zone, noting what's changed in the journal.
+    fn replace(&mut self, data: ZoneData) {
+        if let Some(old_data) = self.data.take() {
+            let old_records = records_without_soa(&old_data.records);
+            let new_records = records_without_soa(&data.records);
+            let old_keys = old_records
+                .iter()
+                .map(|record| record_key(record))
+                .collect::<HashSet<_>>();
+            let new_keys = new_records
+                .iter()
+                .map(|record| record_key(record))
+                .collect::<HashSet<_>>();
+            self.record_change(Change {
+                old_soa: old_data.soa.clone(),
+                removed: old_records
+                    .into_iter()
+                    .filter(|record| !new_keys.contains(&record_key(record)))
+                    .cloned()
+                    .collect(),
+                new_soa: data.soa.clone(),
+                added: new_records
+                    .into_iter()
+                    .filter(|record| !old_keys.contains(&record_key(record)))
+                    .cloned()
+                    .collect(),
+            });
+        }
+        self.data = Some(data);
+    }
+
+    fn record_change(&mut self, change: Change) {
+        if self.journal.len() >= MAX_JOURNAL_ENTRIES {
+            self.journal.pop_front();
+        }
+        self.journal.push_back(change);
+    }
+
+    /// Bring the zone up to date with a transfer from the primary, and save it.
+    fn apply(&mut self, transfer: Transfer) -> anyhow::Result<()> {
+        match transfer {
+            Transfer::UpToDate => return Ok(()),
+            Transfer::Full(records) => {
+                let data = zone_data(&self.origin, records)?;
+                self.replace(data);
+            }
+            Transfer::Incremental(changes) => {
+                let data = self
+                    .data
+                    .as_mut()
+                    .ok_or_else(|| anyhow::format_err!("incremental transfer with no zone"))?;
+                // Checked up front, so a bad transfer leaves the zone as it was
+                let mut expected_serial = serial(&data.soa);
+                for change in changes.iter() {
+                    if serial(&change.old_soa) != expected_serial {
+                        anyhow::bail!(
+                            "incremental transfer from serial {} doesn't follow on from {}",
+                            serial(&change.old_soa),
+                            expected_serial
+                        );
+                    }
+                    expected_serial = serial(&change.new_soa);
+                }
+                for change in changes.iter() {
+                    data.apply(change);
+                }
+                for change in changes {
+                    self.record_change(change);
+                }
+            }
+        }
+        self.queue_notifies();
+        self.save()
+    }
+
+    /// Tell every secondary about the current serial, replacing any NOTIFY still pending for an
+    /// older one.
+    fn queue_notifies(&mut self) {
+        let Some(data) = &self.data else {
+            return;
+        };
+        let serial = serial(&data.soa);
+        self.pending_notifies = self
+            .notify
+            .iter()
+            .map(|secondary| PendingNotify {
+                secondary: *secondary,
+                serial,
+                attempts: 0,
+                next_at: Instant::now(),
+                in_flight: false,
+            })
+            .collect();
+    }
+
+    /// Write the zone to disk, through a temporary file so it's never half written.
+    fn save(&self) -> anyhow::Result<()> {
+        let Some(data) = &self.data else {
+            return Ok(());
+        };
+        let mut records = vec![data.soa.clone()];
+        records.extend(records_without_soa(&data.records).into_iter().cloned());
+        let temporary_path = self.saved_path.with_extension("tmp");
+        fs::write(&temporary_path, zone_file::write(&records)?)?;
+        fs::rename(&temporary_path, &self.saved_path)?;
+        Ok(())
+    }
+
+    /// Check and apply an UPDATE, bumping the serial if anything changed.
+    fn update(
+        &mut self,
+        query_message: &Message,
+        source: SocketAddr,
+        key: Option<&Key>,
+    ) -> Result<(), ResponseCode> {
+        if let Source::Primary { .. } = self.source {
+            // Updates would have to be forwarded to the primary, which isn't supported
+            return Err(ResponseCode::NotImplemented);
+        }
+        let allowed_by_address = self
+            .allow_update
+            .iter()
+            .any(|cidr| cidr.contains(source.ip()));
+        let allowed_by_key = key.is_some_and(|key| {
+            self.update_keys
+                .iter()
+                .any(|update_key| update_key.name == key.name)
+        });
+        if !allowed_by_address && !allowed_by_key {
+            return Err(ResponseCode::Refused);
+        }
+        let data = self.data.as_ref().ok_or(ResponseCode::ServerFailure)?;
+        update::check_prerequisites(&data.records, &self.origin, &query_message.answers)?;
+        update::check_updates(&self.origin, &query_message.authorities)?;
+
+        let mut records = data.records.clone();
+        update::apply_updates(&mut records, &self.origin, &query_message.authorities);
+        let mut new_data = zone_data(&self.origin, records.into_values().flatten().collect())
+            .map_err(|_| ResponseCode::ServerFailure)?;
+        let contents = |data: &ZoneData| {
+            let mut keys = data
+                .records
+                .values()
+                .flatten()
+                .map(record_key)
+                .collect::<Vec<_>>();
+            keys.sort();
+            keys
+        };
+        if contents(data) == contents(&new_data) {
+            return Ok(());
+        }
+        if serial(&new_data.soa) == serial(&data.soa) {
+            // The update didn't set a later serial itself
+            let mut soa = new_data.soa.clone();
+            if let ResourceRecordData::StartOfAuthority { serial, .. } = &mut soa.data {
+                *serial = serial.wrapping_add(1);
+            }
+            let records = new_data.records.entry(self.origin.clone()).or_default();
+            records.retain(|record| record.ty != RecordType::StartOfAuthority);
+            records.push(soa.clone());
+            new_data.soa = soa;
+        }
+        self.replace(new_data);
+        self.queue_notifies();
+        if let Err(e) = self.save() {
+            eprintln!("error saving zone {}: {}", self.origin, e);
+        }
+        Ok(())
+    }
+
+    /// Set when to next refresh a secondary zone from the SOA timers: after the refresh
+    /// interval if this refresh worked, or the retry interval if not. A zone which has gone
+    /// unrefreshed for the expire interval stops being answered.
+    fn schedule_refresh(&mut self, refreshed: bool) {
+        let now = Instant::now();
+        let Source::Primary {
+            refresh_at,
+            expire_at,
+            retry,
+            ..
+        } = &mut self.source
+        else {
+            return;
+        };
+        match (&self.data, refreshed) {
+            (Some(data), true) => {
+                *refresh_at = now + data.timer(Timer::Refresh);
+                *expire_at = Some(now + data.timer(Timer::Expire));
+                *retry = data.timer(Timer::Retry);
+            }
+            (Some(_), false) => {
+                *refresh_at = now + *retry;
+                if expire_at.is_some_and(|expire_at| expire_at <= now) {
+                    eprintln!("zone {} has expired", self.origin);
+                    self.data = None;
+                    *expire_at = None;
+                }
+            }
+            (None, _) => *refresh_at = now + *retry,
+        }
+    }
+
+    fn answer(&self, question: &Question) -> ZoneAnswer {
+        match &self.data {
+            Some(data) => data.answer(&self.origin, question),
+            None => ZoneAnswer {
+                response_code: ResponseCode::ServerFailure,
+                authoritative: false,
+                answers: Vec::new(),
+                authorities: Vec::new(),
+                additionals: Vec::new(),
+            },
+        }
+    }
+
+    /// The changes since `serial` as IXFR sends them, if the journal goes back that far. A
+    /// client which is up to date just gets the SOA.
+    fn changes_since(&self, data: &ZoneData, serial: u32) -> Option<Vec<ResourceRecord>> {
+        if !serial_lt(serial, self::serial(&data.soa)) {
+            return Some(vec![data.soa.clone()]);
+        }
+        let start = self
+            .journal
+            .iter()
+            .position(|change| self::serial(&change.old_soa) == serial)?;
+        let mut records = vec![data.soa.clone()];
+        for change in self.journal.iter().skip(start) {
+            records.push(change.old_soa.clone());
+            records.extend(change.removed.iter().cloned());
+            records.push(change.new_soa.clone());
+            records.extend(change.added.iter().cloned());
+        }
+        records.push(data.soa.clone());
+        Some(records)
+    }
+}
+
+/// The SOA timers which schedule refreshes of secondary zones (RFC 1035 section 3.3.13).
+#[derive(Debug, Clone, Copy)]
+enum Timer {
+    Refresh,
+    Retry,
+    Expire,
+}
+
+impl ZoneData {
+    fn answer(&self, origin: &DomainName, question: &Question) -> ZoneAnswer {
+        let mut answer = ZoneAnswer {
+            response_code: ResponseCode::Ok,
+            authoritative: true,
+            answers: Vec::new(),
+            authorities: Vec::new(),
+            additionals: Vec::new(),
+        };
+        let mut name = question.name.clone();
+        for _ in 0..MAX_ALIAS_CHAIN {
+            if let Some(cut) = self.delegation(origin, &name, question.ty) {
+                // Refer the client to the child zone's servers, along with any addresses we have
+                // for them
+                let name_servers = self.records[&cut]
+                    .iter()
+                    .filter(|record| record.ty == RecordType::NameServer)
+                    .cloned()
+                    .collect::<Vec<_>>();
+                for name_server in name_servers.iter() {
+                    let ResourceRecordData::NameServer(host) = &name_server.data else {
+                        continue;
+                    };
+                    answer.additionals.extend(
+                        self.records
+                            .get(host)
+                            .into_iter()
+                            .flatten()
+                            .filter(|record| {
+                                matches!(record.ty, RecordType::Address | RecordType::Ipv6Address)
+                            })
+                            .cloned(),
+                    );
+                }
+                // Any aliases which led here are still ours
+                answer.authoritative = !answer.answers.is_empty();
+                answer.authorities = name_servers;
+                return answer;
+            }
+
+            let Some(records) = self.lookup(origin, &name) else {
+                // Names with nothing of their own but with names beneath them still exist
+                if !self.exists(&name) {
+                    answer.response_code = ResponseCode::NameError;
+                }
+                answer.authorities.push(self.negative_soa());
+                return answer;
+            };
+            let matching = records
+                .iter()
+                .filter(|record| record.ty == question.ty)
+                .cloned()
+                .collect::<Vec<_>>();
+            if !matching.is_empty() {
+                answer.answers.extend(matching);
+                return answer;
+            }
+            let cname = records
+                .iter()
+                .find(|record| record.ty == RecordType::CName && question.ty != RecordType::CName);
+            let Some(cname) = cname else {
+                answer.authorities.push(self.negative_soa());
+                return answer;
+            };
+            answer.answers.push(cname.clone());
+            match &cname.data {
+                ResourceRecordData::CName(target) if target.is_subdomain_of(origin) => {
+                    name = target.clone();
+                }
+                // Aliases out of the zone are resolved elsewhere
+                _ => return answer,
+            }
+        }
+        answer
+    }
+
+    /// The delegation `name` is at or beneath, if any. The parent side of a delegation is only
+    /// authoritative for DS records.
+    fn delegation(
+        &self,
+        origin: &DomainName,
+        name: &DomainName,
+        ty: RecordType,
+    ) -> Option<DomainName> {
+        (origin.label_count() + 1..=name.label_count())
+            .map(|label_count| name.ancestor(label_count))
+            .find(|ancestor| {
+                let is_cut = self.records.get(ancestor).is_some_and(|records| {
+                    records
+                        .iter()
+                        .any(|record| record.ty == RecordType::NameServer)
+                });
+                is_cut && !(ancestor == name && ty == RecordType::DelegationSigner)
+            })
+    }
+
+    /// The records at `name`, or synthesized from a wildcard if there are none (RFC 4592).
+    fn lookup(&self, origin: &DomainName, name: &DomainName) -> Option<Vec<ResourceRecord>> {
+        if let Some(records) = self.records.get(name) {
+            return Some(records.clone());
+        }
+        // The wildcard at the closest encloser, the deepest ancestor which exists
+        let closest_encloser = (origin.label_count()..name.label_count())
+            .rev()
+            .map(|label_count| name.ancestor(label_count))
+            .find(|ancestor| self.exists(ancestor))?;
+        let wildcard = DomainName::new("*").ok()?.join(&closest_encloser);
+        let records = self.records.get(&wildcard)?;
+        Some(
+            records
+                .iter()
+                .map(|record| {
+                    let mut record = record.clone();
+                    record.name = name.clone();
+                    record
+                })
+                .collect(),
+        )
+    }
+
+    /// Whether `name` or any name beneath it has records. The first name at or after it in
+    /// canonical order is the only one which needs checking.
+    fn exists(&self, name: &DomainName) -> bool {
+        self.names
+            .range(CanonicalName(name.clone())..)
+            .next()
+            .is_some_and(|owner| owner.0.is_subdomain_of(name))
+    }
+
+    /// The SOA to send with negative answers, with the TTL they're cached for (RFC 2308
+    /// section 5).
+    fn negative_soa(&self) -> ResourceRecord {
+        let mut soa = self.soa.clone();
+        if let ResourceRecordData::StartOfAuthority { minimum, .. } = soa.data {
+            soa.time_to_live = soa.time_to_live.min(minimum);
+        }
+        soa
+    }
+
+    /// The whole zone as AXFR sends it, starting and ending with the SOA.
+    fn contents(&self) -> Vec<ResourceRecord> {
+        let mut records = vec![self.soa.clone()];
+        records.extend(records_without_soa(&self.records).into_iter().cloned());
+        records.push(self.soa.clone());
+        records
+    }
+
+    /// An SOA timer, from the seconds in the SOA.
+    fn timer(&self, timer: Timer) -> Duration {
+        let ResourceRecordData::StartOfAuthority {
+            refresh,
+            retry,
+            expire,
+            ..
+        } = self.soa.data
+        else {
+            return INITIAL_RETRY;
+        };
+        let seconds = match timer {
+            Timer::Refresh => refresh,
+            Timer::Retry => retry,
+            Timer::Expire => expire,
+        };
+        Duration::from_secs(seconds.into())
+    }
+
+    /// Apply one change from an incremental transfer.
+    fn apply(&mut self, change: &Change) {
+        let removed = change
+            .removed
+            .iter()
+            .map(record_key)
+            .collect::<HashSet<_>>();
+        for records in self.records.values_mut() {
+            records.retain(|record| {
+                record.ty == RecordType::StartOfAuthority || !removed.contains(&record_key(record))
+            });
+        }
+        for record in change.added.iter() {
+            self.records
+                .entry(record.name.clone())
+                .or_default()
+                .push(record.clone());
+            self.names.insert(CanonicalName(record.name.clone()));
+        }
+        let names = &mut self.names;
+        self.records.retain(|name, records| {
+            if records.is_empty() {
+                names.remove(&CanonicalName(name.clone()));
+            }
+            !records.is_empty()
+        });
+        let soa = self.records.entry(change.new_soa.name.clone()).or_default();
+        soa.retain(|record| record.ty != RecordType::StartOfAuthority);
+        soa.push(change.new_soa.clone());
+        self.soa = change.new_soa.clone();
+    }
+}
+
+/// A zone saved after a transfer or UPDATE, if there is one.
+fn read_saved_zone(origin: &DomainName, path: &PathBuf) -> Option<ZoneData> {
+    match read_zone_file(origin, path) {
+        Ok(data) => Some(data),
+        Err(e) => {
+            if path.exists() {
+                eprintln!("error loading saved zone {}: {}", origin, e);
+            }
+            None
+        }
+    }
+}
+
+fn read_zone_file(origin: &DomainName, path: &PathBuf) -> anyhow::Result<ZoneData> {
+    let contents = fs::read_to_string(path)?;
+    zone_data(origin, zone_file::parse(&contents, origin)?)
+}
+
+/// A zone's records, checked to belong to it and to have one SOA, at its origin.
+fn zone_data(origin: &DomainName, records: Vec<ResourceRecord>) -> anyhow::Result<ZoneData> {
+    let mut soa = None;
+    let mut records_by_name = HashMap::<_, Vec<_>>::new();
+    for record in records {
+        if !record.name.is_subdomain_of(origin) {
+            anyhow::bail!("{} is outside of the zone", record.name);
+        }
+        if record.ty == RecordType::StartOfAuthority {
+            if record.name != *origin || soa.is_some() {
+                anyhow::bail!("a zone should have one SOA, at its origin");
+            }
+            soa = Some(record.clone());
+        }
+        records_by_name
+            .entry(record.name.clone())
+            .or_default()
+            .push(record);
+    }
+    let soa = soa.ok_or_else(|| anyhow::format_err!("no SOA record"))?;
+    let names = records_by_name.keys().cloned().map(CanonicalName).collect();
+    Ok(ZoneData {
+        soa,
+        records: records_by_name,
+        names,
+    })
+}
+
+impl Ord for CanonicalName {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.canonical_cmp(&other.0)
+    }
+}
+
+impl PartialOrd for CanonicalName {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Every record in a zone but the SOA, in canonical order.
+fn records_without_soa(records: &HashMap<DomainName, Vec<ResourceRecord>>) -> Vec<&ResourceRecord> {
+    let mut records = records
+        .values()
+        .flatten()
+        .filter(|record| record.ty != RecordType::StartOfAuthority)
+        .collect::<Vec<_>>();
+    records.sort_by(|a, b| a.name.canonical_cmp(&b.name));
+    records
+}
+
+/// What identifies a record when comparing versions of a zone.
+pub fn record_key(record: &ResourceRecord) -> Vec<u8> {
+    let mut record = record.clone();
+    record.name = record.name.to_lowercase();
+    record.data = record.data.to_canonical();
+    let mut key = Vec::new();
+    record
+        .write(&mut key)
+        .expect("records from zone files should be writable");
+    key
+}
+
+pub fn serial(soa: &ResourceRecord) -> u32 {
+    match soa.data {
+        ResourceRecordData::StartOfAuthority { serial, .. } => serial,
+        _ => 0,
+    }
+}
+
+/// Whether serial `a` comes before `b`, allowing for wrapping around (RFC 1982).
+pub fn serial_lt(a: u32, b: u32) -> bool {
+    a != b && b.wrapping_sub(a) < 0x8000_0000
+}
+
+/// `records` split into as few replies to `query_message` as fit the size limit. Every message
+/// repeats the question, which RFC 5936 section 2.2 allows.
+fn transfer_messages(query_message: &Message, records: Vec<ResourceRecord>) -> Vec<Message> {
+    let new_message = || {
+        let mut message =
+            Message::new_reply(query_message, query_message.questions.clone(), Vec::new());
+        message.header.authoritative_answer = true;
+        message
+    };
+    let header_length = 12
+        + query_message
+            .questions
+            .iter()
+            .map(|question| question.name.length() as usize + 4)
+            .sum::<usize>();
+
+    let mut messages = Vec::new();
+    let mut message = new_message();
+    let mut length = header_length;
+    for record in records {
+        let record_length = record.name.length() as usize + 10 + record.length as usize;
+        if length + record_length > MAX_TRANSFER_MESSAGE_SIZE && !message.answers.is_empty() {
+            messages.push(std::mem::replace(&mut message, new_message()));
+            length = header_length;
+        }
+        length += record_length;
+        message.answers.push(record);
+    }
+    messages.push(message);
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env, mem,
+        net::{IpAddr, UdpSocket},
+        process,
+        sync::{
+            mpsc::{self, Receiver},
+            RwLockWriteGuard,
+        },
+    };
+
+    use bytes::BytesMut;
+
+    use crate::{
+        message::{Class, OpCode},
+        secondary::tests::StandInPrimary,
+    };
+
+    use super::*;
+
+    fn name(name: &str) -> DomainName {
+        DomainName::new(name).unwrap()
+    }
+
+    /// Version `serial` of `example.`, with its SOA first.
+    fn records(serial: u32, contents: &str) -> Vec<ResourceRecord> {
+        let zone = format!(
+            "$TTL 300\n@ SOA ns.example. admin.example. {serial} 3600 600 86400 60\n{contents}"
+        );
+        zone_file::parse(&zone, &name("example")).unwrap()
+    }
+
+    /// `example.` as a secondary zone of `primary`, saved in a directory of its own.
+    fn secondary(primary: &StandInPrimary, test: &str) -> (Zones, PathBuf) {
+        let directory = env::temp_dir().join(format!("dns-server-test-{}-{test}", process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let config = ZonesConfig {
+            secondaries: vec![(name("example"), primary.addr)],
+            directory: directory.clone(),
+            ..ZonesConfig::default()
+        };
+        (Zones::load(&config, &[]).unwrap(), directory)
+    }
+
+    fn zone(zones: &Zones) -> RwLockWriteGuard<'_, Zone> {
+        zones.zones[0].1.write().unwrap()
+    }
+
+    /// Make the zone due a refresh, as if its refresh or retry time had passed.
+    fn make_due(zones: &Zones) {
+        if let Source::Primary { refresh_at, .. } = &mut zone(zones).source {
+            *refresh_at = Instant::now();
+        }
+    }
+
+    /// How long until the zone is next refreshed, rounded to the second.
+    fn refresh_in(zones: &Zones) -> u64 {
+        match zone(zones).source {
+            Source::Primary { refresh_at, .. } => {
+                let remaining = refresh_at.saturating_duration_since(Instant::now());
+                (remaining + Duration::from_millis(500)).as_secs()
+            }
+            _ => panic!("expected a secondary zone"),
+        }
+    }
+
+    fn answer(zones: &Zones, qname: &str) -> ZoneAnswer {
+        zones
+            .answer(&Question {
+                name: name(qname),
+                ty: RecordType::Address,
+                class: Class::Internet,
+            })
+            .unwrap()
+    }
+
+    fn addresses(zones: &Zones, qname: &str) -> Vec<[u8; 4]> {
+        answer(zones, qname)
+            .answers
+            .iter()
+            .filter_map(|record| match record.data {
+                ResourceRecordData::IPv4(ip) => Some(ip),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The queries the primary has had since the last call.
+    fn queries(primary: &StandInPrimary) -> Vec<RecordType> {
+        mem::take(&mut primary.state.lock().unwrap().queries)
+    }
+
+    #[test]
+    fn secondary_zones_are_transferred_and_saved() {
+        let primary = StandInPrimary::start(records(1, "www A 192.0.2.1\n"));
+        let (zones, directory) = secondary(&primary, "axfr");
+
+        // Not answered until the first transfer
+        assert!(matches!(
+            answer(&zones, "www.example").response_code,
+            ResponseCode::ServerFailure
+        ));
+        zones.maintain();
+        assert_eq!(queries(&primary), vec![RecordType::StartOfAuthority, AXFR]);
+        assert_eq!(addresses(&zones, "www.example"), vec![[192, 0, 2, 1]]);
+        assert_eq!(refresh_in(&zones), 3600);
+
+        // Nothing more until the refresh time, then only the SOA while the serial stays put
+        zones.maintain();
+        assert!(queries(&primary).is_empty());
+        make_due(&zones);
+        zones.maintain();
+        assert_eq!(queries(&primary), vec![RecordType::StartOfAuthority]);
+
+        // The saved copy is there on restart
+        let saved = read_saved_zone(&name("example"), &directory.join("example.zone")).unwrap();
+        assert_eq!(serial(&saved.soa), 1);
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn ixfr_falls_back_to_axfr() {
+        let primary = StandInPrimary::start(records(1, "www A 192.0.2.1\n"));
+        let (zones, directory) = secondary(&primary, "ixfr");
+        zones.maintain();
+        queries(&primary);
+
+        // A primary without IXFR sends the whole zone instead
+        primary.state.lock().unwrap().records = records(2, "www A 192.0.2.2\n");
+        make_due(&zones);
+        zones.maintain();
+        assert_eq!(
+            queries(&primary),
+            vec![RecordType::StartOfAuthority, IXFR, AXFR]
+        );
+        assert_eq!(addresses(&zones, "www.example"), vec![[192, 0, 2, 2]]);
+
+        // One with it only sends the changes
+        let old = records(2, "www A 192.0.2.2\n");
+        let new = records(3, "www A 192.0.2.3\n");
+        {
+            let mut state = primary.state.lock().unwrap();
+            state.ixfr = Some(vec![
+                new[0].clone(),
+                old[0].clone(),
+                old[1].clone(),
+                new[0].clone(),
+                new[1].clone(),
+                new[0].clone(),
+            ]);
+            state.records = new;
+        }
+        make_due(&zones);
+        zones.maintain();
+        assert_eq!(queries(&primary), vec![RecordType::StartOfAuthority, IXFR]);
+        assert_eq!(addresses(&zones, "www.example"), vec![[192, 0, 2, 3]]);
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn failed_refreshes_retry_then_expire() {
+        let primary = StandInPrimary::start(records(1, "www A 192.0.2.1\n"));
+        let (zones, directory) = secondary(&primary, "expire");
+        zones.maintain();
+
+        // A failure is retried after the SOA retry time, answering from the old copy meanwhile
+        primary.state.lock().unwrap().refuse = true;
+        make_due(&zones);
+        zones.maintain();
+        assert_eq!(refresh_in(&zones), 600);
+        assert_eq!(addresses(&zones, "www.example"), vec![[192, 0, 2, 1]]);
+
+        // Until the expire time has passed without a refresh
+        if let Source::Primary { expire_at, .. } = &mut zone(&zones).source {
+            *expire_at = Some(Instant::now());
+        }
+        make_due(&zones);
+        zones.maintain();
+        assert!(matches!(
+            answer(&zones, "www.example").response_code,
+            ResponseCode::ServerFailure
+        ));
+        assert_eq!(refresh_in(&zones), 600);
+
+        // Once the primary is back, the whole zone is transferred again
+        primary.state.lock().unwrap().refuse = false;
+        queries(&primary);
+        make_due(&zones);
+        zones.maintain();
+        assert_eq!(queries(&primary), vec![RecordType::StartOfAuthority, AXFR]);
+        assert_eq!(addresses(&zones, "www.example"), vec![[192, 0, 2, 1]]);
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn notify_from_the_primary_refreshes() {
+        let primary = StandInPrimary::start(records(1, "www A 192.0.2.1\n"));
+        let (zones, directory) = secondary(&primary, "notify");
+        zones.maintain();
+        primary.state.lock().unwrap().records = records(2, "www A 192.0.2.2\n");
+
+        let mut notify_message = Message::new_query(vec![Question {
+            name: name("example"),
+            ty: RecordType::StartOfAuthority,
+            class: Class::Internet,
+        }]);
+        notify_message.header.op_code = OpCode::Notify;
+
+        // Only the primary can trigger a refresh
+        let stranger = SocketAddr::new(IpAddr::from([192, 0, 2, 99]), 53);
+        let response_message = zones.notify(&notify_message, stranger, None);
+        assert!(matches!(
+            response_message.header.response_code,
+            ResponseCode::Refused
+        ));
+        assert_eq!(refresh_in(&zones), 3600);
+
+        // From any port, since it may not send from the one it listens on
+        let source = SocketAddr::new(primary.addr.ip(), 5300);
+        let response_message = zones.notify(&notify_message, source, None);
+        assert!(matches!(
+            response_message.header.response_code,
+            ResponseCode::Ok
+        ));
+        assert!(response_message.header.authoritative_answer);
+        assert_eq!(refresh_in(&zones), 0);
+        zones.maintain();
+        assert_eq!(addresses(&zones, "www.example"), vec![[192, 0, 2, 2]]);
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    /// The zone file for version `serial` of `example.`.
+    fn zone_file(serial: u32, contents: &str) -> String {
+        format!("$TTL 300\n@ SOA ns.example. admin.example. {serial} 3600 600 86400 60\n{contents}")
+    }
+
+    /// `example.` as a primary zone loaded from a file, in a directory of its own.
+    fn primary(test: &str, contents: &str) -> (Zones, PathBuf) {
+        let directory = env::temp_dir().join(format!("dns-server-test-{}-{test}", process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("example.zone.in");
+        fs::write(&path, zone_file(1, contents)).unwrap();
+        let config = ZonesConfig {
+            primaries: vec![(name("example"), path)],
+            directory: directory.clone(),
+            ..ZonesConfig::default()
+        };
+        (Zones::load(&config, &[]).unwrap(), directory)
+    }
+
+    fn transfer_query(ty: RecordType, client_serial: Option<u32>) -> Message {
+        let mut query_message = Message::new_query(vec![Question {
+            name: name("example"),
+            ty,
+            class: Class::Internet,
+        }]);
+        query_message
+            .authorities
+            .extend(client_serial.map(|serial| records(serial, "")[0].clone()));
+        query_message
+    }
+
+    /// The records sent in a transfer, as their owner and serial or address.
+    fn transferred(messages: &[Message]) -> Vec<String> {
+        messages
+            .iter()
+            .flat_map(|message| message.answers.iter())
+            .map(|record| match record.data {
+                ResourceRecordData::StartOfAuthority { serial, .. } => format!("SOA {serial}"),
+                ResourceRecordData::IPv4(ip) => format!("{} {:?}", record.name, ip),
+                _ => format!("{} {:?}", record.name, record.ty),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn aliases_wildcards_and_delegations_are_answered() {
+        let (zones, directory) = primary(
+            "answers",
+            "@ NS ns.example.\n\
+             ns A 192.0.2.53\n\
+             www A 192.0.2.1\n\
+             alias CNAME www\n\
+             chain CNAME alias\n\
+             outside CNAME www.example.net.\n\
+             loop1 CNAME loop2\n\
+             loop2 CNAME loop1\n\
+             *.wild A 192.0.2.2\n\
+             sub.wild A 192.0.2.3\n\
+             a.b.deep A 192.0.2.4\n\
+             child NS ns.child\n\
+             ns.child A 192.0.2.54\n\
+             to-child CNAME host.child\n",
+        );
+        let names = |records: &[ResourceRecord]| {
+            records
+                .iter()
+                .map(|record| record.name.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        // Aliases in the zone are followed, and those out of it are left to the client
+        let chain = answer(&zones, "chain.example");
+        assert_eq!(
+            names(&chain.answers),
+            ["chain.example", "alias.example", "www.example"]
+        );
+        assert_eq!(addresses(&zones, "chain.example"), vec![[192, 0, 2, 1]]);
+        let outside = answer(&zones, "outside.example");
+        assert_eq!(names(&outside.answers), ["outside.example"]);
+        assert!(outside.authoritative);
+        assert_eq!(
+            answer(&zones, "loop1.example").answers.len(),
+            MAX_ALIAS_CHAIN
+        );
+
+        // Wildcards answer for names which don't exist beneath their closest encloser
+        assert_eq!(addresses(&zones, "a.wild.example"), vec![[192, 0, 2, 2]]);
+        assert_eq!(
+            names(&answer(&zones, "a.wild.example").answers),
+            ["a.wild.example"]
+        );
+        assert_eq!(addresses(&zones, "a.b.wild.example"), vec![[192, 0, 2, 2]]);
+        assert_eq!(addresses(&zones, "sub.wild.example"), vec![[192, 0, 2, 3]]);
+        let under_sub = answer(&zones, "a.sub.wild.example");
+        assert!(matches!(under_sub.response_code, ResponseCode::NameError));
+
+        // Names with only names beneath them exist, but have no data
+        let empty = answer(&zones, "b.deep.example");
+        assert!(matches!(empty.response_code, ResponseCode::Ok));
+        assert!(empty.answers.is_empty());
+        assert_eq!(empty.authorities[0].ty, RecordType::StartOfAuthority);
+        let missing = answer(&zones, "c.deep.example");
+        assert!(matches!(missing.response_code, ResponseCode::NameError));
+
+        // Delegations are referrals, with glue for the child's name servers
+        let referral = answer(&zones, "host.child.example");
+        assert!(!referral.authoritative && referral.answers.is_empty());
+        assert_eq!(names(&referral.authorities), ["child.example"]);
+        assert_eq!(names(&referral.additionals), ["ns.child.example"]);
+        // An alias into a delegation is still ours
+        let to_child = answer(&zones, "to-child.example");
+        assert!(to_child.authoritative);
+        assert_eq!(names(&to_child.answers), ["to-child.example"]);
+        assert_eq!(names(&to_child.authorities), ["child.example"]);
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn large_zones_are_transferred_in_several_messages() {
+        let mut contents = String::new();
+        for i in 0..1000 {
+            contents.push_str(&format!("host{i} TXT \"{}\"\n", "x".repeat(100)));
+        }
+        let (zones, directory) = primary("axfr-messages", &contents);
+        let messages = zones.transfer(&transfer_query(AXFR, None)).unwrap();
+
+        assert!(messages.len() > 1);
+        for message in messages.iter() {
+            let mut buf = Vec::new();
+            message.write(&mut buf).unwrap();
+            assert!(buf.len() <= MAX_TRANSFER_MESSAGE_SIZE);
+            assert!(message.header.authoritative_answer);
+            assert_eq!(message.questions.len(), 1);
+        }
+        let records = transferred(&messages);
+        assert_eq!(records.len(), 1002);
+        assert_eq!(records[0], "SOA 1");
+        assert_eq!(records[1001], "SOA 1");
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn ixfr_sends_changes_from_the_journal() {
+        let (zones, directory) = primary("ixfr-journal", "www A 192.0.2.1\n");
+        let path = directory.join("example.zone.in");
+        for (serial, contents) in [(2, "www A 192.0.2.2\n"), (3, "www A 192.0.2.3\n")] {
+            fs::write(&path, zone_file(serial, contents)).unwrap();
+            zone(&zones).reload(SystemTime::now()).unwrap();
+        }
+        let ixfr = |client_serial| {
+            transferred(
+                &zones
+                    .transfer(&transfer_query(IXFR, Some(client_serial)))
+                    .unwrap(),
+            )
+        };
+
+        assert_eq!(
+            ixfr(1),
+            [
+                "SOA 3",
+                "SOA 1",
+                "www.example [192, 0, 2, 1]",
+                "SOA 2",
+                "www.example [192, 0, 2, 2]",
+                "SOA 2",
+                "www.example [192, 0, 2, 2]",
+                "SOA 3",
+                "www.example [192, 0, 2, 3]",
+                "SOA 3",
+            ]
+        );
+        assert_eq!(
+            ixfr(2),
+            [
+                "SOA 3",
+                "SOA 2",
+                "www.example [192, 0, 2, 2]",
+                "SOA 3",
+                "www.example [192, 0, 2, 3]",
+                "SOA 3",
+            ]
+        );
+        // Up to date
+        assert_eq!(ixfr(3), ["SOA 3"]);
+        // Further back than the journal goes, so the whole zone is sent as AXFR would
+        let whole_zone = ["SOA 3", "www.example [192, 0, 2, 3]", "SOA 3"];
+        assert_eq!(ixfr(0), whole_zone);
+        assert_eq!(
+            transferred(&zones.transfer(&transfer_query(AXFR, None)).unwrap()),
+            whole_zone
+        );
+        // IXFR needs the client's SOA
+        let messages = zones.transfer(&transfer_query(IXFR, None)).unwrap();
+        assert!(matches!(
+            messages[0].header.response_code,
+            ResponseCode::FormatError
+        ));
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    /// A secondary on a local UDP port which refuses the first NOTIFY and acknowledges the rest,
+    /// passing on the serial of each one it gets.
+    fn stand_in_secondary() -> (SocketAddr, Receiver<u32>) {
+        let udp_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = udp_socket.local_addr().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for attempt in 0.. {
+                let mut buf = [0; 512];
+                let (len, source) = udp_socket.recv_from(&mut buf).unwrap();
+                let query_message = Message::parse(&buf[..len]).unwrap();
+                assert_eq!(query_message.header.op_code, OpCode::Notify);
+                assert_eq!(query_message.questions[0].name, name("example"));
+                if sender.send(serial(&query_message.answers[0])).is_err() {
+                    return;
+                }
+                let response_code = match attempt {
+                    0 => ResponseCode::Refused,
+                    _ => ResponseCode::Ok,
+                };
+                let mut response = BytesMut::new();
+                Message::new_error_reply(&query_message, response_code)
+                    .write(&mut response)
+                    .unwrap();
+                udp_socket.send_to(&response, source).unwrap();
+            }
+        });
+        (addr, receiver)
+    }
+
+    /// Wait up to a few seconds for `condition` to hold, as it may be up to another thread.
+    fn wait_for(condition: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !condition() {
+            assert!(Instant::now() < deadline, "timed out waiting");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn notify_is_sent_until_acknowledged() {
+        let (secondary_addr, notifies) = stand_in_secondary();
+        let directory =
+            env::temp_dir().join(format!("dns-server-test-{}-notify-send", process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("example.zone.in");
+        fs::write(&path, zone_file(1, "www A 192.0.2.1\n")).unwrap();
+        let config = ZonesConfig {
+            primaries: vec![(name("example"), path.clone())],
+            directory: directory.clone(),
+            notify: vec![(name("example"), secondary_addr)],
+            ..ZonesConfig::default()
+        };
+        let zones = Zones::load(&config, &[]).unwrap();
+        let in_flight = || zone(&zones).pending_notifies.iter().any(|p| p.in_flight);
+
+        // A new version is announced
+        fs::write(&path, zone_file(2, "www A 192.0.2.2\n")).unwrap();
+        zones.maintain();
+        assert_eq!(notifies.recv_timeout(Duration::from_secs(5)).unwrap(), 2);
+
+        // The secondary refused it, so it's tried again after a while
+        wait_for(|| !in_flight());
+        {
+            let zone = zone(&zones);
+            let [pending] = zone.pending_notifies.as_slice() else {
+                panic!("expected a pending NOTIFY");
+            };
+            assert_eq!(pending.attempts, 1);
+            assert!(pending.next_at > Instant::now());
+        }
+        zones.maintain();
+        assert!(notifies.recv_timeout(Duration::from_millis(100)).is_err());
+
+        // Once it's acknowledged, there's nothing more to send
+        zone(&zones).pending_notifies[0].next_at = Instant::now();
+        zones.maintain();
+        assert_eq!(notifies.recv_timeout(Duration::from_secs(5)).unwrap(), 2);
+        wait_for(|| zone(&zones).pending_notifies.is_empty());
+        zones.maintain();
+        assert!(notifies.recv_timeout(Duration::from_millis(100)).is_err());
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn unanswered_notifies_dont_hold_up_maintenance() {
+        // Nothing listens here, so each NOTIFY waits until it times out
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let directory =
+            env::temp_dir().join(format!("dns-server-test-{}-notify-silent", process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("example.zone.in");
+        fs::write(&path, zone_file(1, "")).unwrap();
+        let config = ZonesConfig {
+            primaries: vec![(name("example"), path.clone())],
+            directory: directory.clone(),
+            notify: vec![(name("example"), silent.local_addr().unwrap()); 4],
+            ..ZonesConfig::default()
+        };
+        let zones = Zones::load(&config, &[]).unwrap();
+        fs::write(&path, zone_file(2, "")).unwrap();
+
+        let start = Instant::now();
+        zones.maintain();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(zone(&zones).pending_notifies.len(), 4);
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
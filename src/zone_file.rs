@@ -0,0 +1,545 @@
+//! Parsing of zone files in the RFC 1035 master file format.
+//!
+//! Supports `$ORIGIN` and `$TTL` directives, `@`, relative names, omitted owners, parentheses
+//! spanning several lines and `;` comments. `$INCLUDE` isn't supported. Record data of any type
+//! can be given in the generic `\# <length> <hex>` format of RFC 3597, which is also how `write`
+//! saves it.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use crate::{
+    encoding::{decode_base64, decode_hex, encode_hex},
+    message::{Class, DomainName, RecordType, ResourceRecord, ResourceRecordData},
+};
+
+/// TTL used when a file has neither a `$TTL` directive nor a TTL on its first record.
+const DEFAULT_TTL: u32 = 3600;
+
+#[derive(Debug)]
+struct Token {
+    text: String,
+    /// Quoted strings are never names, numbers or mnemonics.
+    quoted: bool,
+}
+
+/// A record's worth of tokens, with parentheses and comments removed.
+#[derive(Debug)]
+struct Entry {
+    /// The line started with whitespace, so the previous owner applies.
+    owner_omitted: bool,
+    tokens: Vec<Token>,
+    line_number: usize,
+}
+
+/// Parse the records in a zone file, with relative names taken to be beneath `origin`.
+pub fn parse(contents: &str, origin: &DomainName) -> anyhow::Result<Vec<ResourceRecord>> {
+    let mut origin = origin.clone();
+    let mut default_ttl = None;
+    let mut last_ttl = None;
+    let mut last_owner: Option<DomainName> = None;
+    let mut records = Vec::new();
+
+    for entry in tokenize(contents)? {
+        let line_number = entry.line_number;
+        parse_entry(
+            entry,
+            &mut origin,
+            &mut default_ttl,
+            &mut last_ttl,
+            &mut last_owner,
+            &mut records,
+        )
+        .map_err(|e| anyhow::format_err!("line {line_number}: {e}"))?;
+    }
+    Ok(records)
+}
+
+fn parse_entry(
+    entry: Entry,
+    origin: &mut DomainName,
+    default_ttl: &mut Option<u32>,
+    last_ttl: &mut Option<u32>,
+    last_owner: &mut Option<DomainName>,
+    records: &mut Vec<ResourceRecord>,
+) -> anyhow::Result<()> {
+    let mut tokens = entry.tokens.into_iter().peekable();
+    if !entry.owner_omitted {
+        let Some(first) = tokens.peek() else {
+            return Ok(());
+        };
+        match first.text.as_str() {
+            "$ORIGIN" => {
+                tokens.next();
+                *origin = parse_name(&next_token(&mut tokens)?, origin)?;
+                return Ok(());
+            }
+            "$TTL" => {
+                tokens.next();
+                *default_ttl = Some(parse_ttl(&next_token(&mut tokens)?)?);
+                return Ok(());
+            }
+            "$INCLUDE" => anyhow::bail!("$INCLUDE isn't supported"),
+            _ => {
+                *last_owner = Some(parse_name(&next_token(&mut tokens)?, origin)?);
+            }
+        }
+    }
+    let owner = last_owner
+        .clone()
+        .ok_or_else(|| anyhow::format_err!("no owner name"))?;
+
+    // The TTL and class can come in either order, and both are optional
+    let mut ttl = None;
+    let mut class = None;
+    let ty = loop {
+        let token = next_token(&mut tokens)?;
+        if ttl.is_none() && token.starts_with(|c: char| c.is_ascii_digit()) {
+            ttl = Some(parse_ttl(&token)?);
+        } else if class.is_none() && token.parse::<Class>().is_ok() {
+            class = token.parse::<Class>().ok();
+        } else {
+            break token.parse::<RecordType>()?;
+        }
+    };
+    let rdata = tokens.collect::<Vec<_>>();
+    let data = parse_data(ty, &rdata, origin)?;
+
+    // Without a $TTL, records default to the TTL of the previous one
+    let ttl = match (ttl, default_ttl.or(*last_ttl), &data) {
+        (Some(ttl), _, _) => ttl,
+        (None, Some(ttl), _) => ttl,
+        (None, None, ResourceRecordData::StartOfAuthority { minimum, .. }) => *minimum,
+        (None, None, _) => DEFAULT_TTL,
+    };
+    *last_ttl = Some(ttl);
+
+    records.push(ResourceRecord::new(
+        owner,
+        ty,
+        class.unwrap_or(Class::Internet),
+        ttl,
+        data,
+    ));
+    Ok(())
+}
+
+fn parse_data(
+    ty: RecordType,
+    tokens: &[Token],
+    origin: &DomainName,
+) -> anyhow::Result<ResourceRecordData> {
+    let text = |i: usize| -> anyhow::Result<&str> {
+        tokens
+            .get(i)
+            .map(|t| t.text.as_str())
+            .ok_or_else(|| anyhow::format_err!("missing data for {ty:?} record"))
+    };
+    if tokens
+        .first()
+        .is_some_and(|token| token.text == "\\#" && !token.quoted)
+    {
+        return parse_generic_data(ty, &tokens[1..]);
+    }
+    let data = match ty {
+        RecordType::Address => ResourceRecordData::IPv4(text(0)?.parse::<Ipv4Addr>()?.octets()),
+        RecordType::Ipv6Address => ResourceRecordData::IPv6(text(0)?.parse::<Ipv6Addr>()?.octets()),
+        RecordType::NameServer => ResourceRecordData::NameServer(parse_name(text(0)?, origin)?),
+        RecordType::CName => ResourceRecordData::CName(parse_name(text(0)?, origin)?),
+        RecordType::Pointer => ResourceRecordData::Pointer(parse_name(text(0)?, origin)?),
+        RecordType::MailExchange => ResourceRecordData::MailExchange {
+            preference: text(0)?.parse()?,
+            exchange: parse_name(text(1)?, origin)?,
+        },
+        RecordType::Text => {
+            if tokens.is_empty() {
+                anyhow::bail!("missing data for {ty:?} record");
+            }
+            ResourceRecordData::Text(tokens.iter().map(|t| t.text.as_bytes().to_vec()).collect())
+        }
+        RecordType::StartOfAuthority => ResourceRecordData::StartOfAuthority {
+            primary_name_server: parse_name(text(0)?, origin)?,
+            responsible_mailbox: parse_name(text(1)?, origin)?,
+            serial: text(2)?.parse()?,
+            refresh: parse_ttl(text(3)?)?,
+            retry: parse_ttl(text(4)?)?,
+            expire: parse_ttl(text(5)?)?,
+            minimum: parse_ttl(text(6)?)?,
+        },
+        RecordType::DelegationSigner => ResourceRecordData::DelegationSigner {
+            key_tag: text(0)?.parse()?,
+            algorithm: text(1)?.parse()?,
+            digest_type: text(2)?.parse()?,
+            digest: decode_hex(&joined(&tokens[3.min(tokens.len())..], ty)?)?,
+        },
+        RecordType::DnsKey => ResourceRecordData::DnsKey {
+            flags: text(0)?.parse()?,
+            protocol: text(1)?.parse()?,
+            algorithm: text(2)?.parse()?,
+            public_key: decode_base64(&joined(&tokens[3.min(tokens.len())..], ty)?)?,
+        },
+        _ => anyhow::bail!("{ty:?} records aren't supported in zone files"),
+    };
+    Ok(data)
+}
+
+/// Data in the generic format, its length in bytes followed by the wire format in hex.
+fn parse_generic_data(ty: RecordType, tokens: &[Token]) -> anyhow::Result<ResourceRecordData> {
+    let length = tokens
+        .first()
+        .ok_or_else(|| anyhow::format_err!("missing data for {ty:?} record"))?
+        .text
+        .parse::<u16>()?;
+    let data = match length {
+        0 => Vec::new(),
+        _ => decode_hex(&joined(&tokens[1..], ty)?)?,
+    };
+    if data.len() != length as usize {
+        anyhow::bail!("{ty:?} record should have {length} bytes of data");
+    }
+    let mut wire = length.to_be_bytes().to_vec();
+    wire.extend_from_slice(&data);
+    let data = match ResourceRecordData::parse(&wire, ty) {
+        // Addresses of the wrong length are only kept as unknown data off the wire
+        Ok((_, ResourceRecordData::Unknown(_)))
+            if matches!(ty, RecordType::Address | RecordType::Ipv6Address) =>
+        {
+            anyhow::bail!("invalid data for {ty:?} record")
+        }
+        Ok((_, data)) => data,
+        Err(_) => anyhow::bail!("invalid data for {ty:?} record"),
+    };
+    Ok(data)
+}
+
+/// Write `records` in zone file format, with the data in the generic format so that every type
+/// reads back exactly.
+pub fn write(records: &[ResourceRecord]) -> anyhow::Result<String> {
+    let mut contents = String::new();
+    for record in records {
+        let mut data = Vec::new();
+        record.data.write(&mut data)?;
+        // Skip the length which starts the data
+        let data = &data[2..];
+        let name = match record.name.label_count() {
+            0 => ".".to_string(),
+            _ => format!("{}.", record.name),
+        };
+        contents.push_str(&format!(
+            "{} {} CLASS{} TYPE{} \\# {} {}\n",
+            name,
+            record.time_to_live,
+            u16::from(record.class),
+            u16::from(record.ty),
+            data.len(),
+            encode_hex(data)
+        ));
+    }
+    Ok(contents)
+}
+
+/// Binary data which may be split by whitespace, like a base64 key.
+fn joined(tokens: &[Token], ty: RecordType) -> anyhow::Result<String> {
+    if tokens.is_empty() {
+        anyhow::bail!("missing data for {ty:?} record");
+    }
+    Ok(tokens.iter().map(|t| t.text.as_str()).collect())
+}
+
+/// A possibly relative name: `@` is the origin itself, and names without a trailing dot are
+/// beneath the origin.
+pub fn parse_name(name: &str, origin: &DomainName) -> anyhow::Result<DomainName> {
+    if name == "@" {
+        Ok(origin.clone())
+    } else if name.ends_with('.') {
+        DomainName::new(name)
+    } else {
+        Ok(DomainName::new(name)?.join(origin))
+    }
+}
+
+/// A TTL in seconds, optionally written with units, e.g. `3600` or `1h30m`.
+pub fn parse_ttl(ttl: &str) -> anyhow::Result<u32> {
+    if let Ok(seconds) = ttl.parse() {
+        return Ok(seconds);
+    }
+    let mut total: u32 = 0;
+    let mut number = String::new();
+    for c in ttl.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let multiplier = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => anyhow::bail!("invalid ttl {ttl:?}"),
+        };
+        let value = number.parse::<u32>()?;
+        number.clear();
+        total = value
+            .checked_mul(multiplier)
+            .and_then(|value| total.checked_add(value))
+            .ok_or_else(|| anyhow::format_err!("ttl {ttl:?} is too large"))?;
+    }
+    if !number.is_empty() {
+        anyhow::bail!("invalid ttl {ttl:?}");
+    }
+    Ok(total)
+}
+
+fn next_token(tokens: &mut impl Iterator<Item = Token>) -> anyhow::Result<String> {
+    match tokens.next() {
+        Some(token) if !token.quoted => Ok(token.text),
+        Some(token) => anyhow::bail!("unexpected quoted string {:?}", token.text),
+        None => anyhow::bail!("unexpected end of line"),
+    }
+}
+
+/// Split a zone file into entries, joining lines within parentheses.
+fn tokenize(contents: &str) -> anyhow::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut entry: Option<Entry> = None;
+    let mut depth = 0;
+
+    for (i, line) in contents.lines().enumerate() {
+        let line_number = i + 1;
+        if depth == 0 {
+            if let Some(entry) = entry.take() {
+                entries.push(entry);
+            }
+            entry = Some(Entry {
+                owner_omitted: line.starts_with(char::is_whitespace),
+                tokens: Vec::new(),
+                line_number,
+            });
+        }
+        let tokens = &mut entry.as_mut().unwrap().tokens;
+
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                ';' => break,
+                '(' => depth += 1,
+                ')' => {
+                    if depth == 0 {
+                        anyhow::bail!("line {line_number}: unbalanced parentheses");
+                    }
+                    depth -= 1;
+                }
+                '"' => {
+                    let mut text = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('"') => break,
+                            Some('\\') => text.push(chars.next().ok_or_else(|| {
+                                anyhow::format_err!("line {line_number}: unterminated escape")
+                            })?),
+                            Some(c) => text.push(c),
+                            None => anyhow::bail!("line {line_number}: unterminated string"),
+                        }
+                    }
+                    tokens.push(Token { text, quoted: true });
+                }
+                c if c.is_whitespace() => {}
+                c => {
+                    let mut text = c.to_string();
+                    while let Some(&c) = chars.peek() {
+                        if c.is_whitespace() || matches!(c, ';' | '(' | ')' | '"') {
+                            break;
+                        }
+                        text.push(c);
+                        chars.next();
+                    }
+                    tokens.push(Token {
+                        text,
+                        quoted: false,
+                    });
+                }
+            }
+        }
+    }
+    if depth != 0 {
+        anyhow::bail!("unbalanced parentheses at end of file");
+    }
+    entries.extend(entry);
+    Ok(entries
+        .into_iter()
+        .filter(|e| !e.tokens.is_empty())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(name: &str) -> DomainName {
+        DomainName::new(name).unwrap()
+    }
+
+    /// The records in wire format, which can be compared.
+    fn wire(records: &[ResourceRecord]) -> Vec<Vec<u8>> {
+        records
+            .iter()
+            .map(|record| {
+                let mut buf = Vec::new();
+                record.write(&mut buf).unwrap();
+                buf
+            })
+            .collect()
+    }
+
+    #[test]
+    fn zone_files_are_parsed() {
+        let contents = r#"
+$ORIGIN example.
+$TTL 1h
+@   IN  SOA ns1 hostmaster (
+        2024010101 ; serial
+        2h 15m 2w 5m )
+    NS  ns1
+    IN 600 MX 10 mail.example.net.
+ns1 A   192.0.2.53
+www 60 IN AAAA 2001:db8::80 ; comment
+txt TXT "v=spf1 -all" "with \"quotes\"; and a semicolon"
+$ORIGIN sub
+host CNAME www.example.
+"#;
+        let records = parse(contents, &name("ignored")).unwrap();
+        let summary = records
+            .iter()
+            .map(|record| (record.name.to_string(), record.ty, record.time_to_live))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                ("example".to_string(), RecordType::StartOfAuthority, 3600),
+                ("example".to_string(), RecordType::NameServer, 3600),
+                ("example".to_string(), RecordType::MailExchange, 600),
+                ("ns1.example".to_string(), RecordType::Address, 3600),
+                ("www.example".to_string(), RecordType::Ipv6Address, 60),
+                ("txt.example".to_string(), RecordType::Text, 3600),
+                ("host.sub.example".to_string(), RecordType::CName, 3600),
+            ]
+        );
+
+        let ResourceRecordData::StartOfAuthority {
+            primary_name_server,
+            responsible_mailbox,
+            serial,
+            refresh,
+            retry,
+            expire,
+            minimum,
+        } = &records[0].data
+        else {
+            panic!("not an SOA record");
+        };
+        assert_eq!(primary_name_server, &name("ns1.example"));
+        assert_eq!(responsible_mailbox, &name("hostmaster.example"));
+        assert_eq!(
+            (*serial, *refresh, *retry, *expire, *minimum),
+            (2024010101, 7200, 900, 1209600, 300)
+        );
+        assert!(matches!(
+            &records[2].data,
+            ResourceRecordData::MailExchange { preference: 10, exchange }
+                if exchange == &name("mail.example.net")
+        ));
+        assert!(matches!(
+            &records[4].data,
+            ResourceRecordData::IPv6(addr)
+                if *addr == "2001:db8::80".parse::<Ipv6Addr>().unwrap().octets()
+        ));
+        assert!(matches!(
+            &records[5].data,
+            ResourceRecordData::Text(strings)
+                if strings == &[b"v=spf1 -all".to_vec(), b"with \"quotes\"; and a semicolon".to_vec()]
+        ));
+        assert!(matches!(
+            &records[6].data,
+            ResourceRecordData::CName(target) if target == &name("www.example")
+        ));
+    }
+
+    #[test]
+    fn ttls_default_to_the_previous_record_without_a_ttl_directive() {
+        let contents = "\
+example. SOA ns1.example. hostmaster.example. 1 3600 600 86400 300
+example. NS ns1.example.
+ns1.example. 120 A 192.0.2.53
+www.example. A 192.0.2.80
+";
+        let ttls = parse(contents, &name("example"))
+            .unwrap()
+            .iter()
+            .map(|record| record.time_to_live)
+            .collect::<Vec<_>>();
+        assert_eq!(ttls, [300, 300, 120, 120]);
+
+        let records = parse("www A 192.0.2.80", &name("example")).unwrap();
+        assert_eq!(records[0].time_to_live, DEFAULT_TTL);
+        assert_eq!(records[0].name, name("www.example"));
+    }
+
+    #[test]
+    fn written_records_read_back_exactly() {
+        let contents = "\
+$ORIGIN example.
+@ 3600 SOA ns1 hostmaster 1 3600 600 86400 300
+@ 3600 DNSKEY 257 3 15 ( 11qYAYKxCrfVS/7TyWQHOg7h
+    cvPapiMlrwIaaPcHURo= )
+@ 3600 DS 2371 15 2 ( 1F2C0E0D8B4F0AB2 1F5A2A6C29D91AC4
+    0E5F3B33C8A78D4D3E1C7F22BC9B0A3C )
+www 300 A 192.0.2.80
+txt 300 TXT \"hello\" world
+";
+        let records = parse(contents, &DomainName::new("").unwrap()).unwrap();
+        let written = write(&records).unwrap();
+        assert!(written.starts_with("example. 3600 CLASS1 TYPE6 \\# "));
+        let read_back = parse(&written, &name("elsewhere")).unwrap();
+        assert_eq!(wire(&read_back), wire(&records));
+
+        // Types without their own syntax can be given in the generic format
+        let records = parse("www.example. 60 TYPE1 \\# 4 C0000250", &name("example")).unwrap();
+        assert!(matches!(
+            records[0].data,
+            ResourceRecordData::IPv4([192, 0, 2, 80])
+        ));
+        let root = parse(". 60 IN TYPE65280 \\# 0", &name("example")).unwrap();
+        assert_eq!(write(&root).unwrap(), ". 60 CLASS1 TYPE65280 \\# 0 \n");
+    }
+
+    #[test]
+    fn errors_give_the_line() {
+        let cases = [
+            ("www A 192.0.2.80\nwww A 192.0.2", "line 2"),
+            ("www A", "line 1"),
+            ("www BOGUS 1", "line 1"),
+            ("$INCLUDE other.zone", "line 1"),
+            ("\n\nwww SOA ( ns1 hostmaster", "end of file"),
+            ("www A 192.0.2.80 )", "line 1"),
+            ("www TXT \"unterminated", "line 1"),
+            ("   A 192.0.2.80", "line 1"),
+            ("www A \\# 3 C00002", "line 1"),
+            ("www A \\# 4 C00002", "line 1"),
+        ];
+        for (contents, expected) in cases {
+            let error = parse(contents, &name("example")).unwrap_err().to_string();
+            assert!(error.contains(expected), "{contents:?}: {error}");
+        }
+    }
+
+    #[test]
+    fn ttls() {
+        assert_eq!(parse_ttl("3600").unwrap(), 3600);
+        assert_eq!(parse_ttl("1h30m").unwrap(), 5400);
+        assert_eq!(parse_ttl("1W2d3H4M5S").unwrap(), 788645);
+        assert!(parse_ttl("1h30").is_err());
+        assert!(parse_ttl("10y").is_err());
+        assert!(parse_ttl("h").is_err());
+        assert!(parse_ttl("100000w").is_err());
+    }
+}